@@ -1,7 +1,9 @@
 #![no_main]
 #![no_std]
 
-use adafruit_feather_nrf52840_express as _;
+use adafruit_feather_nrf52840_express::build_info;
+
+use core::iter;
 
 use rtic::app;
 
@@ -16,37 +18,219 @@ use psila_service::ClusterLibraryHandler;
 
 use nrf_smartled::pwm::Pwm;
 use smart_leds::{gamma, RGB8};
-use smart_leds_trait::SmartLedsWrite;
 
 use palette::{Pixel, Srgb, Yxy};
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use nrf52_utils::{
+    attribute::{Attribute, AttributeTable, Value},
+    basic::{
+        DeviceIdentity, BASIC_ATTR_APPLICATION_VERSION, BASIC_ATTR_DATE_CODE,
+        BASIC_ATTR_HW_VERSION, BASIC_ATTR_MANUFACTURER_NAME, BASIC_ATTR_MODEL_IDENTIFIER,
+        BASIC_ATTR_POWER_SOURCE, BASIC_ATTR_STACK_VERSION, BASIC_ATTR_SW_BUILD_ID,
+        BASIC_ATTR_ZCL_VERSION, CLUSTER_BASIC, POWER_SOURCE_MAINS, ZCL_VERSION,
+    },
+    binding::BindingTable,
+    discovery::{self, is_discovery_command, ClusterCommands},
+    groups::{
+        GroupTable, CLUSTER_GROUPS, GROUPS_ATTR_NAME_SUPPORT, GROUPS_COMMANDS_GENERATED,
+        GROUPS_COMMANDS_RECEIVED,
+    },
+    identify::{Effect, Identify, Indication, TICKS_PER_SECOND},
+    light::{LightState, StartUpOnOff},
+    outbox::{Message, Outbox, Recipient},
+    reporting::{ReportingTable, CMD_CONFIGURE_REPORTING, CMD_READ_REPORTING_CONFIGURATION},
+    reset::{handle_leave_request, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS},
+    scenes::{
+        Color, Recall, SceneState, SceneTable, CLUSTER_SCENES, SCENES_ATTR_CURRENT_GROUP,
+        SCENES_ATTR_CURRENT_SCENE, SCENES_ATTR_NAME_SUPPORT, SCENES_ATTR_SCENE_COUNT,
+        SCENES_ATTR_SCENE_VALID, SCENES_COMMANDS_GENERATED, SCENES_COMMANDS_RECEIVED,
+    },
+    service::Outgoing,
+    strip::{PowerBudget, Strip},
+    transition::Transition,
+};
+
 // Manufacturer name for this example
 const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
 // Model identifier for this example
 const MODEL_IDENTIFIER: &'static str = "Lampan";
+/// Hardware version, the board revision
+const HW_VERSION: u8 = 1;
+/// Psila has not been released, so there is no stack version yet
+const STACK_VERSION: u8 = 0;
+
+/// Identity of the light in the basic cluster
+const IDENTITY: DeviceIdentity = DeviceIdentity {
+    manufacturer_name: MANUFACTURER_NAME,
+    model_identifier: MODEL_IDENTIFIER,
+    application_version: build_info::APPLICATION_VERSION,
+    stack_version: STACK_VERSION,
+    hw_version: HW_VERSION,
+    date_code: build_info::DATE_CODE,
+    power_source: POWER_SOURCE_MAINS,
+    sw_build_id: build_info::SW_BUILD_ID,
+};
 
 /// Home automation profile
 const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
 /// Colour dimmable light device
 const DEVICE_COLOR_DIMMABLE_LIGHT: u16 = 0x0102;
 
-/// Basic cluster
-const CLUSTER_BASIC: u16 = 0x0000;
-/// Basic cluster attribute, library version
-const BASIC_ATTR_LIBRARY_VERSION: u16 = 0x0000;
-/// Basic cluster attribute, manufacturer name
-const BASIC_ATTR_MANUFACTURER_NAME: u16 = 0x0004;
-/// Basic cluster attribute, model identifier
-const BASIC_ATTR_MODEL_IDENTIFIER: u16 = 0x0005;
-/// Basic cluster attribute, power source
-const BASIC_ATTR_POWER_SOURCE: u16 = 0x0007;
+/// The onboard NeoPixel, a strip of one LED
+#[cfg(not(feature = "led-strip"))]
+const STRIP_LENGTH: usize = 1;
+#[cfg(not(feature = "led-strip"))]
+pub const SEGMENTS: usize = 1;
+/// A single LED stays below the limit
+#[cfg(not(feature = "led-strip"))]
+const POWER_LIMIT: u32 = 100;
+
+/// Number of LEDs in the strip
+#[cfg(feature = "led-strip")]
+const STRIP_LENGTH: usize = 30;
+/// Number of segments of the strip, each a light on its own endpoint. With
+/// one segment the whole strip is a single light.
+#[cfg(feature = "led-strip")]
+pub const SEGMENTS: usize = 1;
+/// Largest current the strip may draw, in milliamperes
+#[cfg(feature = "led-strip")]
+const POWER_LIMIT: u32 = 500;
+
+pub type LedStrip = Strip<STRIP_LENGTH, SEGMENTS>;
+
+/// Endpoints of the segments, from 1 and up
+const ENDPOINTS: [u8; SEGMENTS] = segment_endpoints();
+
+const fn segment_endpoints() -> [u8; SEGMENTS] {
+    let mut endpoints = [0u8; SEGMENTS];
+    let mut index = 0;
+    while index < SEGMENTS {
+        endpoints[index] = index as u8 + 1;
+        index += 1;
+    }
+    endpoints
+}
+
+/// Server clusters of the segment endpoints
+const SERVER_CLUSTERS: [u16; 7] = [
+    CLUSTER_BASIC,
+    CLUSTER_IDENTIFY,
+    CLUSTER_GROUPS,
+    CLUSTER_SCENES,
+    CLUSTER_ON_OFF,
+    CLUSTER_LEVEL_CONTROL,
+    CLUSTER_COLOR_CONTROL,
+];
+
+/// Commands of the server clusters of the segment endpoints
+const COMMANDS: [ClusterCommands; 7] = [
+    ClusterCommands {
+        cluster: CLUSTER_BASIC,
+        received: &[BASIC_CMD_RESET_TO_FACTORY_DEFAULTS],
+        generated: &[],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_IDENTIFY,
+        received: &[
+            IDENTIFY_CMD_IDENTIFY,
+            IDENTIFY_CMD_IDENTIFY_QUERY,
+            IDENTIFY_CMD_TRIGGER_EFFECT,
+        ],
+        generated: &[IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_GROUPS,
+        received: &GROUPS_COMMANDS_RECEIVED,
+        generated: &GROUPS_COMMANDS_GENERATED,
+    },
+    ClusterCommands {
+        cluster: CLUSTER_SCENES,
+        received: &SCENES_COMMANDS_RECEIVED,
+        generated: &SCENES_COMMANDS_GENERATED,
+    },
+    ClusterCommands {
+        cluster: CLUSTER_ON_OFF,
+        received: &[ON_OFF_CMD_OFF, ON_OFF_CMD_ON, ON_OFF_CMD_TOGGLE],
+        generated: &[],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_LEVEL_CONTROL,
+        received: &[
+            LEVEL_CONTROL_CMD_MOVE_TO_LEVEL,
+            LEVEL_CONTROL_CMD_MOVE,
+            LEVEL_CONTROL_CMD_STEP,
+            LEVEL_CONTROL_CMD_STOP,
+            LEVEL_CONTROL_CMD_MOVE_TO_LEVEL_ON_OFF,
+            LEVEL_CONTROL_CMD_MOVE_ON_OFF,
+            LEVEL_CONTROL_CMD_STEP_ON_OFF,
+            LEVEL_CONTROL_CMD_STOP_ON_OFF,
+        ],
+        generated: &[],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_COLOR_CONTROL,
+        received: &[
+            COLOR_CONTROL_CMD_MOVE_TO_HUE,
+            COLOR_CONTROL_CMD_MOVE_HUE,
+            COLOR_CONTROL_CMD_STEP_HUE,
+            COLOR_CONTROL_CMD_MOVE_TO_SATURATION,
+            COLOR_CONTROL_CMD_MOVE_SATURATION,
+            COLOR_CONTROL_CMD_STEP_SATURATION,
+            COLOR_CONTROL_CMD_MOVE_TO_HUE_AND_SATURATION,
+            COLOR_CONTROL_CMD_MOVE_TO_COLOR,
+            COLOR_CONTROL_CMD_MOVE_COLOR,
+            COLOR_CONTROL_CMD_STEP_COLOR,
+            COLOR_CONTROL_CMD_STOP_MOVE_STEP,
+        ],
+        generated: &[],
+    },
+];
+
+/// Number of group memberships that can be stored, for all segments
+pub const GROUP_CAPACITY: usize = 16;
+
+pub type Groups = GroupTable<GROUP_CAPACITY>;
+
+/// Number of scenes that can be stored per segment
+pub const SCENE_CAPACITY: usize = 16;
+
+pub type Scenes = SceneTable<SCENE_CAPACITY>;
+
+/// Number of bindings that can be stored
+pub const BINDING_CAPACITY: usize = 16;
+
+pub type Bindings = BindingTable<BINDING_CAPACITY>;
+
+/// Shortest time between reports of an attribute, in seconds
+const REPORT_MIN_INTERVAL: u16 = 1;
+/// Longest time between reports of an attribute, in seconds
+const REPORT_MAX_INTERVAL: u16 = 300;
+/// Reports are sent to the bound destinations, or to the coordinator
+const REPORT_RECIPIENT: Recipient = Recipient::Bound;
+/// Reported attributes, four per segment
+const REPORT_CAPACITY: usize = 4 * SEGMENTS;
+
+/// Identify cluster
+const CLUSTER_IDENTIFY: u16 = 0x0003;
+/// Identify cluster attribute, identify time
+const IDENTIFY_ATTR_IDENTIFY_TIME: u16 = 0x0000;
+/// Identify cluster command, identify
+const IDENTIFY_CMD_IDENTIFY: u8 = 0x00;
+/// Identify cluster command, identify query
+const IDENTIFY_CMD_IDENTIFY_QUERY: u8 = 0x01;
+/// Identify cluster command, trigger effect
+const IDENTIFY_CMD_TRIGGER_EFFECT: u8 = 0x40;
+/// Identify cluster command, identify query response
+const IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE: u8 = 0x00;
 
 /// On/off cluster
 const CLUSTER_ON_OFF: u16 = 0x0006;
 /// On/off cluster attribute, on/off state
 const ON_OFF_ATTR_ON_OFF_STATE: u16 = 0x0000;
+/// On/off cluster attribute, start-up on/off
+const ON_OFF_ATTR_START_UP_ON_OFF: u16 = 0x4003;
 /// On/off cluster command, off
 const ON_OFF_CMD_OFF: u8 = 0x00;
 /// On/off cluster command, on
@@ -58,6 +242,8 @@ const ON_OFF_CMD_TOGGLE: u8 = 0x02;
 const CLUSTER_LEVEL_CONTROL: u16 = 0x0008;
 /// Level control cluster attribute, current level
 const LEVEL_CONTROL_ATTR_CURRENT_LEVEL: u16 = 0x0000;
+/// Level control cluster attribute, start-up current level
+const LEVEL_CONTROL_ATTR_START_UP_CURRENT_LEVEL: u16 = 0x4000;
 /// Level control cluster command, move to level
 const LEVEL_CONTROL_CMD_MOVE_TO_LEVEL: u8 = 0x00;
 /// Level control cluster command, move
@@ -85,6 +271,8 @@ const COLOR_CONTROL_ATTR_CURRENT_Y: u16 = 0x0004;
 const COLOR_CONTROL_ATTR_COLOR_MODE: u16 = 0x0008;
 /// Colour control cluster attribute, Colour capabilities
 const COLOR_CONTROL_ATTR_COLOR_CAPABILITIES: u16 = 0x400a;
+/// Colour control cluster attribute, Start-up colour temperature
+const COLOR_CONTROL_ATTR_START_UP_COLOR_TEMPERATURE: u16 = 0x4010;
 /// Colour control cluster command, Move to hue
 const COLOR_CONTROL_CMD_MOVE_TO_HUE: u8 = 0x00;
 /// Colour control cluster command, Move hue
@@ -108,24 +296,165 @@ const COLOR_CONTROL_CMD_STEP_COLOR: u8 = 0x09;
 /// Colour control cluster command, Stop move step
 const COLOR_CONTROL_CMD_STOP_MOVE_STEP: u8 = 0x47;
 
-pub struct ClusterHandler {
+/// Transition to a recalled scene
+struct SceneTransition {
+    /// Level, x and y
+    values: Transition<3>,
+    /// On/off state at the end of the transition
+    on_off: bool,
+    /// Level at the end of the transition
+    level: u8,
+}
+
+/// A segment of the strip, a light on its own endpoint
+pub struct Segment {
+    endpoint: u8,
     on_off: bool,
-    neopixel: Pwm<pac::PWM0>,
     colour: Yxy,
+    start_up_on_off: StartUpOnOff,
+    start_up_level: u8,
+    start_up_color_temperature: u16,
+    changed: bool,
+    identify: Identify,
+    /// Colour shown while identifying
+    indication: Option<RGB8>,
+    scenes: Scenes,
+    scenes_changed: bool,
+    transition: Option<SceneTransition>,
+    /// The colour has to be written to the strip
+    dirty: bool,
 }
 
-impl ClusterHandler {
-    pub fn new(pin: gpio::Pin<gpio::Output<gpio::PushPull>>, pwm: pac::PWM0) -> Self {
-        let colour = Yxy::new(0.0, 0.0, 0.0);
-        let neopixel = Pwm::new(pwm, pin);
-        Self {
-            on_off: false,
-            neopixel,
+impl Segment {
+    pub fn new(endpoint: u8, state: LightState, scenes: Scenes) -> Self {
+        let start_up = state.start_up();
+        let colour = Yxy::new(
+            (start_up.x as f32) / 65536.0,
+            (start_up.y as f32) / 65536.0,
+            (start_up.level as f32) / 254.0,
+        );
+        let mut segment = Self {
+            endpoint,
+            on_off: start_up.on_off,
             colour,
+            start_up_on_off: state.start_up_on_off,
+            start_up_level: state.start_up_level,
+            start_up_color_temperature: state.start_up_color_temperature,
+            changed: false,
+            identify: Identify::new(),
+            indication: None,
+            scenes,
+            scenes_changed: false,
+            transition: None,
+            dirty: false,
+        };
+        segment.update_led();
+        // Store the state if the start-up attributes changed it
+        segment.changed = start_up != state;
+        segment
+    }
+
+    /// Current state of the segment, to be stored
+    pub fn light_state(&self) -> LightState {
+        LightState {
+            on_off: self.on_off,
+            level: self.get_level(),
+            x: self.get_x(),
+            y: self.get_y(),
+            start_up_on_off: self.start_up_on_off,
+            start_up_level: self.start_up_level,
+            start_up_color_temperature: self.start_up_color_temperature,
         }
     }
 
-    fn update_led(&mut self) {
+    /// The state captured when storing a scene
+    fn scene_state(&self) -> SceneState {
+        SceneState {
+            on_off: Some(self.on_off),
+            level: Some(self.get_level()),
+            color: Some(Color::Xy {
+                x: self.get_x(),
+                y: self.get_y(),
+            }),
+        }
+    }
+
+    /// Start the transition to a recalled scene
+    ///
+    /// Fields that are missing in the scene keep their current value. A light
+    /// that is turned on fades in from zero, a light that is turned off fades
+    /// out to zero.
+    fn recall_scene(&mut self, recall: Recall) {
+        let on_off = recall.state.on_off.unwrap_or(self.on_off);
+        let level = recall.state.level.unwrap_or_else(|| self.get_level());
+        let (x, y) = recall
+            .state
+            .color
+            .map_or((self.get_x(), self.get_y()), |color| color.to_xy());
+        let from_level = if self.on_off { self.get_level() } else { 0 };
+        let to_level = if on_off { level } else { 0 };
+        let ticks = u32::from(recall.transition_time) * TICKS_PER_SECOND / 10;
+        self.on_off = self.on_off || on_off;
+        self.transition = Some(SceneTransition {
+            values: Transition::new(
+                [u16::from(from_level), self.get_x(), self.get_y()],
+                [u16::from(to_level), x, y],
+                ticks,
+            ),
+            on_off,
+            level,
+        });
+        self.transition_tick();
+    }
+
+    /// Advance a scene transition, called `TICKS_PER_SECOND` times per second
+    pub fn transition_tick(&mut self) {
+        let transition = match self.transition.as_mut() {
+            Some(transition) => transition,
+            None => return,
+        };
+        let [level, x, y] = transition.values.tick();
+        self.colour = Yxy::new(
+            (x as f32) / 65536.0,
+            (y as f32) / 65536.0,
+            (level as f32) / 254.0,
+        );
+        if transition.values.is_finished() {
+            self.on_off = transition.on_off;
+            self.colour.luma = (transition.level as f32) / 254.0;
+            self.transition = None;
+            self.changed = true;
+        }
+        self.dirty = true;
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => {
+                let (r, g, b) = match self.identify.effect() {
+                    Some(Effect::Okay) => (0, 255, 0),
+                    Some(Effect::ChannelChange) => (255, 128, 0),
+                    _ => (255, 255, 255),
+                };
+                let scale = |value: u16| ((value * u16::from(level)) / 255) as u8;
+                self.indication = Some(RGB8::new(scale(r), scale(g), scale(b)));
+                self.dirty = true;
+            }
+            Indication::Finished => {
+                self.indication = None;
+                self.dirty = true;
+            }
+            Indication::None => (),
+        }
+    }
+
+    /// Colour of the segment, the indication while identifying
+    fn pixel(&self) -> RGB8 {
+        self.indication.unwrap_or_else(|| self.state_pixel())
+    }
+
+    fn state_pixel(&self) -> RGB8 {
         let mut pixel = RGB8::default();
         if self.on_off {
             let raw: [u8; 3] = Srgb::from(self.colour).into_format().into_raw();
@@ -137,8 +466,15 @@ impl ClusterHandler {
             pixel.g = 0;
             pixel.b = 0;
         }
-        let pixels = [pixel; 1];
-        let _ = self.neopixel.write(gamma(pixels.iter().cloned()));
+        pixel
+    }
+
+    fn update_led(&mut self) {
+        self.dirty = true;
+        self.changed = true;
+        // The state was changed by a command, not by a scene
+        self.transition = None;
+        self.scenes.invalidate();
     }
 
     pub fn set_on_off(&mut self, enable: bool) {
@@ -181,178 +517,319 @@ impl ClusterHandler {
     }
 }
 
-impl ClusterLibraryHandler for ClusterHandler {
-    fn active_endpoints(&self) -> &[u8] {
-        &[0x01]
+pub struct ClusterHandler {
+    segments: [Segment; SEGMENTS],
+    strip: LedStrip,
+    leds: Pwm<pac::PWM0>,
+    groups: Groups,
+    groups_changed: bool,
+    address: u64,
+    bindings: Bindings,
+    bindings_changed: bool,
+    reset_requested: bool,
+    reporting: ReportingTable<REPORT_CAPACITY>,
+    outbox: Outbox<8>,
+}
+
+impl ClusterHandler {
+    pub fn new(
+        pin: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        pwm: pac::PWM0,
+        segments: [Segment; SEGMENTS],
+        groups: Groups,
+        address: u64,
+        bindings: Bindings,
+    ) -> Self {
+        let mut reporting = ReportingTable::new();
+        for endpoint in ENDPOINTS {
+            for attribute in ATTRIBUTES.reportable() {
+                reporting.add(
+                    endpoint,
+                    attribute.cluster,
+                    attribute.id,
+                    u8::from(attribute.data_type),
+                    REPORT_MIN_INTERVAL,
+                    REPORT_MAX_INTERVAL,
+                );
+            }
+        }
+        let mut handler = Self {
+            segments,
+            strip: LedStrip::new(PowerBudget::new(POWER_LIMIT)),
+            leds: Pwm::new(pwm, pin),
+            groups,
+            groups_changed: false,
+            address,
+            bindings,
+            bindings_changed: false,
+            reset_requested: false,
+            reporting,
+            outbox: Outbox::new(),
+        };
+        handler.render();
+        handler
     }
-    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
-        match endpoint {
-            0x01 => Some(SimpleDescriptor::new(
-                0x01,
-                PROFILE_HOME_AUTOMATION,
-                DEVICE_COLOR_DIMMABLE_LIGHT,
-                0,
-                &[
-                    CLUSTER_BASIC,
-                    CLUSTER_ON_OFF,
-                    CLUSTER_LEVEL_CONTROL,
-                    CLUSTER_COLOR_CONTROL,
-                ],
-                &[CLUSTER_ON_OFF],
-            )),
-            _ => None,
+
+    /// Write the strip if the colour of a segment has changed
+    fn render(&mut self) {
+        let mut dirty = false;
+        for (index, segment) in self.segments.iter_mut().enumerate() {
+            if segment.dirty {
+                segment.dirty = false;
+                dirty = true;
+                let pixel = gamma(iter::once(segment.pixel()))
+                    .next()
+                    .unwrap_or_default();
+                self.strip.set_colour(index, pixel);
+            }
+        }
+        if dirty {
+            let _ = self.strip.write(&mut self.leds);
         }
     }
-    fn read_attribute(
-        &self,
-        profile: u16,
-        cluster: u16,
-        _destination: Destination,
-        attribute: u16,
-        value: &mut [u8],
-    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
-        match (profile, cluster, attribute) {
-            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_LIBRARY_VERSION) => {
-                value[0] = 0x02;
-                Ok((AttributeDataType::Unsigned8, 1))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_MANUFACTURER_NAME) => {
-                value[0] = MANUFACTURER_NAME.len() as u8;
-                let end = MANUFACTURER_NAME.len() + 1;
-                value[1..end].copy_from_slice(MANUFACTURER_NAME.as_bytes());
-                Ok((AttributeDataType::CharacterString, end))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_MODEL_IDENTIFIER) => {
-                value[0] = MODEL_IDENTIFIER.len() as u8;
-                let end = MODEL_IDENTIFIER.len() + 1;
-                value[1..end].copy_from_slice(MODEL_IDENTIFIER.as_bytes());
-                Ok((AttributeDataType::CharacterString, end))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_POWER_SOURCE) => {
-                value[0] = 0x01;
-                Ok((AttributeDataType::Enumeration8, 1))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_ON_OFF, ON_OFF_ATTR_ON_OFF_STATE) => {
-                value[0] = if self.on_off { 0x01 } else { 0x00 };
-                Ok((AttributeDataType::Boolean, 1))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_ATTR_CURRENT_LEVEL) => {
-                // current level
-                defmt::info!("Read level: {=u8}", self.get_level());
-                value[0] = self.get_level();
-                Ok((AttributeDataType::Unsigned8, 1))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_ATTR_CURRENT_X) => {
-                // current x
-                defmt::info!("Read X: {=u16}", self.get_x());
-                LittleEndian::write_u16(&mut value[0..=2], self.get_x());
-                Ok((AttributeDataType::Unsigned16, 2))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_ATTR_CURRENT_Y) => {
-                // current y
-                defmt::info!("Read Y: {=u16}", self.get_y());
-                LittleEndian::write_u16(&mut value[0..=2], self.get_y());
-                Ok((AttributeDataType::Unsigned16, 2))
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_ATTR_COLOR_MODE) => {
-                // color mode
-                value[0] = 0x01; // Current X, Current Y
-                Ok((AttributeDataType::Enumeration8, 1))
-            }
-            (
-                PROFILE_HOME_AUTOMATION,
-                CLUSTER_COLOR_CONTROL,
-                COLOR_CONTROL_ATTR_COLOR_CAPABILITIES,
-            ) => {
-                // color capabilities
-                let capabilities = 0b_0000_0000_0000_1000; // XY
-                LittleEndian::write_u16(&mut value[0..=2], capabilities);
-                Ok((AttributeDataType::Bitmap16, 2))
-            }
-            (_, _, _) => {
-                defmt::info!(
-                    "Read attribute: {=u16:04x} {=u16:04x} {=u16:04x}",
-                    profile,
-                    cluster,
-                    attribute
-                );
-                Err(ClusterLibraryStatus::UnsupportedAttribute)
+
+    /// Take the state of a segment that has changed since it was last taken,
+    /// to be stored
+    pub fn take_changed(&mut self) -> Option<(u8, LightState)> {
+        let segment = self.segments.iter_mut().find(|segment| segment.changed)?;
+        segment.changed = false;
+        Some((segment.endpoint, segment.light_state()))
+    }
+
+    /// Group memberships of all segments, to be stored
+    pub fn group_table(&self) -> Groups {
+        self.groups
+    }
+
+    /// Check if the group memberships have changed since last call
+    pub fn take_groups_changed(&mut self) -> bool {
+        let changed = self.groups_changed;
+        self.groups_changed = false;
+        changed
+    }
+
+    /// Queue the attribute reports that are due, called once per second
+    pub fn report_tick(&mut self) {
+        for segment in self.segments.iter() {
+            for attribute in ATTRIBUTES.reportable() {
+                if let Some(value) = (attribute.get)(segment).to_u32() {
+                    self.reporting.set_value(
+                        segment.endpoint,
+                        attribute.cluster,
+                        attribute.id,
+                        value,
+                    );
+                }
             }
         }
+        self.reporting
+            .tick(REPORT_RECIPIENT, PROFILE_HOME_AUTOMATION, &mut self.outbox);
     }
-    fn write_attribute(
+
+    /// Take the scenes of a segment that have changed since they were last
+    /// taken, to be stored
+    pub fn take_scenes_changed(&mut self) -> Option<(u8, Scenes)> {
+        let segment = self
+            .segments
+            .iter_mut()
+            .find(|segment| segment.scenes_changed)?;
+        segment.scenes_changed = false;
+        Some((segment.endpoint, segment.scenes))
+    }
+
+    /// Check if the bindings have changed since last call
+    pub fn take_bindings_changed(&mut self) -> bool {
+        let changed = self.bindings_changed;
+        self.bindings_changed = false;
+        changed
+    }
+
+    /// Check if a factory reset has been requested since last call, by the
+    /// basic cluster or a ZDO leave request
+    pub fn take_reset_requested(&mut self) -> bool {
+        let requested = self.reset_requested;
+        self.reset_requested = false;
+        requested
+    }
+
+    /// Advance the scene transitions, called `TICKS_PER_SECOND` times per
+    /// second
+    pub fn transition_tick(&mut self) {
+        for segment in self.segments.iter_mut() {
+            segment.transition_tick();
+        }
+        self.render();
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        for segment in self.segments.iter_mut() {
+            segment.identify_tick();
+        }
+        self.render();
+    }
+
+    /// Handle a profile wide command for one segment
+    fn run_general_segment(
         &mut self,
+        index: usize,
         profile: u16,
         cluster: u16,
-        _destination: Destination,
-        attribute: u16,
-        data_type: AttributeDataType,
-        value: &[u8],
+        command: u8,
+        arguments: &[u8],
     ) -> Result<(), ClusterLibraryStatus> {
-        match (profile, cluster, attribute, data_type) {
-            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_LIBRARY_VERSION, _)
-            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, BASIC_ATTR_POWER_SOURCE, _) => {
-                Err(ClusterLibraryStatus::ReadOnly)
-            }
-            (
-                PROFILE_HOME_AUTOMATION,
-                CLUSTER_ON_OFF,
-                ON_OFF_ATTR_ON_OFF_STATE,
-                AttributeDataType::Boolean,
-            ) => {
-                self.set_on_off(value[0] == 0x01);
-                Ok(())
-            }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_ON_OFF, ON_OFF_ATTR_ON_OFF_STATE, _) => {
-                Err(ClusterLibraryStatus::InvalidValue)
-            }
-            (_, _, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
-        }
+        let endpoint = self.segments[index].endpoint;
+        let response = match command {
+            CMD_CONFIGURE_REPORTING | CMD_READ_REPORTING_CONFIGURATION => self
+                .reporting
+                .handle_command(endpoint, profile, cluster, command, arguments)?,
+            command if is_discovery_command(command) => discovery::handle_command(
+                &ATTRIBUTES,
+                &COMMANDS,
+                endpoint,
+                profile,
+                cluster,
+                command,
+                arguments,
+            )?,
+            _ => return Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
+        };
+        self.outbox.push(response);
+        Ok(())
     }
-    fn run(
+
+    /// Handle a cluster specific command for one segment
+    fn run_segment(
         &mut self,
+        index: usize,
         profile: u16,
         cluster: u16,
-        _destination: Destination,
+        destination: Destination,
         command: u8,
         arguments: &[u8],
     ) -> Result<(), ClusterLibraryStatus> {
-        match (profile, cluster, command) {
-            (PROFILE_HOME_AUTOMATION, CLUSTER_ON_OFF, ON_OFF_CMD_OFF) => {
+        let segment = &mut self.segments[index];
+        let endpoint = segment.endpoint;
+        match (cluster, command) {
+            (CLUSTER_BASIC, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS) => {
+                // Leaves the network as well
+                self.reset_requested = true;
+                Ok(())
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_IDENTIFY) => {
+                if arguments.len() >= 2 {
+                    let identify_time = LittleEndian::read_u16(&arguments[0..2]);
+                    defmt::info!("Identify: {=u16}", identify_time);
+                    segment.identify.identify(identify_time);
+                    Ok(())
+                } else {
+                    Err(ClusterLibraryStatus::MalformedCommand)
+                }
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_IDENTIFY_QUERY) => {
+                // Only answered while identifying
+                if segment.identify.is_identifying() {
+                    let mut response = Message::new(
+                        Recipient::Reply,
+                        endpoint,
+                        PROFILE_HOME_AUTOMATION,
+                        CLUSTER_IDENTIFY,
+                        IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE,
+                    );
+                    response.append_u16(segment.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_TRIGGER_EFFECT) => {
+                if arguments.len() >= 2 {
+                    let effect = arguments[0];
+                    let _variant = arguments[1];
+                    defmt::info!("Trigger effect: {=u8:02x}", effect);
+                    match Effect::from_u8(effect) {
+                        Some(effect) => {
+                            segment.identify.trigger_effect(effect);
+                            Ok(())
+                        }
+                        None => Err(ClusterLibraryStatus::InvalidValue),
+                    }
+                } else {
+                    Err(ClusterLibraryStatus::MalformedCommand)
+                }
+            }
+            (CLUSTER_GROUPS, _) => {
+                let identifying = segment.identify.is_identifying();
+                let (changed, response) = self.groups.handle_command(
+                    endpoint,
+                    profile,
+                    destination,
+                    command,
+                    arguments,
+                    identifying,
+                )?;
+                if let Some(response) = response {
+                    self.outbox.push(response);
+                }
+                self.groups_changed |= changed;
+                // Scenes of removed groups are removed as well
+                segment.scenes_changed |= segment.scenes.retain_groups(endpoint, &self.groups);
+                Ok(())
+            }
+            (CLUSTER_SCENES, _) => {
+                let current = segment.scene_state();
+                let outcome = segment.scenes.handle_command(
+                    endpoint,
+                    destination,
+                    command,
+                    arguments,
+                    &self.groups,
+                    &current,
+                )?;
+                if let Some(response) = outcome.response {
+                    self.outbox.push(response);
+                }
+                if let Some(recall) = outcome.recall {
+                    defmt::info!("Recall scene, transition {=u16}", recall.transition_time);
+                    segment.recall_scene(recall);
+                }
+                segment.scenes_changed |= outcome.changed;
+                Ok(())
+            }
+            (CLUSTER_ON_OFF, ON_OFF_CMD_OFF) => {
                 // set off
-                self.set_on_off(false);
+                segment.set_on_off(false);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_ON_OFF, ON_OFF_CMD_ON) => {
+            (CLUSTER_ON_OFF, ON_OFF_CMD_ON) => {
                 // set on
-                self.set_on_off(true);
+                segment.set_on_off(true);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_ON_OFF, ON_OFF_CMD_TOGGLE) => {
+            (CLUSTER_ON_OFF, ON_OFF_CMD_TOGGLE) => {
                 // toggle
-                self.set_on_off(!self.on_off);
+                segment.set_on_off(!segment.on_off);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE_TO_LEVEL) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE_TO_LEVEL) => {
                 // move to level
                 if arguments.len() >= 3 {
                     let level = arguments[0];
                     let transition_time = LittleEndian::read_u16(&arguments[1..=2]);
                     defmt::info!("Move to level: {=u8} {=u16}", level, transition_time);
-                    self.set_level(level);
+                    segment.set_level(level);
                 } else {
                     defmt::warn!("Move to level ?");
                 }
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE) => {
                 // move
                 let mode = arguments[0];
                 let rate = arguments[1];
                 defmt::info!("Move: {=u8} {=u8}", mode, rate);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STEP) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STEP) => {
                 // step
                 let mode = arguments[0];
                 let step = arguments[1];
@@ -360,32 +837,28 @@ impl ClusterLibraryHandler for ClusterHandler {
                 defmt::info!("Step: {=u8} {=u8} {=u16}", mode, step, transition_time);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STOP)
-            | (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STOP_ON_OFF) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STOP)
+            | (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STOP_ON_OFF) => {
                 // stop
                 defmt::info!("Stop");
                 Ok(())
             }
-            (
-                PROFILE_HOME_AUTOMATION,
-                CLUSTER_LEVEL_CONTROL,
-                LEVEL_CONTROL_CMD_MOVE_TO_LEVEL_ON_OFF,
-            ) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE_TO_LEVEL_ON_OFF) => {
                 // move to level, on / off
                 let level = arguments[0];
                 let _transition_time = LittleEndian::read_u16(&arguments[1..=2]);
-                self.set_on_off(level > 0);
-                self.set_level(level);
+                segment.set_on_off(level > 0);
+                segment.set_level(level);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE_ON_OFF) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_MOVE_ON_OFF) => {
                 // move, on / off
                 let mode = arguments[0];
                 let rate = arguments[1];
                 defmt::info!("Move (on/off): {=u8} {=u8}", mode, rate);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STEP_ON_OFF) => {
+            (CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STEP_ON_OFF) => {
                 // step, on / off
                 let mode = arguments[0];
                 let step = arguments[1];
@@ -398,7 +871,7 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_HUE) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_HUE) => {
                 // move to hue
                 let hue = arguments[0];
                 let direction = arguments[1];
@@ -411,14 +884,14 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_HUE) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_HUE) => {
                 // move hue
                 let mode = arguments[0];
                 let rate = arguments[1];
                 defmt::info!("Move hue: {=u8} {=u8}", mode, rate);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_HUE) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_HUE) => {
                 // step hue
                 let mode = arguments[0];
                 let step = arguments[1];
@@ -426,11 +899,7 @@ impl ClusterLibraryHandler for ClusterHandler {
                 defmt::info!("Step hue: {=u8} {=u8} {=u16}", mode, step, transition_time);
                 Ok(())
             }
-            (
-                PROFILE_HOME_AUTOMATION,
-                CLUSTER_COLOR_CONTROL,
-                COLOR_CONTROL_CMD_MOVE_TO_SATURATION,
-            ) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_SATURATION) => {
                 // move to saturation
                 let saturation = arguments[0];
                 let transition_time = LittleEndian::read_u16(&arguments[1..3]);
@@ -441,14 +910,14 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_SATURATION) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_SATURATION) => {
                 // move saturation
                 let mode = arguments[0];
                 let rate = arguments[1];
                 defmt::info!("Move saturation: {=u8} {=u8}", mode, rate);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_SATURATION) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_SATURATION) => {
                 // step saturation
                 let mode = arguments[0];
                 let step = arguments[1];
@@ -461,11 +930,7 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (
-                PROFILE_HOME_AUTOMATION,
-                CLUSTER_COLOR_CONTROL,
-                COLOR_CONTROL_CMD_MOVE_TO_HUE_AND_SATURATION,
-            ) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_HUE_AND_SATURATION) => {
                 // move to hue and saturation
                 let hue = arguments[0];
                 let saturation = arguments[1];
@@ -478,27 +943,27 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_COLOR) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_TO_COLOR) => {
                 // move to color
                 if arguments.len() >= 6 {
                     let x = LittleEndian::read_u16(&arguments[0..2]);
                     let y = LittleEndian::read_u16(&arguments[2..4]);
                     let transition_time = LittleEndian::read_u16(&arguments[4..6]);
                     defmt::info!("Move to color: {=u16} {=u16} {=u16}", x, y, transition_time);
-                    self.set_color(x, y);
+                    segment.set_color(x, y);
                 } else {
                     defmt::warn!("Move to color ?");
                 }
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_COLOR) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_MOVE_COLOR) => {
                 // move color
                 let rate_x = LittleEndian::read_u16(&arguments[0..2]);
                 let rate_y = LittleEndian::read_u16(&arguments[2..4]);
                 defmt::info!("Move color: {=u16} {=u16}", rate_x, rate_y);
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_COLOR) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STEP_COLOR) => {
                 // step color
                 let step_x = LittleEndian::read_u16(&arguments[0..2]);
                 let step_y = LittleEndian::read_u16(&arguments[2..4]);
@@ -511,12 +976,12 @@ impl ClusterLibraryHandler for ClusterHandler {
                 );
                 Ok(())
             }
-            (PROFILE_HOME_AUTOMATION, CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STOP_MOVE_STEP) => {
+            (CLUSTER_COLOR_CONTROL, COLOR_CONTROL_CMD_STOP_MOVE_STEP) => {
                 // stop move step
                 defmt::info!("Stop move step");
                 Ok(())
             }
-            (_, _, _) => {
+            (_, _) => {
                 defmt::info!(
                     "Command {=u16:04x} {=u16:04x} {=u8:04x}",
                     profile,
@@ -529,43 +994,459 @@ impl ClusterLibraryHandler for ClusterHandler {
     }
 }
 
+type LightAttribute = Attribute<Segment>;
+
+/// Attributes of a segment endpoint
+const ATTRIBUTES: AttributeTable<Segment> = AttributeTable::new(&[
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_ZCL_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(ZCL_VERSION),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_APPLICATION_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.application_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_STACK_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.stack_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_HW_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.hw_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MANUFACTURER_NAME,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.manufacturer_name),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MODEL_IDENTIFIER,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.model_identifier),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_DATE_CODE,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.date_code),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_POWER_SOURCE,
+        AttributeDataType::Enumeration8,
+        |_| Value::Enumeration8(IDENTITY.power_source),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_SW_BUILD_ID,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.sw_build_id),
+    ),
+    LightAttribute::new(
+        CLUSTER_IDENTIFY,
+        IDENTIFY_ATTR_IDENTIFY_TIME,
+        AttributeDataType::Unsigned16,
+        |segment| Value::Unsigned16(segment.identify.identify_time()),
+    )
+    .writable(|segment, value| {
+        if let Value::Unsigned16(time) = value {
+            segment.identify.identify(time);
+        }
+        Ok(())
+    }),
+    // Group names are not supported
+    LightAttribute::new(
+        CLUSTER_GROUPS,
+        GROUPS_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_COUNT,
+        AttributeDataType::Unsigned8,
+        |segment| Value::Unsigned8(segment.scenes.count()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_SCENE,
+        AttributeDataType::Unsigned8,
+        |segment| Value::Unsigned8(segment.scenes.current_scene()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_GROUP,
+        AttributeDataType::Unsigned16,
+        |segment| Value::Unsigned16(segment.scenes.current_group()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_VALID,
+        AttributeDataType::Boolean,
+        |segment| Value::Boolean(segment.scenes.is_valid()),
+    ),
+    // Scene names are not supported
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    LightAttribute::new(
+        CLUSTER_ON_OFF,
+        ON_OFF_ATTR_ON_OFF_STATE,
+        AttributeDataType::Boolean,
+        |segment| Value::Boolean(segment.on_off),
+    )
+    .reportable()
+    .writable(|segment, value| {
+        if let Value::Boolean(enable) = value {
+            segment.set_on_off(enable);
+        }
+        Ok(())
+    }),
+    LightAttribute::new(
+        CLUSTER_ON_OFF,
+        ON_OFF_ATTR_START_UP_ON_OFF,
+        AttributeDataType::Enumeration8,
+        |segment| Value::Enumeration8(segment.start_up_on_off as u8),
+    )
+    .writable(|segment, value| match value {
+        Value::Enumeration8(value) => {
+            let start_up =
+                StartUpOnOff::from_u8(value).ok_or(ClusterLibraryStatus::InvalidValue)?;
+            segment.start_up_on_off = start_up;
+            segment.changed = true;
+            Ok(())
+        }
+        _ => Err(ClusterLibraryStatus::InvalidValue),
+    }),
+    LightAttribute::new(
+        CLUSTER_LEVEL_CONTROL,
+        LEVEL_CONTROL_ATTR_CURRENT_LEVEL,
+        AttributeDataType::Unsigned8,
+        |segment| Value::Unsigned8(segment.get_level()),
+    )
+    .reportable(),
+    LightAttribute::new(
+        CLUSTER_LEVEL_CONTROL,
+        LEVEL_CONTROL_ATTR_START_UP_CURRENT_LEVEL,
+        AttributeDataType::Unsigned8,
+        |segment| Value::Unsigned8(segment.start_up_level),
+    )
+    .writable(|segment, value| {
+        if let Value::Unsigned8(level) = value {
+            segment.start_up_level = level;
+            segment.changed = true;
+        }
+        Ok(())
+    }),
+    LightAttribute::new(
+        CLUSTER_COLOR_CONTROL,
+        COLOR_CONTROL_ATTR_CURRENT_X,
+        AttributeDataType::Unsigned16,
+        |segment| Value::Unsigned16(segment.get_x()),
+    )
+    .reportable(),
+    LightAttribute::new(
+        CLUSTER_COLOR_CONTROL,
+        COLOR_CONTROL_ATTR_CURRENT_Y,
+        AttributeDataType::Unsigned16,
+        |segment| Value::Unsigned16(segment.get_y()),
+    )
+    .reportable(),
+    // Colour mode, current X and current Y
+    LightAttribute::new(
+        CLUSTER_COLOR_CONTROL,
+        COLOR_CONTROL_ATTR_COLOR_MODE,
+        AttributeDataType::Enumeration8,
+        |_| Value::Enumeration8(0x01),
+    ),
+    // XY
+    LightAttribute::new(
+        CLUSTER_COLOR_CONTROL,
+        COLOR_CONTROL_ATTR_COLOR_CAPABILITIES,
+        AttributeDataType::Bitmap16,
+        |_| Value::Bitmap16(0b_0000_0000_0000_1000),
+    ),
+    LightAttribute::new(
+        CLUSTER_COLOR_CONTROL,
+        COLOR_CONTROL_ATTR_START_UP_COLOR_TEMPERATURE,
+        AttributeDataType::Unsigned16,
+        |segment| Value::Unsigned16(segment.start_up_color_temperature),
+    )
+    .writable(|segment, value| {
+        if let Value::Unsigned16(temperature) = value {
+            segment.start_up_color_temperature = temperature;
+            segment.changed = true;
+        }
+        Ok(())
+    }),
+]);
+
+impl Outgoing<BINDING_CAPACITY> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    fn binding_table(&self) -> Bindings {
+        self.bindings
+    }
+}
+
+impl ClusterLibraryHandler for ClusterHandler {
+    fn active_endpoints(&self) -> &[u8] {
+        &ENDPOINTS
+    }
+    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
+        if !ENDPOINTS.contains(&endpoint) {
+            return None;
+        }
+        Some(SimpleDescriptor::new(
+            endpoint,
+            PROFILE_HOME_AUTOMATION,
+            DEVICE_COLOR_DIMMABLE_LIGHT,
+            0,
+            &SERVER_CLUSTERS,
+            &[CLUSTER_ON_OFF],
+        ))
+    }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        if let Some((length, leave)) =
+            handle_leave_request(self.address, cluster, request, response)
+        {
+            self.reset_requested |= leave;
+            return Some(length);
+        }
+        let (length, changed) =
+            self.bindings
+                .handle_request(self.address, &ENDPOINTS, cluster, request, response)?;
+        self.bindings_changed |= changed;
+        Some(length)
+    }
+    fn read_attribute(
+        &self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        // Reads of a group or broadcast are answered by the first addressed
+        // segment
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| self.groups.is_addressed(destination, segment.endpoint));
+        let result = match (profile, segment) {
+            (PROFILE_HOME_AUTOMATION, Some(segment)) => {
+                ATTRIBUTES.read(segment, cluster, attribute, value)
+            }
+            (_, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        };
+        if result.is_err() {
+            defmt::info!(
+                "Read attribute: {=u16:04x} {=u16:04x} {=u16:04x}",
+                profile,
+                cluster,
+                attribute
+            );
+        }
+        result
+    }
+    fn write_attribute(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedAttribute);
+        }
+        let mut result = Ok(());
+        for segment in self.segments.iter_mut() {
+            if self.groups.is_addressed(destination, segment.endpoint) {
+                let outcome = ATTRIBUTES.write(segment, cluster, attribute, data_type, value);
+                result = result.and(outcome);
+            }
+        }
+        self.render();
+        result
+    }
+    fn run_general(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedGeneralCommand);
+        }
+        let mut result = Ok(());
+        for index in 0..SEGMENTS {
+            if self
+                .groups
+                .is_addressed(destination, self.segments[index].endpoint)
+            {
+                let outcome = self.run_general_segment(index, profile, cluster, command, arguments);
+                result = result.and(outcome);
+            }
+        }
+        result
+    }
+    fn run(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedClusterCommand);
+        }
+        // Each addressed segment runs the command, group commands are only
+        // for member endpoints
+        let mut result = Ok(());
+        for index in 0..SEGMENTS {
+            if self
+                .groups
+                .is_addressed(destination, self.segments[index].endpoint)
+            {
+                let outcome =
+                    self.run_segment(index, profile, cluster, destination, command, arguments);
+                result = result.and(outcome);
+            }
+        }
+        self.render();
+        result
+    }
+}
+
 #[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
 mod app {
-    use super::{pac, ClusterHandler};
+    use super::{
+        pac, Bindings, ClusterHandler, Groups, Scenes, Segment, BINDING_CAPACITY, ENDPOINTS,
+        GROUP_CAPACITY, SCENE_CAPACITY,
+    };
 
     use bbqueue::{self, BBBuffer};
 
-    use nrf52840_hal::{clocks, gpio};
+    use embedded_hal::digital::v2::InputPin;
+
+    use nrf52840_hal::{clocks, gpio, Rng};
 
-    use psila_crypto_rust_crypto::RustCryptoBackend;
-    use psila_data::{security::DEFAULT_LINK_KEY, ExtendedAddress, Key};
+    #[cfg(feature = "cryptocell")]
+    use nrf52_cryptocell::CryptoCellBackend as CryptoBackend;
+    #[cfg(all(feature = "ecb", not(feature = "cryptocell")))]
+    use nrf52_utils::ecb::EcbCryptoBackend as CryptoBackend;
+    #[cfg(not(any(feature = "cryptocell", feature = "ecb")))]
+    use psila_crypto_rust_crypto::RustCryptoBackend as CryptoBackend;
+    use psila_data::{ExtendedAddress, Key};
     use psila_nrf52::{
         radio::{Radio, MAX_PACKET_LENGHT},
         timer::Timer,
     };
-    use psila_service::{self, PsilaService};
+    use psila_service::PsilaService;
+
+    use nrf52_utils::{
+        address,
+        binding::{binding_table_size, BINDING_TABLE_KEY},
+        button::{Button, Event},
+        csma::Backoff,
+        ecb::Ecb,
+        groups::{group_table_size, GROUP_TABLE_KEY},
+        identify::TICKS_PER_SECOND,
+        install_code,
+        kv::{Error, Store},
+        light::{LightState, LIGHT_STATE_SIZE},
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        random::Random,
+        reset,
+        scenes::scene_table_size,
+        service::{
+            handle_frame, network_update, receive_frame, send_messages, Outgoing, COORDINATOR,
+        },
+    };
     use rtic::Mutex;
 
     const TIMER_SECOND: u32 = 1_000_000;
+    /// Largest random delay added to the join attempts
+    const JOIN_JITTER: u32 = TIMER_SECOND / 4;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+    /// The switch is sampled every 10 ms
+    const BUTTON_TICK: u32 = TIMER_SECOND / 100;
+    /// Samples before the switch changes state
+    const BUTTON_DEBOUNCE: u32 = 3;
+    /// Samples before a press is long, 0.8 seconds
+    const BUTTON_LONG_PRESS: u32 = 80;
+    /// Samples before a press is very long, 10 seconds
+    const BUTTON_VERY_LONG_PRESS: u32 = 1000;
+
+    const CHANNEL: u8 = 11;
+
+    /// Key of the light state of a segment, the endpoint is added
+    const SEGMENT_LIGHT_STATE_KEY: u16 = 0x0100;
+    /// Key of the scenes of a segment, the endpoint is added
+    const SEGMENT_SCENE_TABLE_KEY: u16 = 0x0200;
 
     const TX_BUFFER_SIZE: usize = 1024;
     const RX_BUFFER_SIZE: usize = 1024;
+    const FRAME_SIZE: usize = MAX_PACKET_LENGHT as usize;
 
     static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
     static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
 
+    type Service = PsilaService<'static, CryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+
     #[local]
     struct LocalResources {
         rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
         rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
         tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        join_random: Random,
+        backoff_random: Random,
+        switch: gpio::Pin<gpio::Input<gpio::PullUp>>,
+        button: Button,
+    }
+
+    /// The last frame sent, and the timer of its backoff when the channel
+    /// was busy
+    struct Csma {
+        timer: pac::TIMER0,
+        backoff: Backoff<FRAME_SIZE>,
     }
 
     #[shared]
     struct SharedResources {
         timer: pac::TIMER1,
         radio: Radio,
-        service: PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>,
+        service: Service,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+        reset_pending: bool,
+        csma: Csma,
     }
 
     #[init]
@@ -579,140 +1460,421 @@ mod app {
             .set_lfclk_src_external(clocks::LfOscConfiguration::NoExternalNoBypass)
             .start_lfclk();
 
-        let port0 = gpio::p0::Parts::new(cx.device.P0);
-        let led = port0
+        let port1 = gpio::p1::Parts::new(cx.device.P1);
+        // Data input of the onboard NeoPixel
+        #[cfg(not(feature = "led-strip"))]
+        let strip_pin = gpio::p0::Parts::new(cx.device.P0)
             .p0_16
             .into_push_pull_output(gpio::Level::Low)
             .degrade();
+        // Data input of the strip, pin 5
+        #[cfg(feature = "led-strip")]
+        let strip_pin = port1
+            .p1_08
+            .into_push_pull_output(gpio::Level::Low)
+            .degrade();
+        // The user switch
+        let switch = port1.p1_02.into_pullup_input().degrade();
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            adafruit_feather_nrf52840_express::storage::address(),
+            adafruit_feather_nrf52840_express::storage::pages(),
+        );
+        // Written to in init only to keep a generated install code
+        #[cfg_attr(not(feature = "install-code"), allow(unused_mut))]
+        let mut store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+
+        let segments = ENDPOINTS.map(|endpoint| {
+            Segment::new(
+                endpoint,
+                load_light_state(&store, endpoint),
+                load_scene_table(&store, endpoint),
+            )
+        });
+        let handler = ClusterHandler::new(
+            strip_pin,
+            cx.device.PWM0,
+            segments,
+            load_group_table(&store),
+            extended_address,
+            load_binding_table(&store),
+        );
+
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
+
+        // Preconfigured link key from the install code, which has to be
+        // given to the trust center before joining, or the default link key
+        // without the install-code feature
+        let mut rng = Rng::new(cx.device.RNG);
+        #[cfg(feature = "install-code")]
+        let install_code = {
+            let (install_code, origin) = install_code::provision(
+                install_code::read_uicr(&cx.device.UICR),
+                &mut store,
+                || {
+                    let mut code = [0u8; 16];
+                    rng.random(&mut code);
+                    code
+                },
+            );
+            defmt::info!(
+                "Install code {=[u8]:02x} ({=str})",
+                install_code.as_bytes(),
+                origin.as_str()
+            );
+            Some(install_code)
+        };
+        #[cfg(not(feature = "install-code"))]
+        let install_code = None;
+        let mut ecb = Ecb::new(cx.device.ECB);
+        let link_key = install_code::link_key(install_code.as_ref(), &mut ecb);
+        // Seed the sequence numbers and backoffs, so that devices started
+        // at the same time do not collide
+        let mut random = Random::new(rng.random_u32());
 
-        let handler = ClusterHandler::new(led, cx.device.PWM0);
-
-        // MAC (EUI-48) address to EUI-64
-        // Add FF FE in the middle
-        //
-        //    01 23 45 67 89 AB
-        //  /  /  /       \  \  \
-        // 01 23 45 FF FE 67 89 AB
-        let devaddr_lo = cx.device.FICR.deviceaddr[0].read().bits();
-        let devaddr_hi = cx.device.FICR.deviceaddr[1].read().bits() as u16;
-        let extended_address = u64::from(devaddr_hi) << 48
-            | u64::from(devaddr_lo & 0xff00_0000) << 40
-            | u64::from(devaddr_lo & 0x00ff_ffff)
-            | 0x0000_00ff_fe00_0000u64;
         let extended_address = ExtendedAddress::new(extended_address);
 
         let mut timer1 = cx.device.TIMER1;
         timer1.init();
         timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+        timer1.fire_in(3, BUTTON_TICK);
+
+        let stored_network = NetworkState::load(&store);
+        let channel = stored_network.map_or(CHANNEL, |state| state.channel);
 
         let mut radio = Radio::new(cx.device.RADIO);
-        radio.set_channel(11);
+        radio.set_channel(channel);
         radio.set_transmission_power(8);
         radio.receive_prepare();
 
         let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
         let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
 
-        let crypto_backend = RustCryptoBackend::default();
-        let default_link_key = Key::from(DEFAULT_LINK_KEY);
+        // AES-128 and CCM* on the CryptoCell, with the ECB peripheral or in
+        // software
+        #[cfg(feature = "cryptocell")]
+        let crypto_backend = CryptoBackend::new(cx.device.CRYPTOCELL).unwrap();
+        #[cfg(all(feature = "ecb", not(feature = "cryptocell")))]
+        let crypto_backend = CryptoBackend::new(ecb);
+        #[cfg(not(any(feature = "cryptocell", feature = "ecb")))]
+        let crypto_backend = CryptoBackend::default();
+        let link_key = Key::from(link_key);
+
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            extended_address,
+            link_key,
+            handler,
+        );
+        service.set_sequence_numbers(random.next_u8(), random.next_u8(), random.next_u8());
+
+        if let Some(state) = stored_network {
+            defmt::info!(
+                "Restore network {=u16:04x}:{=u16:04x}, channel {=u8}",
+                state.pan_identifier,
+                state.short_address,
+                state.channel
+            );
+            service.restore_network_identity(&state.identity());
+        }
 
         (
             SharedResources {
                 timer: timer1,
                 radio,
-                service: PsilaService::new(
-                    crypto_backend,
-                    tx_producer,
-                    extended_address,
-                    default_link_key,
-                    handler,
-                ),
+                service,
+                stored_network,
+                store,
+                reset_pending: false,
+                csma: Csma {
+                    timer: timer0,
+                    backoff: Backoff::new(),
+                },
             },
             LocalResources {
                 rx_producer,
                 rx_consumer,
                 tx_consumer,
+                join_random: Random::new(random.next_u32()),
+                backoff_random: Random::new(random.next_u32()),
+                switch,
+                button: Button::new(BUTTON_DEBOUNCE, BUTTON_LONG_PRESS, BUTTON_VERY_LONG_PRESS),
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = TIMER1, shared = [service, timer])]
+    /// Read the stored state of a segment, the default state if there is
+    /// none
+    fn load_light_state(store: &Store<NvmcStorage>, endpoint: u8) -> LightState {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        let key = SEGMENT_LIGHT_STATE_KEY + u16::from(endpoint);
+        match store.read(key, &mut data) {
+            Ok(Some(length)) => LightState::unpack(&data[..length]).unwrap_or_default(),
+            _ => LightState::default(),
+        }
+    }
+
+    /// Read the stored group memberships, an empty table if there is none
+    fn load_group_table(store: &Store<NvmcStorage>) -> Groups {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        match store.read(GROUP_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Groups::unpack(&data[..length]).unwrap_or_default(),
+            _ => Groups::default(),
+        }
+    }
+
+    /// Read the stored scenes of a segment, an empty table if there is none
+    fn load_scene_table(store: &Store<NvmcStorage>, endpoint: u8) -> Scenes {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        let key = SEGMENT_SCENE_TABLE_KEY + u16::from(endpoint);
+        match store.read(key, &mut data) {
+            Ok(Some(length)) => Scenes::unpack(&data[..length]).unwrap_or_default(),
+            _ => Scenes::default(),
+        }
+    }
+
+    /// Read the stored bindings, an empty table if there is none
+    fn load_binding_table(store: &Store<NvmcStorage>) -> Bindings {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        match store.read(BINDING_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Bindings::unpack(&data[..length]).unwrap_or_default(),
+            _ => Bindings::default(),
+        }
+    }
+
+    /// Remove the stored state of the segments
+    fn wipe_segments(store: &mut Store<NvmcStorage>) -> Result<(), Error> {
+        for endpoint in ENDPOINTS {
+            store.remove(SEGMENT_LIGHT_STATE_KEY + u16::from(endpoint))?;
+            store.remove(SEGMENT_SCENE_TABLE_KEY + u16::from(endpoint))?;
+        }
+        Ok(())
+    }
+
+    #[task(
+        binds = TIMER1,
+        shared = [service, timer, stored_network, reset_pending],
+        local = [join_random, switch, button]
+    )]
     fn timer(cx: timer::Context) {
-        (cx.shared.timer, cx.shared.service).lock(|timer, service| {
-            if timer.is_compare_event(1) {
-                timer.ack_compare_event(1);
-                let _ = service.update(timer.now());
-                timer.fire_in(1, TIMER_SECOND);
+        let join_random = cx.local.join_random;
+        let switch = cx.local.switch;
+        let button = cx.local.button;
+        (
+            cx.shared.timer,
+            cx.shared.service,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|timer, service, stored_network, reset_pending| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    if *reset_pending {
+                        // The leave has been sent, start over without a network
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    let _ = service.update(timer.now());
+                    if service.network_identity().is_some() {
+                        timer.fire_in(1, TIMER_SECOND);
+                    } else {
+                        // Spread the beacon requests and association retries
+                        timer.fire_in(1, TIMER_SECOND + join_random.below(JOIN_JITTER));
+                    }
+                    if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                        let _ = store_network_state::spawn(state);
+                    }
+                    let handler = service.cluster_library_handler_mut();
+                    // One segment per second, the others on the following seconds
+                    if let Some((endpoint, state)) = handler.take_changed() {
+                        let _ = store_light_state::spawn(endpoint, state);
+                    }
+                    if handler.take_groups_changed() {
+                        let _ = store_group_table::spawn(handler.group_table());
+                    }
+                    if let Some((endpoint, scenes)) = handler.take_scenes_changed() {
+                        let _ = store_scene_table::spawn(endpoint, scenes);
+                    }
+                    if handler.take_bindings_changed() {
+                        let _ = store_binding_table::spawn(handler.binding_table());
+                    }
+                    if handler.take_reset_requested() {
+                        let _ = factory_reset::spawn();
+                    }
+                    handler.report_tick();
+                    send_messages(service, Some(COORDINATOR));
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    let handler = service.cluster_library_handler_mut();
+                    handler.identify_tick();
+                    handler.transition_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                if timer.is_compare_event(3) {
+                    timer.ack_compare_event(3);
+                    let pressed = switch.is_low().unwrap_or(false);
+                    if let Some(Event::VeryLongPress) = button.update(pressed) {
+                        let _ = factory_reset::spawn();
+                    }
+                    timer.fire_in(3, BUTTON_TICK);
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the state of a segment to flash
+    #[task(shared = [store])]
+    fn store_light_state(mut cx: store_light_state::Context, endpoint: u8, state: LightState) {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        let length = state.pack(&mut data);
+        let key = SEGMENT_LIGHT_STATE_KEY + u16::from(endpoint);
+        cx.shared.store.lock(|store| {
+            if store.write(key, &data[..length]).is_err() {
+                defmt::warn!("Failed to store state of segment {=u8}", endpoint);
+            }
+        });
+    }
+
+    /// Write the group memberships to flash
+    #[task(shared = [store])]
+    fn store_group_table(mut cx: store_group_table::Context, groups: Groups) {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        let length = groups.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(GROUP_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store group table");
+            }
+        });
+    }
+
+    /// Write the scenes of a segment to flash
+    #[task(shared = [store])]
+    fn store_scene_table(mut cx: store_scene_table::Context, endpoint: u8, scenes: Scenes) {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        let length = scenes.pack(&mut data);
+        let key = SEGMENT_SCENE_TABLE_KEY + u16::from(endpoint);
+        cx.shared.store.lock(|store| {
+            if store.write(key, &data[..length]).is_err() {
+                defmt::warn!("Failed to store scenes of segment {=u8}", endpoint);
+            }
+        });
+    }
+
+    /// Write the bindings to flash
+    #[task(shared = [store])]
+    fn store_binding_table(mut cx: store_binding_table::Context, bindings: Bindings) {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        let length = bindings.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(BINDING_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store binding table");
             }
-            let _ = radio_tx::spawn();
         });
     }
 
-    #[task(binds = RADIO, shared = [radio, service], local = [rx_producer])]
+    /// Leave the network and erase the stored state
+    ///
+    /// The device is restarted by the timer task once the leave has been sent.
+    #[task(shared = [service, store, stored_network, reset_pending])]
+    fn factory_reset(cx: factory_reset::Context) {
+        defmt::info!("Factory reset");
+        (
+            cx.shared.service,
+            cx.shared.store,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|service, store, stored_network, reset_pending| {
+                if service.leave().is_err() {
+                    defmt::warn!("Failed to leave the network");
+                }
+                if reset::wipe(store).and(wipe_segments(store)).is_err() {
+                    defmt::warn!("Failed to remove the stored state");
+                }
+                *stored_network = None;
+                *reset_pending = true;
+            });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(
+        binds = RADIO,
+        shared = [radio, service, csma],
+        local = [rx_producer, backoff_random]
+    )]
     fn radio(cx: radio::Context) {
         let queue = cx.local.rx_producer;
-        (cx.shared.radio, cx.shared.service).lock(|radio, service| {
-            let mut packet = [0u8; MAX_PACKET_LENGHT as usize];
-            match radio.receive(&mut packet) {
-                Ok(packet_len) => {
-                    if packet_len > 0 {
-                        match service.handle_acknowledge(&packet[1..packet_len - 1]) {
-                            Ok(to_me) => {
-                                if to_me {
-                                    if let Ok(mut grant) = queue.grant_exact(packet_len) {
-                                        grant.copy_from_slice(&packet[..packet_len]);
-                                        grant.commit(packet_len);
-                                    }
-                                }
-                            }
-                            Err(e) => match e {
-                                psila_service::Error::MalformedPacket => {
-                                    defmt::warn!(
-                                        "service handle acknowledge failed, malformed package"
-                                    );
-                                }
-                                psila_service::Error::NotEnoughSpace => {
-                                    defmt::warn!("service handle acknowledge failed, queue full");
-                                }
-                                _ => {
-                                    defmt::warn!("service handle acknowledge failed");
-                                }
-                            },
-                        }
-                    }
-                }
-                Err(psila_nrf52::radio::Error::CcaBusy) => {
-                    defmt::warn!("CCA Busy");
+        let random = cx.local.backoff_random;
+        (cx.shared.radio, cx.shared.service, cx.shared.csma).lock(|radio, service, csma| {
+            if receive_frame(radio, service, queue).is_err() {
+                match csma.backoff.busy(random) {
+                    Some(0) => csma.backoff.expired(),
+                    Some(delay) => csma.timer.fire_in(1, delay),
+                    None => defmt::warn!("CCA Busy, frame dropped"),
                 }
             }
             let _ = radio_tx::spawn();
         });
     }
 
+    #[task(binds = TIMER0, shared = [csma])]
+    fn backoff_expired(mut cx: backoff_expired::Context) {
+        cx.shared.csma.lock(|csma| {
+            if csma.timer.is_compare_event(1) {
+                csma.timer.ack_compare_event(1);
+                csma.backoff.expired();
+            }
+        });
+        let _ = radio_tx::spawn();
+    }
+
     #[task(shared = [service, timer], local = [rx_consumer])]
     fn radio_rx(mut cx: radio_rx::Context) {
         let queue = cx.local.rx_consumer;
         let timestamp = cx.shared.timer.lock(|timer| timer.now());
         cx.shared.service.lock(|service| {
-            if let Ok(grant) = queue.read() {
-                let packet_length = grant[0] as usize;
-                if let Err(_) = service.receive(timestamp, &grant[1..packet_length - 1]) {
-                    defmt::warn!("service receive failed");
-                }
-                grant.release(packet_length);
+            if handle_frame(service, queue, timestamp) {
+                send_messages(service, Some(COORDINATOR));
                 let _ = radio_tx::spawn();
             }
         });
     }
 
-    #[task(shared = [radio], local = [tx_consumer])]
-    fn radio_tx(mut cx: radio_tx::Context) {
+    #[task(shared = [radio, csma], local = [tx_consumer])]
+    fn radio_tx(cx: radio_tx::Context) {
         const NO_CCA_MARKER: u8 = 0x80;
         let queue = cx.local.tx_consumer;
-        cx.shared.radio.lock(|radio| {
-            if !radio.is_tx_busy() {
-                if let Ok(grant) = queue.read() {
+        (cx.shared.radio, cx.shared.csma).lock(|radio, csma| {
+            // Other frames wait while a frame backs off
+            if !radio.is_tx_busy() && !csma.backoff.is_waiting() {
+                if let Some(data) = csma.backoff.retry() {
+                    let _ = radio.queue_transmission(data);
+                } else if let Ok(grant) = queue.read() {
                     let no_cca = (grant[0] & NO_CCA_MARKER) == NO_CCA_MARKER;
                     let packet_length = (grant[0] & 0x7f) as usize;
                     let data = &grant[1..=packet_length];
@@ -720,6 +1882,7 @@ mod app {
                         let _ = radio.queue_transmission_no_cca(data);
                     } else {
                         let _ = radio.queue_transmission(data);
+                        csma.backoff.transmitted(data);
                     }
                     grant.release(packet_length + 1);
                 }
@@ -17,6 +17,33 @@ defmt::timestamp! {
     }
 }
 
+/// Identity of the firmware build, from the package version and git
+pub mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+/// Flash pages of the key/value store, defined in `storage.x`
+pub mod storage {
+    /// Size of a flash page
+    const PAGE_SIZE: usize = 4096;
+
+    extern "C" {
+        static __storage_start: u8;
+        static __storage_end: u8;
+    }
+
+    /// Address of the first page
+    pub fn address() -> u32 {
+        unsafe { core::ptr::addr_of!(__storage_start) as u32 }
+    }
+
+    /// Number of pages
+    pub fn pages() -> usize {
+        let end = unsafe { core::ptr::addr_of!(__storage_end) as u32 };
+        (end - address()) as usize / PAGE_SIZE
+    }
+}
+
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
 pub fn exit() -> ! {
     loop {
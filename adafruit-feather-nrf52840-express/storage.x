@@ -0,0 +1,8 @@
+/* Flash pages of the key/value store, four pages at the start of the user
+   data region below the UF2 bootloader */
+__storage_start = 0x000ed000;
+__storage_end = __storage_start + 4 * 4K;
+
+/* The end of .data in flash is the end of the application image */
+ASSERT(__sidata + (__edata - __sdata) <= __storage_start,
+  "the application overlaps the key/value store");
@@ -0,0 +1,82 @@
+//! Build script helpers shared by the board crates
+//!
+//! Called from `build.rs`, the package version and directory are those of the
+//! board crate being built.
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// Longest software build identifier of the basic cluster
+const SW_BUILD_ID_MAX_LENGTH: usize = 16;
+
+/// Run git in the package directory, `None` if git fails
+fn git(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(arguments).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_string())
+}
+
+/// Software build identifier, `git describe` or the abbreviated commit if
+/// that is too long, the package version outside of git
+fn sw_build_id() -> String {
+    git(&["describe", "--always", "--tags", "--dirty"])
+        .filter(|describe| describe.len() <= SW_BUILD_ID_MAX_LENGTH)
+        .or_else(|| {
+            let commit = git(&["rev-parse", "--short", "HEAD"])?;
+            let dirty = git(&["status", "--porcelain", "--untracked-files=no"])?;
+            Some(if dirty.is_empty() {
+                commit
+            } else {
+                format!("{}-dirty", commit)
+            })
+        })
+        .unwrap_or_else(|| env::var("CARGO_PKG_VERSION").unwrap())
+}
+
+/// Date code, the date of the last commit as YYYYMMDD
+fn date_code() -> String {
+    git(&["log", "-1", "--format=%cd", "--date=format:%Y%m%d"]).unwrap_or_default()
+}
+
+/// Application version, the minor and patch numbers of the package version
+/// in four bits each
+fn application_version() -> u8 {
+    let number = |name: &str| env::var(name).unwrap().parse::<u8>().unwrap_or(0).min(15);
+    number("CARGO_PKG_VERSION_MINOR") << 4 | number("CARGO_PKG_VERSION_PATCH")
+}
+
+/// Write `build_info.rs` with the identity of the firmware build to `OUT_DIR`
+pub fn build_info() {
+    let out_directory = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let build_info = format!(
+        "/// Application version of the basic cluster\n\
+         pub const APPLICATION_VERSION: u8 = {};\n\
+         /// Date code of the basic cluster\n\
+         pub const DATE_CODE: &str = {:?};\n\
+         /// Software build identifier of the basic cluster\n\
+         pub const SW_BUILD_ID: &str = {:?};\n",
+        application_version(),
+        date_code(),
+        sw_build_id(),
+    );
+    fs::write(out_directory.join("build_info.rs"), build_info).unwrap();
+    // Describe the new commit or state of the working tree
+    if let Some(directory) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", directory);
+        println!("cargo:rerun-if-changed={}/index", directory);
+        println!("cargo:rerun-if-changed={}/refs", directory);
+    }
+}
+
+/// Link the examples with the linker script `name` of the package, which
+/// keeps the flash pages of the key/value store clear of the application
+pub fn storage_script(name: &str) {
+    let out_directory = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let package_directory = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    fs::copy(package_directory.join(name), out_directory.join(name)).unwrap();
+    println!("cargo:rustc-link-search={}", out_directory.display());
+    println!("cargo:rustc-link-arg-examples=-T{}", name);
+    println!("cargo:rerun-if-changed={}", name);
+}
@@ -0,0 +1,23 @@
+use std::{env, path::PathBuf};
+
+/// The CC310 library from the nRF5 SDK, hard float ABI without interrupts
+const LIBRARY_DIRECTORY: &str = "nrf_cc310/lib/cortex-m4/hard-float/no-interrupts";
+const LIBRARY_NAME: &str = "nrf_cc310_0.9.13";
+
+fn main() {
+    let manifest_directory = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let directory = manifest_directory.join(LIBRARY_DIRECTORY);
+    let library = directory.join(format!("lib{}.a", LIBRARY_NAME));
+    println!("cargo:rerun-if-changed={}", library.display());
+    // The library is only needed when linking, so that the crate still
+    // builds without the SDK
+    if library.exists() {
+        println!("cargo:rustc-link-search=native={}", directory.display());
+        println!("cargo:rustc-link-lib=static={}", LIBRARY_NAME);
+    } else {
+        println!(
+            "cargo:warning=CC310 library not found, copy external/nrf_cc310 from the nRF5 SDK into {}",
+            manifest_directory.display()
+        );
+    }
+}
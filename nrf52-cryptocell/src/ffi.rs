@@ -0,0 +1,92 @@
+//! Declarations of the CC310 library functions in use
+//!
+//! From the headers of `nrf_cc310` 0.9.13 in the nRF5 SDK 17.
+
+#![allow(non_camel_case_types, non_snake_case)]
+
+/// `CRYS_OK`, `SA_SILIB_RET_OK` and `SASI_OK`
+pub const OK: u32 = 0;
+
+/// `SASI_AES_USER_CTX_SIZE_IN_WORDS`
+pub const AES_USER_CTX_SIZE_IN_WORDS: usize = 131;
+
+/// `SaSiAesEncryptMode_t`, `SASI_AES_ENCRYPT`
+pub const AES_ENCRYPT: u32 = 0;
+/// `SaSiAesEncryptMode_t`, `SASI_AES_DECRYPT`
+pub const AES_DECRYPT: u32 = 1;
+/// `SaSiAesOperationMode_t`, `SASI_AES_MODE_ECB`
+pub const AES_MODE_ECB: u32 = 0;
+/// `SaSiAesPaddingType_t`, `SASI_AES_PADDING_NONE`
+pub const AES_PADDING_NONE: u32 = 0;
+/// `SaSiAesKeyType_t`, `SASI_AES_USER_KEY`
+pub const AES_USER_KEY: u32 = 0;
+
+/// `CRYS_AESCCM_KeySize_t`, `CRYS_AES_Key128BitSize`
+pub const AESCCM_KEY_128_BIT_SIZE: u32 = 0;
+/// `CRYS_AESCCM_MODE_STAR`
+pub const AESCCM_MODE_STAR: u32 = 1;
+/// `CRYS_AESCCM_NONCE_SIZE_BYTES` of CCM*
+pub const AESCCM_STAR_NONCE_SIZE: u8 = 13;
+
+/// `SaSiAesUserContext_t`
+#[repr(C)]
+pub struct SaSiAesUserContext_t {
+    pub buff: [u32; AES_USER_CTX_SIZE_IN_WORDS],
+}
+
+/// `SaSiAesUserKeyData_t`
+#[repr(C)]
+pub struct SaSiAesUserKeyData_t {
+    pub pKey: *mut u8,
+    pub keySize: usize,
+}
+
+extern "C" {
+    pub fn SaSi_LibInit() -> u32;
+    pub fn SaSi_LibFini();
+
+    pub fn SaSi_AesInit(
+        pContext: *mut SaSiAesUserContext_t,
+        encryptDecryptFlag: u32,
+        operationMode: u32,
+        paddingType: u32,
+    ) -> u32;
+    pub fn SaSi_AesSetKey(
+        pContext: *mut SaSiAesUserContext_t,
+        keyType: u32,
+        pKeyData: *mut SaSiAesUserKeyData_t,
+        keyDataSize: usize,
+    ) -> u32;
+    pub fn SaSi_AesBlock(
+        pContext: *mut SaSiAesUserContext_t,
+        pDataIn: *mut u8,
+        dataInSize: usize,
+        pDataOut: *mut u8,
+    ) -> u32;
+    pub fn SaSi_AesFinish(
+        pContext: *mut SaSiAesUserContext_t,
+        dataSize: usize,
+        pDataIn: *mut u8,
+        dataInBuffSize: usize,
+        pDataOut: *mut u8,
+        dataOutBuffSize: *mut usize,
+    ) -> u32;
+    pub fn SaSi_AesFree(pContext: *mut SaSiAesUserContext_t) -> u32;
+
+    /// The function behind the `CRYS_AESCCMStar` macro
+    pub fn CC_AESCCM(
+        EncrDecrMode: u32,
+        CCM_Key: *mut u8,
+        KeySizeId: u32,
+        N_ptr: *mut u8,
+        SizeOfN: u8,
+        ADataIn_ptr: *mut u8,
+        ADataInSize: u32,
+        TextDataIn_ptr: *mut u8,
+        TextDataInSize: u32,
+        TextDataOut_ptr: *mut u8,
+        SizeOfT: u8,
+        Mac_Res: *mut u8,
+        ccmMode: u32,
+    ) -> u32;
+}
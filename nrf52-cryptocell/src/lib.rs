@@ -0,0 +1,267 @@
+//! Psila crypto backend using the CryptoCell CC310 of the nRF52840
+//!
+//! AES-128 ECB and CCM* are run by the CC310 instead of in software. The
+//! CC310 is only powered while an operation runs. The library from the nRF5
+//! SDK has to be copied into this crate, see the README.
+
+#![no_std]
+
+mod ffi;
+
+use core::mem;
+
+use nrf52840_pac::CRYPTOCELL;
+use psila_crypto::{CryptoBackend, Error};
+
+/// Size of an AES-128 key
+const KEY_SIZE: usize = 16;
+/// Size of an AES block
+const BLOCK_SIZE: usize = 16;
+/// Size of the CCM* nonce
+const NONCE_SIZE: usize = ffi::AESCCM_STAR_NONCE_SIZE as usize;
+/// Largest message or additional data, an IEEE 802.15.4 frame is at most
+/// 127 bytes
+const MAX_DATA_SIZE: usize = 128;
+/// Largest message integrity code
+const MAX_MIC_SIZE: usize = 16;
+
+/// Crypto backend using the CC310
+pub struct CryptoCellBackend {
+    cryptocell: CRYPTOCELL,
+    context: ffi::SaSiAesUserContext_t,
+    key: [u8; KEY_SIZE],
+}
+
+impl CryptoCellBackend {
+    /// Initialise the CC310 library, returns `None` if it fails
+    pub fn new(cryptocell: CRYPTOCELL) -> Option<Self> {
+        let mut backend = Self {
+            cryptocell,
+            context: ffi::SaSiAesUserContext_t {
+                buff: [0; ffi::AES_USER_CTX_SIZE_IN_WORDS],
+            },
+            key: [0; KEY_SIZE],
+        };
+        let result = backend.powered(|_| unsafe { ffi::SaSi_LibInit() });
+        if result == ffi::OK {
+            Some(backend)
+        } else {
+            None
+        }
+    }
+
+    /// Shut down the CC310 library and release the peripheral
+    pub fn free(mut self) -> CRYPTOCELL {
+        self.powered(|_| unsafe { ffi::SaSi_LibFini() });
+        self.cryptocell
+    }
+
+    /// Run `f` with the CC310 enabled
+    fn powered<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.cryptocell.enable.write(|w| w.enable().enabled());
+        let result = f(self);
+        self.cryptocell.enable.write(|w| w.enable().disabled());
+        result
+    }
+
+    /// CCM* encryption or decryption
+    ///
+    /// The library reads the data through DMA, so the inputs are copied to
+    /// RAM first as they may be placed in flash.
+    #[allow(clippy::too_many_arguments)]
+    fn ccm_star(
+        &mut self,
+        mode: u32,
+        key: &[u8],
+        nonce: &[u8],
+        text: &[u8],
+        mic: &mut [u8],
+        additional_data: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), Error> {
+        if key.len() != KEY_SIZE {
+            return Err(Error::InvalidKey);
+        }
+        if nonce.len() != NONCE_SIZE || mic.len() > MAX_MIC_SIZE {
+            return Err(Error::InvalidData);
+        }
+        if text.len() > MAX_DATA_SIZE || additional_data.len() > MAX_DATA_SIZE {
+            return Err(Error::NotEnoughSpace);
+        }
+        if output.len() < text.len() {
+            return Err(Error::NotEnoughSpace);
+        }
+        // CRYS_AESCCM_Key_t is large enough for 256-bit keys
+        let mut ccm_key = [0u8; 2 * KEY_SIZE];
+        ccm_key[..KEY_SIZE].copy_from_slice(key);
+        let mut ccm_nonce = [0u8; NONCE_SIZE];
+        ccm_nonce.copy_from_slice(nonce);
+        let mut ccm_additional_data = [0u8; MAX_DATA_SIZE];
+        ccm_additional_data[..additional_data.len()].copy_from_slice(additional_data);
+        let mut ccm_text = [0u8; MAX_DATA_SIZE];
+        ccm_text[..text.len()].copy_from_slice(text);
+        let mut ccm_output = [0u8; MAX_DATA_SIZE];
+        let mut ccm_mic = [0u8; MAX_MIC_SIZE];
+        ccm_mic[..mic.len()].copy_from_slice(mic);
+        let result = self.powered(|_| unsafe {
+            ffi::CC_AESCCM(
+                mode,
+                ccm_key.as_mut_ptr(),
+                ffi::AESCCM_KEY_128_BIT_SIZE,
+                ccm_nonce.as_mut_ptr(),
+                ffi::AESCCM_STAR_NONCE_SIZE,
+                ccm_additional_data.as_mut_ptr(),
+                additional_data.len() as u32,
+                ccm_text.as_mut_ptr(),
+                text.len() as u32,
+                ccm_output.as_mut_ptr(),
+                mic.len() as u8,
+                ccm_mic.as_mut_ptr(),
+                ffi::AESCCM_MODE_STAR,
+            )
+        });
+        if result != ffi::OK {
+            return Err(Error::Other(result));
+        }
+        output[..text.len()].copy_from_slice(&ccm_output[..text.len()]);
+        let mic_length = mic.len();
+        mic.copy_from_slice(&ccm_mic[..mic_length]);
+        Ok(())
+    }
+
+    /// Encrypt whole blocks with the key of the AES context
+    fn aes_blocks(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        if !input.len().is_multiple_of(BLOCK_SIZE) || input.len() > MAX_DATA_SIZE {
+            return Err(Error::InvalidData);
+        }
+        if output.len() < input.len() {
+            return Err(Error::NotEnoughSpace);
+        }
+        if input.is_empty() {
+            return Ok(());
+        }
+        let mut data = [0u8; MAX_DATA_SIZE];
+        data[..input.len()].copy_from_slice(input);
+        let mut result_data = [0u8; MAX_DATA_SIZE];
+        let result = self.powered(|backend| unsafe {
+            ffi::SaSi_AesBlock(
+                &mut backend.context,
+                data.as_mut_ptr(),
+                input.len(),
+                result_data.as_mut_ptr(),
+            )
+        });
+        if result != ffi::OK {
+            return Err(Error::Other(result));
+        }
+        output[..input.len()].copy_from_slice(&result_data[..input.len()]);
+        Ok(())
+    }
+}
+
+impl CryptoBackend for CryptoCellBackend {
+    fn ccmstar_decrypt(
+        &mut self,
+        key: &[u8],
+        nonce: &[u8],
+        message: &[u8],
+        mic_length: usize,
+        additional_data: &[u8],
+        message_output: &mut [u8],
+    ) -> Result<usize, Error> {
+        if message.len() < mic_length || mic_length > MAX_MIC_SIZE {
+            return Err(Error::InvalidData);
+        }
+        let length = message.len() - mic_length;
+        let mut mic = [0u8; MAX_MIC_SIZE];
+        mic[..mic_length].copy_from_slice(&message[length..]);
+        // The library verifies the received MIC
+        self.ccm_star(
+            ffi::AES_DECRYPT,
+            key,
+            nonce,
+            &message[..length],
+            &mut mic[..mic_length],
+            additional_data,
+            message_output,
+        )?;
+        Ok(length)
+    }
+
+    fn ccmstar_encrypt(
+        &mut self,
+        key: &[u8],
+        nonce: &[u8],
+        message: &[u8],
+        mic_length: usize,
+        additional_data: &[u8],
+        message_output: &mut [u8],
+    ) -> Result<usize, Error> {
+        let length = message.len() + mic_length;
+        if mic_length > MAX_MIC_SIZE {
+            return Err(Error::InvalidData);
+        }
+        if message_output.len() < length {
+            return Err(Error::NotEnoughSpace);
+        }
+        let mut mic = [0u8; MAX_MIC_SIZE];
+        self.ccm_star(
+            ffi::AES_ENCRYPT,
+            key,
+            nonce,
+            message,
+            &mut mic[..mic_length],
+            additional_data,
+            message_output,
+        )?;
+        message_output[message.len()..length].copy_from_slice(&mic[..mic_length]);
+        Ok(length)
+    }
+
+    fn aes128_ecb_encrypt_set_key(&mut self, key: &[u8]) -> Result<(), Error> {
+        if key.len() != KEY_SIZE {
+            return Err(Error::InvalidKey);
+        }
+        self.key.copy_from_slice(key);
+        let result = self.powered(|backend| unsafe {
+            let result = ffi::SaSi_AesInit(
+                &mut backend.context,
+                ffi::AES_ENCRYPT,
+                ffi::AES_MODE_ECB,
+                ffi::AES_PADDING_NONE,
+            );
+            if result != ffi::OK {
+                return result;
+            }
+            let mut key_data = ffi::SaSiAesUserKeyData_t {
+                pKey: backend.key.as_mut_ptr(),
+                keySize: KEY_SIZE,
+            };
+            ffi::SaSi_AesSetKey(
+                &mut backend.context,
+                ffi::AES_USER_KEY,
+                &mut key_data,
+                mem::size_of::<ffi::SaSiAesUserKeyData_t>(),
+            )
+        });
+        if result == ffi::OK {
+            Ok(())
+        } else {
+            Err(Error::Other(result))
+        }
+    }
+
+    fn aes128_ecb_encrypt_process_block(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), Error> {
+        self.aes_blocks(input, output)
+    }
+
+    fn aes128_ecb_encrypt_finish(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        let result = self.aes_blocks(input, output);
+        self.powered(|backend| unsafe { ffi::SaSi_AesFree(&mut backend.context) });
+        result
+    }
+}
@@ -0,0 +1,107 @@
+//! Extended address
+//!
+//! The IEEE 802.15.4 extended address of the device. An EUI-64 can be
+//! provisioned in the UICR customer registers 0 and 1, low word first.
+//! Otherwise the address is derived from the device address in the FICR,
+//! which is a random BLE address and not a registered EUI-48.
+
+/// First UICR customer register of a provisioned EUI-64
+pub const UICR_EUI64_REGISTER: usize = 0;
+
+/// Where the extended address comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Origin {
+    /// Provisioned in the UICR
+    Uicr,
+    /// Derived from the device address in the FICR
+    Ficr,
+}
+
+impl Origin {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Origin::Uicr => "UICR",
+            Origin::Ficr => "FICR",
+        }
+    }
+}
+
+/// EUI-64 from the two UICR registers, low word first
+///
+/// Returns `None` for erased registers, and for the all zero address.
+pub fn from_registers(low: u32, high: u32) -> Option<u64> {
+    match u64::from(high) << 32 | u64::from(low) {
+        0 | u64::MAX => None,
+        address => Some(address),
+    }
+}
+
+/// EUI-48 of the FICR device address to EUI-64
+///
+/// FF FE is inserted in the middle.
+///
+/// ```text
+///    01 23 45 67 89 AB
+///  /  /  /       \  \  \
+/// 01 23 45 FF FE 67 89 AB
+/// ```
+pub fn from_device_address(low: u32, high: u32) -> u64 {
+    u64::from(high & 0xffff) << 48
+        | u64::from(low & 0xff00_0000) << 16
+        | 0x0000_00ff_fe00_0000
+        | u64::from(low & 0x00ff_ffff)
+}
+
+/// Extended address provisioned in the UICR, or derived from the device
+/// address in the FICR
+#[cfg(feature = "52840")]
+pub fn read(uicr: &nrf52840_pac::UICR, ficr: &nrf52840_pac::FICR) -> (u64, Origin) {
+    let low = uicr.customer[UICR_EUI64_REGISTER].read().bits();
+    let high = uicr.customer[UICR_EUI64_REGISTER + 1].read().bits();
+    match from_registers(low, high) {
+        Some(address) => (address, Origin::Uicr),
+        None => {
+            let low = ficr.deviceaddr[0].read().bits();
+            let high = ficr.deviceaddr[1].read().bits();
+            (from_device_address(low, high), Origin::Ficr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        assert_eq!(
+            from_registers(0x89ab_cdef, 0x0123_4567),
+            Some(0x0123_4567_89ab_cdef)
+        );
+        assert_eq!(from_registers(0xffff_ffff, 0xffff_ffff), None);
+        assert_eq!(from_registers(0, 0), None);
+        // Only one erased register is still an address
+        assert_eq!(from_registers(0xffff_ffff, 0), Some(0x0000_0000_ffff_ffff));
+    }
+
+    #[test]
+    fn device_address() {
+        // 01:23:45:67:89:AB, low 32 bits in the first register
+        let address = from_device_address(0x4567_89ab, 0x0123);
+        assert_eq!(address, 0x0123_45ff_fe67_89ab);
+        assert_eq!(
+            address.to_be_bytes(),
+            [0x01, 0x23, 0x45, 0xff, 0xfe, 0x67, 0x89, 0xab]
+        );
+        // The upper bytes of the first register end up in the top bytes, and
+        // the unused bits of the second register are ignored
+        assert_eq!(
+            from_device_address(0xff00_0000, 0xffff_ff00),
+            0xff00_ffff_fe00_0000
+        );
+        assert_eq!(
+            from_device_address(0x0000_0000, 0x0000_00ff),
+            0x00ff_00ff_fe00_0000
+        );
+    }
+}
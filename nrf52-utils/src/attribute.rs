@@ -0,0 +1,275 @@
+//! Attribute tables
+//!
+//! The attributes of an endpoint are declared once in a table, with the
+//! identifier, data type, if the attribute is reportable, and functions to
+//! get and set the value. The table answers reads and writes for the cluster
+//! library handler, rejects writes of read-only attributes and of values of
+//! the wrong type, and lists the attributes for discovery and reporting.
+//!
+//! The cluster revision global attribute is answered for every cluster in
+//! the table.
+//!
+//! The handler type is named through an alias, so that the types of the
+//! closures are known.
+//!
+//! ```ignore
+//! type LightAttribute = Attribute<Light>;
+//!
+//! const ATTRIBUTES: AttributeTable<Light> = AttributeTable::new(&[
+//!     LightAttribute::new(0x0006, 0x0000, AttributeDataType::Boolean, |light| {
+//!         Value::Boolean(light.on_off)
+//!     })
+//!     .reportable(),
+//! ]);
+//! ```
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::cluster_library::{AttributeDataType, ClusterLibraryStatus};
+
+use crate::basic::{ATTR_CLUSTER_REVISION, CLUSTER_REVISION};
+
+/// Longest character string, a length of 0xff marks an invalid string
+const MAX_STRING_LENGTH: usize = 0xfe;
+
+/// Value of an attribute
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Boolean(bool),
+    Unsigned8(u8),
+    Unsigned16(u16),
+    Signed16(i16),
+    Bitmap8(u8),
+    Bitmap16(u16),
+    Enumeration8(u8),
+    CharacterString(&'a str),
+}
+
+impl<'a> Value<'a> {
+    /// Data type of the value
+    pub fn data_type(&self) -> AttributeDataType {
+        match self {
+            Value::Boolean(_) => AttributeDataType::Boolean,
+            Value::Unsigned8(_) => AttributeDataType::Unsigned8,
+            Value::Unsigned16(_) => AttributeDataType::Unsigned16,
+            Value::Signed16(_) => AttributeDataType::Signed16,
+            Value::Bitmap8(_) => AttributeDataType::Bitmap8,
+            Value::Bitmap16(_) => AttributeDataType::Bitmap16,
+            Value::Enumeration8(_) => AttributeDataType::Enumeration8,
+            Value::CharacterString(_) => AttributeDataType::CharacterString,
+        }
+    }
+
+    /// Write the value into `data`, returns the number of bytes written
+    ///
+    /// Character strings are truncated to fit `data`.
+    pub fn encode(&self, data: &mut [u8]) -> usize {
+        match *self {
+            Value::Boolean(value) => {
+                data[0] = value as u8;
+                1
+            }
+            Value::Unsigned8(value) | Value::Bitmap8(value) | Value::Enumeration8(value) => {
+                data[0] = value;
+                1
+            }
+            Value::Unsigned16(value) | Value::Bitmap16(value) => {
+                LittleEndian::write_u16(&mut data[..2], value);
+                2
+            }
+            Value::Signed16(value) => {
+                LittleEndian::write_i16(&mut data[..2], value);
+                2
+            }
+            Value::CharacterString(text) => {
+                let length = text
+                    .len()
+                    .min(MAX_STRING_LENGTH)
+                    .min(data.len().saturating_sub(1));
+                data[0] = length as u8;
+                data[1..=length].copy_from_slice(&text.as_bytes()[..length]);
+                length + 1
+            }
+        }
+    }
+
+    /// Read a value of the data type from `data`
+    ///
+    /// Returns `None` if the data is too short or the data type is not
+    /// supported.
+    pub fn decode(data_type: AttributeDataType, data: &'a [u8]) -> Option<Self> {
+        let byte = data.first().copied();
+        let word = data.get(..2).map(LittleEndian::read_u16);
+        match data_type {
+            AttributeDataType::Boolean => byte.map(|value| Value::Boolean(value == 0x01)),
+            AttributeDataType::Unsigned8 => byte.map(Value::Unsigned8),
+            AttributeDataType::Unsigned16 => word.map(Value::Unsigned16),
+            AttributeDataType::Signed16 => word.map(|value| Value::Signed16(value as i16)),
+            AttributeDataType::Bitmap8 => byte.map(Value::Bitmap8),
+            AttributeDataType::Bitmap16 => word.map(Value::Bitmap16),
+            AttributeDataType::Enumeration8 => byte.map(Value::Enumeration8),
+            AttributeDataType::CharacterString => {
+                let length = usize::from(byte?);
+                let text = data.get(1..=length)?;
+                core::str::from_utf8(text).ok().map(Value::CharacterString)
+            }
+            _ => None,
+        }
+    }
+
+    /// Value as used by the reporting table, signed values are sign extended
+    ///
+    /// Returns `None` for character strings.
+    pub fn to_u32(&self) -> Option<u32> {
+        match *self {
+            Value::Boolean(value) => Some(u32::from(value)),
+            Value::Unsigned8(value) | Value::Bitmap8(value) | Value::Enumeration8(value) => {
+                Some(u32::from(value))
+            }
+            Value::Unsigned16(value) | Value::Bitmap16(value) => Some(u32::from(value)),
+            Value::Signed16(value) => Some(i32::from(value) as u32),
+            Value::CharacterString(_) => None,
+        }
+    }
+}
+
+/// Get the value of an attribute
+pub type Getter<H> = fn(&H) -> Value<'_>;
+
+/// Set the value of an attribute, the value has the declared type
+pub type Setter<H> = fn(&mut H, Value<'_>) -> Result<(), ClusterLibraryStatus>;
+
+/// Declaration of an attribute
+pub struct Attribute<H> {
+    pub cluster: u16,
+    pub id: u16,
+    pub data_type: AttributeDataType,
+    pub reportable: bool,
+    pub get: Getter<H>,
+    /// Set the value, `None` for a read-only attribute
+    pub set: Option<Setter<H>>,
+}
+
+impl<H> Attribute<H> {
+    /// Declare a read-only attribute that is not reportable
+    pub const fn new(cluster: u16, id: u16, data_type: AttributeDataType, get: Getter<H>) -> Self {
+        Self {
+            cluster,
+            id,
+            data_type,
+            reportable: false,
+            get,
+            set: None,
+        }
+    }
+
+    /// Make the attribute writable
+    pub const fn writable(mut self, set: Setter<H>) -> Self {
+        self.set = Some(set);
+        self
+    }
+
+    /// Make the attribute reportable
+    pub const fn reportable(mut self) -> Self {
+        self.reportable = true;
+        self
+    }
+
+    /// Access control bits, as in the Discover Attributes Extended response
+    pub fn access(&self) -> u8 {
+        let mut access = 0b001;
+        if self.set.is_some() {
+            access |= 0b010;
+        }
+        if self.reportable {
+            access |= 0b100;
+        }
+        access
+    }
+}
+
+/// Table of the attributes of an endpoint
+///
+/// The attributes are declared in ascending order of cluster and attribute
+/// identifier, which is the order they are discovered in.
+pub struct AttributeTable<H: 'static> {
+    attributes: &'static [Attribute<H>],
+}
+
+impl<H> AttributeTable<H> {
+    pub const fn new(attributes: &'static [Attribute<H>]) -> Self {
+        Self { attributes }
+    }
+
+    /// Find the declaration of an attribute
+    pub fn find(&self, cluster: u16, attribute: u16) -> Option<&'static Attribute<H>> {
+        self.attributes
+            .iter()
+            .find(|entry| entry.cluster == cluster && entry.id == attribute)
+    }
+
+    /// Check if the table has attributes of the cluster
+    pub fn has_cluster(&self, cluster: u16) -> bool {
+        self.attributes.iter().any(|entry| entry.cluster == cluster)
+    }
+
+    /// Attributes of a cluster, without the cluster revision
+    pub fn cluster(&self, cluster: u16) -> impl Iterator<Item = &'static Attribute<H>> {
+        self.attributes
+            .iter()
+            .filter(move |entry| entry.cluster == cluster)
+    }
+
+    /// Attributes that are reportable
+    pub fn reportable(&self) -> impl Iterator<Item = &'static Attribute<H>> {
+        self.attributes.iter().filter(|entry| entry.reportable)
+    }
+
+    /// Read an attribute into `value`
+    ///
+    /// Returns the data type and length of the value.
+    pub fn read(
+        &self,
+        handler: &H,
+        cluster: u16,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        if attribute == ATTR_CLUSTER_REVISION && self.has_cluster(cluster) {
+            let length = Value::Unsigned16(CLUSTER_REVISION).encode(value);
+            return Ok((AttributeDataType::Unsigned16, length));
+        }
+        let entry = self
+            .find(cluster, attribute)
+            .ok_or(ClusterLibraryStatus::UnsupportedAttribute)?;
+        let current = (entry.get)(handler);
+        debug_assert!(current.data_type() == entry.data_type);
+        Ok((entry.data_type, current.encode(value)))
+    }
+
+    /// Write an attribute
+    ///
+    /// Writes of read-only attributes are rejected, as are values that are
+    /// not of the declared type.
+    pub fn write(
+        &self,
+        handler: &mut H,
+        cluster: u16,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if attribute == ATTR_CLUSTER_REVISION && self.has_cluster(cluster) {
+            return Err(ClusterLibraryStatus::ReadOnly);
+        }
+        let entry = self
+            .find(cluster, attribute)
+            .ok_or(ClusterLibraryStatus::UnsupportedAttribute)?;
+        let set = entry.set.ok_or(ClusterLibraryStatus::ReadOnly)?;
+        if data_type != entry.data_type {
+            return Err(ClusterLibraryStatus::InvalidValue);
+        }
+        let value = Value::decode(data_type, value).ok_or(ClusterLibraryStatus::InvalidValue)?;
+        set(handler, value)
+    }
+}
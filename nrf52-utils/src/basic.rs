@@ -0,0 +1,54 @@
+//! Basic cluster
+//!
+//! Attributes of the basic cluster that identify the device and its firmware,
+//! and the cluster revision global attribute of all clusters. The attributes
+//! are declared in the attribute table of the application.
+
+/// Basic cluster
+pub const CLUSTER_BASIC: u16 = 0x0000;
+/// Basic cluster attribute, ZCL version
+pub const BASIC_ATTR_ZCL_VERSION: u16 = 0x0000;
+/// Basic cluster attribute, application version
+pub const BASIC_ATTR_APPLICATION_VERSION: u16 = 0x0001;
+/// Basic cluster attribute, stack version
+pub const BASIC_ATTR_STACK_VERSION: u16 = 0x0002;
+/// Basic cluster attribute, hardware version
+pub const BASIC_ATTR_HW_VERSION: u16 = 0x0003;
+/// Basic cluster attribute, manufacturer name
+pub const BASIC_ATTR_MANUFACTURER_NAME: u16 = 0x0004;
+/// Basic cluster attribute, model identifier
+pub const BASIC_ATTR_MODEL_IDENTIFIER: u16 = 0x0005;
+/// Basic cluster attribute, date code
+pub const BASIC_ATTR_DATE_CODE: u16 = 0x0006;
+/// Basic cluster attribute, power source
+pub const BASIC_ATTR_POWER_SOURCE: u16 = 0x0007;
+/// Basic cluster attribute, software build identifier
+pub const BASIC_ATTR_SW_BUILD_ID: u16 = 0x4000;
+
+/// Global attribute of all clusters, cluster revision
+pub const ATTR_CLUSTER_REVISION: u16 = 0xfffd;
+
+/// ZCL revision 6, the first with the cluster revision attribute
+pub const ZCL_VERSION: u8 = 0x02;
+/// Revision of the clusters, the first revision of each cluster in ZCL
+/// revision 6
+pub const CLUSTER_REVISION: u16 = 1;
+
+/// Power source, mains with a single phase
+pub const POWER_SOURCE_MAINS: u8 = 0x01;
+/// Power source, battery
+pub const POWER_SOURCE_BATTERY: u8 = 0x03;
+
+/// Identity of the device and its firmware
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceIdentity {
+    pub manufacturer_name: &'static str,
+    pub model_identifier: &'static str,
+    pub application_version: u8,
+    pub stack_version: u8,
+    pub hw_version: u8,
+    /// Date of the firmware as YYYYMMDD
+    pub date_code: &'static str,
+    pub power_source: u8,
+    pub sw_build_id: &'static str,
+}
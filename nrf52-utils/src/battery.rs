@@ -0,0 +1,42 @@
+//! Battery
+//!
+//! Conversion of SAADC readings of the supply voltage to the battery
+//! attributes of the power configuration cluster.
+
+/// Power configuration cluster
+pub const CLUSTER_POWER_CONFIGURATION: u16 = 0x0001;
+/// Power configuration cluster attribute, battery voltage
+pub const POWER_CONFIGURATION_ATTR_BATTERY_VOLTAGE: u16 = 0x0020;
+/// Power configuration cluster attribute, battery percentage remaining
+pub const POWER_CONFIGURATION_ATTR_BATTERY_PERCENTAGE_REMAINING: u16 = 0x0021;
+
+/// Full scale of the SAADC with a gain of 1/6 and the internal 0.6 V
+/// reference, in millivolts
+const FULL_SCALE: i32 = 3600;
+/// Resolution of the SAADC, in bits
+const RESOLUTION: u32 = 12;
+
+/// Voltage of an empty CR2032 cell, in millivolts
+pub const BATTERY_EMPTY: u16 = 2000;
+/// Voltage of a full CR2032 cell, in millivolts
+pub const BATTERY_FULL: u16 = 3000;
+
+/// Convert a 12-bit SAADC reading of VDD to millivolts
+pub fn vdd_millivolts(raw: i16) -> u16 {
+    let raw = core::cmp::max(i32::from(raw), 0);
+    ((raw * FULL_SCALE) >> RESOLUTION) as u16
+}
+
+/// The BatteryVoltage attribute, in units of 100 mV
+pub fn battery_voltage(millivolts: u16) -> u8 {
+    core::cmp::min((millivolts + 50) / 100, 0xfe) as u8
+}
+
+/// The BatteryPercentageRemaining attribute, in units of 0.5 %
+///
+/// The charge is estimated linearly between `BATTERY_EMPTY` and
+/// `BATTERY_FULL`.
+pub fn battery_percentage_remaining(millivolts: u16) -> u8 {
+    let millivolts = millivolts.clamp(BATTERY_EMPTY, BATTERY_FULL);
+    (u32::from(millivolts - BATTERY_EMPTY) * 200 / u32::from(BATTERY_FULL - BATTERY_EMPTY)) as u8
+}
@@ -0,0 +1,517 @@
+//! Binding table
+//!
+//! Bindings from a cluster on a local endpoint to a group or to an endpoint
+//! on another device, and handling of the ZDO Bind_req, Unbind_req and
+//! Mgmt_Bind_req requests.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Key of the binding table in the key/value store
+pub const BINDING_TABLE_KEY: u16 = 0x0005;
+
+/// ZDO request, bind
+pub const ZDO_BIND_REQ: u16 = 0x0021;
+/// ZDO request, unbind
+pub const ZDO_UNBIND_REQ: u16 = 0x0022;
+/// ZDO request, management bind
+pub const ZDO_MGMT_BIND_REQ: u16 = 0x0033;
+
+/// Address mode, group address
+const ADDRESS_MODE_GROUP: u8 = 0x01;
+/// Address mode, extended address and endpoint
+const ADDRESS_MODE_EXTENDED: u8 = 0x03;
+
+/// ZDO status, success
+const STATUS_SUCCESS: u8 = 0x00;
+/// ZDO status, invalid endpoint
+const STATUS_INVALID_EP: u8 = 0x82;
+/// ZDO status, not supported
+const STATUS_NOT_SUPPORTED: u8 = 0x84;
+/// ZDO status, no entry
+const STATUS_NO_ENTRY: u8 = 0x88;
+/// ZDO status, table full
+const STATUS_TABLE_FULL: u8 = 0x8c;
+
+/// Size of a packed binding
+const BINDING_SIZE: usize = 13;
+
+/// Size of a packed binding table with `N` entries
+pub const fn binding_table_size(entries: usize) -> usize {
+    1 + entries * BINDING_SIZE
+}
+
+/// Destination of a binding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindingDestination {
+    /// A group of devices
+    Group(u16),
+    /// Endpoint of a device with an extended address
+    Device { address: u64, endpoint: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Binding {
+    endpoint: u8,
+    cluster: u16,
+    destination: BindingDestination,
+}
+
+impl Binding {
+    /// Parse the binding fields of a Bind_req or Unbind_req
+    ///
+    /// Returns the source address and the binding.
+    fn parse(data: &[u8]) -> Option<(u64, Self)> {
+        if data.len() < 12 {
+            return None;
+        }
+        let source = LittleEndian::read_u64(&data[0..8]);
+        let endpoint = data[8];
+        let cluster = LittleEndian::read_u16(&data[9..11]);
+        let destination = match data[11] {
+            ADDRESS_MODE_GROUP if data.len() >= 14 => {
+                BindingDestination::Group(LittleEndian::read_u16(&data[12..14]))
+            }
+            ADDRESS_MODE_EXTENDED if data.len() >= 21 => BindingDestination::Device {
+                address: LittleEndian::read_u64(&data[12..20]),
+                endpoint: data[20],
+            },
+            _ => return None,
+        };
+        Some((
+            source,
+            Self {
+                endpoint,
+                cluster,
+                destination,
+            },
+        ))
+    }
+
+    /// Write the binding as in a Mgmt_Bind_rsp, returns the number of bytes
+    /// used or `None` if it does not fit
+    fn write(&self, source: u64, data: &mut [u8]) -> Option<usize> {
+        let size = match self.destination {
+            BindingDestination::Group(_) => 14,
+            BindingDestination::Device { .. } => 21,
+        };
+        if data.len() < size {
+            return None;
+        }
+        LittleEndian::write_u64(&mut data[0..8], source);
+        data[8] = self.endpoint;
+        LittleEndian::write_u16(&mut data[9..11], self.cluster);
+        match self.destination {
+            BindingDestination::Group(group) => {
+                data[11] = ADDRESS_MODE_GROUP;
+                LittleEndian::write_u16(&mut data[12..14], group);
+            }
+            BindingDestination::Device { address, endpoint } => {
+                data[11] = ADDRESS_MODE_EXTENDED;
+                LittleEndian::write_u64(&mut data[12..20], address);
+                data[20] = endpoint;
+            }
+        }
+        Some(size)
+    }
+
+    fn pack(&self, data: &mut [u8]) {
+        data[0] = self.endpoint;
+        LittleEndian::write_u16(&mut data[1..3], self.cluster);
+        match self.destination {
+            BindingDestination::Group(group) => {
+                data[3] = ADDRESS_MODE_GROUP;
+                LittleEndian::write_u64(&mut data[4..12], u64::from(group));
+                data[12] = 0;
+            }
+            BindingDestination::Device { address, endpoint } => {
+                data[3] = ADDRESS_MODE_EXTENDED;
+                LittleEndian::write_u64(&mut data[4..12], address);
+                data[12] = endpoint;
+            }
+        }
+    }
+
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let address = LittleEndian::read_u64(&data[4..12]);
+        let destination = match data[3] {
+            ADDRESS_MODE_GROUP => BindingDestination::Group(address as u16),
+            ADDRESS_MODE_EXTENDED => BindingDestination::Device {
+                address,
+                endpoint: data[12],
+            },
+            _ => return None,
+        };
+        Some(Self {
+            endpoint: data[0],
+            cluster: LittleEndian::read_u16(&data[1..3]),
+            destination,
+        })
+    }
+}
+
+/// Bindings
+#[derive(Clone, Copy, Debug)]
+pub struct BindingTable<const N: usize> {
+    entries: [Option<Binding>; N],
+}
+
+impl<const N: usize> Default for BindingTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BindingTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Number of bindings
+    pub fn count(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// Remove all bindings
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Destinations bound to `cluster` on `endpoint`
+    pub fn destinations(
+        &self,
+        endpoint: u8,
+        cluster: u16,
+    ) -> impl Iterator<Item = BindingDestination> + '_ {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(move |binding| binding.endpoint == endpoint && binding.cluster == cluster)
+            .map(|binding| binding.destination)
+    }
+
+    /// Add a binding, returns false if the table is full
+    fn bind(&mut self, binding: Binding) -> bool {
+        if self.entries.iter().flatten().any(|entry| *entry == binding) {
+            return true;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(binding);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a binding, returns false if there was no such binding
+    fn unbind(&mut self, binding: Binding) -> bool {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| **entry == Some(binding))
+        {
+            Some(entry) => {
+                *entry = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pack the table into `data`, returns the number of bytes used
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        let mut offset = 1;
+        let mut count = 0;
+        for binding in self.entries.iter().flatten() {
+            binding.pack(&mut data[offset..offset + BINDING_SIZE]);
+            offset += BINDING_SIZE;
+            count += 1;
+        }
+        data[0] = count;
+        offset
+    }
+
+    /// Unpack a table from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let count = *data.first()? as usize;
+        if count > N || data.len() < binding_table_size(count) {
+            return None;
+        }
+        let mut table = Self::new();
+        for (n, entry) in data[1..binding_table_size(count)]
+            .chunks(BINDING_SIZE)
+            .enumerate()
+        {
+            table.entries[n] = Some(Binding::unpack(entry)?);
+        }
+        Some(table)
+    }
+
+    /// Handle a ZDO binding request
+    ///
+    /// `address` is the extended address of this device and `endpoints` are
+    /// the active endpoints. The response payload, without the transaction
+    /// sequence number, is written to `response`. Returns the response length
+    /// and if the table changed, or `None` if the request is not a binding
+    /// request.
+    pub fn handle_request(
+        &mut self,
+        address: u64,
+        endpoints: &[u8],
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<(usize, bool)> {
+        match cluster {
+            ZDO_BIND_REQ | ZDO_UNBIND_REQ => {
+                let mut changed = false;
+                response[0] = match Binding::parse(request) {
+                    None => STATUS_NOT_SUPPORTED,
+                    Some((source, _)) if source != address => STATUS_NOT_SUPPORTED,
+                    Some((_, binding)) if !endpoints.contains(&binding.endpoint) => {
+                        STATUS_INVALID_EP
+                    }
+                    Some((_, binding)) if cluster == ZDO_BIND_REQ => {
+                        if self.bind(binding) {
+                            changed = true;
+                            STATUS_SUCCESS
+                        } else {
+                            STATUS_TABLE_FULL
+                        }
+                    }
+                    Some((_, binding)) => {
+                        if self.unbind(binding) {
+                            changed = true;
+                            STATUS_SUCCESS
+                        } else {
+                            STATUS_NO_ENTRY
+                        }
+                    }
+                };
+                Some((1, changed))
+            }
+            ZDO_MGMT_BIND_REQ => {
+                let start = usize::from(*request.first().unwrap_or(&0));
+                let total = self.count();
+                response[0] = STATUS_SUCCESS;
+                response[1] = total as u8;
+                response[2] = start as u8;
+                let mut offset = 4;
+                let mut listed = 0;
+                for binding in self.entries.iter().flatten().skip(start) {
+                    match binding.write(address, &mut response[offset..]) {
+                        Some(size) => {
+                            offset += size;
+                            listed += 1;
+                        }
+                        None => break,
+                    }
+                }
+                response[3] = listed;
+                Some((offset, false))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: u64 = 0x0011_2233_4455_6677;
+    const ENDPOINTS: [u8; 2] = [1, 2];
+
+    /// Bind_req or Unbind_req from `endpoint`, cluster 6, to a device
+    fn device_request(endpoint: u8, target: u64) -> [u8; 21] {
+        let mut request = [0u8; 21];
+        LittleEndian::write_u64(&mut request[0..8], ADDRESS);
+        request[8] = endpoint;
+        LittleEndian::write_u16(&mut request[9..11], 0x0006);
+        request[11] = ADDRESS_MODE_EXTENDED;
+        LittleEndian::write_u64(&mut request[12..20], target);
+        request[20] = 1;
+        request
+    }
+
+    /// Bind_req or Unbind_req from endpoint 1, cluster 6, to a group
+    fn group_request(group: u16) -> [u8; 14] {
+        let mut request = [0u8; 14];
+        LittleEndian::write_u64(&mut request[0..8], ADDRESS);
+        request[8] = 1;
+        LittleEndian::write_u16(&mut request[9..11], 0x0006);
+        request[11] = ADDRESS_MODE_GROUP;
+        LittleEndian::write_u16(&mut request[12..14], group);
+        request
+    }
+
+    /// Handle `request`, returns the status and if the table changed
+    fn handle<const N: usize>(
+        table: &mut BindingTable<N>,
+        cluster: u16,
+        request: &[u8],
+    ) -> (u8, bool) {
+        let mut response = [0u8; 64];
+        let (length, changed) = table
+            .handle_request(ADDRESS, &ENDPOINTS, cluster, request, &mut response)
+            .unwrap();
+        assert_eq!(length, 1);
+        (response[0], changed)
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let mut table = BindingTable::<4>::new();
+        handle(&mut table, ZDO_BIND_REQ, &group_request(0x1234));
+        handle(
+            &mut table,
+            ZDO_BIND_REQ,
+            &device_request(2, 0x8899_aabb_ccdd_eeff),
+        );
+        let mut data = [0u8; binding_table_size(4)];
+        assert_eq!(table.pack(&mut data), binding_table_size(2));
+        assert_eq!(data[0], 2);
+        // The group is stored in the address field
+        assert_eq!(
+            data[1..8],
+            [1, 0x06, 0x00, ADDRESS_MODE_GROUP, 0x34, 0x12, 0x00]
+        );
+        let unpacked = BindingTable::<4>::unpack(&data).unwrap();
+        assert_eq!(unpacked.count(), 2);
+        assert!(unpacked
+            .destinations(1, 0x0006)
+            .eq([BindingDestination::Group(0x1234)]));
+        assert!(unpacked
+            .destinations(2, 0x0006)
+            .eq([BindingDestination::Device {
+                address: 0x8899_aabb_ccdd_eeff,
+                endpoint: 1
+            }]));
+        assert!(BindingTable::<4>::unpack(&data[..binding_table_size(2) - 1]).is_none());
+        assert!(BindingTable::<1>::unpack(&data).is_none());
+        // Unknown address mode
+        data[4] = 0x02;
+        assert!(BindingTable::<4>::unpack(&data).is_none());
+    }
+
+    #[test]
+    fn bind() {
+        let mut table = BindingTable::<4>::new();
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &group_request(0x0001)),
+            (STATUS_SUCCESS, true)
+        );
+        assert_eq!(table.count(), 1);
+        assert!(table
+            .destinations(1, 0x0006)
+            .eq([BindingDestination::Group(0x0001)]));
+        assert_eq!(table.destinations(1, 0x0008).count(), 0);
+        assert_eq!(table.destinations(2, 0x0006).count(), 0);
+    }
+
+    #[test]
+    fn duplicate_bind() {
+        let mut table = BindingTable::<4>::new();
+        let request = device_request(1, 0x1234);
+        handle(&mut table, ZDO_BIND_REQ, &request);
+        // Succeeds without a second entry
+        assert_eq!(handle(&mut table, ZDO_BIND_REQ, &request).0, STATUS_SUCCESS);
+        assert_eq!(table.count(), 1);
+    }
+
+    #[test]
+    fn table_full() {
+        let mut table = BindingTable::<2>::new();
+        handle(&mut table, ZDO_BIND_REQ, &group_request(0x0001));
+        handle(&mut table, ZDO_BIND_REQ, &group_request(0x0002));
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &group_request(0x0003)),
+            (STATUS_TABLE_FULL, false)
+        );
+        assert_eq!(table.count(), 2);
+        // Binding again to an existing destination still succeeds
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &group_request(0x0002)).0,
+            STATUS_SUCCESS
+        );
+    }
+
+    #[test]
+    fn unbind() {
+        let mut table = BindingTable::<4>::new();
+        handle(&mut table, ZDO_BIND_REQ, &group_request(0x0001));
+        assert_eq!(
+            handle(&mut table, ZDO_UNBIND_REQ, &group_request(0x0002)),
+            (STATUS_NO_ENTRY, false)
+        );
+        assert_eq!(
+            handle(&mut table, ZDO_UNBIND_REQ, &device_request(1, 0x0001)),
+            (STATUS_NO_ENTRY, false)
+        );
+        assert_eq!(
+            handle(&mut table, ZDO_UNBIND_REQ, &group_request(0x0001)),
+            (STATUS_SUCCESS, true)
+        );
+        assert_eq!(table.count(), 0);
+        assert_eq!(
+            handle(&mut table, ZDO_UNBIND_REQ, &group_request(0x0001)),
+            (STATUS_NO_ENTRY, false)
+        );
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut table = BindingTable::<4>::new();
+        // Unknown endpoint
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &device_request(3, 0x1234)),
+            (STATUS_INVALID_EP, false)
+        );
+        // Source is another device
+        let mut request = group_request(0x0001);
+        request[0] ^= 0xff;
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &request),
+            (STATUS_NOT_SUPPORTED, false)
+        );
+        // Truncated
+        assert_eq!(
+            handle(&mut table, ZDO_BIND_REQ, &device_request(1, 0x1234)[..20]),
+            (STATUS_NOT_SUPPORTED, false)
+        );
+        assert_eq!(table.count(), 0);
+        let mut response = [0u8; 8];
+        assert!(table
+            .handle_request(ADDRESS, &ENDPOINTS, 0x0005, &[], &mut response)
+            .is_none());
+    }
+
+    #[test]
+    fn mgmt_bind() {
+        let mut table = BindingTable::<4>::new();
+        handle(&mut table, ZDO_BIND_REQ, &group_request(0x0001));
+        handle(&mut table, ZDO_BIND_REQ, &device_request(2, 0x1234));
+        let mut response = [0u8; 64];
+        let (length, changed) = table
+            .handle_request(ADDRESS, &ENDPOINTS, ZDO_MGMT_BIND_REQ, &[0], &mut response)
+            .unwrap();
+        assert!(!changed);
+        assert_eq!(length, 4 + 14 + 21);
+        assert_eq!(response[..4], [STATUS_SUCCESS, 2, 0, 2]);
+        assert_eq!(response[4..18], group_request(0x0001));
+        assert_eq!(response[18..39], device_request(2, 0x1234));
+        // From the second entry
+        let (length, _) = table
+            .handle_request(ADDRESS, &ENDPOINTS, ZDO_MGMT_BIND_REQ, &[1], &mut response)
+            .unwrap();
+        assert_eq!(length, 4 + 21);
+        assert_eq!(response[..4], [STATUS_SUCCESS, 2, 1, 1]);
+        // Only entries that fit are listed
+        let mut response = [0u8; 20];
+        let (length, _) = table
+            .handle_request(ADDRESS, &ENDPOINTS, ZDO_MGMT_BIND_REQ, &[0], &mut response)
+            .unwrap();
+        assert_eq!(length, 4 + 14);
+        assert_eq!(response[..4], [STATUS_SUCCESS, 2, 0, 1]);
+    }
+}
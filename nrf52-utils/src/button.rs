@@ -0,0 +1,90 @@
+//! Push buttons
+//!
+//! Debouncing and press classification of a push button. The button is
+//! sampled by calling `update` at a fixed rate, all thresholds are given in
+//! number of samples.
+
+/// Button event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// Pressed and released before the long press threshold
+    Click,
+    /// Held past the long press threshold
+    LongPress,
+    /// Still held after a long press, sent for every sample
+    Held,
+    /// Released after a long press
+    LongRelease,
+    /// Held past the very long press threshold, no more events are sent
+    /// until the button is released
+    VeryLongPress,
+}
+
+/// Debounced push button
+pub struct Button {
+    debounce: u32,
+    long_press: u32,
+    very_long_press: u32,
+    sample: bool,
+    stable: u32,
+    pressed: bool,
+    held: u32,
+}
+
+impl Button {
+    /// A button that changes state after `debounce` equal samples
+    pub const fn new(debounce: u32, long_press: u32, very_long_press: u32) -> Self {
+        Self {
+            debounce,
+            long_press,
+            very_long_press,
+            sample: false,
+            stable: 0,
+            pressed: false,
+            held: 0,
+        }
+    }
+
+    /// Check if the debounced button is pressed
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Feed a sample, `true` if the button is pressed
+    pub fn update(&mut self, sample: bool) -> Option<Event> {
+        if sample != self.sample {
+            self.sample = sample;
+            self.stable = 0;
+        }
+        self.stable = self.stable.saturating_add(1);
+        if self.stable >= self.debounce && self.sample != self.pressed {
+            self.pressed = self.sample;
+            if self.pressed {
+                self.held = 0;
+                return None;
+            }
+            return if self.held >= self.very_long_press {
+                None
+            } else if self.held >= self.long_press {
+                Some(Event::LongRelease)
+            } else {
+                Some(Event::Click)
+            };
+        }
+        if !self.pressed {
+            return None;
+        }
+        self.held = self.held.saturating_add(1);
+        if self.held == self.very_long_press {
+            Some(Event::VeryLongPress)
+        } else if self.held > self.very_long_press {
+            None
+        } else if self.held == self.long_press {
+            Some(Event::LongPress)
+        } else if self.held > self.long_press {
+            Some(Event::Held)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! CCM* mode
+//!
+//! Authenticated encryption as used by IEEE 802.15.4 and Zigbee frame
+//! security, built on an AES-128 block cipher. CCM* is CCM with a two byte
+//! length field and a 13 byte nonce, that also allows encryption without a
+//! message integrity code.
+
+use crate::cipher::{BlockCipher, BLOCK_SIZE};
+
+/// Size of the nonce
+pub const NONCE_SIZE: usize = 13;
+/// Size of the length field, L
+const LENGTH_SIZE: usize = 2;
+/// Largest message or additional data
+const MAX_LENGTH: usize = 0xff00 - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The MIC length is not 0, 4, 6, 8, 10, 12, 14 or 16
+    InvalidMicLength,
+    /// The data is too long or the output is too small
+    NotEnoughSpace,
+    /// The received MIC does not match
+    InvalidMic,
+}
+
+fn check_mic_length(mic_length: usize) -> Result<(), Error> {
+    match mic_length {
+        0 | 4 | 6 | 8 | 10 | 12 | 14 | 16 => Ok(()),
+        _ => Err(Error::InvalidMicLength),
+    }
+}
+
+/// Counter block `A_i`
+fn counter_block(nonce: &[u8; NONCE_SIZE], counter: u16) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0] = (LENGTH_SIZE - 1) as u8;
+    block[1..=NONCE_SIZE].copy_from_slice(nonce);
+    block[14..16].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+/// XOR `data` with the key stream starting at counter 1
+fn apply_key_stream<C: BlockCipher>(
+    cipher: &mut C,
+    key: &[u8; BLOCK_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    input: &[u8],
+    output: &mut [u8],
+) {
+    for (n, (input, output)) in input
+        .chunks(BLOCK_SIZE)
+        .zip(output.chunks_mut(BLOCK_SIZE))
+        .enumerate()
+    {
+        let mut stream = counter_block(nonce, n as u16 + 1);
+        cipher.encrypt_block(key, &mut stream);
+        for (o, (i, s)) in output.iter_mut().zip(input.iter().zip(stream.iter())) {
+            *o = i ^ s;
+        }
+    }
+}
+
+/// CBC-MAC state over a stream of bytes
+struct Mac<'a, C: BlockCipher> {
+    cipher: &'a mut C,
+    key: &'a [u8; BLOCK_SIZE],
+    state: [u8; BLOCK_SIZE],
+    offset: usize,
+}
+
+impl<'a, C: BlockCipher> Mac<'a, C> {
+    fn new(cipher: &'a mut C, key: &'a [u8; BLOCK_SIZE], first: &[u8; BLOCK_SIZE]) -> Self {
+        let mut state = *first;
+        cipher.encrypt_block(key, &mut state);
+        Self {
+            cipher,
+            key,
+            state,
+            offset: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state[self.offset] ^= byte;
+            self.offset += 1;
+            if self.offset == BLOCK_SIZE {
+                self.cipher.encrypt_block(self.key, &mut self.state);
+                self.offset = 0;
+            }
+        }
+    }
+
+    /// Zero pad to the end of the block
+    fn pad(&mut self) {
+        if self.offset != 0 {
+            self.cipher.encrypt_block(self.key, &mut self.state);
+            self.offset = 0;
+        }
+    }
+}
+
+/// The authentication tag `T` of the message
+fn authenticate<C: BlockCipher>(
+    cipher: &mut C,
+    key: &[u8; BLOCK_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    message: &[u8],
+    mic_length: usize,
+    additional_data: &[u8],
+) -> [u8; BLOCK_SIZE] {
+    let mut first = [0u8; BLOCK_SIZE];
+    first[0] = (LENGTH_SIZE - 1) as u8 | (((mic_length as u8).saturating_sub(2) / 2) << 3);
+    if !additional_data.is_empty() {
+        first[0] |= 0x40;
+    }
+    first[1..=NONCE_SIZE].copy_from_slice(nonce);
+    first[14..16].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    let mut mac = Mac::new(cipher, key, &first);
+    if !additional_data.is_empty() {
+        mac.update(&(additional_data.len() as u16).to_be_bytes());
+        mac.update(additional_data);
+        mac.pad();
+    }
+    mac.update(message);
+    mac.pad();
+    mac.state
+}
+
+/// Encrypt `message` and append a MIC of `mic_length` bytes, returns the
+/// number of bytes written to `output`
+pub fn encrypt<C: BlockCipher>(
+    cipher: &mut C,
+    key: &[u8; BLOCK_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    message: &[u8],
+    mic_length: usize,
+    additional_data: &[u8],
+    output: &mut [u8],
+) -> Result<usize, Error> {
+    check_mic_length(mic_length)?;
+    let length = message.len() + mic_length;
+    if message.len() > MAX_LENGTH || additional_data.len() > MAX_LENGTH || output.len() < length {
+        return Err(Error::NotEnoughSpace);
+    }
+    if mic_length > 0 {
+        let tag = authenticate(cipher, key, nonce, message, mic_length, additional_data);
+        let mut stream = counter_block(nonce, 0);
+        cipher.encrypt_block(key, &mut stream);
+        for (o, (t, s)) in output[message.len()..length]
+            .iter_mut()
+            .zip(tag.iter().zip(stream.iter()))
+        {
+            *o = t ^ s;
+        }
+    }
+    apply_key_stream(cipher, key, nonce, message, &mut output[..message.len()]);
+    Ok(length)
+}
+
+/// Decrypt `message`, which ends with a MIC of `mic_length` bytes, and verify
+/// the MIC, returns the number of bytes written to `output`
+pub fn decrypt<C: BlockCipher>(
+    cipher: &mut C,
+    key: &[u8; BLOCK_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    message: &[u8],
+    mic_length: usize,
+    additional_data: &[u8],
+    output: &mut [u8],
+) -> Result<usize, Error> {
+    check_mic_length(mic_length)?;
+    let length = message
+        .len()
+        .checked_sub(mic_length)
+        .ok_or(Error::NotEnoughSpace)?;
+    if length > MAX_LENGTH || additional_data.len() > MAX_LENGTH || output.len() < length {
+        return Err(Error::NotEnoughSpace);
+    }
+    let (encrypted, mic) = message.split_at(length);
+    apply_key_stream(cipher, key, nonce, encrypted, &mut output[..length]);
+    if mic_length > 0 {
+        let tag = authenticate(
+            cipher,
+            key,
+            nonce,
+            &output[..length],
+            mic_length,
+            additional_data,
+        );
+        let mut stream = counter_block(nonce, 0);
+        cipher.encrypt_block(key, &mut stream);
+        // Compare every byte, independent of where a difference is
+        let difference = mic
+            .iter()
+            .zip(tag.iter().zip(stream.iter()))
+            .fold(0, |difference, (m, (t, s))| difference | (m ^ t ^ s));
+        if difference != 0 {
+            output[..length].iter_mut().for_each(|byte| *byte = 0);
+            return Err(Error::InvalidMic);
+        }
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::SoftAes;
+    use ::ccm::{
+        aead::{generic_array::ArrayLength, AeadInPlace, KeyInit},
+        consts::{U13, U16, U4, U8},
+        Ccm, NonceSize, TagSize,
+    };
+
+    const KEY: [u8; BLOCK_SIZE] = [
+        0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xcb, 0xcc, 0xcd, 0xce,
+        0xcf,
+    ];
+    const NONCE: [u8; NONCE_SIZE] = [
+        0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0x03, 0x02, 0x01, 0x00, 0x06,
+    ];
+    /// Message lengths around the block boundaries
+    const LENGTHS: [usize; 7] = [0, 1, 15, 16, 17, 31, 33];
+
+    fn data(length: usize, seed: u8) -> [u8; 64] {
+        let mut data = [0u8; 64];
+        for (n, byte) in data[..length].iter_mut().enumerate() {
+            *byte = seed.wrapping_add((n as u8).wrapping_mul(7));
+        }
+        data
+    }
+
+    /// Encrypt with RustCrypto CCM, the message followed by the MIC
+    fn reference<M: ArrayLength<u8> + TagSize>(
+        message: &[u8],
+        additional_data: &[u8],
+        output: &mut [u8],
+    ) -> usize
+    where
+        U13: NonceSize,
+    {
+        let cipher = Ccm::<aes::Aes128, M, U13>::new(&KEY.into());
+        output[..message.len()].copy_from_slice(message);
+        let tag = cipher
+            .encrypt_in_place_detached(&NONCE.into(), additional_data, &mut output[..message.len()])
+            .unwrap();
+        output[message.len()..message.len() + tag.len()].copy_from_slice(&tag);
+        message.len() + tag.len()
+    }
+
+    fn check<M: ArrayLength<u8> + TagSize>(mic_length: usize)
+    where
+        U13: NonceSize,
+    {
+        for &length in LENGTHS.iter() {
+            for &additional_length in LENGTHS.iter() {
+                let message = data(length, 0x20);
+                let message = &message[..length];
+                let additional_data = data(additional_length, 0x80);
+                let additional_data = &additional_data[..additional_length];
+
+                let mut expected = [0u8; 80];
+                let expected_length = reference::<M>(message, additional_data, &mut expected);
+                let mut output = [0u8; 80];
+                let output_length = encrypt(
+                    &mut SoftAes,
+                    &KEY,
+                    &NONCE,
+                    message,
+                    mic_length,
+                    additional_data,
+                    &mut output,
+                )
+                .unwrap();
+                assert_eq!(output[..output_length], expected[..expected_length]);
+
+                let mut clear = [0u8; 64];
+                let clear_length = decrypt(
+                    &mut SoftAes,
+                    &KEY,
+                    &NONCE,
+                    &output[..output_length],
+                    mic_length,
+                    additional_data,
+                    &mut clear,
+                )
+                .unwrap();
+                assert_eq!(&clear[..clear_length], message);
+
+                // A changed MIC is rejected and nothing is returned
+                output[output_length - 1] ^= 0x01;
+                assert_eq!(
+                    decrypt(
+                        &mut SoftAes,
+                        &KEY,
+                        &NONCE,
+                        &output[..output_length],
+                        mic_length,
+                        additional_data,
+                        &mut clear,
+                    ),
+                    Err(Error::InvalidMic)
+                );
+                assert!(clear[..clear_length].iter().all(|&byte| byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn mic_4() {
+        check::<U4>(4);
+    }
+
+    #[test]
+    fn mic_8() {
+        check::<U8>(8);
+    }
+
+    #[test]
+    fn mic_16() {
+        check::<U16>(16);
+    }
+
+    #[test]
+    fn without_mic() {
+        // The key stream does not depend on the MIC length, so the message is
+        // encrypted as with a MIC
+        for &length in LENGTHS.iter() {
+            let message = data(length, 0x20);
+            let message = &message[..length];
+            let mut expected = [0u8; 80];
+            reference::<U4>(message, &[0x01, 0x02], &mut expected);
+            let mut output = [0u8; 64];
+            let output_length = encrypt(
+                &mut SoftAes,
+                &KEY,
+                &NONCE,
+                message,
+                0,
+                &[0x01, 0x02],
+                &mut output,
+            )
+            .unwrap();
+            assert_eq!(output_length, length);
+            assert_eq!(output[..length], expected[..length]);
+
+            let mut clear = [0u8; 64];
+            let clear_length = decrypt(
+                &mut SoftAes,
+                &KEY,
+                &NONCE,
+                &output[..length],
+                0,
+                &[],
+                &mut clear,
+            )
+            .unwrap();
+            assert_eq!(&clear[..clear_length], message);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut output = [0u8; 16];
+        assert_eq!(
+            encrypt(&mut SoftAes, &KEY, &NONCE, &[0; 4], 5, &[], &mut output),
+            Err(Error::InvalidMicLength)
+        );
+        assert_eq!(
+            encrypt(&mut SoftAes, &KEY, &NONCE, &[0; 13], 4, &[], &mut output),
+            Err(Error::NotEnoughSpace)
+        );
+        assert_eq!(
+            decrypt(&mut SoftAes, &KEY, &NONCE, &[0; 3], 4, &[], &mut output),
+            Err(Error::NotEnoughSpace)
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Block cipher based hashing
+//!
+//! The Matyas-Meyer-Oseas hash used by Zigbee to derive keys, built on an
+//! AES-128 block cipher provided by software, by a peripheral or by a Psila
+//! crypto backend.
+
+#[cfg(feature = "52840")]
+use psila_crypto::CryptoBackend;
+
+/// Size of an AES block and key in bytes
+pub const BLOCK_SIZE: usize = 16;
+
+/// AES-128 encryption of single blocks
+pub trait BlockCipher {
+    /// Encrypt `block` in place with `key`
+    fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]);
+}
+
+/// Block encryption with the AES-128 ECB functions of a Psila crypto backend
+#[cfg(feature = "52840")]
+pub struct BackendCipher<B: CryptoBackend> {
+    backend: B,
+}
+
+#[cfg(feature = "52840")]
+impl<B: CryptoBackend> BackendCipher<B> {
+    pub fn new(backend: B) -> Self {
+        Self { backend }
+    }
+}
+
+#[cfg(feature = "52840")]
+impl<B: CryptoBackend> BlockCipher for BackendCipher<B> {
+    fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]) {
+        let input = *block;
+        // The backends only fail on keys and blocks of the wrong size
+        let _ = self.backend.aes128_ecb_encrypt_set_key(key);
+        let _ = self.backend.aes128_ecb_encrypt_process_block(&input, block);
+    }
+}
+
+/// Software AES-128 from RustCrypto, the reference for the host tests
+#[cfg(test)]
+pub(crate) struct SoftAes;
+
+#[cfg(test)]
+impl BlockCipher for SoftAes {
+    fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]) {
+        use aes::cipher::{BlockEncrypt, KeyInit};
+        aes::Aes128::new(key.into()).encrypt_block(block.into());
+    }
+}
+
+/// Matyas-Meyer-Oseas hash of `data`
+///
+/// The data is padded with a one bit, zeros and the length in bits as a 16-bit
+/// big endian value, which limits the data to less than 8 KiB.
+pub fn mmo_hash<C: BlockCipher>(cipher: &mut C, data: &[u8]) -> [u8; BLOCK_SIZE] {
+    let bits = (data.len() * 8) as u16;
+    let mut hash = [0u8; BLOCK_SIZE];
+    let mut chunks = data.chunks_exact(BLOCK_SIZE);
+    for chunk in &mut chunks {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(chunk);
+        mmo_round(cipher, &mut hash, &block);
+    }
+    // The remainder, the padding and the length fill one or two blocks
+    let remainder = chunks.remainder();
+    let mut padding = [0u8; 2 * BLOCK_SIZE];
+    padding[..remainder.len()].copy_from_slice(remainder);
+    padding[remainder.len()] = 0x80;
+    let end = if remainder.len() < BLOCK_SIZE - 2 {
+        BLOCK_SIZE
+    } else {
+        2 * BLOCK_SIZE
+    };
+    padding[end - 2] = (bits >> 8) as u8;
+    padding[end - 1] = bits as u8;
+    for chunk in padding[..end].chunks_exact(BLOCK_SIZE) {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(chunk);
+        mmo_round(cipher, &mut hash, &block);
+    }
+    hash
+}
+
+/// Hash one block, `hash = E(hash, block) ^ block`
+fn mmo_round<C: BlockCipher>(
+    cipher: &mut C,
+    hash: &mut [u8; BLOCK_SIZE],
+    block: &[u8; BLOCK_SIZE],
+) {
+    let mut output = *block;
+    cipher.encrypt_block(hash, &mut output);
+    for (h, (o, b)) in hash.iter_mut().zip(output.iter().zip(block.iter())) {
+        *h = o ^ b;
+    }
+}
@@ -0,0 +1,43 @@
+//! Cyclic redundancy checks
+
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// CRC-32 (IEEE 802.3) of the provided data
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xffff_ffff, data) ^ 0xffff_ffff
+}
+
+/// Continue a CRC-32 calculation
+///
+/// Start with `0xffff_ffff` and invert the final value.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ CRC32_POLYNOMIAL;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+const CRC16_POLYNOMIAL: u16 = 0x8408;
+
+/// CRC-16 (ITU-T X.25) of the provided data, as used for install codes
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ CRC16_POLYNOMIAL;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc ^ 0xffff
+}
@@ -0,0 +1,104 @@
+//! CSMA-CA backoff
+//!
+//! The radio drops a frame when the clear channel assessment finds the
+//! channel busy. The last frame queued for transmission is kept, and sent
+//! again after a random backoff as in the unslotted CSMA-CA of
+//! IEEE 802.15.4.
+
+use crate::random::Random;
+
+/// Length of a backoff period in microseconds, 20 symbols
+pub const UNIT_BACKOFF_PERIOD: u32 = 320;
+/// Backoff exponent of the first attempt
+pub const MIN_BACKOFF_EXPONENT: u8 = 3;
+/// Largest backoff exponent
+pub const MAX_BACKOFF_EXPONENT: u8 = 5;
+/// Backoffs before the frame is dropped
+pub const MAX_CSMA_BACKOFFS: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Waiting,
+    Ready,
+}
+
+/// Backoff state and a copy of the frame being transmitted
+pub struct Backoff<const N: usize> {
+    frame: [u8; N],
+    length: usize,
+    exponent: u8,
+    backoffs: u8,
+    state: State,
+}
+
+impl<const N: usize> Backoff<N> {
+    pub const fn new() -> Self {
+        Self {
+            frame: [0u8; N],
+            length: 0,
+            exponent: MIN_BACKOFF_EXPONENT,
+            backoffs: 0,
+            state: State::Idle,
+        }
+    }
+
+    /// Keep a copy of a frame queued for transmission, frames larger than
+    /// the buffer are not retried
+    pub fn transmitted(&mut self, frame: &[u8]) {
+        self.length = if frame.len() <= N {
+            self.frame[..frame.len()].copy_from_slice(frame);
+            frame.len()
+        } else {
+            0
+        };
+        self.exponent = MIN_BACKOFF_EXPONENT;
+        self.backoffs = 0;
+        self.state = State::Idle;
+    }
+
+    /// The channel was busy, returns the delay in microseconds before the
+    /// frame is sent again
+    ///
+    /// Returns `None` when the frame is dropped, after too many backoffs.
+    pub fn busy(&mut self, random: &mut Random) -> Option<u32> {
+        if self.length == 0 || self.backoffs >= MAX_CSMA_BACKOFFS {
+            self.length = 0;
+            self.state = State::Idle;
+            return None;
+        }
+        let periods = random.below(1 << self.exponent);
+        self.exponent = (self.exponent + 1).min(MAX_BACKOFF_EXPONENT);
+        self.backoffs += 1;
+        self.state = State::Waiting;
+        Some(periods * UNIT_BACKOFF_PERIOD)
+    }
+
+    /// The backoff delay has passed
+    pub fn expired(&mut self) {
+        if self.state == State::Waiting {
+            self.state = State::Ready;
+        }
+    }
+
+    /// Waiting for the backoff delay, no other frame should be sent
+    pub fn is_waiting(&self) -> bool {
+        self.state == State::Waiting
+    }
+
+    /// The frame to send again, once the backoff delay has passed
+    pub fn retry(&mut self) -> Option<&[u8]> {
+        if self.state == State::Ready {
+            self.state = State::Idle;
+            Some(&self.frame[..self.length])
+        } else {
+            None
+        }
+    }
+}
+
+impl<const N: usize> Default for Backoff<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
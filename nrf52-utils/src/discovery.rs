@@ -0,0 +1,157 @@
+//! Attribute and command discovery
+//!
+//! Answers Discover Attributes, Discover Attributes Extended and Discover
+//! Commands Received and Generated from the attribute table of an endpoint
+//! and the commands of its clusters. Controllers use these while
+//! interviewing a device.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::cluster_library::{AttributeDataType, ClusterLibraryStatus};
+
+use crate::{
+    attribute::AttributeTable,
+    basic::ATTR_CLUSTER_REVISION,
+    outbox::{Message, Recipient, MAX_PAYLOAD_SIZE},
+};
+
+/// General command, discover attributes
+pub const CMD_DISCOVER_ATTRIBUTES: u8 = 0x0c;
+/// General command, discover attributes response
+const CMD_DISCOVER_ATTRIBUTES_RESPONSE: u8 = 0x0d;
+/// General command, discover commands received
+pub const CMD_DISCOVER_COMMANDS_RECEIVED: u8 = 0x11;
+/// General command, discover commands received response
+const CMD_DISCOVER_COMMANDS_RECEIVED_RESPONSE: u8 = 0x12;
+/// General command, discover commands generated
+pub const CMD_DISCOVER_COMMANDS_GENERATED: u8 = 0x13;
+/// General command, discover commands generated response
+const CMD_DISCOVER_COMMANDS_GENERATED_RESPONSE: u8 = 0x14;
+/// General command, discover attributes extended
+pub const CMD_DISCOVER_ATTRIBUTES_EXTENDED: u8 = 0x15;
+/// General command, discover attributes extended response
+const CMD_DISCOVER_ATTRIBUTES_EXTENDED_RESPONSE: u8 = 0x16;
+
+/// Access control of the cluster revision attribute, read-only
+const CLUSTER_REVISION_ACCESS: u8 = 0b001;
+
+/// Commands of a server cluster
+#[derive(Clone, Copy, Debug)]
+pub struct ClusterCommands {
+    pub cluster: u16,
+    /// Commands received by the server, in ascending order
+    pub received: &'static [u8],
+    /// Commands generated by the server, in ascending order
+    pub generated: &'static [u8],
+}
+
+/// Check if the command is a discovery command
+pub fn is_discovery_command(command: u8) -> bool {
+    matches!(
+        command,
+        CMD_DISCOVER_ATTRIBUTES
+            | CMD_DISCOVER_COMMANDS_RECEIVED
+            | CMD_DISCOVER_COMMANDS_GENERATED
+            | CMD_DISCOVER_ATTRIBUTES_EXTENDED
+    )
+}
+
+/// Attributes of a cluster from `start`, with the data type and access
+/// control
+fn attributes_from<H>(
+    attributes: &AttributeTable<H>,
+    cluster: u16,
+    start: u16,
+) -> impl Iterator<Item = (u16, AttributeDataType, u8)> {
+    let revision = if attributes.has_cluster(cluster) {
+        Some((
+            ATTR_CLUSTER_REVISION,
+            AttributeDataType::Unsigned16,
+            CLUSTER_REVISION_ACCESS,
+        ))
+    } else {
+        None
+    };
+    attributes
+        .cluster(cluster)
+        .map(|entry| (entry.id, entry.data_type, entry.access()))
+        .chain(revision)
+        .filter(move |(id, _, _)| *id >= start)
+}
+
+/// Handle the discovery commands
+///
+/// `commands` lists the commands of the server clusters of the endpoint.
+/// Returns the response to send.
+pub fn handle_command<H>(
+    attributes: &AttributeTable<H>,
+    commands: &[ClusterCommands],
+    endpoint: u8,
+    profile: u16,
+    cluster: u16,
+    command: u8,
+    arguments: &[u8],
+) -> Result<Message, ClusterLibraryStatus> {
+    match command {
+        CMD_DISCOVER_ATTRIBUTES | CMD_DISCOVER_ATTRIBUTES_EXTENDED => {
+            if arguments.len() < 3 {
+                return Err(ClusterLibraryStatus::MalformedCommand);
+            }
+            let start = LittleEndian::read_u16(&arguments[0..2]);
+            let maximum = usize::from(arguments[2]);
+            let extended = command == CMD_DISCOVER_ATTRIBUTES_EXTENDED;
+            let (response, size) = if extended {
+                (CMD_DISCOVER_ATTRIBUTES_EXTENDED_RESPONSE, 4)
+            } else {
+                (CMD_DISCOVER_ATTRIBUTES_RESPONSE, 3)
+            };
+            let mut message =
+                Message::general(Recipient::Reply, endpoint, profile, cluster, response);
+            // Discovery complete, followed by as many attributes as fit
+            let count = maximum.min((MAX_PAYLOAD_SIZE - 1) / size);
+            let remaining = attributes_from(attributes, cluster, start).count();
+            message.append_u8((remaining <= count) as u8);
+            for (id, data_type, access) in attributes_from(attributes, cluster, start).take(count) {
+                message.append_u16(id);
+                message.append_u8(u8::from(data_type));
+                if extended {
+                    message.append_u8(access);
+                }
+            }
+            Ok(message)
+        }
+        CMD_DISCOVER_COMMANDS_RECEIVED | CMD_DISCOVER_COMMANDS_GENERATED => {
+            if arguments.len() < 2 {
+                return Err(ClusterLibraryStatus::MalformedCommand);
+            }
+            let start = arguments[0];
+            let maximum = usize::from(arguments[1]);
+            let received = command == CMD_DISCOVER_COMMANDS_RECEIVED;
+            let response = if received {
+                CMD_DISCOVER_COMMANDS_RECEIVED_RESPONSE
+            } else {
+                CMD_DISCOVER_COMMANDS_GENERATED_RESPONSE
+            };
+            let identifiers = commands
+                .iter()
+                .find(|entry| entry.cluster == cluster)
+                .map_or(&[][..], |entry| {
+                    if received {
+                        entry.received
+                    } else {
+                        entry.generated
+                    }
+                });
+            let mut message =
+                Message::general(Recipient::Reply, endpoint, profile, cluster, response);
+            let count = maximum.min(MAX_PAYLOAD_SIZE - 1);
+            let remaining = identifiers.iter().filter(|id| **id >= start);
+            message.append_u8((remaining.clone().count() <= count) as u8);
+            for id in remaining.take(count) {
+                message.append_u8(*id);
+            }
+            Ok(message)
+        }
+        _ => Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
+    }
+}
@@ -0,0 +1,196 @@
+//! AES electronic codebook peripheral
+//!
+//! AES-128 encryption of single blocks with the ECB peripheral, and a Psila
+//! crypto backend doing CCM* on top of it.
+
+use nrf52840_pac::ECB;
+use psila_crypto::{CryptoBackend, Error};
+
+use crate::{
+    ccm::{self, NONCE_SIZE},
+    cipher::{BlockCipher, BLOCK_SIZE},
+};
+
+/// Error code of a MIC that does not match, as `Error::Other`
+pub const ERROR_INVALID_MIC: u32 = 1;
+
+/// Key, clear text and cipher text, as read and written by the peripheral
+#[repr(C)]
+struct EcbData {
+    key: [u8; BLOCK_SIZE],
+    clear_text: [u8; BLOCK_SIZE],
+    cipher_text: [u8; BLOCK_SIZE],
+}
+
+/// Block encryption with the ECB peripheral
+pub struct Ecb {
+    ecb: ECB,
+}
+
+impl Ecb {
+    pub fn new(ecb: ECB) -> Self {
+        Self { ecb }
+    }
+
+    /// Release the peripheral
+    pub fn free(self) -> ECB {
+        self.ecb
+    }
+}
+
+impl BlockCipher for Ecb {
+    fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]) {
+        let mut data = EcbData {
+            key: *key,
+            clear_text: *block,
+            cipher_text: [0; BLOCK_SIZE],
+        };
+        self.ecb
+            .ecbdataptr
+            .write(|w| unsafe { w.bits(&mut data as *mut EcbData as u32) });
+        loop {
+            self.ecb.events_endecb.reset();
+            self.ecb.events_errorecb.reset();
+            self.ecb.tasks_startecb.write(|w| unsafe { w.bits(1) });
+            // The operation only fails if it is interrupted by the radio
+            // using the AES core, in which case it is started again
+            while self.ecb.events_endecb.read().bits() == 0
+                && self.ecb.events_errorecb.read().bits() == 0
+            {}
+            if self.ecb.events_endecb.read().bits() != 0 {
+                break;
+            }
+        }
+        self.ecb.events_endecb.reset();
+        // Keep the compiler from reading the cipher text before the
+        // peripheral has written it
+        let cipher_text = unsafe { core::ptr::read_volatile(&data.cipher_text) };
+        *block = cipher_text;
+    }
+}
+
+/// Crypto backend doing CCM* with the ECB peripheral
+///
+/// The blocks are encrypted by the peripheral, the CCM* logic runs on the
+/// CPU.
+pub struct EcbCryptoBackend {
+    ecb: Ecb,
+    key: [u8; BLOCK_SIZE],
+}
+
+impl EcbCryptoBackend {
+    pub fn new(ecb: Ecb) -> Self {
+        Self {
+            ecb,
+            key: [0; BLOCK_SIZE],
+        }
+    }
+
+    /// Release the peripheral
+    pub fn free(self) -> Ecb {
+        self.ecb
+    }
+
+    fn encrypt_blocks(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        if !input.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidData);
+        }
+        if output.len() < input.len() {
+            return Err(Error::NotEnoughSpace);
+        }
+        for (input, output) in input
+            .chunks_exact(BLOCK_SIZE)
+            .zip(output.chunks_exact_mut(BLOCK_SIZE))
+        {
+            let mut block = [0u8; BLOCK_SIZE];
+            block.copy_from_slice(input);
+            self.ecb.encrypt_block(&self.key, &mut block);
+            output.copy_from_slice(&block);
+        }
+        Ok(())
+    }
+}
+
+fn ccm_parameters<'a>(
+    key: &'a [u8],
+    nonce: &'a [u8],
+) -> Result<(&'a [u8; BLOCK_SIZE], &'a [u8; NONCE_SIZE]), Error> {
+    use core::convert::TryInto;
+    let key = key.try_into().map_err(|_| Error::InvalidKey)?;
+    let nonce = nonce.try_into().map_err(|_| Error::InvalidData)?;
+    Ok((key, nonce))
+}
+
+fn from_ccm_error(error: ccm::Error) -> Error {
+    match error {
+        ccm::Error::InvalidMicLength => Error::InvalidData,
+        ccm::Error::NotEnoughSpace => Error::NotEnoughSpace,
+        ccm::Error::InvalidMic => Error::Other(ERROR_INVALID_MIC),
+    }
+}
+
+impl CryptoBackend for EcbCryptoBackend {
+    fn ccmstar_decrypt(
+        &mut self,
+        key: &[u8],
+        nonce: &[u8],
+        message: &[u8],
+        mic_length: usize,
+        additional_data: &[u8],
+        message_output: &mut [u8],
+    ) -> Result<usize, Error> {
+        let (key, nonce) = ccm_parameters(key, nonce)?;
+        ccm::decrypt(
+            &mut self.ecb,
+            key,
+            nonce,
+            message,
+            mic_length,
+            additional_data,
+            message_output,
+        )
+        .map_err(from_ccm_error)
+    }
+
+    fn ccmstar_encrypt(
+        &mut self,
+        key: &[u8],
+        nonce: &[u8],
+        message: &[u8],
+        mic_length: usize,
+        additional_data: &[u8],
+        message_output: &mut [u8],
+    ) -> Result<usize, Error> {
+        let (key, nonce) = ccm_parameters(key, nonce)?;
+        ccm::encrypt(
+            &mut self.ecb,
+            key,
+            nonce,
+            message,
+            mic_length,
+            additional_data,
+            message_output,
+        )
+        .map_err(from_ccm_error)
+    }
+
+    fn aes128_ecb_encrypt_set_key(&mut self, key: &[u8]) -> Result<(), Error> {
+        if key.len() != BLOCK_SIZE {
+            return Err(Error::InvalidKey);
+        }
+        self.key.copy_from_slice(key);
+        Ok(())
+    }
+
+    fn aes128_ecb_encrypt_process_block(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), Error> {
+        self.encrypt_blocks(input, output)
+    }
+
+    fn aes128_ecb_encrypt_finish(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        self.encrypt_blocks(input, output)
+    }
+}
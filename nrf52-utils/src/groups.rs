@@ -0,0 +1,581 @@
+//! Groups cluster
+//!
+//! A table of group memberships per endpoint and handling of the groups
+//! cluster commands. Group names are not supported.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::cluster_library::{ClusterLibraryStatus, Destination};
+
+use crate::outbox::{Message, Recipient};
+
+/// Key of the group table in the key/value store
+pub const GROUP_TABLE_KEY: u16 = 0x0003;
+
+/// Groups cluster
+pub const CLUSTER_GROUPS: u16 = 0x0004;
+/// Groups cluster attribute, name support
+pub const GROUPS_ATTR_NAME_SUPPORT: u16 = 0x0000;
+
+/// Groups cluster command, add group
+const GROUPS_CMD_ADD_GROUP: u8 = 0x00;
+/// Groups cluster command, view group
+const GROUPS_CMD_VIEW_GROUP: u8 = 0x01;
+/// Groups cluster command, get group membership
+const GROUPS_CMD_GET_GROUP_MEMBERSHIP: u8 = 0x02;
+/// Groups cluster command, remove group
+const GROUPS_CMD_REMOVE_GROUP: u8 = 0x03;
+/// Groups cluster command, remove all groups
+const GROUPS_CMD_REMOVE_ALL_GROUPS: u8 = 0x04;
+/// Groups cluster command, add group if identifying
+const GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING: u8 = 0x05;
+
+/// Commands received by the server side of the groups cluster
+pub const GROUPS_COMMANDS_RECEIVED: [u8; 6] = [
+    GROUPS_CMD_ADD_GROUP,
+    GROUPS_CMD_VIEW_GROUP,
+    GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+    GROUPS_CMD_REMOVE_GROUP,
+    GROUPS_CMD_REMOVE_ALL_GROUPS,
+    GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING,
+];
+/// Commands generated by the server side of the groups cluster, the
+/// responses share the identifiers of the requests
+pub const GROUPS_COMMANDS_GENERATED: [u8; 4] = [
+    GROUPS_CMD_ADD_GROUP,
+    GROUPS_CMD_VIEW_GROUP,
+    GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+    GROUPS_CMD_REMOVE_GROUP,
+];
+
+/// Status, success
+const STATUS_SUCCESS: u8 = 0x00;
+/// Status, invalid value
+const STATUS_INVALID_VALUE: u8 = 0x87;
+/// Status, insufficient space
+const STATUS_INSUFFICIENT_SPACE: u8 = 0x89;
+/// Status, duplicate exists
+const STATUS_DUPLICATE_EXISTS: u8 = 0x8a;
+/// Status, not found
+const STATUS_NOT_FOUND: u8 = 0x8b;
+
+/// Largest valid group identifier
+const GROUP_MAX: u16 = 0xfff7;
+
+/// Size of a packed group table with `N` entries
+pub const fn group_table_size(entries: usize) -> usize {
+    1 + entries * 3
+}
+
+/// Errors from the group table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The group identifier is not valid
+    InvalidGroup,
+    /// The table is full
+    Full,
+    /// The endpoint is already member of the group
+    Duplicate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Membership {
+    endpoint: u8,
+    group: u16,
+}
+
+/// Group memberships
+#[derive(Clone, Copy, Debug)]
+pub struct GroupTable<const N: usize> {
+    entries: [Option<Membership>; N],
+}
+
+impl<const N: usize> Default for GroupTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> GroupTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Add `endpoint` to `group`
+    pub fn add(&mut self, endpoint: u8, group: u16) -> Result<(), Error> {
+        if group == 0 || group > GROUP_MAX {
+            return Err(Error::InvalidGroup);
+        }
+        if self.contains(endpoint, group) {
+            return Err(Error::Duplicate);
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(Membership { endpoint, group });
+                Ok(())
+            }
+            None => Err(Error::Full),
+        }
+    }
+
+    /// Remove `endpoint` from `group`, returns false if it was not a member
+    pub fn remove(&mut self, endpoint: u8, group: u16) -> bool {
+        let membership = Some(Membership { endpoint, group });
+        match self.entries.iter_mut().find(|entry| **entry == membership) {
+            Some(entry) => {
+                *entry = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove `endpoint` from all groups, returns false if it had no groups
+    pub fn remove_all(&mut self, endpoint: u8) -> bool {
+        let mut removed = false;
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|membership| membership.endpoint == endpoint) {
+                *entry = None;
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Remove all memberships
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Check if `endpoint` is member of `group`
+    pub fn contains(&self, endpoint: u8, group: u16) -> bool {
+        self.entries
+            .iter()
+            .flatten()
+            .any(|membership| membership.endpoint == endpoint && membership.group == group)
+    }
+
+    /// Groups that `endpoint` is member of
+    pub fn groups(&self, endpoint: u8) -> impl Iterator<Item = u16> + '_ {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(move |membership| membership.endpoint == endpoint)
+            .map(|membership| membership.group)
+    }
+
+    /// Number of free entries
+    pub fn free(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_none()).count()
+    }
+
+    /// Check if a frame sent to `destination` is addressed to `endpoint`
+    ///
+    /// Group addressed frames are only for endpoints that are members of the
+    /// group.
+    pub fn is_addressed(&self, destination: Destination, endpoint: u8) -> bool {
+        match destination {
+            Destination::Unicast(target) => target == endpoint,
+            Destination::Group(group) => self.contains(endpoint, group),
+            Destination::Broadcast => true,
+        }
+    }
+
+    /// Pack the table into `data`, returns the number of bytes used
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        let mut offset = 1;
+        let mut count = 0;
+        for membership in self.entries.iter().flatten() {
+            data[offset] = membership.endpoint;
+            LittleEndian::write_u16(&mut data[offset + 1..offset + 3], membership.group);
+            offset += 3;
+            count += 1;
+        }
+        data[0] = count;
+        offset
+    }
+
+    /// Unpack a table from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let count = *data.first()? as usize;
+        if count > N || data.len() < group_table_size(count) {
+            return None;
+        }
+        let mut table = Self::new();
+        for (n, entry) in data[1..group_table_size(count)].chunks(3).enumerate() {
+            table.entries[n] = Some(Membership {
+                endpoint: entry[0],
+                group: LittleEndian::read_u16(&entry[1..3]),
+            });
+        }
+        Some(table)
+    }
+
+    /// Handle a groups cluster command for `endpoint`
+    ///
+    /// Returns true if the table changed, together with the response to
+    /// send, if any.
+    pub fn handle_command(
+        &mut self,
+        endpoint: u8,
+        profile: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+        identifying: bool,
+    ) -> Result<(bool, Option<Message>), ClusterLibraryStatus> {
+        // Only requests sent directly to the device are answered
+        let respond = matches!(destination, Destination::Unicast(_));
+        let response = |command: u8| {
+            Message::new(Recipient::Reply, endpoint, profile, CLUSTER_GROUPS, command)
+        };
+        match command {
+            GROUPS_CMD_ADD_GROUP | GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                if command == GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING && !identifying {
+                    return Ok((false, None));
+                }
+                let (status, changed) = match self.add(endpoint, group) {
+                    Ok(()) => (STATUS_SUCCESS, true),
+                    Err(Error::InvalidGroup) => (STATUS_INVALID_VALUE, false),
+                    Err(Error::Full) => (STATUS_INSUFFICIENT_SPACE, false),
+                    Err(Error::Duplicate) => (STATUS_DUPLICATE_EXISTS, false),
+                };
+                if respond && command == GROUPS_CMD_ADD_GROUP {
+                    let mut message = response(GROUPS_CMD_ADD_GROUP);
+                    message.append_u8(status);
+                    message.append_u16(group);
+                    return Ok((changed, Some(message)));
+                }
+                Ok((changed, None))
+            }
+            GROUPS_CMD_VIEW_GROUP => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                if !respond {
+                    return Ok((false, None));
+                }
+                let status = if self.contains(endpoint, group) {
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_NOT_FOUND
+                };
+                let mut message = response(GROUPS_CMD_VIEW_GROUP);
+                message.append_u8(status);
+                message.append_u16(group);
+                // Empty group name
+                message.append_u8(0);
+                Ok((false, Some(message)))
+            }
+            GROUPS_CMD_GET_GROUP_MEMBERSHIP => {
+                let count = *arguments
+                    .first()
+                    .ok_or(ClusterLibraryStatus::MalformedCommand)?
+                    as usize;
+                if arguments.len() < 1 + count * 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                if !respond {
+                    return Ok((false, None));
+                }
+                let requested = &arguments[1..1 + count * 2];
+                let mut message = response(GROUPS_CMD_GET_GROUP_MEMBERSHIP);
+                message.append_u8(core::cmp::min(self.free(), 0xfe) as u8);
+                let mut groups = [0u16; N];
+                let mut found = 0;
+                for group in self.groups(endpoint) {
+                    if count == 0
+                        || requested
+                            .chunks(2)
+                            .any(|entry| LittleEndian::read_u16(entry) == group)
+                    {
+                        groups[found] = group;
+                        found += 1;
+                    }
+                }
+                // Nothing is sent if none of the requested groups are found
+                if count > 0 && found == 0 {
+                    return Ok((false, None));
+                }
+                message.append_u8(found as u8);
+                for group in &groups[..found] {
+                    message.append_u16(*group);
+                }
+                Ok((false, Some(message)))
+            }
+            GROUPS_CMD_REMOVE_GROUP => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let changed = self.remove(endpoint, group);
+                if !respond {
+                    return Ok((changed, None));
+                }
+                let status = if changed {
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_NOT_FOUND
+                };
+                let mut message = response(GROUPS_CMD_REMOVE_GROUP);
+                message.append_u8(status);
+                message.append_u16(group);
+                Ok((changed, Some(message)))
+            }
+            GROUPS_CMD_REMOVE_ALL_GROUPS => Ok((self.remove_all(endpoint), None)),
+            _ => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: u16 = 0x0104;
+    const ENDPOINT: u8 = 1;
+
+    fn handle<const N: usize>(
+        table: &mut GroupTable<N>,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(bool, Option<Message>), ClusterLibraryStatus> {
+        table.handle_command(ENDPOINT, PROFILE, destination, command, arguments, false)
+    }
+
+    #[test]
+    fn add_remove() {
+        let mut table = GroupTable::<4>::new();
+        assert_eq!(table.add(1, 0x0001), Ok(()));
+        assert_eq!(table.add(1, 0x0002), Ok(()));
+        assert_eq!(table.add(2, 0x0001), Ok(()));
+        assert_eq!(table.add(1, 0x0001), Err(Error::Duplicate));
+        assert_eq!(table.add(1, 0x0000), Err(Error::InvalidGroup));
+        assert_eq!(table.add(1, 0xfff8), Err(Error::InvalidGroup));
+        assert!(table.contains(1, 0x0002));
+        assert_eq!(table.free(), 1);
+        assert!(table.remove(1, 0x0002));
+        assert!(!table.remove(1, 0x0002));
+        assert!(!table.contains(1, 0x0002));
+        assert!(table.remove_all(1));
+        assert!(!table.remove_all(1));
+        assert!(!table.contains(1, 0x0001));
+        // Other endpoints keep their groups
+        assert!(table.contains(2, 0x0001));
+        table.clear();
+        assert_eq!(table.free(), 4);
+    }
+
+    #[test]
+    fn full() {
+        let mut table = GroupTable::<2>::new();
+        assert_eq!(table.add(1, 0x0001), Ok(()));
+        assert_eq!(table.add(1, 0x0002), Ok(()));
+        assert_eq!(table.add(1, 0x0003), Err(Error::Full));
+        // A removed entry is reused
+        assert!(table.remove(1, 0x0001));
+        assert_eq!(table.add(1, 0x0003), Ok(()));
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let mut table = GroupTable::<4>::new();
+        table.add(1, 0x1234).unwrap();
+        table.add(2, 0x0002).unwrap();
+        let mut data = [0u8; group_table_size(4)];
+        assert_eq!(table.pack(&mut data), group_table_size(2));
+        assert_eq!(data[..7], [0x02, 0x01, 0x34, 0x12, 0x02, 0x02, 0x00]);
+        let unpacked = GroupTable::<4>::unpack(&data).unwrap();
+        assert!(unpacked.contains(1, 0x1234));
+        assert!(unpacked.contains(2, 0x0002));
+        assert_eq!(unpacked.free(), 2);
+        // Truncated, or too many entries for the table
+        assert!(GroupTable::<4>::unpack(&data[..6]).is_none());
+        assert!(GroupTable::<1>::unpack(&data).is_none());
+        assert!(GroupTable::<4>::unpack(&[]).is_none());
+    }
+
+    #[test]
+    fn is_addressed() {
+        let mut table = GroupTable::<4>::new();
+        table.add(1, 0x0010).unwrap();
+        assert!(table.is_addressed(Destination::Group(0x0010), 1));
+        assert!(!table.is_addressed(Destination::Group(0x0010), 2));
+        assert!(!table.is_addressed(Destination::Group(0x0011), 1));
+        assert!(table.is_addressed(Destination::Unicast(1), 1));
+        assert!(!table.is_addressed(Destination::Unicast(2), 1));
+        assert!(table.is_addressed(Destination::Broadcast, 2));
+    }
+
+    #[test]
+    fn add_group_command() {
+        let mut table = GroupTable::<1>::new();
+        let unicast = Destination::Unicast(ENDPOINT);
+        let (changed, message) = table
+            .handle_command(
+                ENDPOINT,
+                PROFILE,
+                unicast,
+                GROUPS_CMD_ADD_GROUP,
+                &[0x34, 0x12],
+                false,
+            )
+            .unwrap();
+        assert!(changed);
+        let message = message.unwrap();
+        assert_eq!(message.command, GROUPS_CMD_ADD_GROUP);
+        assert_eq!(message.payload(), [STATUS_SUCCESS, 0x34, 0x12]);
+        // Table full
+        let (changed, message) =
+            handle(&mut table, unicast, GROUPS_CMD_ADD_GROUP, &[0x35, 0x12]).unwrap();
+        assert!(!changed);
+        assert_eq!(
+            message.unwrap().payload(),
+            [STATUS_INSUFFICIENT_SPACE, 0x35, 0x12]
+        );
+        assert_eq!(
+            handle(&mut table, unicast, GROUPS_CMD_ADD_GROUP, &[0x34]).unwrap_err(),
+            ClusterLibraryStatus::MalformedCommand
+        );
+    }
+
+    #[test]
+    fn add_group_if_identifying() {
+        let mut table = GroupTable::<4>::new();
+        let arguments = [0x01, 0x00];
+        let (changed, message) = handle(
+            &mut table,
+            Destination::Unicast(ENDPOINT),
+            GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING,
+            &arguments,
+        )
+        .unwrap();
+        assert!(!changed && message.is_none());
+        let (changed, message) = table
+            .handle_command(
+                ENDPOINT,
+                PROFILE,
+                Destination::Unicast(ENDPOINT),
+                GROUPS_CMD_ADD_GROUP_IF_IDENTIFYING,
+                &arguments,
+                true,
+            )
+            .unwrap();
+        // Added, without a response
+        assert!(changed && message.is_none());
+        assert!(table.contains(ENDPOINT, 0x0001));
+    }
+
+    #[test]
+    fn remove_commands() {
+        let mut table = GroupTable::<4>::new();
+        table.add(ENDPOINT, 0x0001).unwrap();
+        table.add(ENDPOINT, 0x0002).unwrap();
+        let unicast = Destination::Unicast(ENDPOINT);
+        let (changed, message) =
+            handle(&mut table, unicast, GROUPS_CMD_REMOVE_GROUP, &[0x01, 0x00]).unwrap();
+        assert!(changed);
+        assert_eq!(message.unwrap().payload(), [STATUS_SUCCESS, 0x01, 0x00]);
+        let (changed, message) =
+            handle(&mut table, unicast, GROUPS_CMD_REMOVE_GROUP, &[0x01, 0x00]).unwrap();
+        assert!(!changed);
+        assert_eq!(message.unwrap().payload(), [STATUS_NOT_FOUND, 0x01, 0x00]);
+        // Group addressed requests are not answered
+        table.add(ENDPOINT, 0x0001).unwrap();
+        let (changed, message) = handle(
+            &mut table,
+            Destination::Group(0x0001),
+            GROUPS_CMD_REMOVE_GROUP,
+            &[0x01, 0x00],
+        )
+        .unwrap();
+        assert!(changed && message.is_none());
+        let (changed, message) =
+            handle(&mut table, unicast, GROUPS_CMD_REMOVE_ALL_GROUPS, &[]).unwrap();
+        assert!(changed && message.is_none());
+        assert_eq!(table.free(), 4);
+    }
+
+    #[test]
+    fn view_group_command() {
+        let mut table = GroupTable::<4>::new();
+        table.add(ENDPOINT, 0x0001).unwrap();
+        let unicast = Destination::Unicast(ENDPOINT);
+        let (_, message) =
+            handle(&mut table, unicast, GROUPS_CMD_VIEW_GROUP, &[0x01, 0x00]).unwrap();
+        assert_eq!(
+            message.unwrap().payload(),
+            [STATUS_SUCCESS, 0x01, 0x00, 0x00]
+        );
+        let (_, message) =
+            handle(&mut table, unicast, GROUPS_CMD_VIEW_GROUP, &[0x02, 0x00]).unwrap();
+        assert_eq!(
+            message.unwrap().payload(),
+            [STATUS_NOT_FOUND, 0x02, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn get_group_membership() {
+        let mut table = GroupTable::<4>::new();
+        table.add(ENDPOINT, 0x0001).unwrap();
+        table.add(ENDPOINT, 0x0002).unwrap();
+        table.add(2, 0x0003).unwrap();
+        let unicast = Destination::Unicast(ENDPOINT);
+        // All groups of the endpoint
+        let (changed, message) = handle(
+            &mut table,
+            unicast,
+            GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+            &[0x00],
+        )
+        .unwrap();
+        assert!(!changed);
+        let message = message.unwrap();
+        assert_eq!(message.command, GROUPS_CMD_GET_GROUP_MEMBERSHIP);
+        assert_eq!(message.payload(), [0x01, 0x02, 0x01, 0x00, 0x02, 0x00]);
+        // Only the requested groups
+        let (_, message) = handle(
+            &mut table,
+            unicast,
+            GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+            &[0x02, 0x02, 0x00, 0x03, 0x00],
+        )
+        .unwrap();
+        assert_eq!(message.unwrap().payload(), [0x01, 0x01, 0x02, 0x00]);
+        // None of the requested groups
+        let (_, message) = handle(
+            &mut table,
+            unicast,
+            GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+            &[0x01, 0x03, 0x00],
+        )
+        .unwrap();
+        assert!(message.is_none());
+        // Group addressed requests are not answered
+        let (changed, message) = handle(
+            &mut table,
+            Destination::Group(0x0001),
+            GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+            &[0x00],
+        )
+        .unwrap();
+        assert!(!changed && message.is_none());
+        assert_eq!(
+            handle(
+                &mut table,
+                unicast,
+                GROUPS_CMD_GET_GROUP_MEMBERSHIP,
+                &[0x02, 0x01, 0x00]
+            )
+            .unwrap_err(),
+            ClusterLibraryStatus::MalformedCommand
+        );
+    }
+}
@@ -0,0 +1,544 @@
+//! Host serial interface
+//!
+//! Requests from a host program and the responses to them, sent as SLIP
+//! (RFC 1055) frames over a serial port. The first byte of a frame identifies
+//! the request or response, multi-byte fields are little endian.
+//!
+//! | Request        | Id     | Fields                                                   |
+//! |----------------|--------|----------------------------------------------------------|
+//! | List devices   | `0x01` |                                                          |
+//! | Permit joining | `0x02` | duration (u8)                                            |
+//! | Send command   | `0x03` | address (u16), endpoint (u8), profile (u16),             |
+//! |                |        | cluster (u16), flags (u8), command (u8), payload         |
+//! | Network        | `0x04` |                                                          |
+//! | Install code   | `0x05` | extended address (u64), install code with CRC            |
+//!
+//! The flags of a command are `0x01` for a profile wide command and `0x02`
+//! for a command from the server side of the cluster.
+//!
+//! | Response       | Id     | Fields                                                   |
+//! |----------------|--------|----------------------------------------------------------|
+//! | Device         | `0x81` | short address (u16), extended address (u64), flags (u8)  |
+//! | Device list end| `0x82` | number of devices (u8)                                   |
+//! | Status         | `0x83` | request id (u8), status (u8)                             |
+//! | Network        | `0x84` | channel (u8), PAN identifier (u16), extended PAN (u64)   |
+//!
+//! The flags of a device are `0x01` for a device with the receiver on when
+//! idle.
+//!
+//! A device given an install code joins with the link key derived from it,
+//! other devices join with the default link key.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{cipher::BLOCK_SIZE, install_code::InstallCode};
+
+/// Key of the device table in the key/value store
+pub const DEVICE_TABLE_KEY: u16 = 0x0006;
+/// Key of the link key table in the key/value store
+pub const LINK_KEY_TABLE_KEY: u16 = 0x0008;
+
+/// ZDO request, device announce
+pub const ZDO_DEVICE_ANNCE: u16 = 0x0013;
+
+/// Request, list the devices in the network
+pub const REQUEST_LIST_DEVICES: u8 = 0x01;
+/// Request, permit devices to join
+pub const REQUEST_PERMIT_JOINING: u8 = 0x02;
+/// Request, send a cluster library command
+pub const REQUEST_SEND_COMMAND: u8 = 0x03;
+/// Request, network parameters
+pub const REQUEST_NETWORK: u8 = 0x04;
+/// Request, install code of a device that will join
+pub const REQUEST_INSTALL_CODE: u8 = 0x05;
+
+/// Response, a device in the network
+pub const RESPONSE_DEVICE: u8 = 0x81;
+/// Response, end of the device list
+pub const RESPONSE_DEVICE_LIST_END: u8 = 0x82;
+/// Response, status of a request
+pub const RESPONSE_STATUS: u8 = 0x83;
+/// Response, network parameters
+pub const RESPONSE_NETWORK: u8 = 0x84;
+
+/// Status, the request succeeded
+pub const STATUS_SUCCESS: u8 = 0x00;
+/// Status, the request failed
+pub const STATUS_FAILURE: u8 = 0x01;
+/// Status, the request is malformed or unknown
+pub const STATUS_INVALID: u8 = 0x02;
+
+/// Command flag, profile wide command
+const FLAG_GENERAL: u8 = 0x01;
+/// Command flag, sent from the server side of the cluster
+const FLAG_FROM_SERVER: u8 = 0x02;
+/// Device flag, receiver on when idle
+const FLAG_RX_ON_WHEN_IDLE: u8 = 0x01;
+/// MAC capability, receiver on when idle
+const CAPABILITY_RX_ON_WHEN_IDLE: u8 = 0x08;
+
+/// Size of a packed device
+const DEVICE_SIZE: usize = 11;
+
+/// Size of a packed device table with `N` entries
+pub const fn device_table_size(entries: usize) -> usize {
+    1 + entries * DEVICE_SIZE
+}
+
+/// Size of a packed link key
+const LINK_KEY_SIZE: usize = 8 + BLOCK_SIZE;
+
+/// Size of a packed link key table with `N` entries
+pub const fn link_key_table_size(entries: usize) -> usize {
+    1 + entries * LINK_KEY_SIZE
+}
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// SLIP encode `data` into `output`, returns the number of bytes used or
+/// `None` if the frame does not fit
+///
+/// The frame starts and ends with an END byte.
+pub fn slip_encode(data: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut length = 0;
+    let mut put = |byte: u8| -> Option<()> {
+        *output.get_mut(length)? = byte;
+        length += 1;
+        Some(())
+    };
+    put(SLIP_END)?;
+    for &byte in data {
+        match byte {
+            SLIP_END => {
+                put(SLIP_ESC)?;
+                put(SLIP_ESC_END)?;
+            }
+            SLIP_ESC => {
+                put(SLIP_ESC)?;
+                put(SLIP_ESC_ESC)?;
+            }
+            _ => put(byte)?,
+        }
+    }
+    put(SLIP_END)?;
+    Some(length)
+}
+
+/// SLIP decoder collecting frames of at most `N` bytes
+#[derive(Clone, Copy, Debug)]
+pub struct SlipDecoder<const N: usize> {
+    buffer: [u8; N],
+    length: usize,
+    escape: bool,
+    overflow: bool,
+}
+
+impl<const N: usize> Default for SlipDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SlipDecoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            length: 0,
+            escape: false,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte, returns a frame when it is complete
+    ///
+    /// Empty frames and frames that are too large are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        let byte = match (self.escape, byte) {
+            (false, SLIP_END) => {
+                let length = self.length;
+                let overflow = self.overflow;
+                self.length = 0;
+                self.overflow = false;
+                if length == 0 || overflow {
+                    return None;
+                }
+                return Some(&self.buffer[..length]);
+            }
+            (false, SLIP_ESC) => {
+                self.escape = true;
+                return None;
+            }
+            (true, SLIP_ESC_END) => SLIP_END,
+            (true, SLIP_ESC_ESC) => SLIP_ESC,
+            (_, byte) => byte,
+        };
+        self.escape = false;
+        if self.length < N {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        } else {
+            self.overflow = true;
+        }
+        None
+    }
+}
+
+/// A cluster library command to send on behalf of the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Command<'a> {
+    pub address: u16,
+    pub endpoint: u8,
+    pub profile: u16,
+    pub cluster: u16,
+    /// A profile wide command instead of a cluster specific command
+    pub general: bool,
+    /// Sent from the server side of the cluster
+    pub from_server: bool,
+    pub command: u8,
+    pub payload: &'a [u8],
+}
+
+/// A request from the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request<'a> {
+    ListDevices,
+    PermitJoining {
+        duration: u8,
+    },
+    SendCommand(Command<'a>),
+    Network,
+    InstallCode {
+        extended_address: u64,
+        install_code: InstallCode,
+    },
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request frame
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let (&identifier, data) = frame.split_first()?;
+        match identifier {
+            REQUEST_LIST_DEVICES => Some(Request::ListDevices),
+            REQUEST_PERMIT_JOINING => Some(Request::PermitJoining {
+                duration: *data.first()?,
+            }),
+            REQUEST_SEND_COMMAND if data.len() >= 9 => Some(Request::SendCommand(Command {
+                address: LittleEndian::read_u16(&data[0..2]),
+                endpoint: data[2],
+                profile: LittleEndian::read_u16(&data[3..5]),
+                cluster: LittleEndian::read_u16(&data[5..7]),
+                general: data[7] & FLAG_GENERAL == FLAG_GENERAL,
+                from_server: data[7] & FLAG_FROM_SERVER == FLAG_FROM_SERVER,
+                command: data[8],
+                payload: &data[9..],
+            })),
+            REQUEST_NETWORK => Some(Request::Network),
+            REQUEST_INSTALL_CODE if data.len() > 8 => Some(Request::InstallCode {
+                extended_address: LittleEndian::read_u64(&data[0..8]),
+                install_code: InstallCode::parse(&data[8..])?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Write a device response, returns the number of bytes used
+///
+/// `data` must be at least 12 bytes.
+pub fn write_device(device: &Device, data: &mut [u8]) -> usize {
+    data[0] = RESPONSE_DEVICE;
+    LittleEndian::write_u16(&mut data[1..3], device.short_address);
+    LittleEndian::write_u64(&mut data[3..11], device.extended_address);
+    data[11] = if device.rx_on_when_idle {
+        FLAG_RX_ON_WHEN_IDLE
+    } else {
+        0
+    };
+    12
+}
+
+/// Write the end of a device list, returns the number of bytes used
+pub fn write_device_list_end(count: usize, data: &mut [u8]) -> usize {
+    data[0] = RESPONSE_DEVICE_LIST_END;
+    data[1] = count as u8;
+    2
+}
+
+/// Write the status of a request, returns the number of bytes used
+pub fn write_status(request: u8, status: u8, data: &mut [u8]) -> usize {
+    data[0] = RESPONSE_STATUS;
+    data[1] = request;
+    data[2] = status;
+    3
+}
+
+/// Write the network parameters, returns the number of bytes used
+///
+/// `data` must be at least 12 bytes.
+pub fn write_network(
+    channel: u8,
+    pan_identifier: u16,
+    extended_pan_identifier: u64,
+    data: &mut [u8],
+) -> usize {
+    data[0] = RESPONSE_NETWORK;
+    data[1] = channel;
+    LittleEndian::write_u16(&mut data[2..4], pan_identifier);
+    LittleEndian::write_u64(&mut data[4..12], extended_pan_identifier);
+    12
+}
+
+/// A device that joined the network
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Device {
+    pub short_address: u16,
+    pub extended_address: u64,
+    pub rx_on_when_idle: bool,
+}
+
+impl Device {
+    /// Parse a ZDO Device_annce, without the transaction sequence number
+    pub fn from_announce(data: &[u8]) -> Option<Self> {
+        if data.len() < 11 {
+            return None;
+        }
+        Some(Self {
+            short_address: LittleEndian::read_u16(&data[0..2]),
+            extended_address: LittleEndian::read_u64(&data[2..10]),
+            rx_on_when_idle: data[10] & CAPABILITY_RX_ON_WHEN_IDLE == CAPABILITY_RX_ON_WHEN_IDLE,
+        })
+    }
+
+    fn pack(&self, data: &mut [u8]) {
+        LittleEndian::write_u16(&mut data[0..2], self.short_address);
+        LittleEndian::write_u64(&mut data[2..10], self.extended_address);
+        data[10] = u8::from(self.rx_on_when_idle);
+    }
+
+    fn unpack(data: &[u8]) -> Self {
+        Self {
+            short_address: LittleEndian::read_u16(&data[0..2]),
+            extended_address: LittleEndian::read_u64(&data[2..10]),
+            rx_on_when_idle: data[10] != 0,
+        }
+    }
+}
+
+/// Devices known to the coordinator
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceTable<const N: usize> {
+    entries: [Option<Device>; N],
+}
+
+impl<const N: usize> Default for DeviceTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DeviceTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Number of devices
+    pub fn count(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// The known devices
+    pub fn devices(&self) -> impl Iterator<Item = &Device> + '_ {
+        self.entries.iter().flatten()
+    }
+
+    /// Remove all devices
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Add a device, or update the short address of a known device
+    ///
+    /// Returns false if the table is full.
+    pub fn add(&mut self, device: Device) -> bool {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.extended_address == device.extended_address)
+        {
+            *entry = device;
+            return true;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(device);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pack the table into `data`, returns the number of bytes used
+    ///
+    /// `data` must be at least `device_table_size(N)` bytes.
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        let mut offset = 1;
+        let mut count = 0;
+        for device in self.entries.iter().flatten() {
+            device.pack(&mut data[offset..offset + DEVICE_SIZE]);
+            offset += DEVICE_SIZE;
+            count += 1;
+        }
+        data[0] = count;
+        offset
+    }
+
+    /// Unpack a table from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let count = *data.first()? as usize;
+        if count > N || data.len() < device_table_size(count) {
+            return None;
+        }
+        let mut table = Self::new();
+        for (n, entry) in data[1..device_table_size(count)]
+            .chunks(DEVICE_SIZE)
+            .enumerate()
+        {
+            table.entries[n] = Some(Device::unpack(entry));
+        }
+        Some(table)
+    }
+}
+
+/// Preconfigured link keys of devices that will join, derived from their
+/// install codes
+#[derive(Clone, Copy, Debug)]
+pub struct LinkKeyTable<const N: usize> {
+    entries: [Option<(u64, [u8; BLOCK_SIZE])>; N],
+}
+
+impl<const N: usize> Default for LinkKeyTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LinkKeyTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// The link key of a device, `None` if no install code was given
+    pub fn get(&self, extended_address: u64) -> Option<[u8; BLOCK_SIZE]> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(address, _)| *address == extended_address)
+            .map(|(_, key)| *key)
+    }
+
+    /// Add or replace the link key of a device
+    ///
+    /// Returns false if the table is full.
+    pub fn add(&mut self, extended_address: u64, key: [u8; BLOCK_SIZE]) -> bool {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|(address, _)| *address == extended_address)
+        {
+            entry.1 = key;
+            return true;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some((extended_address, key));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pack the table into `data`, returns the number of bytes used
+    ///
+    /// `data` must be at least `link_key_table_size(N)` bytes.
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        let mut offset = 1;
+        let mut count = 0;
+        for (address, key) in self.entries.iter().flatten() {
+            LittleEndian::write_u64(&mut data[offset..offset + 8], *address);
+            data[offset + 8..offset + LINK_KEY_SIZE].copy_from_slice(key);
+            offset += LINK_KEY_SIZE;
+            count += 1;
+        }
+        data[0] = count;
+        offset
+    }
+
+    /// Unpack a table from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let count = *data.first()? as usize;
+        if count > N || data.len() < link_key_table_size(count) {
+            return None;
+        }
+        let mut table = Self::new();
+        for (n, entry) in data[1..link_key_table_size(count)]
+            .chunks(LINK_KEY_SIZE)
+            .enumerate()
+        {
+            let mut key = [0u8; BLOCK_SIZE];
+            key.copy_from_slice(&entry[8..]);
+            table.entries[n] = Some((LittleEndian::read_u64(&entry[0..8]), key));
+        }
+        Some(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_code_request() {
+        let code = InstallCode::new(&[
+            0x83, 0xfe, 0xd3, 0x40, 0x7a, 0x93, 0x97, 0x23, 0xa5, 0xc6, 0x39, 0xb2, 0x69, 0x16,
+            0xd5, 0x05,
+        ]);
+        let mut frame = [0u8; 27];
+        frame[0] = REQUEST_INSTALL_CODE;
+        LittleEndian::write_u64(&mut frame[1..9], 0x0123_4567_89ab_cdef);
+        frame[9..].copy_from_slice(code.as_bytes());
+        assert_eq!(
+            Request::parse(&frame),
+            Some(Request::InstallCode {
+                extended_address: 0x0123_4567_89ab_cdef,
+                install_code: code,
+            })
+        );
+        // A wrong CRC or a missing install code is rejected
+        frame[26] ^= 0x01;
+        assert_eq!(Request::parse(&frame), None);
+        assert_eq!(Request::parse(&frame[..9]), None);
+    }
+
+    #[test]
+    fn link_keys() {
+        let mut table = LinkKeyTable::<2>::new();
+        assert!(table.add(1, [0x11; BLOCK_SIZE]));
+        assert!(table.add(2, [0x22; BLOCK_SIZE]));
+        assert!(table.add(1, [0x33; BLOCK_SIZE]));
+        assert!(!table.add(3, [0x44; BLOCK_SIZE]));
+        assert_eq!(table.get(1), Some([0x33; BLOCK_SIZE]));
+        assert_eq!(table.get(3), None);
+
+        let mut data = [0u8; link_key_table_size(2)];
+        assert_eq!(table.pack(&mut data), data.len());
+        let unpacked = LinkKeyTable::<2>::unpack(&data).unwrap();
+        assert_eq!(unpacked.get(1), Some([0x33; BLOCK_SIZE]));
+        assert_eq!(unpacked.get(2), Some([0x22; BLOCK_SIZE]));
+        assert!(LinkKeyTable::<1>::unpack(&data).is_none());
+    }
+}
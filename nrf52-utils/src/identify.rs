@@ -0,0 +1,394 @@
+//! Identify cluster
+//!
+//! Keeps track of the identify time and of the trigger effects. The state is
+//! advanced by calling `tick` `TICKS_PER_SECOND` times per second, each tick
+//! tells how the light should be lit.
+
+/// Number of ticks per second
+pub const TICKS_PER_SECOND: u32 = 10;
+
+/// Ticks in one blink or breathe cycle
+const CYCLE_TICKS: u32 = TICKS_PER_SECOND;
+/// Number of breathe cycles in the breathe effect
+const BREATHE_CYCLES: u32 = 15;
+/// Length of the channel change effect
+const CHANNEL_CHANGE_TICKS: u32 = 8 * TICKS_PER_SECOND;
+/// Ticks in one blink of the okay effect
+const OKAY_BLINK_TICKS: u32 = 4;
+
+/// Identify cluster trigger effects
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    /// Light is turned on and off once
+    Blink = 0x00,
+    /// Light is turned on and off smoothly, 15 times
+    Breathe = 0x01,
+    /// Two quick blinks, green on colour lights
+    Okay = 0x02,
+    /// Light is lit for 8 seconds, orange on colour lights
+    ChannelChange = 0x0b,
+    /// Complete the current cycle of the effect and stop
+    FinishEffect = 0xfe,
+    /// Stop the effect
+    StopEffect = 0xff,
+}
+
+impl Effect {
+    /// Convert from the effect identifier
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Effect::Blink),
+            0x01 => Some(Effect::Breathe),
+            0x02 => Some(Effect::Okay),
+            0x0b => Some(Effect::ChannelChange),
+            0xfe => Some(Effect::FinishEffect),
+            0xff => Some(Effect::StopEffect),
+            _ => None,
+        }
+    }
+
+    /// Length of the effect in ticks
+    fn length(self) -> u32 {
+        match self {
+            Effect::Blink => CYCLE_TICKS,
+            Effect::Breathe => BREATHE_CYCLES * CYCLE_TICKS,
+            Effect::Okay => 2 * OKAY_BLINK_TICKS,
+            Effect::ChannelChange => CHANNEL_CHANGE_TICKS,
+            Effect::FinishEffect | Effect::StopEffect => 0,
+        }
+    }
+
+    /// Light level at `tick` of the effect
+    fn level(self, tick: u32) -> u8 {
+        match self {
+            Effect::Blink => blink(tick),
+            Effect::Breathe => {
+                let half = CYCLE_TICKS / 2;
+                let position = tick % CYCLE_TICKS;
+                let ramp = if position < half {
+                    position
+                } else {
+                    CYCLE_TICKS - position
+                };
+                (ramp * 255 / half) as u8
+            }
+            Effect::Okay => {
+                if tick % OKAY_BLINK_TICKS < OKAY_BLINK_TICKS / 2 {
+                    255
+                } else {
+                    0
+                }
+            }
+            Effect::ChannelChange => 255,
+            Effect::FinishEffect | Effect::StopEffect => 0,
+        }
+    }
+}
+
+/// Half a cycle on, half a cycle off
+fn blink(tick: u32) -> u8 {
+    if tick % CYCLE_TICKS < CYCLE_TICKS / 2 {
+        255
+    } else {
+        0
+    }
+}
+
+/// What to show for a tick
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Indication {
+    /// Not identifying, show the normal state
+    None,
+    /// Show the identification at the level, 0 to 255
+    Level(u8),
+    /// Identification ended, return to the normal state
+    Finished,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Idle,
+    Identify { remaining: u32 },
+    Effect { effect: Effect, end: u32 },
+}
+
+/// Identify state
+pub struct Identify {
+    mode: Mode,
+    tick: u32,
+}
+
+impl Default for Identify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Identify {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Idle,
+            tick: 0,
+        }
+    }
+
+    /// Check if identifying or running an effect
+    pub fn is_identifying(&self) -> bool {
+        self.mode != Mode::Idle
+    }
+
+    /// The running effect, if any
+    pub fn effect(&self) -> Option<Effect> {
+        match self.mode {
+            Mode::Effect { effect, .. } => Some(effect),
+            _ => None,
+        }
+    }
+
+    /// Remaining identify time in seconds, the IdentifyTime attribute
+    pub fn identify_time(&self) -> u16 {
+        match self.mode {
+            Mode::Identify { remaining } => remaining.div_ceil(TICKS_PER_SECOND) as u16,
+            _ => 0,
+        }
+    }
+
+    /// Identify for `seconds`, zero stops identifying
+    pub fn identify(&mut self, seconds: u16) {
+        if seconds == 0 {
+            self.mode = Mode::Idle;
+        } else {
+            self.tick = 0;
+            self.mode = Mode::Identify {
+                remaining: u32::from(seconds) * TICKS_PER_SECOND,
+            };
+        }
+    }
+
+    /// Start, finish or stop an effect
+    pub fn trigger_effect(&mut self, effect: Effect) {
+        match effect {
+            Effect::StopEffect => {
+                self.mode = Mode::Idle;
+            }
+            Effect::FinishEffect => {
+                if let Mode::Effect { effect, .. } = self.mode {
+                    let end = (self.tick / CYCLE_TICKS + 1) * CYCLE_TICKS;
+                    self.mode = Mode::Effect {
+                        effect,
+                        end: core::cmp::min(end, effect.length()),
+                    };
+                } else {
+                    self.mode = Mode::Idle;
+                }
+            }
+            effect => {
+                self.tick = 0;
+                self.mode = Mode::Effect {
+                    effect,
+                    end: effect.length(),
+                };
+            }
+        }
+    }
+
+    /// Advance the state by one tick
+    pub fn tick(&mut self) -> Indication {
+        let (mode, indication) = match self.mode {
+            Mode::Idle => {
+                if self.tick == 0 {
+                    return Indication::None;
+                }
+                (Mode::Idle, Indication::Finished)
+            }
+            Mode::Identify { remaining } => {
+                if remaining <= 1 {
+                    (Mode::Idle, Indication::Finished)
+                } else {
+                    (
+                        Mode::Identify {
+                            remaining: remaining - 1,
+                        },
+                        Indication::Level(blink(self.tick)),
+                    )
+                }
+            }
+            Mode::Effect { effect, end } => {
+                if self.tick >= end {
+                    (Mode::Idle, Indication::Finished)
+                } else {
+                    (
+                        Mode::Effect { effect, end },
+                        Indication::Level(effect.level(self.tick)),
+                    )
+                }
+            }
+        };
+        self.mode = mode;
+        self.tick = match indication {
+            Indication::Finished => 0,
+            _ => self.tick + 1,
+        };
+        indication
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `N` ticks, returns the indications
+    fn run<const N: usize>(identify: &mut Identify) -> [Indication; N] {
+        let mut indications = [Indication::None; N];
+        for indication in indications.iter_mut() {
+            *indication = identify.tick();
+        }
+        indications
+    }
+
+    #[test]
+    fn idle() {
+        let mut identify = Identify::new();
+        assert!(!identify.is_identifying());
+        assert_eq!(identify.identify_time(), 0);
+        assert_eq!(identify.tick(), Indication::None);
+        assert_eq!(identify.tick(), Indication::None);
+    }
+
+    #[test]
+    fn identify_time() {
+        let mut identify = Identify::new();
+        identify.identify(2);
+        assert!(identify.is_identifying());
+        assert_eq!(identify.identify_time(), 2);
+        // The attribute counts down whole seconds, rounded up
+        run::<1>(&mut identify);
+        assert_eq!(identify.identify_time(), 2);
+        run::<9>(&mut identify);
+        assert_eq!(identify.identify_time(), 1);
+        let indications = run::<9>(&mut identify);
+        assert_eq!(identify.identify_time(), 1);
+        assert_eq!(indications[..5], [Indication::Level(255); 5]);
+        assert_eq!(indications[5..], [Indication::Level(0); 4]);
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert!(!identify.is_identifying());
+        assert_eq!(identify.identify_time(), 0);
+        assert_eq!(identify.tick(), Indication::None);
+    }
+
+    #[test]
+    fn identify_stop() {
+        let mut identify = Identify::new();
+        identify.identify(5);
+        run::<3>(&mut identify);
+        identify.identify(0);
+        assert!(!identify.is_identifying());
+        assert_eq!(identify.identify_time(), 0);
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert_eq!(identify.tick(), Indication::None);
+    }
+
+    #[test]
+    fn effect_identifiers() {
+        for &effect in &[
+            Effect::Blink,
+            Effect::Breathe,
+            Effect::Okay,
+            Effect::ChannelChange,
+            Effect::FinishEffect,
+            Effect::StopEffect,
+        ] {
+            assert_eq!(Effect::from_u8(effect as u8), Some(effect));
+        }
+        assert_eq!(Effect::from_u8(0x03), None);
+    }
+
+    #[test]
+    fn blink() {
+        let mut identify = Identify::new();
+        identify.trigger_effect(Effect::Blink);
+        assert_eq!(identify.effect(), Some(Effect::Blink));
+        // The effect does not change the IdentifyTime attribute
+        assert_eq!(identify.identify_time(), 0);
+        let indications = run::<10>(&mut identify);
+        assert_eq!(indications[..5], [Indication::Level(255); 5]);
+        assert_eq!(indications[5..], [Indication::Level(0); 5]);
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert_eq!(identify.effect(), None);
+        assert_eq!(identify.tick(), Indication::None);
+    }
+
+    #[test]
+    fn breathe() {
+        let mut identify = Identify::new();
+        identify.trigger_effect(Effect::Breathe);
+        let indications = run::<150>(&mut identify);
+        // Ramps up and down every cycle
+        for cycle in indications.chunks(10) {
+            assert_eq!(cycle[0], Indication::Level(0));
+            assert_eq!(cycle[5], Indication::Level(255));
+            assert_eq!(cycle[2], cycle[8]);
+        }
+        assert_eq!(identify.tick(), Indication::Finished);
+    }
+
+    #[test]
+    fn finish_effect() {
+        let mut identify = Identify::new();
+        identify.trigger_effect(Effect::Breathe);
+        run::<23>(&mut identify);
+        // Runs to the end of the current cycle
+        identify.trigger_effect(Effect::FinishEffect);
+        assert_eq!(identify.effect(), Some(Effect::Breathe));
+        let indications = run::<7>(&mut identify);
+        assert!(indications
+            .iter()
+            .all(|indication| matches!(indication, Indication::Level(_))));
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert_eq!(identify.effect(), None);
+        // Without an effect it stops right away
+        identify.identify(3);
+        identify.trigger_effect(Effect::FinishEffect);
+        assert!(!identify.is_identifying());
+    }
+
+    #[test]
+    fn stop_effect() {
+        let mut identify = Identify::new();
+        identify.trigger_effect(Effect::ChannelChange);
+        run::<12>(&mut identify);
+        identify.trigger_effect(Effect::StopEffect);
+        assert_eq!(identify.effect(), None);
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert_eq!(identify.tick(), Indication::None);
+    }
+
+    #[test]
+    fn identify_during_effect() {
+        let mut identify = Identify::new();
+        identify.trigger_effect(Effect::Breathe);
+        run::<42>(&mut identify);
+        // Identify with zero stops the effect too
+        identify.identify(0);
+        assert_eq!(identify.effect(), None);
+        assert!(!identify.is_identifying());
+        assert_eq!(identify.tick(), Indication::Finished);
+        assert_eq!(identify.tick(), Indication::None);
+        // A new effect starts from the beginning
+        identify.trigger_effect(Effect::Okay);
+        assert_eq!(
+            run::<9>(&mut identify),
+            [
+                Indication::Level(255),
+                Indication::Level(255),
+                Indication::Level(0),
+                Indication::Level(0),
+                Indication::Level(255),
+                Indication::Level(255),
+                Indication::Level(0),
+                Indication::Level(0),
+                Indication::Finished,
+            ]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Install codes
+//!
+//! An install code is a random value of 6, 8, 12 or 16 bytes followed by its
+//! CRC-16, printed on or shipped with a device. The trust center is given the
+//! install code out of band and both sides derive the preconfigured link key
+//! from it with the Matyas-Meyer-Oseas hash, instead of using the well-known
+//! default link key.
+//!
+//! A 16 byte install code can be provisioned in the UICR customer registers
+//! 2 to 6, code followed by the CRC, little endian. Otherwise one is generated
+//! on the first start and kept in the key/value store.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::security::DEFAULT_LINK_KEY;
+
+use crate::{
+    cipher::{mmo_hash, BlockCipher, BLOCK_SIZE},
+    crc::crc16,
+    kv::Store,
+    storage::Storage,
+};
+
+/// Key of the install code in the key/value store
+pub const INSTALL_CODE_KEY: u16 = 0x0007;
+
+/// Size of the longest install code, with the CRC
+pub const MAX_INSTALL_CODE_SIZE: usize = 18;
+
+/// First UICR customer register of a provisioned install code
+pub const UICR_INSTALL_CODE_REGISTER: usize = 2;
+
+/// Size of the CRC following the install code
+const CRC_SIZE: usize = 2;
+
+/// An install code with its CRC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstallCode {
+    data: [u8; MAX_INSTALL_CODE_SIZE],
+    length: usize,
+}
+
+impl InstallCode {
+    /// A 16 byte install code, the CRC is calculated
+    pub fn new(code: &[u8; 16]) -> Self {
+        let mut data = [0u8; MAX_INSTALL_CODE_SIZE];
+        data[..16].copy_from_slice(code);
+        LittleEndian::write_u16(&mut data[16..], crc16(code));
+        Self {
+            data,
+            length: MAX_INSTALL_CODE_SIZE,
+        }
+    }
+
+    /// Parse an install code followed by its CRC
+    ///
+    /// Returns `None` if the length is not valid or if the CRC does not match.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data.len() {
+            8 | 10 | 14 | 18 => (),
+            _ => return None,
+        }
+        let (code, crc) = data.split_at(data.len() - CRC_SIZE);
+        if crc16(code) != LittleEndian::read_u16(crc) {
+            return None;
+        }
+        let mut install_code = Self {
+            data: [0u8; MAX_INSTALL_CODE_SIZE],
+            length: data.len(),
+        };
+        install_code.data[..data.len()].copy_from_slice(data);
+        Some(install_code)
+    }
+
+    /// The install code followed by the CRC
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.length]
+    }
+
+    /// The CRC of the install code
+    pub fn crc(&self) -> u16 {
+        LittleEndian::read_u16(&self.data[self.length - CRC_SIZE..self.length])
+    }
+
+    /// The preconfigured link key derived from the install code
+    pub fn link_key<C: BlockCipher>(&self, cipher: &mut C) -> [u8; BLOCK_SIZE] {
+        mmo_hash(cipher, self.as_bytes())
+    }
+}
+
+/// Where the install code in use came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Origin {
+    /// Provisioned in the UICR
+    Uicr,
+    /// Read from the key/value store
+    Store,
+    /// Generated at this start
+    Generated,
+}
+
+impl Origin {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Origin::Uicr => "UICR",
+            Origin::Store => "store",
+            Origin::Generated => "generated",
+        }
+    }
+}
+
+/// Pick the install code to use
+///
+/// A `provisioned` install code is used first, then one from the store.
+/// Otherwise an install code is created from `generate` and written to the
+/// store.
+pub fn provision<S: Storage>(
+    provisioned: Option<InstallCode>,
+    store: &mut Store<S>,
+    generate: impl FnOnce() -> [u8; 16],
+) -> (InstallCode, Origin) {
+    if let Some(install_code) = provisioned {
+        return (install_code, Origin::Uicr);
+    }
+    let mut data = [0u8; MAX_INSTALL_CODE_SIZE];
+    if let Ok(Some(length)) = store.read(INSTALL_CODE_KEY, &mut data) {
+        if let Some(install_code) = InstallCode::parse(&data[..length]) {
+            return (install_code, Origin::Store);
+        }
+    }
+    let install_code = InstallCode::new(&generate());
+    // Without the store a new install code is used at every start
+    let _ = store.write(INSTALL_CODE_KEY, install_code.as_bytes());
+    (install_code, Origin::Generated)
+}
+
+/// The preconfigured link key to join with
+///
+/// The link key derived from `install_code`, or the well-known default link
+/// key without an install code.
+pub fn link_key<C: BlockCipher>(
+    install_code: Option<&InstallCode>,
+    cipher: &mut C,
+) -> [u8; BLOCK_SIZE] {
+    install_code.map_or(DEFAULT_LINK_KEY, |install_code| {
+        install_code.link_key(cipher)
+    })
+}
+
+/// Read an install code provisioned in the UICR
+#[cfg(feature = "52840")]
+pub fn read_uicr(uicr: &nrf52840_pac::UICR) -> Option<InstallCode> {
+    let mut data = [0u8; 20];
+    for (n, chunk) in data.chunks_exact_mut(4).enumerate() {
+        let value = uicr.customer[UICR_INSTALL_CODE_REGISTER + n].read().bits();
+        LittleEndian::write_u32(chunk, value);
+    }
+    // Erased registers read as all ones, which fails the CRC check
+    InstallCode::parse(&data[..MAX_INSTALL_CODE_SIZE])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cipher::SoftAes, vectors::HASH_VECTORS};
+
+    /// Install code of `length` bytes with its CRC appended
+    fn with_crc(length: usize) -> ([u8; MAX_INSTALL_CODE_SIZE], usize) {
+        let mut data = [0u8; MAX_INSTALL_CODE_SIZE];
+        for (n, byte) in data[..length].iter_mut().enumerate() {
+            *byte = 0x11 * n as u8;
+        }
+        let crc = crc16(&data[..length]);
+        LittleEndian::write_u16(&mut data[length..length + CRC_SIZE], crc);
+        (data, length + CRC_SIZE)
+    }
+
+    #[test]
+    fn crc() {
+        // CRC-16/X.25 check value
+        assert_eq!(crc16(b"123456789"), 0x906e);
+        // Zigbee install code example
+        let install_code = InstallCode::parse(HASH_VECTORS[0].data).unwrap();
+        assert_eq!(install_code.crc(), 0xb5c3);
+        assert_eq!(install_code.as_bytes(), HASH_VECTORS[0].data);
+    }
+
+    #[test]
+    fn lengths() {
+        for &length in &[6, 8, 12, 16] {
+            let (data, size) = with_crc(length);
+            let install_code = InstallCode::parse(&data[..size]).unwrap();
+            assert_eq!(install_code.as_bytes(), &data[..size]);
+            assert_eq!(install_code.crc(), crc16(&data[..length]));
+        }
+        // Other lengths are rejected even with a valid CRC
+        for &length in &[0, 4, 7, 10, 14] {
+            let (data, size) = with_crc(length);
+            assert_eq!(InstallCode::parse(&data[..size]), None, "{}", size);
+        }
+        assert_eq!(InstallCode::parse(&[]), None);
+    }
+
+    #[test]
+    fn bad_crc() {
+        for &length in &[6, 8, 12, 16] {
+            let (data, size) = with_crc(length);
+            for n in 0..size {
+                let mut corrupted = data;
+                corrupted[n] ^= 0x01;
+                assert_eq!(InstallCode::parse(&corrupted[..size]), None);
+            }
+            // CRC in big endian order
+            let mut swapped = data;
+            swapped.swap(length, length + 1);
+            assert_eq!(InstallCode::parse(&swapped[..size]), None);
+        }
+    }
+
+    #[test]
+    fn new() {
+        let (data, size) = with_crc(16);
+        let mut code = [0u8; 16];
+        code.copy_from_slice(&data[..16]);
+        assert_eq!(
+            InstallCode::new(&code),
+            InstallCode::parse(&data[..size]).unwrap()
+        );
+    }
+
+    #[test]
+    fn link_keys() {
+        let install_code = InstallCode::parse(HASH_VECTORS[0].data).unwrap();
+        assert_eq!(install_code.link_key(&mut SoftAes), HASH_VECTORS[0].hash);
+        assert_eq!(
+            link_key(Some(&install_code), &mut SoftAes),
+            HASH_VECTORS[0].hash
+        );
+        // Without an install code, the default link key
+        assert_eq!(link_key(None, &mut SoftAes), *b"ZigBeeAlliance09");
+    }
+}
@@ -0,0 +1,666 @@
+//! Key/value store
+//!
+//! A log structured store on top of a page based `Storage`. Records are
+//! appended to the active page, the last record with a key holds the current
+//! value. Removal appends a record without data.
+//!
+//! The pages are used as a ring. The page after the active page is always
+//! kept erased. When the active page is full, the erased page becomes active
+//! and the oldest page is garbage collected: records that still hold the
+//! current value of a key are copied into the new active page before the
+//! oldest page is erased. All pages are erased equally often.
+//!
+//! Each record carries a CRC-32, records that were not completely written are
+//! ignored. An interrupted garbage collection is completed when the store is
+//! opened.
+//!
+//! ```text
+//! Page:   | magic (4) | sequence (4) | record | record | ... | 0xff ... |
+//! Record: | key (2) | length (2) | crc (4) | data (length) | padding |
+//! ```
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::crc::crc32_update;
+use crate::storage::{self, Storage, WORD_SIZE};
+
+/// Page magic, "PSKV"
+const PAGE_MAGIC: u32 = 0x564b_5350;
+const PAGE_HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 8;
+const ERASED_WORD: u32 = 0xffff_ffff;
+/// Size of the buffer used when processing record data
+const CHUNK_SIZE: usize = 16;
+
+/// Key that marks unused space and cannot be used
+pub const INVALID_KEY: u16 = 0xffff;
+
+/// Errors from the key/value store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Error from the underlying storage
+    Storage(storage::Error),
+    /// The store needs at least two pages
+    TooFewPages,
+    /// The key is reserved
+    InvalidKey,
+    /// The value does not fit in a page
+    TooLarge,
+    /// The store is full
+    Full,
+    /// The provided buffer is too small for the value
+    NotEnoughSpace,
+}
+
+impl From<storage::Error> for Error {
+    fn from(error: storage::Error) -> Self {
+        Error::Storage(error)
+    }
+}
+
+/// Location of a record
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    key: u16,
+    length: usize,
+    /// Offset of the record header in the storage
+    offset: usize,
+}
+
+impl Record {
+    fn data_offset(&self) -> usize {
+        self.offset + RECORD_HEADER_SIZE
+    }
+
+    fn size(&self) -> usize {
+        record_size(self.length)
+    }
+}
+
+fn record_size(length: usize) -> usize {
+    RECORD_HEADER_SIZE + length.div_ceil(WORD_SIZE) * WORD_SIZE
+}
+
+/// Key/value store
+pub struct Store<S: Storage> {
+    storage: S,
+    /// Active page, records are appended here
+    active: usize,
+    /// Sequence number of the active page
+    sequence: u32,
+    /// Offset of the next record in the active page
+    position: usize,
+}
+
+impl<S: Storage> Store<S> {
+    /// Open the store, formatting the storage if it holds no store
+    pub fn new(storage: S) -> Result<Self, Error> {
+        if storage.page_count() < 2 {
+            return Err(Error::TooFewPages);
+        }
+        let mut store = Self {
+            storage,
+            active: 0,
+            sequence: 0,
+            position: PAGE_HEADER_SIZE,
+        };
+        store.mount()?;
+        Ok(store)
+    }
+
+    /// Release the underlying storage
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Read the value of `key` into `value`
+    ///
+    /// Returns the length of the value or `None` if there is no value.
+    pub fn read(&self, key: u16, value: &mut [u8]) -> Result<Option<usize>, Error> {
+        match self.find(key)? {
+            Some(record) => {
+                if record.length > value.len() {
+                    return Err(Error::NotEnoughSpace);
+                }
+                self.storage
+                    .read(record.data_offset(), &mut value[..record.length])?;
+                Ok(Some(record.length))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Check if there is a value for `key`
+    pub fn contains(&self, key: u16) -> Result<bool, Error> {
+        Ok(self.find(key)?.is_some())
+    }
+
+    /// Write `value` for `key`
+    ///
+    /// Nothing is written if the value is unchanged. An empty value is the
+    /// same as removing the key.
+    pub fn write(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if key == INVALID_KEY {
+            return Err(Error::InvalidKey);
+        }
+        if record_size(value.len()) > self.storage.page_size() - PAGE_HEADER_SIZE {
+            return Err(Error::TooLarge);
+        }
+        if self.is_current(key, value)? {
+            return Ok(());
+        }
+        self.append(key, value)
+    }
+
+    /// Remove the value for `key`
+    pub fn remove(&mut self, key: u16) -> Result<(), Error> {
+        if key == INVALID_KEY {
+            return Err(Error::InvalidKey);
+        }
+        if self.find(key)?.is_none() {
+            return Ok(());
+        }
+        self.append(key, &[])
+    }
+
+    /// Remove all values by erasing all pages
+    pub fn clear(&mut self) -> Result<(), Error> {
+        for page in 0..self.storage.page_count() {
+            self.storage.erase_page(page)?;
+        }
+        self.activate(0, 1)
+    }
+
+    fn page_offset(&self, page: usize) -> usize {
+        page * self.storage.page_size()
+    }
+
+    fn next_page(&self, page: usize) -> usize {
+        (page + 1) % self.storage.page_count()
+    }
+
+    fn read_word(&self, offset: usize) -> Result<u32, Error> {
+        let mut word = [0u8; WORD_SIZE];
+        self.storage.read(offset, &mut word)?;
+        Ok(LittleEndian::read_u32(&word))
+    }
+
+    /// Sequence number of a page, `None` if the page holds no valid header
+    fn page_sequence(&self, page: usize) -> Result<Option<u32>, Error> {
+        let offset = self.page_offset(page);
+        if self.read_word(offset)? != PAGE_MAGIC {
+            return Ok(None);
+        }
+        let sequence = self.read_word(offset + WORD_SIZE)?;
+        if sequence == ERASED_WORD {
+            return Ok(None);
+        }
+        Ok(Some(sequence))
+    }
+
+    fn is_page_erased(&self, page: usize) -> Result<bool, Error> {
+        let offset = self.page_offset(page);
+        for word in 0..self.storage.page_size() / WORD_SIZE {
+            if self.read_word(offset + word * WORD_SIZE)? != ERASED_WORD {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn mount(&mut self) -> Result<(), Error> {
+        let mut newest: Option<(usize, u32)> = None;
+        for page in 0..self.storage.page_count() {
+            match self.page_sequence(page)? {
+                Some(sequence) => {
+                    if newest.is_none_or(|(_, newest)| sequence > newest) {
+                        newest = Some((page, sequence));
+                    }
+                }
+                None => {
+                    // Partially erased or partially formatted page
+                    if !self.is_page_erased(page)? {
+                        self.storage.erase_page(page)?;
+                    }
+                }
+            }
+        }
+        match newest {
+            Some((page, sequence)) => {
+                self.active = page;
+                self.sequence = sequence;
+                self.position = self.end_of_records(page)?;
+                let spare = self.next_page(page);
+                if self.page_sequence(spare)?.is_some() {
+                    // Garbage collection was interrupted
+                    self.collect(spare)?;
+                }
+                Ok(())
+            }
+            None => self.activate(0, 1),
+        }
+    }
+
+    /// Format `page` as the active page
+    fn activate(&mut self, page: usize, sequence: u32) -> Result<(), Error> {
+        let mut header = [0u8; PAGE_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], PAGE_MAGIC);
+        LittleEndian::write_u32(&mut header[4..8], sequence);
+        let offset = self.page_offset(page);
+        self.storage.write(offset, &header)?;
+        self.active = page;
+        self.sequence = sequence;
+        self.position = PAGE_HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Parse the record header at `position` of `page`
+    ///
+    /// Returns the record and whether its CRC is valid, or `None` if there are
+    /// no more records in the page.
+    fn record_at(&self, page: usize, position: usize) -> Result<Option<(Record, bool)>, Error> {
+        let page_size = self.storage.page_size();
+        if position + RECORD_HEADER_SIZE > page_size {
+            return Ok(None);
+        }
+        let offset = self.page_offset(page) + position;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.storage.read(offset, &mut header)?;
+        let key = LittleEndian::read_u16(&header[0..2]);
+        let length = LittleEndian::read_u16(&header[2..4]) as usize;
+        if key == INVALID_KEY || position + record_size(length) > page_size {
+            return Ok(None);
+        }
+        let record = Record {
+            key,
+            length,
+            offset,
+        };
+        let crc = LittleEndian::read_u32(&header[4..8]);
+        let mut calculated = crc32_update(0xffff_ffff, &header[0..4]);
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let size = core::cmp::min(CHUNK_SIZE, length - done);
+            self.storage
+                .read(record.data_offset() + done, &mut chunk[..size])?;
+            calculated = crc32_update(calculated, &chunk[..size]);
+            done += size;
+        }
+        Ok(Some((record, crc == calculated ^ 0xffff_ffff)))
+    }
+
+    /// Offset after the last record of `page`
+    fn end_of_records(&self, page: usize) -> Result<usize, Error> {
+        let mut position = PAGE_HEADER_SIZE;
+        while let Some((record, _)) = self.record_at(page, position)? {
+            position += record.size();
+        }
+        // Anything after the records that is not erased is the remains of an
+        // interrupted write, leave it be
+        let page_size = self.storage.page_size();
+        let offset = self.page_offset(page);
+        let mut end = position;
+        while end < page_size {
+            if self.read_word(offset + end)? != ERASED_WORD {
+                return Ok(page_size);
+            }
+            end += WORD_SIZE;
+        }
+        Ok(position)
+    }
+
+    /// Pages that hold records, from the oldest to the active page
+    fn pages(&self) -> impl Iterator<Item = usize> {
+        let count = self.storage.page_count();
+        let active = self.active;
+        (1..count).map(move |n| (active + 1 + n) % count)
+    }
+
+    /// Find the newest valid record for `key`, including removals
+    fn newest(&self, key: u16) -> Result<Option<Record>, Error> {
+        let mut found = None;
+        for page in self.pages() {
+            if self.page_sequence(page)?.is_none() {
+                continue;
+            }
+            let mut position = PAGE_HEADER_SIZE;
+            while let Some((record, valid)) = self.record_at(page, position)? {
+                if valid && record.key == key {
+                    found = Some(record);
+                }
+                position += record.size();
+            }
+        }
+        Ok(found)
+    }
+
+    /// Find the record holding the value of `key`, `None` if removed or missing
+    fn find(&self, key: u16) -> Result<Option<Record>, Error> {
+        Ok(self.newest(key)?.filter(|record| record.length > 0))
+    }
+
+    /// Check if `value` is the current value of `key`
+    fn is_current(&self, key: u16, value: &[u8]) -> Result<bool, Error> {
+        let record = match self.find(key)? {
+            Some(record) => record,
+            None => return Ok(value.is_empty()),
+        };
+        if record.length != value.len() {
+            return Ok(false);
+        }
+        let mut chunk = [0u8; CHUNK_SIZE];
+        for (n, part) in value.chunks(CHUNK_SIZE).enumerate() {
+            let stored = &mut chunk[..part.len()];
+            self.storage
+                .read(record.data_offset() + n * CHUNK_SIZE, stored)?;
+            if stored != part {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Append a record to the active page, moving to a new page if needed
+    fn append(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        let size = record_size(value.len());
+        let mut rotations = 0;
+        while self.position + size > self.storage.page_size() {
+            if rotations == self.storage.page_count() {
+                return Err(Error::Full);
+            }
+            self.rotate()?;
+            rotations += 1;
+        }
+        let offset = self.page_offset(self.active) + self.position;
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        LittleEndian::write_u16(&mut header[0..2], key);
+        LittleEndian::write_u16(&mut header[2..4], value.len() as u16);
+        let crc = crc32_update(crc32_update(0xffff_ffff, &header[0..4]), value) ^ 0xffff_ffff;
+        // Key, length and data first, the CRC completes the record
+        self.storage.write(offset, &header[0..4])?;
+        if !value.is_empty() {
+            self.storage.write(offset + RECORD_HEADER_SIZE, value)?;
+        }
+        LittleEndian::write_u32(&mut header[4..8], crc);
+        self.storage.write(offset + WORD_SIZE, &header[4..8])?;
+        self.position += size;
+        Ok(())
+    }
+
+    /// Move to the erased page and garbage collect the oldest page
+    ///
+    /// If the current values of the oldest page do not fit in the new page,
+    /// the new page is erased again and the previous page stays active, so
+    /// that the page after the active page is still erased.
+    fn rotate(&mut self) -> Result<(), Error> {
+        let (active, sequence, position) = (self.active, self.sequence, self.position);
+        let page = self.next_page(active);
+        self.activate(page, sequence.wrapping_add(1))?;
+        let oldest = self.next_page(page);
+        match self.collect(oldest) {
+            Err(Error::Full) => {
+                self.storage.erase_page(page)?;
+                self.active = active;
+                self.sequence = sequence;
+                self.position = position;
+                Err(Error::Full)
+            }
+            result => result,
+        }
+    }
+
+    /// Check if there is a later valid record for `key` in `page`
+    fn is_superseded_in_page(
+        &self,
+        page: usize,
+        mut position: usize,
+        key: u16,
+    ) -> Result<bool, Error> {
+        while let Some((record, valid)) = self.record_at(page, position)? {
+            if valid && record.key == key {
+                return Ok(true);
+            }
+            position += record.size();
+        }
+        Ok(false)
+    }
+
+    /// Copy the current values in `page` to the active page and erase `page`
+    ///
+    /// `page` must be the oldest page, it is not part of the pages searched
+    /// for newer records. Removed keys are dropped as there are no older
+    /// records left to hide. `page` is left as is if the values do not fit.
+    fn collect(&mut self, page: usize) -> Result<(), Error> {
+        let mut position = PAGE_HEADER_SIZE;
+        while let Some((record, valid)) = self.record_at(page, position)? {
+            position += record.size();
+            if !valid
+                || record.length == 0
+                || self.is_superseded_in_page(page, position, record.key)?
+                || self.newest(record.key)?.is_some()
+            {
+                continue;
+            }
+            let size = record.size();
+            if self.position + size > self.storage.page_size() {
+                return Err(Error::Full);
+            }
+            let mut offset = 0;
+            let mut chunk = [0u8; CHUNK_SIZE];
+            while offset < size {
+                let length = core::cmp::min(CHUNK_SIZE, size - offset);
+                self.storage
+                    .read(record.offset + offset, &mut chunk[..length])?;
+                let target = self.page_offset(self.active) + self.position + offset;
+                self.storage.write(target, &chunk[..length])?;
+                offset += length;
+            }
+            self.position += size;
+        }
+        self.storage.erase_page(page)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RamStorage;
+
+    type Ram = RamStorage<128, 3>;
+
+    /// Storage that loses power after a number of writes and erases
+    struct PowerLoss {
+        storage: Ram,
+        budget: usize,
+    }
+
+    impl PowerLoss {
+        fn spend(&mut self) -> Result<(), storage::Error> {
+            if self.budget == 0 {
+                return Err(storage::Error::OutOfBounds);
+            }
+            self.budget -= 1;
+            Ok(())
+        }
+    }
+
+    impl Storage for PowerLoss {
+        fn page_size(&self) -> usize {
+            self.storage.page_size()
+        }
+
+        fn page_count(&self) -> usize {
+            self.storage.page_count()
+        }
+
+        fn read(&self, offset: usize, data: &mut [u8]) -> Result<(), storage::Error> {
+            self.storage.read(offset, data)
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), storage::Error> {
+            self.spend()?;
+            self.storage.write(offset, data)
+        }
+
+        fn erase_page(&mut self, page: usize) -> Result<(), storage::Error> {
+            self.spend()?;
+            self.storage.erase_page(page)
+        }
+    }
+
+    fn value<S: Storage>(store: &Store<S>, key: u16) -> Option<u32> {
+        let mut data = [0u8; 4];
+        let length = store.read(key, &mut data).unwrap()?;
+        assert_eq!(length, 4);
+        Some(u32::from_le_bytes(data))
+    }
+
+    /// The page after the active page has to be kept erased
+    fn assert_spare_erased<S: Storage>(store: &Store<S>) {
+        assert!(store.is_page_erased(store.next_page(store.active)).unwrap());
+    }
+
+    #[test]
+    fn write() {
+        let mut store = Store::new(Ram::new()).unwrap();
+        assert_eq!(value(&store, 1), None);
+        store.write(1, &7u32.to_le_bytes()).unwrap();
+        store.write(2, &8u32.to_le_bytes()).unwrap();
+        assert_eq!(value(&store, 1), Some(7));
+        assert_eq!(value(&store, 2), Some(8));
+        assert!(store.contains(1).unwrap());
+        let mut short = [0u8; 3];
+        assert_eq!(store.read(1, &mut short), Err(Error::NotEnoughSpace));
+        assert_eq!(store.write(INVALID_KEY, &[1]), Err(Error::InvalidKey));
+        assert_eq!(store.write(3, &[0; 128]), Err(Error::TooLarge));
+
+        let store = Store::new(store.into_inner()).unwrap();
+        assert_eq!(value(&store, 1), Some(7));
+        assert_eq!(value(&store, 2), Some(8));
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut store = Store::new(Ram::new()).unwrap();
+        store.write(1, &1u32.to_le_bytes()).unwrap();
+        // Unchanged values are not written again
+        let position = store.position;
+        store.write(1, &1u32.to_le_bytes()).unwrap();
+        assert_eq!(store.position, position);
+        // Enough writes to go around the pages a number of times
+        for n in 0..200u32 {
+            store.write(2, &n.to_le_bytes()).unwrap();
+            assert_eq!(value(&store, 2), Some(n));
+            assert_spare_erased(&store);
+            if n % 7 == 0 {
+                store = Store::new(store.into_inner()).unwrap();
+            }
+        }
+        assert_eq!(value(&store, 1), Some(1));
+        assert_eq!(value(&store, 2), Some(199));
+    }
+
+    #[test]
+    fn remove() {
+        let mut store = Store::new(Ram::new()).unwrap();
+        store.write(1, &1u32.to_le_bytes()).unwrap();
+        store.write(2, &2u32.to_le_bytes()).unwrap();
+        store.remove(1).unwrap();
+        assert_eq!(value(&store, 1), None);
+        assert!(!store.contains(1).unwrap());
+        // An empty value removes the key as well
+        store.write(2, &[]).unwrap();
+        assert_eq!(value(&store, 2), None);
+        // Removals survive garbage collection of the older records
+        for n in 0..100u32 {
+            store.write(3, &n.to_le_bytes()).unwrap();
+        }
+        let mut store = Store::new(store.into_inner()).unwrap();
+        assert_eq!(value(&store, 1), None);
+        assert_eq!(value(&store, 2), None);
+        assert_eq!(value(&store, 3), Some(99));
+        store.clear().unwrap();
+        assert_eq!(value(&store, 3), None);
+    }
+
+    /// A store with keys 1 to 3, where the next write of key 4 moves to a
+    /// new page
+    fn before_rotation() -> Ram {
+        let mut store = Store::new(Ram::new()).unwrap();
+        for key in 1..=3 {
+            store.write(key, &u32::from(key).to_le_bytes()).unwrap();
+        }
+        let mut n = 0u32;
+        while store.position + record_size(4) <= store.storage.page_size() {
+            store.write(4, &n.to_le_bytes()).unwrap();
+            n += 1;
+        }
+        store.into_inner()
+    }
+
+    #[test]
+    fn interrupted_collect() {
+        let mut completed = false;
+        let mut budget = 0;
+        while !completed {
+            let storage = PowerLoss {
+                storage: before_rotation(),
+                budget,
+            };
+            let mut store = Store::new(storage).unwrap();
+            let previous = value(&store, 4).unwrap();
+            completed = store.write(4, &1000u32.to_le_bytes()).is_ok();
+
+            let mut store = Store::new(store.into_inner().storage).unwrap();
+            for key in 1..=3 {
+                assert_eq!(value(&store, key), Some(u32::from(key)));
+            }
+            let current = value(&store, 4).unwrap();
+            if completed {
+                assert_eq!(current, 1000);
+            } else {
+                assert!(current == previous || current == 1000);
+            }
+            assert_spare_erased(&store);
+            store.write(5, &5u32.to_le_bytes()).unwrap();
+            assert_eq!(value(&store, 5), Some(5));
+            budget += 1;
+        }
+        // Interrupted in the middle of copying records
+        assert!(budget > 4);
+    }
+
+    #[test]
+    fn full() {
+        let mut store = Store::new(Ram::new()).unwrap();
+        let mut key = 0;
+        let error = loop {
+            match store.write(key, &u32::from(key).to_le_bytes()) {
+                Ok(()) => key += 1,
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(error, Error::Full);
+        assert!(key > 0);
+        assert_spare_erased(&store);
+        for stored in 0..key {
+            assert_eq!(value(&store, stored), Some(u32::from(stored)));
+        }
+        assert_eq!(value(&store, key), None);
+
+        let mut store = Store::new(store.into_inner()).unwrap();
+        for stored in 0..key {
+            assert_eq!(value(&store, stored), Some(u32::from(stored)));
+        }
+        // Unchanged values need no space
+        store.write(0, &0u32.to_le_bytes()).unwrap();
+        assert_eq!(store.write(0, &1u32.to_le_bytes()), Err(Error::Full));
+        assert_eq!(value(&store, 0), Some(0));
+        assert_spare_erased(&store);
+        store.clear().unwrap();
+        store.write(key, &u32::from(key).to_le_bytes()).unwrap();
+        assert_eq!(value(&store, key), Some(u32::from(key)));
+    }
+}
@@ -0,0 +1,41 @@
+//! Helpers shared by the nRF52840 experiments
+
+#![no_std]
+
+pub mod address;
+pub mod attribute;
+pub mod basic;
+pub mod battery;
+pub mod binding;
+pub mod button;
+pub mod ccm;
+pub mod cipher;
+pub mod crc;
+pub mod csma;
+pub mod discovery;
+#[cfg(feature = "52840")]
+pub mod ecb;
+pub mod groups;
+pub mod host;
+pub mod identify;
+pub mod install_code;
+pub mod kv;
+pub mod light;
+pub mod network;
+#[cfg(feature = "52840")]
+pub mod nvmc;
+pub mod outbox;
+pub mod poll;
+pub mod random;
+pub mod reporting;
+pub mod reset;
+pub mod router;
+pub mod scan;
+pub mod scenes;
+#[cfg(feature = "psila-service-api")]
+pub mod service;
+pub mod storage;
+pub mod strip;
+pub mod temperature;
+pub mod transition;
+pub mod vectors;
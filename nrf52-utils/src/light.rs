@@ -0,0 +1,360 @@
+//! Light state
+//!
+//! The state of a light together with the start-up attributes of the on/off,
+//! level control and colour control clusters. The start-up attributes decide
+//! the state of the light when it is powered on.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Key of the light state in the key/value store
+pub const LIGHT_STATE_KEY: u16 = 0x0002;
+
+/// Record format version
+const VERSION: u8 = 1;
+
+/// Size of a packed light state
+pub const LIGHT_STATE_SIZE: usize = 11;
+
+/// Start-up current level, use the previous level
+pub const START_UP_LEVEL_PREVIOUS: u8 = 0xff;
+/// Start-up current level, use the minimum level
+pub const START_UP_LEVEL_MINIMUM: u8 = 0x00;
+/// Start-up colour temperature, use the previous colour
+pub const START_UP_COLOR_TEMPERATURE_PREVIOUS: u16 = 0xffff;
+
+/// Smallest colour temperature handled, in mireds
+const COLOR_TEMPERATURE_MIN: u16 = 40;
+/// Largest colour temperature handled, in mireds
+const COLOR_TEMPERATURE_MAX: u16 = 600;
+
+/// On/off cluster StartUpOnOff attribute
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartUpOnOff {
+    /// Start off
+    Off = 0x00,
+    /// Start on
+    On = 0x01,
+    /// Start in the opposite of the previous state
+    Toggle = 0x02,
+    /// Start in the previous state
+    Previous = 0xff,
+}
+
+impl StartUpOnOff {
+    /// Convert from the attribute value
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(StartUpOnOff::Off),
+            0x01 => Some(StartUpOnOff::On),
+            0x02 => Some(StartUpOnOff::Toggle),
+            0xff => Some(StartUpOnOff::Previous),
+            _ => None,
+        }
+    }
+
+    /// On/off state at start-up given the previous state
+    pub fn resolve(self, previous: bool) -> bool {
+        match self {
+            StartUpOnOff::Off => false,
+            StartUpOnOff::On => true,
+            StartUpOnOff::Toggle => !previous,
+            StartUpOnOff::Previous => previous,
+        }
+    }
+}
+
+/// State of a light
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LightState {
+    /// On/off state
+    pub on_off: bool,
+    /// Current level
+    pub level: u8,
+    /// Current X
+    pub x: u16,
+    /// Current Y
+    pub y: u16,
+    /// On/off state at start-up
+    pub start_up_on_off: StartUpOnOff,
+    /// Level at start-up
+    pub start_up_level: u8,
+    /// Colour temperature at start-up, in mireds
+    pub start_up_color_temperature: u16,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        Self {
+            on_off: false,
+            level: 0,
+            x: 0,
+            y: 0,
+            start_up_on_off: StartUpOnOff::Previous,
+            start_up_level: START_UP_LEVEL_PREVIOUS,
+            start_up_color_temperature: START_UP_COLOR_TEMPERATURE_PREVIOUS,
+        }
+    }
+}
+
+impl LightState {
+    /// Pack the state into `data`, returns the number of bytes used
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        data[0] = VERSION;
+        data[1] = self.on_off as u8;
+        data[2] = self.level;
+        LittleEndian::write_u16(&mut data[3..5], self.x);
+        LittleEndian::write_u16(&mut data[5..7], self.y);
+        data[7] = self.start_up_on_off as u8;
+        data[8] = self.start_up_level;
+        LittleEndian::write_u16(&mut data[9..11], self.start_up_color_temperature);
+        LIGHT_STATE_SIZE
+    }
+
+    /// Unpack a state from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < LIGHT_STATE_SIZE || data[0] != VERSION {
+            return None;
+        }
+        Some(Self {
+            on_off: data[1] != 0,
+            level: data[2],
+            x: LittleEndian::read_u16(&data[3..5]),
+            y: LittleEndian::read_u16(&data[5..7]),
+            start_up_on_off: StartUpOnOff::from_u8(data[7])?,
+            start_up_level: data[8],
+            start_up_color_temperature: LittleEndian::read_u16(&data[9..11]),
+        })
+    }
+
+    /// The state to use when the light is powered on
+    ///
+    /// The start-up attributes are applied to the previous state.
+    pub fn start_up(&self) -> Self {
+        let mut state = *self;
+        state.on_off = self.start_up_on_off.resolve(self.on_off);
+        state.level = match self.start_up_level {
+            START_UP_LEVEL_PREVIOUS => self.level,
+            START_UP_LEVEL_MINIMUM => 1,
+            level => level,
+        };
+        if self.start_up_color_temperature != START_UP_COLOR_TEMPERATURE_PREVIOUS {
+            let (x, y) = color_temperature_to_xy(self.start_up_color_temperature);
+            state.x = x;
+            state.y = y;
+        }
+        state
+    }
+}
+
+/// Convert a colour temperature in mireds to CIE 1931 x, y
+///
+/// The x and y values are scaled as the CurrentX and CurrentY attributes,
+/// 0 to 65279 for 0.0 to 0.9961. Uses the cubic spline approximation of the
+/// Planckian locus by Kim et al.
+pub fn color_temperature_to_xy(mireds: u16) -> (u16, u16) {
+    let mireds = mireds.clamp(COLOR_TEMPERATURE_MIN, COLOR_TEMPERATURE_MAX);
+    let t = 1_000_000.0 / f32::from(mireds);
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let x = if t <= 4000.0 {
+        -0.266_124e9 / t3 - 0.234_359e6 / t2 + 0.877_696e3 / t + 0.179_910
+    } else {
+        -3.025_847e9 / t3 + 2.107_038e6 / t2 + 0.222_635e3 / t + 0.240_390
+    };
+    let x2 = x * x;
+    let x3 = x2 * x;
+    let y = if t <= 2222.0 {
+        -1.106_381 * x3 - 1.348_11 * x2 + 2.185_558 * x - 0.202_197
+    } else if t <= 4000.0 {
+        -0.954_948 * x3 - 1.374_186 * x2 + 2.091_37 * x - 0.167_489
+    } else {
+        3.081_758 * x3 - 5.873_387 * x2 + 3.751_13 * x - 0.370_015
+    };
+    ((x * 65536.0) as u16, (y * 65536.0) as u16)
+}
+
+/// Convert enhanced hue and saturation to CIE 1931 x, y
+///
+/// The hue is the EnhancedCurrentHue attribute, 0 to 65535 for a full turn,
+/// the saturation is the CurrentSaturation attribute, 0 to 254. The colour is
+/// taken at full value in the sRGB colour space, the gamma is approximated by
+/// squaring the components.
+pub fn hue_saturation_to_xy(hue: u16, saturation: u8) -> (u16, u16) {
+    let h = f32::from(hue) * 6.0 / 65536.0;
+    let s = f32::from(core::cmp::min(saturation, 254)) / 254.0;
+    let sector = h as u32;
+    let f = h - sector as f32;
+    let p = 1.0 - s;
+    let q = 1.0 - s * f;
+    let t = 1.0 - s * (1.0 - f);
+    let (r, g, b) = match sector {
+        0 => (1.0, t, p),
+        1 => (q, 1.0, p),
+        2 => (p, 1.0, t),
+        3 => (p, q, 1.0),
+        4 => (t, p, 1.0),
+        _ => (1.0, p, q),
+    };
+    let (r, g, b) = (r * r, g * g, b * b);
+    let cx = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+    let cy = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let cz = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+    let sum = cx + cy + cz;
+    let scale = |value: f32| core::cmp::min((value / sum * 65536.0) as u32, 0xfeff) as u16;
+    (scale(cx), scale(cy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check x, y against CIE 1931 chromaticity coordinates
+    fn assert_xy(actual: (u16, u16), expected: (f32, f32)) {
+        let x = f32::from(actual.0) / 65536.0;
+        let y = f32::from(actual.1) / 65536.0;
+        assert!(
+            (x - expected.0).abs() < 0.001 && (y - expected.1).abs() < 0.001,
+            "({}, {}) != {:?}",
+            x,
+            y,
+            expected
+        );
+    }
+
+    fn state() -> LightState {
+        LightState {
+            on_off: true,
+            level: 0x80,
+            x: 0x1234,
+            y: 0x5678,
+            ..LightState::default()
+        }
+    }
+
+    #[test]
+    fn start_up_on_off() {
+        assert_eq!(StartUpOnOff::from_u8(0x00), Some(StartUpOnOff::Off));
+        assert_eq!(StartUpOnOff::from_u8(0x01), Some(StartUpOnOff::On));
+        assert_eq!(StartUpOnOff::from_u8(0x02), Some(StartUpOnOff::Toggle));
+        assert_eq!(StartUpOnOff::from_u8(0xff), Some(StartUpOnOff::Previous));
+        assert_eq!(StartUpOnOff::from_u8(0x03), None);
+        assert_eq!(StartUpOnOff::from_u8(0xfe), None);
+        for &previous in &[false, true] {
+            assert!(!StartUpOnOff::Off.resolve(previous));
+            assert!(StartUpOnOff::On.resolve(previous));
+            assert_eq!(StartUpOnOff::Toggle.resolve(previous), !previous);
+            assert_eq!(StartUpOnOff::Previous.resolve(previous), previous);
+        }
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let state = LightState {
+            start_up_on_off: StartUpOnOff::Toggle,
+            start_up_level: 0x40,
+            start_up_color_temperature: 370,
+            ..state()
+        };
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        assert_eq!(state.pack(&mut data), LIGHT_STATE_SIZE);
+        assert_eq!(
+            data,
+            [0x01, 0x01, 0x80, 0x34, 0x12, 0x78, 0x56, 0x02, 0x40, 0x72, 0x01]
+        );
+        assert_eq!(LightState::unpack(&data), Some(state));
+        assert_eq!(LightState::unpack(&data[..LIGHT_STATE_SIZE - 1]), None);
+        // Unknown version
+        let mut other = data;
+        other[0] = 2;
+        assert_eq!(LightState::unpack(&other), None);
+        // Invalid StartUpOnOff
+        let mut other = data;
+        other[7] = 0x03;
+        assert_eq!(LightState::unpack(&other), None);
+    }
+
+    #[test]
+    fn start_up_previous() {
+        // The defaults keep the previous state
+        assert_eq!(state().start_up(), state());
+        let off = LightState {
+            on_off: false,
+            ..state()
+        };
+        assert_eq!(off.start_up(), off);
+    }
+
+    #[test]
+    fn start_up_on_off_rules() {
+        let cases = [
+            (StartUpOnOff::Off, false, false),
+            (StartUpOnOff::Off, true, false),
+            (StartUpOnOff::On, false, true),
+            (StartUpOnOff::On, true, true),
+            (StartUpOnOff::Toggle, false, true),
+            (StartUpOnOff::Toggle, true, false),
+            (StartUpOnOff::Previous, false, false),
+            (StartUpOnOff::Previous, true, true),
+        ];
+        for &(start_up_on_off, previous, expected) in &cases {
+            let state = LightState {
+                on_off: previous,
+                start_up_on_off,
+                ..state()
+            };
+            assert_eq!(
+                state.start_up().on_off,
+                expected,
+                "{:?} {}",
+                start_up_on_off,
+                previous
+            );
+        }
+    }
+
+    #[test]
+    fn start_up_level() {
+        let level = |start_up_level| {
+            LightState {
+                start_up_level,
+                ..state()
+            }
+            .start_up()
+            .level
+        };
+        assert_eq!(level(START_UP_LEVEL_PREVIOUS), 0x80);
+        assert_eq!(level(START_UP_LEVEL_MINIMUM), 1);
+        assert_eq!(level(0x20), 0x20);
+    }
+
+    #[test]
+    fn start_up_color_temperature() {
+        let state = LightState {
+            start_up_color_temperature: 153,
+            ..state()
+        };
+        let started = state.start_up();
+        assert_eq!((started.x, started.y), color_temperature_to_xy(153));
+        // The start-up attributes are kept
+        assert_eq!(started.start_up_color_temperature, 153);
+    }
+
+    #[test]
+    fn color_temperature() {
+        // 6536 K and 2000 K on the Planckian locus
+        assert_xy(color_temperature_to_xy(153), (0.3129, 0.3231));
+        assert_xy(color_temperature_to_xy(500), (0.5269, 0.4133));
+        // Out of range values are clamped
+        assert_eq!(color_temperature_to_xy(0), color_temperature_to_xy(40));
+        assert_eq!(color_temperature_to_xy(1000), color_temperature_to_xy(600));
+    }
+
+    #[test]
+    fn hue_saturation() {
+        // sRGB red primary
+        assert_xy(hue_saturation_to_xy(0, 254), (0.64, 0.33));
+        assert_eq!(hue_saturation_to_xy(0, 255), hue_saturation_to_xy(0, 254));
+        // No saturation is the sRGB white point
+        assert_xy(hue_saturation_to_xy(0x4000, 0), (0.3127, 0.3290));
+    }
+}
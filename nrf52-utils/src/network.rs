@@ -0,0 +1,325 @@
+//! Network association state
+//!
+//! The state that is needed to rejoin a network without a new association.
+//! The state is packed into a record protected by a CRC-32 so that a
+//! partially written or erased record is never restored.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    crc::crc32,
+    kv::{Error, Store},
+    storage::Storage,
+};
+
+/// Key of the network state in the key/value store
+pub const NETWORK_STATE_KEY: u16 = 0x0001;
+
+/// Record magic, "PSNS"
+const MAGIC: u32 = 0x534e_5350;
+/// Record format version
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+const PAYLOAD_SIZE: usize = 48;
+const CRC_SIZE: usize = 4;
+
+/// Size of a packed network state record
+pub const NETWORK_STATE_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + CRC_SIZE;
+
+/// Frame counters are stored with this margin added
+///
+/// Storing the counters for every frame would wear out the flash. Instead the
+/// stored counters are ahead of the used ones, and a new record is stored when
+/// the used counter catch up. After a restart, the restored counters are always
+/// larger than any counter used before the restart.
+pub const FRAME_COUNTER_MARGIN: u32 = 1024;
+
+/// Network association state
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkState {
+    /// IEEE 802.15.4 channel
+    pub channel: u8,
+    /// PAN identifier
+    pub pan_identifier: u16,
+    /// Extended PAN identifier
+    pub extended_pan_identifier: u64,
+    /// Short address of this device
+    pub short_address: u16,
+    /// Short address of the parent
+    pub parent_short_address: u16,
+    /// Extended address of the parent
+    pub parent_extended_address: u64,
+    /// Network key
+    pub network_key: [u8; 16],
+    /// Network key sequence number
+    pub network_key_sequence: u8,
+    /// Outgoing network frame counter
+    pub network_frame_counter: u32,
+    /// Outgoing APS frame counter
+    pub application_frame_counter: u32,
+}
+
+impl NetworkState {
+    /// Pack the state into `data`, returns the number of bytes used
+    ///
+    /// `data` must be at least `NETWORK_STATE_SIZE` bytes.
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        LittleEndian::write_u32(&mut data[0..4], MAGIC);
+        LittleEndian::write_u16(&mut data[4..6], PAYLOAD_SIZE as u16);
+        data[6] = VERSION;
+        data[7] = 0xff;
+        let payload = &mut data[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE];
+        payload[0] = self.channel;
+        LittleEndian::write_u16(&mut payload[1..3], self.pan_identifier);
+        LittleEndian::write_u64(&mut payload[3..11], self.extended_pan_identifier);
+        LittleEndian::write_u16(&mut payload[11..13], self.short_address);
+        LittleEndian::write_u16(&mut payload[13..15], self.parent_short_address);
+        LittleEndian::write_u64(&mut payload[15..23], self.parent_extended_address);
+        payload[23..39].copy_from_slice(&self.network_key);
+        payload[39] = self.network_key_sequence;
+        LittleEndian::write_u32(&mut payload[40..44], self.network_frame_counter);
+        LittleEndian::write_u32(&mut payload[44..48], self.application_frame_counter);
+        let crc = crc32(&data[..HEADER_SIZE + PAYLOAD_SIZE]);
+        LittleEndian::write_u32(
+            &mut data[HEADER_SIZE + PAYLOAD_SIZE..NETWORK_STATE_SIZE],
+            crc,
+        );
+        NETWORK_STATE_SIZE
+    }
+
+    /// Unpack a state from `data`
+    ///
+    /// Returns `None` if `data` does not hold a valid record.
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < NETWORK_STATE_SIZE
+            || LittleEndian::read_u32(&data[0..4]) != MAGIC
+            || LittleEndian::read_u16(&data[4..6]) as usize != PAYLOAD_SIZE
+            || data[6] != VERSION
+        {
+            return None;
+        }
+        let crc = LittleEndian::read_u32(&data[HEADER_SIZE + PAYLOAD_SIZE..NETWORK_STATE_SIZE]);
+        if crc != crc32(&data[..HEADER_SIZE + PAYLOAD_SIZE]) {
+            return None;
+        }
+        let payload = &data[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE];
+        let mut network_key = [0u8; 16];
+        network_key.copy_from_slice(&payload[23..39]);
+        Some(Self {
+            channel: payload[0],
+            pan_identifier: LittleEndian::read_u16(&payload[1..3]),
+            extended_pan_identifier: LittleEndian::read_u64(&payload[3..11]),
+            short_address: LittleEndian::read_u16(&payload[11..13]),
+            parent_short_address: LittleEndian::read_u16(&payload[13..15]),
+            parent_extended_address: LittleEndian::read_u64(&payload[15..23]),
+            network_key,
+            network_key_sequence: payload[39],
+            network_frame_counter: LittleEndian::read_u32(&payload[40..44]),
+            application_frame_counter: LittleEndian::read_u32(&payload[44..48]),
+        })
+    }
+
+    /// Returns a copy of the state with the frame counters moved ahead by
+    /// `FRAME_COUNTER_MARGIN`, this is the state that should be stored
+    pub fn with_margin(&self) -> Self {
+        let mut state = *self;
+        state.network_frame_counter = state
+            .network_frame_counter
+            .saturating_add(FRAME_COUNTER_MARGIN);
+        state.application_frame_counter = state
+            .application_frame_counter
+            .saturating_add(FRAME_COUNTER_MARGIN);
+        state
+    }
+
+    /// Check if the state must be stored again, given the stored state
+    ///
+    /// This is the case when any of the association parameters changed or
+    /// when a frame counter passed the stored counter. The counters are the
+    /// next ones to use, so a state restored from `stored` is not stored
+    /// again until a frame is sent with the stored counter.
+    pub fn needs_store(&self, stored: &NetworkState) -> bool {
+        self.channel != stored.channel
+            || self.pan_identifier != stored.pan_identifier
+            || self.extended_pan_identifier != stored.extended_pan_identifier
+            || self.short_address != stored.short_address
+            || self.parent_short_address != stored.parent_short_address
+            || self.parent_extended_address != stored.parent_extended_address
+            || self.network_key != stored.network_key
+            || self.network_key_sequence != stored.network_key_sequence
+            || self.network_frame_counter > stored.network_frame_counter
+            || self.application_frame_counter > stored.application_frame_counter
+    }
+
+    /// Read the stored state, if any
+    pub fn load<S: Storage>(store: &Store<S>) -> Option<Self> {
+        let mut data = [0u8; NETWORK_STATE_SIZE];
+        match store.read(NETWORK_STATE_KEY, &mut data) {
+            Ok(Some(length)) => Self::unpack(&data[..length]),
+            _ => None,
+        }
+    }
+
+    /// Write the state to the store
+    pub fn store<S: Storage>(&self, store: &mut Store<S>) -> Result<(), Error> {
+        let mut data = [0u8; NETWORK_STATE_SIZE];
+        let length = self.pack(&mut data);
+        store.write(NETWORK_STATE_KEY, &data[..length])
+    }
+
+    /// Compare the current state with the stored state
+    ///
+    /// Returns the state to store, with margin, if there is no stored state
+    /// or if the stored state `needs_store`. `stored` is then updated to the
+    /// returned state.
+    pub fn track(stored: &mut Option<Self>, state: Self) -> Option<Self> {
+        let store = match stored {
+            Some(stored) => state.needs_store(stored),
+            None => true,
+        };
+        if store {
+            let state = state.with_margin();
+            *stored = Some(state);
+            Some(state)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RamStorage;
+
+    fn state() -> NetworkState {
+        NetworkState {
+            channel: 15,
+            pan_identifier: 0x1234,
+            extended_pan_identifier: 0x0011_2233_4455_6677,
+            short_address: 0x8765,
+            parent_short_address: 0x0000,
+            parent_extended_address: 0x8899_aabb_ccdd_eeff,
+            network_key: [0x5a; 16],
+            network_key_sequence: 3,
+            network_frame_counter: 1000,
+            application_frame_counter: 20,
+        }
+    }
+
+    #[test]
+    fn pack_unpack() {
+        let mut data = [0u8; NETWORK_STATE_SIZE];
+        assert_eq!(state().pack(&mut data), NETWORK_STATE_SIZE);
+        assert_eq!(NetworkState::unpack(&data), Some(state()));
+        assert_eq!(NetworkState::unpack(&data[..NETWORK_STATE_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut data = [0u8; NETWORK_STATE_SIZE];
+        state().pack(&mut data);
+        // Every changed byte is caught, by the header checks or the CRC
+        for n in 0..NETWORK_STATE_SIZE {
+            let mut corrupted = data;
+            corrupted[n] ^= 0x10;
+            assert_eq!(NetworkState::unpack(&corrupted), None, "byte {}", n);
+        }
+        // An erased record
+        assert_eq!(NetworkState::unpack(&[0xff; NETWORK_STATE_SIZE]), None);
+    }
+
+    #[test]
+    fn margin() {
+        let stored = state().with_margin();
+        assert_eq!(stored.network_frame_counter, 1000 + FRAME_COUNTER_MARGIN);
+        assert_eq!(stored.application_frame_counter, 20 + FRAME_COUNTER_MARGIN);
+        assert_eq!(
+            NetworkState {
+                network_frame_counter: 1000,
+                application_frame_counter: 20,
+                ..stored
+            },
+            state()
+        );
+        let last = NetworkState {
+            network_frame_counter: u32::MAX - 1,
+            ..state()
+        };
+        assert_eq!(last.with_margin().network_frame_counter, u32::MAX);
+    }
+
+    #[test]
+    fn needs_store() {
+        let stored = state().with_margin();
+        // Restored from the stored record, nothing is used yet
+        assert!(!stored.needs_store(&stored));
+        assert!(!state().needs_store(&stored));
+        // The stored counter was used
+        let network = NetworkState {
+            network_frame_counter: stored.network_frame_counter + 1,
+            ..stored
+        };
+        assert!(network.needs_store(&stored));
+        let application = NetworkState {
+            application_frame_counter: stored.application_frame_counter + 1,
+            ..stored
+        };
+        assert!(application.needs_store(&stored));
+        // Changed association
+        let moved = NetworkState {
+            short_address: 0x4321,
+            ..state()
+        };
+        assert!(moved.needs_store(&stored));
+        let rekeyed = NetworkState {
+            network_key_sequence: 4,
+            ..state()
+        };
+        assert!(rekeyed.needs_store(&stored));
+    }
+
+    #[test]
+    fn track() {
+        let mut stored = None;
+        // Nothing stored yet
+        assert_eq!(
+            NetworkState::track(&mut stored, state()),
+            Some(state().with_margin())
+        );
+        assert_eq!(stored, Some(state().with_margin()));
+        // Frames sent, but the counters are still below the stored ones
+        let used = NetworkState {
+            network_frame_counter: 1100,
+            ..state()
+        };
+        assert_eq!(NetworkState::track(&mut stored, used), None);
+        assert_eq!(stored, Some(state().with_margin()));
+        // The stored counter was used
+        let passed = NetworkState {
+            network_frame_counter: 1001 + FRAME_COUNTER_MARGIN,
+            ..state()
+        };
+        assert_eq!(
+            NetworkState::track(&mut stored, passed),
+            Some(passed.with_margin())
+        );
+        assert_eq!(stored, Some(passed.with_margin()));
+    }
+
+    #[test]
+    fn load_store() {
+        let mut store = Store::new(RamStorage::<128, 3>::new()).unwrap();
+        assert_eq!(NetworkState::load(&store), None);
+        state().store(&mut store).unwrap();
+        assert_eq!(NetworkState::load(&store), Some(state()));
+        let moved = NetworkState {
+            channel: 20,
+            ..state()
+        };
+        moved.store(&mut store).unwrap();
+        assert_eq!(NetworkState::load(&store), Some(moved));
+        store.remove(NETWORK_STATE_KEY).unwrap();
+        assert_eq!(NetworkState::load(&store), None);
+    }
+}
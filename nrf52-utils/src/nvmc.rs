@@ -0,0 +1,128 @@
+//! Non-volatile memory controller
+//!
+//! Erase, write and read of the internal flash memory.
+
+use core::ptr;
+
+use nrf52840_pac::NVMC;
+
+use crate::storage::{Error, Storage, WORD_SIZE};
+
+/// Size of a flash page in bytes
+pub const PAGE_SIZE: usize = 4096;
+
+/// Flash memory access through the NVMC peripheral
+pub struct Nvmc {
+    nvmc: NVMC,
+}
+
+impl Nvmc {
+    pub fn new(nvmc: NVMC) -> Self {
+        Self { nvmc }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+
+    /// Erase the page starting at `address`
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        if !(address as usize).is_multiple_of(PAGE_SIZE) {
+            return Err(Error::Unaligned);
+        }
+        self.nvmc.config.write(|w| w.wen().een());
+        self.wait_ready();
+        self.nvmc
+            .erasepage()
+            .write(|w| unsafe { w.erasepage().bits(address) });
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+
+    /// Write `data` to `address`
+    ///
+    /// The address must be word aligned. A trailing partial word is padded with
+    /// `0xff`, which leaves those bytes in the erased state.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        if !(address as usize).is_multiple_of(WORD_SIZE) {
+            return Err(Error::Unaligned);
+        }
+        self.nvmc.config.write(|w| w.wen().wen());
+        for (n, chunk) in data.chunks(WORD_SIZE).enumerate() {
+            let mut word = [0xffu8; WORD_SIZE];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let target = (address as usize + n * WORD_SIZE) as *mut u32;
+            unsafe {
+                ptr::write_volatile(target, u32::from_le_bytes(word));
+            }
+            self.wait_ready();
+        }
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+
+    /// Read from `address` into `data`
+    pub fn read(&self, address: u32, data: &mut [u8]) {
+        for (n, byte) in data.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((address as usize + n) as *const u8) };
+        }
+    }
+}
+
+/// Storage in a range of flash pages
+pub struct NvmcStorage {
+    nvmc: Nvmc,
+    address: u32,
+    pages: usize,
+}
+
+impl NvmcStorage {
+    /// Use `pages` flash pages starting at `address` as storage
+    ///
+    /// The range must be reserved, not used by the program.
+    pub fn new(nvmc: Nvmc, address: u32, pages: usize) -> Self {
+        Self {
+            nvmc,
+            address,
+            pages,
+        }
+    }
+
+    fn check(&self, offset: usize, length: usize) -> Result<u32, Error> {
+        if offset + length > PAGE_SIZE * self.pages {
+            Err(Error::OutOfBounds)
+        } else {
+            Ok(self.address + offset as u32)
+        }
+    }
+}
+
+impl Storage for NvmcStorage {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        self.pages
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<(), Error> {
+        let address = self.check(offset, data.len())?;
+        self.nvmc.read(address, data);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let address = self.check(offset, data.len())?;
+        self.nvmc.write(address, data)
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), Error> {
+        if page >= self.pages {
+            return Err(Error::OutOfBounds);
+        }
+        let address = self.address + (page * PAGE_SIZE) as u32;
+        self.nvmc.erase_page(address)
+    }
+}
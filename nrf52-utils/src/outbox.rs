@@ -0,0 +1,164 @@
+//! Outgoing cluster library commands
+//!
+//! Cluster handlers queue commands, such as responses and reports, in an
+//! outbox. The application takes the commands from the outbox and sends them
+//! through the network service.
+
+/// Largest command payload
+pub const MAX_PAYLOAD_SIZE: usize = 64;
+
+/// Recipient of a command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recipient {
+    /// The sender of the request that is being handled
+    Reply,
+    /// Endpoint of a device with a short address
+    Device { address: u16, endpoint: u8 },
+    /// A group of devices
+    Group(u16),
+    /// The destinations bound to the cluster of the source endpoint, the
+    /// coordinator if there are none
+    Bound,
+}
+
+/// Outgoing command
+#[derive(Clone, Copy, Debug)]
+pub struct Message {
+    /// Recipient of the command
+    pub recipient: Recipient,
+    /// Source endpoint
+    pub endpoint: u8,
+    /// Profile identifier
+    pub profile: u16,
+    /// Cluster identifier
+    pub cluster: u16,
+    /// A profile wide command instead of a cluster specific command
+    pub general: bool,
+    /// Sent from the server side of the cluster
+    pub from_server: bool,
+    /// Command identifier
+    pub command: u8,
+    payload: [u8; MAX_PAYLOAD_SIZE],
+    length: usize,
+}
+
+impl Message {
+    /// A cluster specific command from the server side of the cluster
+    pub fn new(
+        recipient: Recipient,
+        endpoint: u8,
+        profile: u16,
+        cluster: u16,
+        command: u8,
+    ) -> Self {
+        Self {
+            recipient,
+            endpoint,
+            profile,
+            cluster,
+            general: false,
+            from_server: true,
+            command,
+            payload: [0; MAX_PAYLOAD_SIZE],
+            length: 0,
+        }
+    }
+
+    /// A profile wide command from the server side of the cluster
+    pub fn general(
+        recipient: Recipient,
+        endpoint: u8,
+        profile: u16,
+        cluster: u16,
+        command: u8,
+    ) -> Self {
+        Self {
+            general: true,
+            ..Self::new(recipient, endpoint, profile, cluster, command)
+        }
+    }
+
+    /// A cluster specific command from the client side of the cluster
+    pub fn client(
+        recipient: Recipient,
+        endpoint: u8,
+        profile: u16,
+        cluster: u16,
+        command: u8,
+    ) -> Self {
+        Self {
+            from_server: false,
+            ..Self::new(recipient, endpoint, profile, cluster, command)
+        }
+    }
+
+    /// The command payload
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.length]
+    }
+
+    /// Append data to the payload, returns false if it does not fit
+    pub fn append(&mut self, data: &[u8]) -> bool {
+        let end = self.length + data.len();
+        if end > MAX_PAYLOAD_SIZE {
+            return false;
+        }
+        self.payload[self.length..end].copy_from_slice(data);
+        self.length = end;
+        true
+    }
+
+    /// Append a byte to the payload
+    pub fn append_u8(&mut self, value: u8) -> bool {
+        self.append(&[value])
+    }
+
+    /// Append a little endian 16-bit value to the payload
+    pub fn append_u16(&mut self, value: u16) -> bool {
+        self.append(&value.to_le_bytes())
+    }
+}
+
+/// Queue of outgoing commands
+pub struct Outbox<const N: usize> {
+    messages: [Option<Message>; N],
+    head: usize,
+    count: usize,
+}
+
+impl<const N: usize> Default for Outbox<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Outbox<N> {
+    pub fn new() -> Self {
+        Self {
+            messages: [None; N],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    /// Queue a command, returns false if the outbox is full
+    pub fn push(&mut self, message: Message) -> bool {
+        if self.count == N {
+            return false;
+        }
+        self.messages[(self.head + self.count) % N] = Some(message);
+        self.count += 1;
+        true
+    }
+
+    /// Take the oldest command
+    pub fn pop(&mut self) -> Option<Message> {
+        if self.count == 0 {
+            return None;
+        }
+        let message = self.messages[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.count -= 1;
+        message
+    }
+}
@@ -0,0 +1,55 @@
+//! Parent polling
+//!
+//! A sleepy end device turns the receiver off between polls of its parent.
+//! Normally the parent is polled at a long interval, after sending a request
+//! or receiving data the parent is polled at a short interval for a while so
+//! that responses are picked up quickly.
+
+/// Poll interval control of a sleepy end device
+#[derive(Clone, Copy, Debug)]
+pub struct PollControl {
+    long_interval: u32,
+    short_interval: u32,
+    fast_polls: u8,
+    remaining: u8,
+}
+
+impl PollControl {
+    /// Poll every `long_interval`, or `fast_polls` times every
+    /// `short_interval` when fast polling
+    ///
+    /// The unit of the intervals is up to the caller.
+    pub const fn new(long_interval: u32, short_interval: u32, fast_polls: u8) -> Self {
+        Self {
+            long_interval,
+            short_interval,
+            fast_polls,
+            remaining: 0,
+        }
+    }
+
+    /// Poll at the short interval for the next polls
+    pub fn fast_poll(&mut self) {
+        self.remaining = self.fast_polls;
+    }
+
+    /// Check if the parent is polled at the short interval
+    pub fn is_fast_polling(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Time until the next poll
+    pub fn next_interval(&mut self) -> u32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.short_interval
+        } else {
+            self.long_interval
+        }
+    }
+
+    /// Change the long poll interval
+    pub fn set_long_interval(&mut self, interval: u32) {
+        self.long_interval = interval;
+    }
+}
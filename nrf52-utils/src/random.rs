@@ -0,0 +1,44 @@
+//! Pseudo random numbers
+//!
+//! A small xorshift generator, seeded from the RNG peripheral, for the values
+//! that only have to differ between devices and resets, such as initial
+//! sequence numbers and backoff jitter.
+
+/// Used in place of a zero seed, which would only ever produce zeroes
+const DEFAULT_SEED: u32 = 0x2545_f491;
+
+/// Xorshift32 pseudo random number generator
+#[derive(Clone, Copy, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u32() >> 24) as u8
+    }
+
+    /// A number in the range `0..bound`, zero if `bound` is zero
+    pub fn below(&mut self, bound: u32) -> u32 {
+        if bound == 0 {
+            return 0;
+        }
+        // Multiply and shift, the bias is negligible for small bounds
+        ((u64::from(self.next_u32()) * u64::from(bound)) >> 32) as u32
+    }
+}
@@ -0,0 +1,417 @@
+//! Attribute reporting
+//!
+//! Keeps the reporting configuration of the reportable attributes and
+//! decides when to send Report Attributes. The handler sets the current
+//! attribute values and calls `tick` once per second. A report is sent when
+//! a value has changed by at least the reportable change and the minimum
+//! interval has passed, or when the maximum interval has passed.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::cluster_library::ClusterLibraryStatus;
+
+use crate::outbox::{Message, Outbox, Recipient};
+
+/// General command, configure reporting
+pub const CMD_CONFIGURE_REPORTING: u8 = 0x06;
+/// General command, configure reporting response
+const CMD_CONFIGURE_REPORTING_RESPONSE: u8 = 0x07;
+/// General command, read reporting configuration
+pub const CMD_READ_REPORTING_CONFIGURATION: u8 = 0x08;
+/// General command, read reporting configuration response
+const CMD_READ_REPORTING_CONFIGURATION_RESPONSE: u8 = 0x09;
+/// General command, report attributes
+const CMD_REPORT_ATTRIBUTES: u8 = 0x0a;
+
+/// Attribute data type, boolean
+pub const TYPE_BOOLEAN: u8 = 0x10;
+/// Attribute data type, unsigned 8-bit integer
+pub const TYPE_UNSIGNED8: u8 = 0x20;
+/// Attribute data type, unsigned 16-bit integer
+pub const TYPE_UNSIGNED16: u8 = 0x21;
+/// Attribute data type, signed 16-bit integer
+pub const TYPE_SIGNED16: u8 = 0x29;
+
+/// Reporting direction, attribute is reported
+const DIRECTION_REPORTED: u8 = 0x00;
+/// Reporting direction, reports of the attribute are received
+const DIRECTION_RECEIVED: u8 = 0x01;
+
+/// Maximum interval, the attribute is not reported
+const MAX_INTERVAL_DISABLED: u16 = 0xffff;
+
+/// Status, success
+const STATUS_SUCCESS: u8 = 0x00;
+/// Status, unsupported attribute
+const STATUS_UNSUPPORTED_ATTRIBUTE: u8 = 0x86;
+/// Status, invalid value
+const STATUS_INVALID_VALUE: u8 = 0x87;
+/// Status, unreportable attribute
+const STATUS_UNREPORTABLE_ATTRIBUTE: u8 = 0x8c;
+/// Status, invalid data type
+const STATUS_INVALID_DATA_TYPE: u8 = 0x8d;
+
+/// Size of a value of the data type
+fn value_size(data_type: u8) -> usize {
+    match data_type {
+        TYPE_UNSIGNED16 | TYPE_SIGNED16 => 2,
+        _ => 1,
+    }
+}
+
+/// Difference between two values of the data type
+///
+/// Values of signed types are kept sign extended from their size.
+fn difference(data_type: u8, a: u32, b: u32) -> u32 {
+    match data_type {
+        0x28..=0x2f => {
+            let shift = 32 - 8 * value_size(data_type) as u32;
+            let a = ((a << shift) as i32) >> shift;
+            let b = ((b << shift) as i32) >> shift;
+            (i64::from(a) - i64::from(b)).unsigned_abs() as u32
+        }
+        _ => a.abs_diff(b),
+    }
+}
+
+/// Check if the data type is analog and has a reportable change
+fn is_analog(data_type: u8) -> bool {
+    matches!(data_type, 0x20..=0x2f | 0x38..=0x3a | 0xe0..=0xe2)
+}
+
+/// Size of the reportable change field of the data type
+fn change_size(data_type: u8) -> usize {
+    if !is_analog(data_type) {
+        0
+    } else if data_type <= 0x2f {
+        usize::from(data_type & 0x07) + 1
+    } else {
+        match data_type {
+            0x38 => 2,
+            0x39 | 0xe0..=0xe2 => 4,
+            0x3a => 8,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    endpoint: u8,
+    cluster: u16,
+    attribute: u16,
+    data_type: u8,
+    min_interval: u16,
+    max_interval: u16,
+    reportable_change: u32,
+    value: u32,
+    reported: Option<u32>,
+    elapsed: u32,
+}
+
+impl Entry {
+    /// Check if the attribute should be reported now
+    fn is_due(&self) -> bool {
+        if self.max_interval == MAX_INTERVAL_DISABLED {
+            return false;
+        }
+        let reported = match self.reported {
+            Some(reported) => reported,
+            None => return true,
+        };
+        let change = if is_analog(self.data_type) {
+            core::cmp::max(self.reportable_change, 1)
+        } else {
+            1
+        };
+        let changed = difference(self.data_type, self.value, reported) >= change;
+        (changed && self.elapsed >= u32::from(self.min_interval))
+            || (self.max_interval != 0 && self.elapsed >= u32::from(self.max_interval))
+    }
+}
+
+/// Reporting configuration of the reportable attributes
+pub struct ReportingTable<const N: usize> {
+    entries: [Option<Entry>; N],
+}
+
+impl<const N: usize> Default for ReportingTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportingTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Make an attribute reportable with a default configuration
+    ///
+    /// Returns false if the table is full.
+    pub fn add(
+        &mut self,
+        endpoint: u8,
+        cluster: u16,
+        attribute: u16,
+        data_type: u8,
+        min_interval: u16,
+        max_interval: u16,
+    ) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(Entry {
+                    endpoint,
+                    cluster,
+                    attribute,
+                    data_type,
+                    min_interval,
+                    max_interval,
+                    reportable_change: 1,
+                    value: 0,
+                    reported: None,
+                    elapsed: 0,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn find_mut(&mut self, endpoint: u8, cluster: u16, attribute: u16) -> Option<&mut Entry> {
+        self.entries.iter_mut().flatten().find(|entry| {
+            entry.endpoint == endpoint && entry.cluster == cluster && entry.attribute == attribute
+        })
+    }
+
+    /// Set the current value of an attribute
+    pub fn set_value(&mut self, endpoint: u8, cluster: u16, attribute: u16, value: u32) {
+        if let Some(entry) = self.find_mut(endpoint, cluster, attribute) {
+            entry.value = value;
+        }
+    }
+
+    /// Advance one second and queue the reports that are due
+    ///
+    /// Attributes of the same cluster that are due at the same time are
+    /// reported in one command.
+    pub fn tick<const M: usize>(
+        &mut self,
+        recipient: Recipient,
+        profile: u16,
+        outbox: &mut Outbox<M>,
+    ) {
+        let mut pending: Option<Message> = None;
+        for entry in self.entries.iter_mut().flatten() {
+            entry.elapsed = entry.elapsed.saturating_add(1);
+            if !entry.is_due() {
+                continue;
+            }
+            let same_cluster = pending.as_ref().is_some_and(|message| {
+                message.endpoint == entry.endpoint && message.cluster == entry.cluster
+            });
+            if !same_cluster {
+                if let Some(message) = pending.take() {
+                    outbox.push(message);
+                }
+                pending = Some(Message::general(
+                    recipient,
+                    entry.endpoint,
+                    profile,
+                    entry.cluster,
+                    CMD_REPORT_ATTRIBUTES,
+                ));
+            }
+            if let Some(message) = pending.as_mut() {
+                message.append_u16(entry.attribute);
+                message.append_u8(entry.data_type);
+                let mut value = [0u8; 4];
+                LittleEndian::write_u32(&mut value, entry.value);
+                message.append(&value[..value_size(entry.data_type)]);
+            }
+            entry.reported = Some(entry.value);
+            entry.elapsed = 0;
+        }
+        if let Some(message) = pending {
+            outbox.push(message);
+        }
+    }
+
+    /// Handle Configure Reporting and Read Reporting Configuration
+    ///
+    /// Returns the response to send.
+    pub fn handle_command(
+        &mut self,
+        endpoint: u8,
+        profile: u16,
+        cluster: u16,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<Message, ClusterLibraryStatus> {
+        match command {
+            CMD_CONFIGURE_REPORTING => {
+                let mut message = Message::general(
+                    Recipient::Reply,
+                    endpoint,
+                    profile,
+                    cluster,
+                    CMD_CONFIGURE_REPORTING_RESPONSE,
+                );
+                let mut data = arguments;
+                while !data.is_empty() {
+                    if data.len() < 3 {
+                        return Err(ClusterLibraryStatus::MalformedCommand);
+                    }
+                    let direction = data[0];
+                    let attribute = LittleEndian::read_u16(&data[1..3]);
+                    let status = match direction {
+                        DIRECTION_REPORTED => {
+                            if data.len() < 8 {
+                                return Err(ClusterLibraryStatus::MalformedCommand);
+                            }
+                            let data_type = data[3];
+                            let min_interval = LittleEndian::read_u16(&data[4..6]);
+                            let max_interval = LittleEndian::read_u16(&data[6..8]);
+                            let size = change_size(data_type);
+                            if data.len() < 8 + size {
+                                return Err(ClusterLibraryStatus::MalformedCommand);
+                            }
+                            let mut change = [0u8; 8];
+                            change[..size].copy_from_slice(&data[8..8 + size]);
+                            let reportable_change = LittleEndian::read_u32(&change[..4]);
+                            data = &data[8 + size..];
+                            match self.find_mut(endpoint, cluster, attribute) {
+                                None => STATUS_UNREPORTABLE_ATTRIBUTE,
+                                Some(entry) if entry.data_type != data_type => {
+                                    STATUS_INVALID_DATA_TYPE
+                                }
+                                Some(_)
+                                    if max_interval != 0
+                                        && max_interval != MAX_INTERVAL_DISABLED
+                                        && min_interval > max_interval =>
+                                {
+                                    STATUS_INVALID_VALUE
+                                }
+                                Some(entry) => {
+                                    entry.min_interval = min_interval;
+                                    entry.max_interval = max_interval;
+                                    entry.reportable_change = reportable_change;
+                                    STATUS_SUCCESS
+                                }
+                            }
+                        }
+                        DIRECTION_RECEIVED => {
+                            if data.len() < 5 {
+                                return Err(ClusterLibraryStatus::MalformedCommand);
+                            }
+                            data = &data[5..];
+                            // Reports from other devices are not handled
+                            STATUS_UNSUPPORTED_ATTRIBUTE
+                        }
+                        _ => return Err(ClusterLibraryStatus::MalformedCommand),
+                    };
+                    // Only failures are listed
+                    if status != STATUS_SUCCESS {
+                        message.append_u8(status);
+                        message.append_u8(direction);
+                        message.append_u16(attribute);
+                    }
+                }
+                if message.payload().is_empty() {
+                    message.append_u8(STATUS_SUCCESS);
+                }
+                Ok(message)
+            }
+            CMD_READ_REPORTING_CONFIGURATION => {
+                let mut message = Message::general(
+                    Recipient::Reply,
+                    endpoint,
+                    profile,
+                    cluster,
+                    CMD_READ_REPORTING_CONFIGURATION_RESPONSE,
+                );
+                for record in arguments.chunks(3) {
+                    if record.len() < 3 {
+                        return Err(ClusterLibraryStatus::MalformedCommand);
+                    }
+                    let direction = record[0];
+                    let attribute = LittleEndian::read_u16(&record[1..3]);
+                    let entry = match self.find_mut(endpoint, cluster, attribute) {
+                        Some(entry) if direction == DIRECTION_REPORTED => *entry,
+                        _ => {
+                            message.append_u8(STATUS_UNREPORTABLE_ATTRIBUTE);
+                            message.append_u8(direction);
+                            message.append_u16(attribute);
+                            continue;
+                        }
+                    };
+                    message.append_u8(STATUS_SUCCESS);
+                    message.append_u8(direction);
+                    message.append_u16(attribute);
+                    message.append_u8(entry.data_type);
+                    message.append_u16(entry.min_interval);
+                    message.append_u16(entry.max_interval);
+                    // The change of 64 bit types does not fit in the stored
+                    // 32 bits, the upper bits are zero
+                    let mut change = [0u8; 8];
+                    LittleEndian::write_u32(&mut change[..4], entry.reportable_change);
+                    message.append(&change[..change_size(entry.data_type)]);
+                }
+                Ok(message)
+            }
+            _ => Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_configuration_of_wide_types() {
+        let mut table: ReportingTable<1> = ReportingTable::new();
+        // Unsigned 64 bit integer
+        table.add(0x01, 0x0702, 0x0000, 0x27, 1, 300);
+        let configure = [DIRECTION_REPORTED, 0x00, 0x00, 0x27, 0x02, 0x00, 0x10, 0x00];
+        let mut arguments = [0u8; 16];
+        arguments[..8].copy_from_slice(&configure);
+        arguments[8..].copy_from_slice(&[0x34, 0x12, 0, 0, 0, 0, 0, 0]);
+        let response = table
+            .handle_command(0x01, 0x0104, 0x0702, CMD_CONFIGURE_REPORTING, &arguments)
+            .unwrap();
+        assert_eq!(response.payload(), &[STATUS_SUCCESS]);
+
+        let response = table
+            .handle_command(
+                0x01,
+                0x0104,
+                0x0702,
+                CMD_READ_REPORTING_CONFIGURATION,
+                &configure[..3],
+            )
+            .unwrap();
+        assert_eq!(
+            response.payload(),
+            &[
+                STATUS_SUCCESS,
+                DIRECTION_REPORTED,
+                0x00,
+                0x00,
+                0x27,
+                0x02,
+                0x00,
+                0x10,
+                0x00,
+                0x34,
+                0x12,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00
+            ]
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Factory reset
+//!
+//! Handling of the ZDO Mgmt_Leave_req request, and removal of the stored
+//! network state and cluster tables when a device leaves the network. The
+//! install code is kept, so that the device can join again with the same
+//! link key.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    binding::BINDING_TABLE_KEY,
+    groups::GROUP_TABLE_KEY,
+    kv::{Error, Store},
+    light::LIGHT_STATE_KEY,
+    network::NETWORK_STATE_KEY,
+    scenes::SCENE_TABLE_KEY,
+    storage::Storage,
+};
+
+/// ZDO request, management leave
+pub const ZDO_MGMT_LEAVE_REQ: u16 = 0x0034;
+
+/// Basic cluster command, reset to factory defaults
+pub const BASIC_CMD_RESET_TO_FACTORY_DEFAULTS: u8 = 0x00;
+
+/// Keys removed by a factory reset
+pub const FACTORY_RESET_KEYS: [u16; 5] = [
+    NETWORK_STATE_KEY,
+    LIGHT_STATE_KEY,
+    GROUP_TABLE_KEY,
+    SCENE_TABLE_KEY,
+    BINDING_TABLE_KEY,
+];
+
+/// ZDO status, success
+const STATUS_SUCCESS: u8 = 0x00;
+/// ZDO status, not supported
+const STATUS_NOT_SUPPORTED: u8 = 0x84;
+
+/// Handle a ZDO Mgmt_Leave_req request
+///
+/// `address` is the extended address of this device. The response payload,
+/// without the transaction sequence number, is written to `response`.
+/// Returns the response length and if the device should leave, or `None` if
+/// the request is not a leave request.
+///
+/// Only requests for this device are accepted, a device address of zero
+/// also means this device. Leaving with rejoin is handled as a plain leave.
+pub fn handle_leave_request(
+    address: u64,
+    cluster: u16,
+    request: &[u8],
+    response: &mut [u8],
+) -> Option<(usize, bool)> {
+    if cluster != ZDO_MGMT_LEAVE_REQ {
+        return None;
+    }
+    let leave = match request.get(..8).map(LittleEndian::read_u64) {
+        Some(target) => target == 0 || target == address,
+        None => false,
+    };
+    response[0] = if leave {
+        STATUS_SUCCESS
+    } else {
+        STATUS_NOT_SUPPORTED
+    };
+    Some((1, leave))
+}
+
+/// Remove the stored network state and cluster tables
+pub fn wipe<S: Storage>(store: &mut Store<S>) -> Result<(), Error> {
+    for key in FACTORY_RESET_KEYS.iter() {
+        store.remove(*key)?;
+    }
+    Ok(())
+}
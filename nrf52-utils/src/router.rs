@@ -0,0 +1,957 @@
+//! Router
+//!
+//! Neighbour table of a Zigbee router, the Link Status command, and relaying
+//! of NWK frames. Frames that are addressed to another device are passed on
+//! to the next hop by replacing the MAC header, frames for sleepy children
+//! are held until the child polls for them.
+//!
+//! There is no route discovery. Frames are relayed to neighbours directly,
+//! anything else is passed up the tree to the parent.
+//!
+//! NWK frame security is hop by hop. The header, including the radius, is
+//! authenticated, so a secured frame is decrypted and secured again with the
+//! frame counter and extended address of this device before it is relayed.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::ccm::{self, NONCE_SIZE};
+use crate::cipher::BlockCipher;
+use crate::cipher::BLOCK_SIZE;
+
+/// NWK command, link status
+pub const NWK_CMD_LINK_STATUS: u8 = 0x08;
+/// Time between Link Status commands, in seconds
+pub const LINK_STATUS_INTERVAL: u16 = 15;
+/// Number of link status periods without a Link Status command from a
+/// router neighbour before it is removed
+pub const ROUTER_AGE_LIMIT: u8 = 3;
+/// Time a frame is held for a sleepy child, in seconds
+///
+/// The MAC transaction persistence time is 7.68 seconds.
+pub const INDIRECT_PERSISTENCE: u8 = 8;
+/// Time a broadcast is remembered, in seconds
+pub const BROADCAST_DELIVERY_TIME: u8 = 9;
+
+/// Broadcast address, all devices
+pub const BROADCAST_ALL: u16 = 0xffff;
+/// Broadcast address, devices with the receiver on when idle
+pub const BROADCAST_RX_ON_WHEN_IDLE: u16 = 0xfffd;
+/// Broadcast address, routers and the coordinator
+pub const BROADCAST_ROUTERS: u16 = 0xfffc;
+
+/// MAC frame type, data
+pub const MAC_FRAME_DATA: u8 = 0x01;
+/// MAC frame type, acknowledge
+pub const MAC_FRAME_ACKNOWLEDGE: u8 = 0x02;
+/// MAC frame type, command
+pub const MAC_FRAME_COMMAND: u8 = 0x03;
+/// MAC command, data request
+pub const MAC_CMD_DATA_REQUEST: u8 = 0x04;
+
+/// Size of a MAC header with PAN identifier compression and short addresses
+pub const MAC_HEADER_SIZE: usize = 9;
+/// Size of an acknowledge frame, without the FCS
+pub const MAC_ACKNOWLEDGE_SIZE: usize = 3;
+/// MAC frame control, frame pending
+const MAC_FRAME_PENDING: u16 = 0x0010;
+/// Largest MAC frame, without the PHR
+pub const MAX_FRAME_SIZE: usize = 127;
+
+/// Size of the NWK header fields needed for relaying
+const NWK_HEADER_SIZE: usize = 8;
+/// Offset of the radius in the NWK header
+const NWK_RADIUS_OFFSET: usize = 6;
+/// NWK frame control, multicast
+const NWK_FC_MULTICAST: u16 = 0x0100;
+/// NWK frame control, security
+const NWK_FC_SECURITY: u16 = 0x0200;
+/// NWK frame control, source route
+const NWK_FC_SOURCE_ROUTE: u16 = 0x0400;
+/// NWK frame control, destination IEEE address
+const NWK_FC_DESTINATION_IEEE: u16 = 0x0800;
+/// NWK frame control, source IEEE address
+const NWK_FC_SOURCE_IEEE: u16 = 0x1000;
+
+/// Security level of NWK frames, encryption with a 32 bit MIC
+///
+/// The level is not sent, the field is zero in the frame.
+const SECURITY_LEVEL_ENC_MIC_32: u8 = 0x05;
+const SECURITY_LEVEL_MASK: u8 = 0x07;
+/// Security control, network key
+const SECURITY_KEY_NETWORK: u8 = 0x08;
+const SECURITY_KEY_MASK: u8 = 0x18;
+/// Security control, the extended source address is in the header
+const SECURITY_EXTENDED_NONCE: u8 = 0x20;
+/// Size of the auxiliary header of NWK frames, security control, frame
+/// counter, extended source address and key sequence number
+const NWK_AUX_HEADER_SIZE: usize = 14;
+/// Size of the MIC of NWK frames
+const NWK_MIC_SIZE: usize = 4;
+
+/// Maximum number of entries in a Link Status command
+const LINK_STATUS_MAX_ENTRIES: usize = 0x1f;
+/// Link status option, first frame
+const LINK_STATUS_FIRST_FRAME: u8 = 0x20;
+/// Link status option, last frame
+const LINK_STATUS_LAST_FRAME: u8 = 0x40;
+
+/// Check if a NWK address is a broadcast address
+pub fn is_broadcast(address: u16) -> bool {
+    address >= 0xfff8
+}
+
+/// Link cost from the link quality indication of a received frame
+///
+/// The cost ranges from 1, for a good link, to 7.
+pub fn link_cost(lqi: u8) -> u8 {
+    match lqi {
+        0xc0..=0xff => 1,
+        0x80..=0xbf => 3,
+        0x40..=0x7f => 5,
+        _ => 7,
+    }
+}
+
+/// MAC header of a frame within the PAN using short addresses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MacHeader {
+    pub frame_type: u8,
+    pub sequence: u8,
+    pub pan_identifier: u16,
+    pub destination: u16,
+    pub source: u16,
+}
+
+impl MacHeader {
+    /// Parse the MAC header of a frame, without the PHR
+    ///
+    /// Only frames with PAN identifier compression and short destination and
+    /// source addresses are handled, which are the frames relayed within a
+    /// network.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < MAC_HEADER_SIZE {
+            return None;
+        }
+        let control = LittleEndian::read_u16(&frame[0..2]);
+        let pan_compression = control & 0x0040 == 0x0040;
+        let destination_mode = (control >> 10) & 0x03;
+        let source_mode = (control >> 14) & 0x03;
+        if !pan_compression || destination_mode != 0x02 || source_mode != 0x02 {
+            return None;
+        }
+        Some(Self {
+            frame_type: (control & 0x0007) as u8,
+            sequence: frame[2],
+            pan_identifier: LittleEndian::read_u16(&frame[3..5]),
+            destination: LittleEndian::read_u16(&frame[5..7]),
+            source: LittleEndian::read_u16(&frame[7..9]),
+        })
+    }
+
+    /// Write the header of a data frame, returns the number of bytes used
+    ///
+    /// An acknowledge is requested for unicast frames. `data` must be at
+    /// least `MAC_HEADER_SIZE` bytes.
+    pub fn write_data(&self, data: &mut [u8]) -> usize {
+        // Data frame, PAN identifier compression, short addresses
+        let mut control = 0x8841;
+        if self.destination != BROADCAST_ALL {
+            // Acknowledge request
+            control |= 0x0020;
+        }
+        LittleEndian::write_u16(&mut data[0..2], control);
+        data[2] = self.sequence;
+        LittleEndian::write_u16(&mut data[3..5], self.pan_identifier);
+        LittleEndian::write_u16(&mut data[5..7], self.destination);
+        LittleEndian::write_u16(&mut data[7..9], self.source);
+        MAC_HEADER_SIZE
+    }
+}
+
+/// Write an acknowledge frame, returns the number of bytes used
+///
+/// The frame pending bit tells a child that polled that a frame held for it
+/// follows. `data` must be at least `MAC_ACKNOWLEDGE_SIZE` bytes.
+pub fn write_acknowledge(sequence: u8, frame_pending: bool, data: &mut [u8]) -> usize {
+    let mut control = u16::from(MAC_FRAME_ACKNOWLEDGE);
+    if frame_pending {
+        control |= MAC_FRAME_PENDING;
+    }
+    LittleEndian::write_u16(&mut data[0..2], control);
+    data[2] = sequence;
+    MAC_ACKNOWLEDGE_SIZE
+}
+
+/// The NWK header fields used when relaying a frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkHeader {
+    pub destination: u16,
+    pub source: u16,
+    pub radius: u8,
+    pub sequence: u8,
+}
+
+impl NetworkHeader {
+    /// Parse the start of a NWK frame
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < NWK_HEADER_SIZE {
+            return None;
+        }
+        Some(Self {
+            destination: LittleEndian::read_u16(&frame[2..4]),
+            source: LittleEndian::read_u16(&frame[4..6]),
+            radius: frame[6],
+            sequence: frame[7],
+        })
+    }
+
+    /// Decrement the radius of a NWK frame before it is relayed
+    ///
+    /// Returns false if the frame may not be relayed any further.
+    pub fn decrement_radius(frame: &mut [u8]) -> bool {
+        match frame.get_mut(NWK_RADIUS_OFFSET) {
+            Some(radius) if *radius > 1 => {
+                *radius -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Size of the NWK header of a frame, with the optional fields and
+    /// without the auxiliary security header
+    fn size(frame: &[u8]) -> Option<usize> {
+        if frame.len() < NWK_HEADER_SIZE {
+            return None;
+        }
+        let control = LittleEndian::read_u16(&frame[0..2]);
+        let mut size = NWK_HEADER_SIZE;
+        if control & NWK_FC_DESTINATION_IEEE != 0 {
+            size += 8;
+        }
+        if control & NWK_FC_SOURCE_IEEE != 0 {
+            size += 8;
+        }
+        if control & NWK_FC_MULTICAST != 0 {
+            size += 1;
+        }
+        if control & NWK_FC_SOURCE_ROUTE != 0 {
+            // Relay count, relay index and the relay list
+            size += 2 + 2 * usize::from(*frame.get(size)?);
+        }
+        if frame.len() < size {
+            return None;
+        }
+        Some(size)
+    }
+}
+
+/// Nonce of a secured NWK frame, from the auxiliary header
+///
+/// The security level, which is not sent, is filled in.
+fn nonce(auxiliary: &[u8]) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    // Extended source address and frame counter
+    nonce[0..8].copy_from_slice(&auxiliary[5..13]);
+    nonce[8..12].copy_from_slice(&auxiliary[1..5]);
+    nonce[12] = auxiliary[0] & !SECURITY_LEVEL_MASK | SECURITY_LEVEL_ENC_MIC_32;
+    nonce
+}
+
+/// Prepare a NWK frame to be relayed, returns the length of the frame
+/// written to `output`
+///
+/// The radius is decremented. A secured frame is decrypted with the network
+/// `key`, and secured again with the `extended_address` of this device and a
+/// frame counter taken from `frame_counter`.
+///
+/// Returns `None` if the frame may not be relayed: the radius is spent, the
+/// frame is source routed, it is not secured with the network key, the MIC
+/// does not match, or `output` is too small.
+pub fn relay_frame<C: BlockCipher>(
+    cipher: &mut C,
+    key: &[u8; BLOCK_SIZE],
+    extended_address: u64,
+    frame_counter: impl FnOnce() -> u32,
+    frame: &[u8],
+    output: &mut [u8],
+) -> Option<usize> {
+    let header_size = NetworkHeader::size(frame)?;
+    let control = LittleEndian::read_u16(&frame[0..2]);
+    if control & NWK_FC_SOURCE_ROUTE != 0 || frame.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+    let output = output.get_mut(..frame.len())?;
+    if control & NWK_FC_SECURITY == 0 {
+        output.copy_from_slice(frame);
+        return if NetworkHeader::decrement_radius(output) {
+            Some(frame.len())
+        } else {
+            None
+        };
+    }
+    let headers_size = header_size + NWK_AUX_HEADER_SIZE;
+    if frame.len() < headers_size + NWK_MIC_SIZE {
+        return None;
+    }
+    let security_control = frame[header_size];
+    if security_control & (SECURITY_KEY_MASK | SECURITY_EXTENDED_NONCE)
+        != SECURITY_KEY_NETWORK | SECURITY_EXTENDED_NONCE
+    {
+        return None;
+    }
+    // The headers are the additional data, with the security level filled in
+    let (headers, payload) = output.split_at_mut(headers_size);
+    headers.copy_from_slice(&frame[..headers_size]);
+    headers[header_size] = security_control & !SECURITY_LEVEL_MASK | SECURITY_LEVEL_ENC_MIC_32;
+    let mut clear = [0u8; MAX_FRAME_SIZE];
+    let length = ccm::decrypt(
+        cipher,
+        key,
+        &nonce(&headers[header_size..]),
+        &frame[headers_size..],
+        NWK_MIC_SIZE,
+        headers,
+        &mut clear,
+    )
+    .ok()?;
+    if !NetworkHeader::decrement_radius(headers) {
+        return None;
+    }
+    let auxiliary = &mut headers[header_size..];
+    LittleEndian::write_u32(&mut auxiliary[1..5], frame_counter());
+    LittleEndian::write_u64(&mut auxiliary[5..13], extended_address);
+    let nonce = nonce(auxiliary);
+    let length = ccm::encrypt(
+        cipher,
+        key,
+        &nonce,
+        &clear[..length],
+        NWK_MIC_SIZE,
+        headers,
+        payload,
+    )
+    .ok()?;
+    headers[header_size] = security_control;
+    Some(headers_size + length)
+}
+
+/// Relationship to a neighbour
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Relationship {
+    Parent,
+    Child,
+    Sibling,
+}
+
+/// A device within radio range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Neighbour {
+    pub short_address: u16,
+    /// Extended address, zero if unknown
+    pub extended_address: u64,
+    pub relationship: Relationship,
+    pub rx_on_when_idle: bool,
+    /// Cost of frames from the neighbour, 0 if unknown
+    pub incoming_cost: u8,
+    /// Cost of frames to the neighbour as reported by the neighbour, 0 if
+    /// unknown
+    pub outgoing_cost: u8,
+    /// Link status periods since the last Link Status command
+    pub age: u8,
+}
+
+impl Neighbour {
+    /// Check if the neighbour is a router that sends Link Status commands
+    pub fn is_router(&self) -> bool {
+        self.relationship != Relationship::Child
+    }
+}
+
+/// Neighbour table
+#[derive(Clone, Copy, Debug)]
+pub struct NeighbourTable<const N: usize> {
+    entries: [Option<Neighbour>; N],
+}
+
+impl<const N: usize> Default for NeighbourTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> NeighbourTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Number of neighbours
+    pub fn count(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// Remove all neighbours
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// Look up a neighbour
+    pub fn get(&self, short_address: u16) -> Option<&Neighbour> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|neighbour| neighbour.short_address == short_address)
+    }
+
+    fn get_mut(&mut self, short_address: u16) -> Option<&mut Neighbour> {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .find(|neighbour| neighbour.short_address == short_address)
+    }
+
+    /// Add or replace a neighbour, returns false if the table is full
+    pub fn add(&mut self, neighbour: Neighbour) -> bool {
+        if let Some(entry) = self.get_mut(neighbour.short_address) {
+            *entry = neighbour;
+            return true;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(neighbour);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add a device that associated with this router
+    pub fn add_child(
+        &mut self,
+        short_address: u16,
+        extended_address: u64,
+        rx_on_when_idle: bool,
+    ) -> bool {
+        self.add(Neighbour {
+            short_address,
+            extended_address,
+            relationship: Relationship::Child,
+            rx_on_when_idle,
+            incoming_cost: 0,
+            outgoing_cost: 0,
+            age: 0,
+        })
+    }
+
+    /// Remove a neighbour, returns false if there was no such neighbour
+    pub fn remove(&mut self, short_address: u16) -> bool {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.is_some_and(|n| n.short_address == short_address))
+        {
+            Some(entry) => {
+                *entry = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Update the incoming cost of a neighbour a frame was received from
+    pub fn heard(&mut self, short_address: u16, lqi: u8) {
+        if let Some(neighbour) = self.get_mut(short_address) {
+            neighbour.incoming_cost = link_cost(lqi);
+        }
+    }
+
+    /// Handle a Link Status command from a router
+    ///
+    /// `payload` starts with the command identifier. The sender is added as a
+    /// sibling if it is not a neighbour already, and the outgoing cost is
+    /// taken from the entry for `own_address`.
+    pub fn link_status_received(&mut self, source: u16, own_address: u16, payload: &[u8]) {
+        if payload.len() < 2 || payload[0] != NWK_CMD_LINK_STATUS {
+            return;
+        }
+        let count = usize::from(payload[1] & 0x1f);
+        let outgoing_cost = payload[2..]
+            .chunks_exact(3)
+            .take(count)
+            .find(|entry| LittleEndian::read_u16(&entry[0..2]) == own_address)
+            .map_or(0, |entry| entry[2] & 0x07);
+        if let Some(neighbour) = self.get_mut(source) {
+            neighbour.outgoing_cost = outgoing_cost;
+            neighbour.age = 0;
+        } else {
+            let _ = self.add(Neighbour {
+                short_address: source,
+                extended_address: 0,
+                relationship: Relationship::Sibling,
+                rx_on_when_idle: true,
+                incoming_cost: 0,
+                outgoing_cost,
+                age: 0,
+            });
+        }
+    }
+
+    /// Age the router neighbours, called once per link status period
+    ///
+    /// Siblings that have not sent a Link Status command for
+    /// `ROUTER_AGE_LIMIT` periods are removed.
+    pub fn age(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if let Some(neighbour) = entry {
+                if !neighbour.is_router() {
+                    continue;
+                }
+                neighbour.age = neighbour.age.saturating_add(1);
+                if neighbour.age > ROUTER_AGE_LIMIT {
+                    if neighbour.relationship == Relationship::Sibling {
+                        *entry = None;
+                    } else {
+                        neighbour.outgoing_cost = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Write a Link Status command, returns the number of bytes used
+    ///
+    /// The command lists the router neighbours in ascending address order.
+    /// At most 31 neighbours are listed. `data` must hold 2 bytes plus 3
+    /// bytes per listed neighbour.
+    pub fn write_link_status(&self, data: &mut [u8]) -> usize {
+        data[0] = NWK_CMD_LINK_STATUS;
+        let mut count = 0;
+        let mut previous = None;
+        while count < LINK_STATUS_MAX_ENTRIES {
+            // The router with the lowest address above the previous one
+            let next = self
+                .entries
+                .iter()
+                .flatten()
+                .filter(|n| n.is_router())
+                .filter(|n| previous < Some(n.short_address))
+                .min_by_key(|n| n.short_address);
+            let neighbour = match next {
+                Some(neighbour) => neighbour,
+                None => break,
+            };
+            let offset = 2 + count * 3;
+            LittleEndian::write_u16(&mut data[offset..offset + 2], neighbour.short_address);
+            data[offset + 2] =
+                (neighbour.outgoing_cost & 0x07) << 4 | neighbour.incoming_cost & 0x07;
+            previous = Some(neighbour.short_address);
+            count += 1;
+        }
+        data[1] = count as u8 | LINK_STATUS_FIRST_FRAME | LINK_STATUS_LAST_FRAME;
+        2 + count * 3
+    }
+}
+
+/// How a relayed frame reaches its destination
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NextHop {
+    /// Sent to a router neighbour, or a child with the receiver on
+    Direct(u16),
+    /// Held until the sleepy child polls for it
+    Indirect(u16),
+}
+
+/// Routing state of a router
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Router<const NEIGHBOURS: usize> {
+    pub neighbours: NeighbourTable<NEIGHBOURS>,
+    address: u16,
+    link_status_elapsed: u16,
+}
+
+impl<const NEIGHBOURS: usize> Router<NEIGHBOURS> {
+    pub fn new() -> Self {
+        Self {
+            neighbours: NeighbourTable::new(),
+            address: 0,
+            link_status_elapsed: 0,
+        }
+    }
+
+    /// Set the short address of this device
+    pub fn set_address(&mut self, address: u16) {
+        self.address = address;
+    }
+
+    /// Handle a Link Status command from a router neighbour
+    pub fn link_status_received(&mut self, source: u16, payload: &[u8]) {
+        self.neighbours
+            .link_status_received(source, self.address, payload);
+    }
+
+    /// The next hop of a frame to `destination`
+    ///
+    /// Neighbours are reached directly, frames to other devices are sent to
+    /// `parent`, which is the coordinator for the routers joined to it.
+    pub fn next_hop(&self, destination: u16, parent: u16) -> NextHop {
+        if let Some(neighbour) = self.neighbours.get(destination) {
+            if neighbour.rx_on_when_idle {
+                NextHop::Direct(destination)
+            } else {
+                NextHop::Indirect(destination)
+            }
+        } else {
+            NextHop::Direct(parent)
+        }
+    }
+
+    /// Count seconds, returns true when a Link Status command is due
+    ///
+    /// The router neighbours are aged for every link status period.
+    pub fn tick(&mut self) -> bool {
+        self.link_status_elapsed += 1;
+        if self.link_status_elapsed < LINK_STATUS_INTERVAL {
+            return false;
+        }
+        self.link_status_elapsed = 0;
+        self.neighbours.age();
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PendingFrame<const S: usize> {
+    destination: u16,
+    remaining: u8,
+    length: usize,
+    data: [u8; S],
+}
+
+/// Frames held for sleepy children
+///
+/// Each slot holds one NWK frame of at most `S` bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct IndirectQueue<const N: usize, const S: usize> {
+    entries: [Option<PendingFrame<S>>; N],
+}
+
+impl<const N: usize, const S: usize> Default for IndirectQueue<N, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const S: usize> IndirectQueue<N, S> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Hold a frame for a child, returns false if the frame does not fit
+    pub fn push(&mut self, destination: u16, frame: &[u8]) -> bool {
+        if frame.len() > S {
+            return false;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                let mut data = [0u8; S];
+                data[..frame.len()].copy_from_slice(frame);
+                *entry = Some(PendingFrame {
+                    destination,
+                    remaining: INDIRECT_PERSISTENCE,
+                    length: frame.len(),
+                    data,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check if there is a frame held for a child
+    pub fn has_pending(&self, destination: u16) -> bool {
+        self.entries
+            .iter()
+            .flatten()
+            .any(|frame| frame.destination == destination)
+    }
+
+    /// Take the oldest frame held for a child, returns the length of the
+    /// frame copied to `data`
+    pub fn take(&mut self, destination: u16, data: &mut [u8]) -> Option<usize> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.is_some_and(|frame| frame.destination == destination))
+            .min_by_key(|entry| entry.map_or(u8::MAX, |frame| frame.remaining))?;
+        let frame = entry.take()?;
+        if data.len() < frame.length {
+            return None;
+        }
+        data[..frame.length].copy_from_slice(&frame.data[..frame.length]);
+        Some(frame.length)
+    }
+
+    /// Drop the frames held for a child
+    pub fn remove(&mut self, destination: u16) {
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|frame| frame.destination == destination) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Count seconds, frames that are not polled for in time are dropped
+    ///
+    /// Returns the number of dropped frames.
+    pub fn tick(&mut self) -> usize {
+        let mut dropped = 0;
+        for entry in self.entries.iter_mut() {
+            if let Some(frame) = entry {
+                frame.remaining = frame.remaining.saturating_sub(1);
+                if frame.remaining == 0 {
+                    *entry = None;
+                    dropped += 1;
+                }
+            }
+        }
+        dropped
+    }
+}
+
+/// Recently seen broadcasts, so that each broadcast is relayed once
+#[derive(Clone, Copy, Debug)]
+pub struct BroadcastTable<const N: usize> {
+    entries: [Option<(u16, u8, u8)>; N],
+}
+
+impl<const N: usize> Default for BroadcastTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BroadcastTable<N> {
+    pub fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Record a broadcast, returns false if it has been seen before or if the
+    /// table is full
+    pub fn is_new(&mut self, source: u16, sequence: u8) -> bool {
+        if self
+            .entries
+            .iter()
+            .flatten()
+            .any(|&(s, n, _)| s == source && n == sequence)
+        {
+            return false;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some((source, sequence, BROADCAST_DELIVERY_TIME));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Count seconds, forget broadcasts after the delivery time
+    pub fn tick(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if let Some((_, _, remaining)) = entry {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    *entry = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-in for AES, CCM* only uses the forward direction
+    struct TestCipher;
+
+    impl BlockCipher for TestCipher {
+        fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]) {
+            for round in 0..8 {
+                let previous = *block;
+                for (n, byte) in block.iter_mut().enumerate() {
+                    let next = previous[(n + round + 1) % BLOCK_SIZE].rotate_left(3);
+                    *byte = (previous[n] ^ key[n]).wrapping_add(next).wrapping_mul(0x1d) ^ n as u8;
+                }
+            }
+        }
+    }
+
+    const KEY: [u8; BLOCK_SIZE] = [
+        0x01, 0x03, 0x05, 0x07, 0x09, 0x0b, 0x0d, 0x0f, 0x00, 0x02, 0x04, 0x06, 0x08, 0x0a, 0x0c,
+        0x0d,
+    ];
+    const PAYLOAD: [u8; 6] = [0x08, 0x00, 0x01, 0x02, 0x03, 0x04];
+
+    /// NWK data frame from 0x8eaf to 0x1234 with radius 5, with both IEEE
+    /// addresses and an auxiliary header for a frame counter of 0x0102
+    fn headers() -> [u8; 38] {
+        let mut headers = [0u8; 38];
+        headers[..8].copy_from_slice(&[0x48, 0x1a, 0x34, 0x12, 0xaf, 0x8e, 0x05, 0x1e]);
+        headers[8..16].copy_from_slice(&0x0011_2233_4455_6677u64.to_le_bytes());
+        headers[16..24].copy_from_slice(&0x8899_aabb_ccdd_eeffu64.to_le_bytes());
+        headers[24] = SECURITY_KEY_NETWORK | SECURITY_EXTENDED_NONCE;
+        headers[25..29].copy_from_slice(&0x0102u32.to_le_bytes());
+        headers[29..37].copy_from_slice(&0x8899_aabb_ccdd_eeffu64.to_le_bytes());
+        headers[37] = 0x01;
+        headers
+    }
+
+    /// Secure `PAYLOAD` as the source would, returns the frame length
+    fn secure(frame: &mut [u8; MAX_FRAME_SIZE]) -> usize {
+        let mut headers = headers();
+        frame[..38].copy_from_slice(&headers);
+        headers[24] |= SECURITY_LEVEL_ENC_MIC_32;
+        let length = ccm::encrypt(
+            &mut TestCipher,
+            &KEY,
+            &nonce(&headers[24..]),
+            &PAYLOAD,
+            NWK_MIC_SIZE,
+            &headers,
+            &mut frame[38..],
+        )
+        .unwrap();
+        38 + length
+    }
+
+    /// Decrypt a relayed frame as the next hop would
+    fn open(frame: &[u8]) -> Option<[u8; 6]> {
+        let mut headers = [0u8; 38];
+        headers.copy_from_slice(&frame[..38]);
+        headers[24] |= SECURITY_LEVEL_ENC_MIC_32;
+        let mut payload = [0u8; 6];
+        ccm::decrypt(
+            &mut TestCipher,
+            &KEY,
+            &nonce(&headers[24..]),
+            &frame[38..],
+            NWK_MIC_SIZE,
+            &headers,
+            &mut payload,
+        )
+        .ok()?;
+        Some(payload)
+    }
+
+    #[test]
+    fn acknowledge() {
+        let mut data = [0u8; MAC_ACKNOWLEDGE_SIZE];
+        assert_eq!(
+            write_acknowledge(0x42, false, &mut data),
+            MAC_ACKNOWLEDGE_SIZE
+        );
+        assert_eq!(data, [0x02, 0x00, 0x42]);
+        write_acknowledge(0x43, true, &mut data);
+        assert_eq!(data, [0x12, 0x00, 0x43]);
+
+        let mut queue = IndirectQueue::<2, 8>::new();
+        assert!(!queue.has_pending(0x1234));
+        assert!(queue.push(0x1234, &[1, 2, 3]));
+        assert!(queue.has_pending(0x1234));
+        assert!(!queue.has_pending(0x4321));
+    }
+
+    #[test]
+    fn header_size() {
+        let headers = headers();
+        assert_eq!(NetworkHeader::size(&headers), Some(24));
+        assert_eq!(NetworkHeader::size(&headers[..20]), None);
+        let multicast = [0x08, 0x01, 0x01, 0x00, 0xaf, 0x8e, 0x05, 0x1e, 0x12];
+        assert_eq!(NetworkHeader::size(&multicast), Some(9));
+        let source_route = [
+            0x08, 0x04, 0x01, 0x00, 0xaf, 0x8e, 0x05, 0x1e, 2, 0, 1, 0, 2, 0,
+        ];
+        assert_eq!(NetworkHeader::size(&source_route), Some(14));
+        assert_eq!(NetworkHeader::size(&source_route[..12]), None);
+    }
+
+    #[test]
+    fn relay_unsecured() {
+        let frame = [0x08, 0x00, 0x34, 0x12, 0xaf, 0x8e, 0x02, 0x1e, 0x01, 0x02];
+        let mut output = [0u8; MAX_FRAME_SIZE];
+        let relay = |frame: &[u8], output: &mut [u8]| {
+            relay_frame(&mut TestCipher, &KEY, 1, || panic!(), frame, output)
+        };
+        assert_eq!(relay(&frame, &mut output), Some(frame.len()));
+        assert_eq!(output[NWK_RADIUS_OFFSET], 0x01);
+        assert_eq!(&output[7..10], &frame[7..10]);
+        // The radius is spent
+        let spent = output;
+        assert_eq!(relay(&spent[..frame.len()], &mut output), None);
+        // Too small for the frame
+        assert_eq!(relay(&frame, &mut output[..8]), None);
+    }
+
+    #[test]
+    fn relay_secured() {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let length = secure(&mut frame);
+        assert_eq!(open(&frame[..length]), Some(PAYLOAD));
+
+        let mut output = [0u8; MAX_FRAME_SIZE];
+        let relayed = relay_frame(
+            &mut TestCipher,
+            &KEY,
+            0x0123_4567_89ab_cdef,
+            || 0x0a0b_0c0d,
+            &frame[..length],
+            &mut output,
+        );
+        assert_eq!(relayed, Some(length));
+        let output = &output[..length];
+        // Radius decremented, the rest of the NWK header kept
+        assert_eq!(output[NWK_RADIUS_OFFSET], 0x04);
+        assert_eq!(&output[..6], &frame[..6]);
+        assert_eq!(&output[7..24], &frame[7..24]);
+        // Frame counter and extended source address of the relaying device,
+        // the security level is not sent
+        assert_eq!(output[24], SECURITY_KEY_NETWORK | SECURITY_EXTENDED_NONCE);
+        assert_eq!(LittleEndian::read_u32(&output[25..29]), 0x0a0b_0c0d);
+        assert_eq!(
+            LittleEndian::read_u64(&output[29..37]),
+            0x0123_4567_89ab_cdef
+        );
+        assert_eq!(output[37], 0x01);
+        assert_eq!(open(output), Some(PAYLOAD));
+    }
+
+    #[test]
+    fn relay_rejected() {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let length = secure(&mut frame);
+        let mut output = [0u8; MAX_FRAME_SIZE];
+        let mut relay = |frame: &[u8]| {
+            // No frame counter is used for frames that are not relayed
+            relay_frame(&mut TestCipher, &KEY, 1, || panic!(), frame, &mut output)
+        };
+        // The radius is authenticated
+        let mut changed = frame;
+        changed[NWK_RADIUS_OFFSET] = 0x07;
+        assert_eq!(relay(&changed[..length]), None);
+        // The MIC does not match
+        let mut changed = frame;
+        changed[length - 1] ^= 0x01;
+        assert_eq!(relay(&changed[..length]), None);
+        // Secured with another key than the network key
+        let mut changed = frame;
+        changed[24] = SECURITY_EXTENDED_NONCE;
+        assert_eq!(relay(&changed[..length]), None);
+        // Radius spent
+        let mut changed = frame;
+        changed[NWK_RADIUS_OFFSET] = 0x01;
+        assert_eq!(relay(&changed[..length]), None);
+        // Shorter than the headers and the MIC
+        assert_eq!(relay(&frame[..40]), None);
+    }
+}
@@ -0,0 +1,70 @@
+//! Channel selection
+//!
+//! Energy detection results for the IEEE 802.15.4 channels in the 2.4 GHz
+//! band, used to pick the quietest channel for a new network.
+
+/// First channel in the 2.4 GHz band
+pub const FIRST_CHANNEL: u8 = 11;
+/// Last channel in the 2.4 GHz band
+pub const LAST_CHANNEL: u8 = 26;
+/// Channels that overlap the least with Wi-Fi channels 1, 6 and 11
+pub const PREFERRED_CHANNELS: [u8; 4] = [15, 20, 25, 11];
+
+const CHANNEL_COUNT: usize = (LAST_CHANNEL - FIRST_CHANNEL + 1) as usize;
+
+/// Highest energy detected on each channel
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelScan {
+    energy: [Option<u8>; CHANNEL_COUNT],
+}
+
+impl ChannelScan {
+    pub const fn new() -> Self {
+        Self {
+            energy: [None; CHANNEL_COUNT],
+        }
+    }
+
+    /// Record an energy detection result, channels outside the band are
+    /// ignored
+    pub fn record(&mut self, channel: u8, energy: u8) {
+        if let Some(entry) = channel
+            .checked_sub(FIRST_CHANNEL)
+            .and_then(|index| self.energy.get_mut(usize::from(index)))
+        {
+            *entry = Some(entry.map_or(energy, |previous| previous.max(energy)));
+        }
+    }
+
+    /// The channel to scan after `channel`, `None` when all channels are
+    /// scanned
+    pub fn next_channel(channel: u8) -> Option<u8> {
+        if channel < LAST_CHANNEL {
+            Some(channel + 1)
+        } else {
+            None
+        }
+    }
+
+    /// The scanned channel with the lowest energy
+    ///
+    /// The preferred channels are picked over other channels with the same
+    /// energy. Returns `None` if no channel has been scanned.
+    pub fn quietest(&self) -> Option<u8> {
+        let energy = |channel: u8| self.energy[usize::from(channel - FIRST_CHANNEL)];
+        let preferred = PREFERRED_CHANNELS.iter().copied();
+        let others = (FIRST_CHANNEL..=LAST_CHANNEL).filter(|c| !PREFERRED_CHANNELS.contains(c));
+        preferred
+            .chain(others)
+            .filter_map(|channel| energy(channel).map(|level| (level, channel)))
+            // The first of the channels with the lowest energy
+            .fold(
+                None,
+                |best: Option<(u8, u8)>, (level, channel)| match best {
+                    Some((best_level, _)) if best_level <= level => best,
+                    _ => Some((level, channel)),
+                },
+            )
+            .map(|(_, channel)| channel)
+    }
+}
@@ -0,0 +1,1000 @@
+//! Scenes cluster
+//!
+//! A table of scenes per endpoint and handling of the scenes cluster
+//! commands, including the enhanced commands. A scene holds the extension
+//! field sets of the on/off, level control and colour control clusters.
+//! Scene names are not supported.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::cluster_library::{ClusterLibraryStatus, Destination};
+
+use crate::groups::GroupTable;
+use crate::light::{color_temperature_to_xy, hue_saturation_to_xy};
+use crate::outbox::{Message, Recipient};
+
+/// Key of the scene table in the key/value store
+pub const SCENE_TABLE_KEY: u16 = 0x0004;
+
+/// Scenes cluster
+pub const CLUSTER_SCENES: u16 = 0x0005;
+/// Scenes cluster attribute, scene count
+pub const SCENES_ATTR_SCENE_COUNT: u16 = 0x0000;
+/// Scenes cluster attribute, current scene
+pub const SCENES_ATTR_CURRENT_SCENE: u16 = 0x0001;
+/// Scenes cluster attribute, current group
+pub const SCENES_ATTR_CURRENT_GROUP: u16 = 0x0002;
+/// Scenes cluster attribute, scene valid
+pub const SCENES_ATTR_SCENE_VALID: u16 = 0x0003;
+/// Scenes cluster attribute, name support
+pub const SCENES_ATTR_NAME_SUPPORT: u16 = 0x0004;
+
+/// Scenes cluster command, add scene
+const SCENES_CMD_ADD_SCENE: u8 = 0x00;
+/// Scenes cluster command, view scene
+const SCENES_CMD_VIEW_SCENE: u8 = 0x01;
+/// Scenes cluster command, remove scene
+const SCENES_CMD_REMOVE_SCENE: u8 = 0x02;
+/// Scenes cluster command, remove all scenes
+const SCENES_CMD_REMOVE_ALL_SCENES: u8 = 0x03;
+/// Scenes cluster command, store scene
+const SCENES_CMD_STORE_SCENE: u8 = 0x04;
+/// Scenes cluster command, recall scene
+const SCENES_CMD_RECALL_SCENE: u8 = 0x05;
+/// Scenes cluster command, get scene membership
+const SCENES_CMD_GET_SCENE_MEMBERSHIP: u8 = 0x06;
+/// Scenes cluster command, enhanced add scene
+const SCENES_CMD_ENHANCED_ADD_SCENE: u8 = 0x40;
+/// Scenes cluster command, enhanced view scene
+const SCENES_CMD_ENHANCED_VIEW_SCENE: u8 = 0x41;
+
+/// Commands received by the server side of the scenes cluster
+pub const SCENES_COMMANDS_RECEIVED: [u8; 9] = [
+    SCENES_CMD_ADD_SCENE,
+    SCENES_CMD_VIEW_SCENE,
+    SCENES_CMD_REMOVE_SCENE,
+    SCENES_CMD_REMOVE_ALL_SCENES,
+    SCENES_CMD_STORE_SCENE,
+    SCENES_CMD_RECALL_SCENE,
+    SCENES_CMD_GET_SCENE_MEMBERSHIP,
+    SCENES_CMD_ENHANCED_ADD_SCENE,
+    SCENES_CMD_ENHANCED_VIEW_SCENE,
+];
+/// Commands generated by the server side of the scenes cluster, the
+/// responses share the identifiers of the requests
+pub const SCENES_COMMANDS_GENERATED: [u8; 8] = [
+    SCENES_CMD_ADD_SCENE,
+    SCENES_CMD_VIEW_SCENE,
+    SCENES_CMD_REMOVE_SCENE,
+    SCENES_CMD_REMOVE_ALL_SCENES,
+    SCENES_CMD_STORE_SCENE,
+    SCENES_CMD_GET_SCENE_MEMBERSHIP,
+    SCENES_CMD_ENHANCED_ADD_SCENE,
+    SCENES_CMD_ENHANCED_VIEW_SCENE,
+];
+
+/// Home automation profile
+const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
+/// On/off cluster
+const CLUSTER_ON_OFF: u16 = 0x0006;
+/// Level control cluster
+const CLUSTER_LEVEL_CONTROL: u16 = 0x0008;
+/// Colour control cluster
+const CLUSTER_COLOR_CONTROL: u16 = 0x0300;
+
+/// Length of the colour control extension field set, from CurrentX to
+/// ColorTemperatureMireds
+const COLOR_EXTENSION_SIZE: usize = 13;
+
+/// Status, success
+const STATUS_SUCCESS: u8 = 0x00;
+/// Status, invalid field
+const STATUS_INVALID_FIELD: u8 = 0x85;
+/// Status, insufficient space
+const STATUS_INSUFFICIENT_SPACE: u8 = 0x89;
+/// Status, not found
+const STATUS_NOT_FOUND: u8 = 0x8b;
+
+/// Recall scene, use the transition time of the scene
+const TRANSITION_TIME_SCENE: u16 = 0xffff;
+
+/// Size of a packed scene
+const SCENE_SIZE: usize = 14;
+
+/// Size of a packed scene table with `N` entries
+pub const fn scene_table_size(entries: usize) -> usize {
+    1 + entries * SCENE_SIZE
+}
+
+/// Colour of a scene
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    /// CIE 1931 x, y as the CurrentX and CurrentY attributes
+    Xy { x: u16, y: u16 },
+    /// Enhanced hue and saturation
+    HueSaturation { hue: u16, saturation: u8 },
+    /// Colour temperature in mireds
+    Temperature(u16),
+}
+
+impl Color {
+    /// The colour as CIE 1931 x, y
+    pub fn to_xy(self) -> (u16, u16) {
+        match self {
+            Color::Xy { x, y } => (x, y),
+            Color::HueSaturation { hue, saturation } => hue_saturation_to_xy(hue, saturation),
+            Color::Temperature(mireds) => color_temperature_to_xy(mireds),
+        }
+    }
+
+    /// Parse the colour control extension field set
+    ///
+    /// A non-zero x, y is preferred, then a non-zero colour temperature and
+    /// last hue and saturation.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() >= 4 {
+            let x = LittleEndian::read_u16(&data[0..2]);
+            let y = LittleEndian::read_u16(&data[2..4]);
+            if x != 0 || y != 0 {
+                return Some(Color::Xy { x, y });
+            }
+        }
+        if data.len() >= COLOR_EXTENSION_SIZE {
+            let mireds = LittleEndian::read_u16(&data[11..13]);
+            if mireds != 0 {
+                return Some(Color::Temperature(mireds));
+            }
+        }
+        if data.len() >= 7 {
+            return Some(Color::HueSaturation {
+                hue: LittleEndian::read_u16(&data[4..6]),
+                saturation: data[6],
+            });
+        }
+        None
+    }
+
+    /// Write the colour control extension field set
+    fn extension(self, data: &mut [u8; COLOR_EXTENSION_SIZE]) {
+        *data = [0; COLOR_EXTENSION_SIZE];
+        match self {
+            Color::Xy { x, y } => {
+                LittleEndian::write_u16(&mut data[0..2], x);
+                LittleEndian::write_u16(&mut data[2..4], y);
+            }
+            Color::HueSaturation { hue, saturation } => {
+                LittleEndian::write_u16(&mut data[4..6], hue);
+                data[6] = saturation;
+            }
+            Color::Temperature(mireds) => {
+                LittleEndian::write_u16(&mut data[11..13], mireds);
+            }
+        }
+    }
+}
+
+/// State captured by a scene, the extension field sets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SceneState {
+    /// On/off state
+    pub on_off: Option<bool>,
+    /// Current level
+    pub level: Option<u8>,
+    /// Colour
+    pub color: Option<Color>,
+}
+
+impl SceneState {
+    /// Parse extension field sets
+    fn parse(mut data: &[u8]) -> Result<Self, ClusterLibraryStatus> {
+        let mut state = SceneState::default();
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(ClusterLibraryStatus::MalformedCommand);
+            }
+            let cluster = LittleEndian::read_u16(&data[0..2]);
+            let length = data[2] as usize;
+            if data.len() < 3 + length {
+                return Err(ClusterLibraryStatus::MalformedCommand);
+            }
+            let field = &data[3..3 + length];
+            match cluster {
+                CLUSTER_ON_OFF => state.on_off = field.first().map(|value| *value != 0),
+                CLUSTER_LEVEL_CONTROL => state.level = field.first().copied(),
+                CLUSTER_COLOR_CONTROL => state.color = Color::parse(field),
+                _ => (),
+            }
+            data = &data[3 + length..];
+        }
+        Ok(state)
+    }
+
+    /// Append the extension field sets to `message`
+    fn append_to(&self, message: &mut Message) {
+        if let Some(on_off) = self.on_off {
+            message.append_u16(CLUSTER_ON_OFF);
+            message.append_u8(1);
+            message.append_u8(on_off as u8);
+        }
+        if let Some(level) = self.level {
+            message.append_u16(CLUSTER_LEVEL_CONTROL);
+            message.append_u8(1);
+            message.append_u8(level);
+        }
+        if let Some(color) = self.color {
+            let mut field = [0u8; COLOR_EXTENSION_SIZE];
+            color.extension(&mut field);
+            message.append_u16(CLUSTER_COLOR_CONTROL);
+            message.append_u8(COLOR_EXTENSION_SIZE as u8);
+            message.append(&field);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Scene {
+    endpoint: u8,
+    group: u16,
+    scene: u8,
+    /// Transition time in tenths of a second
+    transition_time: u16,
+    state: SceneState,
+}
+
+impl Scene {
+    fn pack(&self, data: &mut [u8]) {
+        data[0] = self.endpoint;
+        LittleEndian::write_u16(&mut data[1..3], self.group);
+        data[3] = self.scene;
+        LittleEndian::write_u16(&mut data[4..6], self.transition_time);
+        data[6] = self.state.on_off.is_some() as u8 | (self.state.level.is_some() as u8) << 1;
+        data[7] = self.state.on_off.unwrap_or_default() as u8;
+        data[8] = self.state.level.unwrap_or_default();
+        data[9..14].copy_from_slice(&[0; 5]);
+        match self.state.color {
+            None => (),
+            Some(Color::Xy { x, y }) => {
+                data[9] = 1;
+                LittleEndian::write_u16(&mut data[10..12], x);
+                LittleEndian::write_u16(&mut data[12..14], y);
+            }
+            Some(Color::HueSaturation { hue, saturation }) => {
+                data[9] = 2;
+                LittleEndian::write_u16(&mut data[10..12], hue);
+                data[12] = saturation;
+            }
+            Some(Color::Temperature(mireds)) => {
+                data[9] = 3;
+                LittleEndian::write_u16(&mut data[10..12], mireds);
+            }
+        }
+    }
+
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let color = match data[9] {
+            0 => None,
+            1 => Some(Color::Xy {
+                x: LittleEndian::read_u16(&data[10..12]),
+                y: LittleEndian::read_u16(&data[12..14]),
+            }),
+            2 => Some(Color::HueSaturation {
+                hue: LittleEndian::read_u16(&data[10..12]),
+                saturation: data[12],
+            }),
+            3 => Some(Color::Temperature(LittleEndian::read_u16(&data[10..12]))),
+            _ => return None,
+        };
+        Some(Self {
+            endpoint: data[0],
+            group: LittleEndian::read_u16(&data[1..3]),
+            scene: data[3],
+            transition_time: LittleEndian::read_u16(&data[4..6]),
+            state: SceneState {
+                on_off: if data[6] & 0x01 != 0 {
+                    Some(data[7] != 0)
+                } else {
+                    None
+                },
+                level: if data[6] & 0x02 != 0 {
+                    Some(data[8])
+                } else {
+                    None
+                },
+                color,
+            },
+        })
+    }
+}
+
+/// A scene to recall
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Recall {
+    /// The state to move to
+    pub state: SceneState,
+    /// Transition time in tenths of a second
+    pub transition_time: u16,
+}
+
+/// Result of a scenes cluster command
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Outcome {
+    /// The scene table changed and should be stored
+    pub changed: bool,
+    /// Response to send
+    pub response: Option<Message>,
+    /// Scene to recall
+    pub recall: Option<Recall>,
+}
+
+/// Scenes
+#[derive(Clone, Copy, Debug)]
+pub struct SceneTable<const N: usize> {
+    entries: [Option<Scene>; N],
+    current: Option<(u16, u8)>,
+    valid: bool,
+}
+
+impl<const N: usize> Default for SceneTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SceneTable<N> {
+    pub fn new() -> Self {
+        Self {
+            entries: [None; N],
+            current: None,
+            valid: false,
+        }
+    }
+
+    /// Number of scenes, the SceneCount attribute
+    pub fn count(&self) -> u8 {
+        self.entries.iter().flatten().count() as u8
+    }
+
+    /// The last recalled or stored scene, the CurrentScene attribute
+    pub fn current_scene(&self) -> u8 {
+        self.current.map_or(0, |(_, scene)| scene)
+    }
+
+    /// Group of the current scene, the CurrentGroup attribute
+    pub fn current_group(&self) -> u16 {
+        self.current.map_or(0, |(group, _)| group)
+    }
+
+    /// Check if the state matches the current scene, the SceneValid attribute
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Mark that the state no longer matches the current scene
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /// Remove scenes of groups that `endpoint` no longer is member of
+    ///
+    /// Returns true if any scene was removed.
+    pub fn retain_groups<const G: usize>(&mut self, endpoint: u8, groups: &GroupTable<G>) -> bool {
+        let mut removed = false;
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|scene| {
+                scene.endpoint == endpoint
+                    && scene.group != 0
+                    && !groups.contains(endpoint, scene.group)
+            }) {
+                *entry = None;
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Remove all scenes
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Number of free entries
+    fn free(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_none()).count()
+    }
+
+    fn find(&self, endpoint: u8, group: u16, scene: u8) -> Option<&Scene> {
+        self.entries.iter().flatten().find(|entry| {
+            entry.endpoint == endpoint && entry.group == group && entry.scene == scene
+        })
+    }
+
+    /// Add or replace a scene
+    fn insert(&mut self, new: Scene) -> bool {
+        let existing = self.entries.iter().position(|entry| {
+            entry.is_some_and(|entry| {
+                entry.endpoint == new.endpoint
+                    && entry.group == new.group
+                    && entry.scene == new.scene
+            })
+        });
+        match existing.or_else(|| self.entries.iter().position(|entry| entry.is_none())) {
+            Some(index) => {
+                self.entries[index] = Some(new);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, endpoint: u8, group: u16, scene: u8) -> bool {
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|entry| {
+                entry.endpoint == endpoint && entry.group == group && entry.scene == scene
+            }) {
+                *entry = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn remove_all(&mut self, endpoint: u8, group: u16) -> bool {
+        let mut removed = false;
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|entry| entry.endpoint == endpoint && entry.group == group) {
+                *entry = None;
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Pack the table into `data`, returns the number of bytes used
+    pub fn pack(&self, data: &mut [u8]) -> usize {
+        let mut offset = 1;
+        let mut count = 0;
+        for scene in self.entries.iter().flatten() {
+            scene.pack(&mut data[offset..offset + SCENE_SIZE]);
+            offset += SCENE_SIZE;
+            count += 1;
+        }
+        data[0] = count;
+        offset
+    }
+
+    /// Unpack a table from `data`
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let count = *data.first()? as usize;
+        if count > N || data.len() < scene_table_size(count) {
+            return None;
+        }
+        let mut table = Self::new();
+        for (n, entry) in data[1..scene_table_size(count)]
+            .chunks(SCENE_SIZE)
+            .enumerate()
+        {
+            table.entries[n] = Some(Scene::unpack(entry)?);
+        }
+        Some(table)
+    }
+
+    /// Handle a scenes cluster command for `endpoint`
+    ///
+    /// `groups` is used to check that the group of a scene exists and
+    /// `current` is the state that is captured by Store Scene.
+    pub fn handle_command<const G: usize>(
+        &mut self,
+        endpoint: u8,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+        groups: &GroupTable<G>,
+        current: &SceneState,
+    ) -> Result<Outcome, ClusterLibraryStatus> {
+        // Only requests sent directly to the device are answered
+        let respond = matches!(destination, Destination::Unicast(_));
+        let response = |command: u8| {
+            Message::new(
+                Recipient::Reply,
+                endpoint,
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_SCENES,
+                command,
+            )
+        };
+        let group_exists = |group: u16| group == 0 || groups.contains(endpoint, group);
+        let mut outcome = Outcome::default();
+        match command {
+            SCENES_CMD_ADD_SCENE | SCENES_CMD_ENHANCED_ADD_SCENE => {
+                if arguments.len() < 6 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let scene = arguments[2];
+                let transition_time = LittleEndian::read_u16(&arguments[3..5]);
+                let transition_time = if command == SCENES_CMD_ADD_SCENE {
+                    transition_time.saturating_mul(10)
+                } else {
+                    transition_time
+                };
+                let name_end = 6 + arguments[5] as usize;
+                if arguments.len() < name_end {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let state = SceneState::parse(&arguments[name_end..])?;
+                let status = if !group_exists(group) {
+                    STATUS_INVALID_FIELD
+                } else if self.insert(Scene {
+                    endpoint,
+                    group,
+                    scene,
+                    transition_time,
+                    state,
+                }) {
+                    outcome.changed = true;
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_INSUFFICIENT_SPACE
+                };
+                if respond {
+                    let mut message = response(command);
+                    message.append_u8(status);
+                    message.append_u16(group);
+                    message.append_u8(scene);
+                    outcome.response = Some(message);
+                }
+            }
+            SCENES_CMD_VIEW_SCENE | SCENES_CMD_ENHANCED_VIEW_SCENE => {
+                if arguments.len() < 3 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let scene = arguments[2];
+                if respond {
+                    let mut message = response(command);
+                    if !group_exists(group) {
+                        message.append_u8(STATUS_INVALID_FIELD);
+                        message.append_u16(group);
+                        message.append_u8(scene);
+                    } else if let Some(entry) = self.find(endpoint, group, scene) {
+                        let transition_time = if command == SCENES_CMD_VIEW_SCENE {
+                            entry.transition_time / 10
+                        } else {
+                            entry.transition_time
+                        };
+                        message.append_u8(STATUS_SUCCESS);
+                        message.append_u16(group);
+                        message.append_u8(scene);
+                        message.append_u16(transition_time);
+                        // Empty scene name
+                        message.append_u8(0);
+                        entry.state.append_to(&mut message);
+                    } else {
+                        message.append_u8(STATUS_NOT_FOUND);
+                        message.append_u16(group);
+                        message.append_u8(scene);
+                    }
+                    outcome.response = Some(message);
+                }
+            }
+            SCENES_CMD_REMOVE_SCENE => {
+                if arguments.len() < 3 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let scene = arguments[2];
+                let status = if !group_exists(group) {
+                    STATUS_INVALID_FIELD
+                } else if self.remove(endpoint, group, scene) {
+                    outcome.changed = true;
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_NOT_FOUND
+                };
+                if respond {
+                    let mut message = response(command);
+                    message.append_u8(status);
+                    message.append_u16(group);
+                    message.append_u8(scene);
+                    outcome.response = Some(message);
+                }
+            }
+            SCENES_CMD_REMOVE_ALL_SCENES => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let status = if group_exists(group) {
+                    outcome.changed = self.remove_all(endpoint, group);
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_INVALID_FIELD
+                };
+                if respond {
+                    let mut message = response(command);
+                    message.append_u8(status);
+                    message.append_u16(group);
+                    outcome.response = Some(message);
+                }
+            }
+            SCENES_CMD_STORE_SCENE => {
+                if arguments.len() < 3 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let scene = arguments[2];
+                let transition_time = self
+                    .find(endpoint, group, scene)
+                    .map_or(0, |entry| entry.transition_time);
+                let status = if !group_exists(group) {
+                    STATUS_INVALID_FIELD
+                } else if self.insert(Scene {
+                    endpoint,
+                    group,
+                    scene,
+                    transition_time,
+                    state: *current,
+                }) {
+                    self.current = Some((group, scene));
+                    self.valid = true;
+                    outcome.changed = true;
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_INSUFFICIENT_SPACE
+                };
+                if respond {
+                    let mut message = response(command);
+                    message.append_u8(status);
+                    message.append_u16(group);
+                    message.append_u8(scene);
+                    outcome.response = Some(message);
+                }
+            }
+            SCENES_CMD_RECALL_SCENE => {
+                if arguments.len() < 3 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                let scene = arguments[2];
+                if !group_exists(group) {
+                    return Err(ClusterLibraryStatus::InvalidField);
+                }
+                let entry = *self
+                    .find(endpoint, group, scene)
+                    .ok_or(ClusterLibraryStatus::NotFound)?;
+                let transition_time = match arguments.get(3..5) {
+                    Some(field) if LittleEndian::read_u16(field) != TRANSITION_TIME_SCENE => {
+                        LittleEndian::read_u16(field)
+                    }
+                    _ => entry.transition_time,
+                };
+                self.current = Some((group, scene));
+                self.valid = true;
+                outcome.recall = Some(Recall {
+                    state: entry.state,
+                    transition_time,
+                });
+            }
+            SCENES_CMD_GET_SCENE_MEMBERSHIP => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let group = LittleEndian::read_u16(&arguments[0..2]);
+                if respond {
+                    let mut message = response(command);
+                    let capacity = core::cmp::min(self.free(), 0xfe) as u8;
+                    if group_exists(group) {
+                        message.append_u8(STATUS_SUCCESS);
+                        message.append_u8(capacity);
+                        message.append_u16(group);
+                        let scenes = self
+                            .entries
+                            .iter()
+                            .flatten()
+                            .filter(|entry| entry.endpoint == endpoint && entry.group == group);
+                        message.append_u8(scenes.clone().count() as u8);
+                        for entry in scenes {
+                            message.append_u8(entry.scene);
+                        }
+                    } else {
+                        message.append_u8(STATUS_INVALID_FIELD);
+                        message.append_u8(capacity);
+                        message.append_u16(group);
+                    }
+                    outcome.response = Some(message);
+                }
+            }
+            _ => return Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: u8 = 1;
+    const UNICAST: Destination = Destination::Unicast(ENDPOINT);
+
+    /// On/off, level and an x, y colour as extension field sets
+    const EXTENSIONS: [u8; 24] = [
+        0x06, 0x00, 0x01, 0x01, // on
+        0x08, 0x00, 0x01, 0x80, // level
+        0x00, 0x03, 0x0d, 0x34, 0x12, 0x78, 0x56, 0, 0, 0, 0, 0, 0, 0, 0, 0, // colour
+    ];
+
+    fn groups() -> GroupTable<4> {
+        let mut groups = GroupTable::new();
+        groups.add(ENDPOINT, 0x0001).unwrap();
+        groups
+    }
+
+    fn handle<const N: usize>(
+        table: &mut SceneTable<N>,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<Outcome, ClusterLibraryStatus> {
+        table.handle_command(
+            ENDPOINT,
+            UNICAST,
+            command,
+            arguments,
+            &groups(),
+            &SceneState::default(),
+        )
+    }
+
+    /// Arguments of Add Scene for group 1, with an empty name
+    fn add_arguments(scene: u8, transition_time: u16) -> [u8; 30] {
+        let mut arguments = [0u8; 30];
+        arguments[0] = 0x01;
+        arguments[2] = scene;
+        LittleEndian::write_u16(&mut arguments[3..5], transition_time);
+        arguments[6..].copy_from_slice(&EXTENSIONS);
+        arguments
+    }
+
+    fn response(outcome: &Outcome) -> &[u8] {
+        outcome.response.as_ref().unwrap().payload()
+    }
+
+    #[test]
+    fn scene_pack_unpack() {
+        let scene = Scene {
+            endpoint: 1,
+            group: 0x1234,
+            scene: 5,
+            transition_time: 25,
+            state: SceneState {
+                on_off: Some(true),
+                level: None,
+                color: Some(Color::HueSaturation {
+                    hue: 0xabcd,
+                    saturation: 0xfe,
+                }),
+            },
+        };
+        let mut data = [0xffu8; SCENE_SIZE];
+        scene.pack(&mut data);
+        assert_eq!(
+            data,
+            [1, 0x34, 0x12, 5, 25, 0, 0x01, 0x01, 0x00, 2, 0xcd, 0xab, 0xfe, 0x00]
+        );
+        assert_eq!(Scene::unpack(&data), Some(scene));
+        for color in [
+            None,
+            Some(Color::Xy {
+                x: 0x1234,
+                y: 0x5678,
+            }),
+            Some(Color::Temperature(370)),
+        ] {
+            let scene = Scene {
+                state: SceneState {
+                    on_off: None,
+                    level: Some(0),
+                    color,
+                },
+                ..scene
+            };
+            scene.pack(&mut data);
+            assert_eq!(Scene::unpack(&data), Some(scene));
+        }
+        // Unknown colour mode
+        data[9] = 4;
+        assert_eq!(Scene::unpack(&data), None);
+    }
+
+    #[test]
+    fn table_pack_unpack() {
+        let mut table = SceneTable::<4>::new();
+        handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(1, 2)).unwrap();
+        handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(2, 3)).unwrap();
+        let mut data = [0u8; scene_table_size(4)];
+        assert_eq!(table.pack(&mut data), scene_table_size(2));
+        let unpacked = SceneTable::<4>::unpack(&data).unwrap();
+        assert_eq!(unpacked.count(), 2);
+        assert_eq!(unpacked.find(ENDPOINT, 1, 2), table.find(ENDPOINT, 1, 2));
+        assert!(SceneTable::<4>::unpack(&data[..scene_table_size(2) - 1]).is_none());
+        assert!(SceneTable::<1>::unpack(&data).is_none());
+    }
+
+    #[test]
+    fn color_parse() {
+        let mut field = [0u8; COLOR_EXTENSION_SIZE];
+        LittleEndian::write_u16(&mut field[0..2], 0x1234);
+        LittleEndian::write_u16(&mut field[4..6], 0xabcd);
+        field[6] = 0x80;
+        LittleEndian::write_u16(&mut field[11..13], 370);
+        // x, y first
+        assert_eq!(Color::parse(&field), Some(Color::Xy { x: 0x1234, y: 0 }));
+        // then the colour temperature
+        field[0..4].copy_from_slice(&[0; 4]);
+        assert_eq!(Color::parse(&field), Some(Color::Temperature(370)));
+        // last hue and saturation
+        field[11..13].copy_from_slice(&[0; 2]);
+        assert_eq!(
+            Color::parse(&field),
+            Some(Color::HueSaturation {
+                hue: 0xabcd,
+                saturation: 0x80
+            })
+        );
+        // A short field without the colour temperature
+        assert_eq!(
+            Color::parse(&field[..7]),
+            Some(Color::HueSaturation {
+                hue: 0xabcd,
+                saturation: 0x80
+            })
+        );
+        assert_eq!(Color::parse(&field[..4]), None);
+        assert_eq!(Color::parse(&[]), None);
+    }
+
+    #[test]
+    fn scene_state_parse() {
+        assert_eq!(
+            SceneState::parse(&EXTENSIONS),
+            Ok(SceneState {
+                on_off: Some(true),
+                level: Some(0x80),
+                color: Some(Color::Xy {
+                    x: 0x1234,
+                    y: 0x5678
+                }),
+            })
+        );
+        assert_eq!(SceneState::parse(&[]), Ok(SceneState::default()));
+        // Unknown clusters and empty fields are skipped
+        assert_eq!(
+            SceneState::parse(&[0x02, 0x03, 0x02, 0xaa, 0xbb, 0x08, 0x00, 0x00]),
+            Ok(SceneState::default())
+        );
+    }
+
+    #[test]
+    fn scene_state_malformed() {
+        // Header cut short
+        assert_eq!(
+            SceneState::parse(&EXTENSIONS[..2]),
+            Err(ClusterLibraryStatus::MalformedCommand)
+        );
+        // Length past the end of the data
+        assert_eq!(
+            SceneState::parse(&EXTENSIONS[..EXTENSIONS.len() - 1]),
+            Err(ClusterLibraryStatus::MalformedCommand)
+        );
+        assert_eq!(
+            SceneState::parse(&[0x06, 0x00, 0x02, 0x01]),
+            Err(ClusterLibraryStatus::MalformedCommand)
+        );
+        // A malformed command does not add a scene
+        let mut table = SceneTable::<4>::new();
+        let arguments = add_arguments(1, 0);
+        assert_eq!(
+            handle(&mut table, SCENES_CMD_ADD_SCENE, &arguments[..29]).unwrap_err(),
+            ClusterLibraryStatus::MalformedCommand
+        );
+        assert_eq!(table.count(), 0);
+    }
+
+    #[test]
+    fn transition_time_units() {
+        let mut table = SceneTable::<4>::new();
+        // Add Scene in seconds, Enhanced Add Scene in tenths
+        let outcome = handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(1, 3)).unwrap();
+        assert!(outcome.changed);
+        assert_eq!(response(&outcome), [STATUS_SUCCESS, 0x01, 0x00, 1]);
+        handle(
+            &mut table,
+            SCENES_CMD_ENHANCED_ADD_SCENE,
+            &add_arguments(2, 15),
+        )
+        .unwrap();
+        assert_eq!(table.find(ENDPOINT, 1, 1).unwrap().transition_time, 30);
+        assert_eq!(table.find(ENDPOINT, 1, 2).unwrap().transition_time, 15);
+        // View Scene in seconds, Enhanced View Scene in tenths
+        let outcome = handle(&mut table, SCENES_CMD_VIEW_SCENE, &[0x01, 0x00, 2]).unwrap();
+        assert_eq!(
+            response(&outcome)[..7],
+            [STATUS_SUCCESS, 0x01, 0x00, 2, 1, 0, 0]
+        );
+        assert_eq!(response(&outcome)[7..], EXTENSIONS);
+        let outcome = handle(&mut table, SCENES_CMD_ENHANCED_VIEW_SCENE, &[0x01, 0x00, 2]).unwrap();
+        assert_eq!(
+            response(&outcome)[..7],
+            [STATUS_SUCCESS, 0x01, 0x00, 2, 15, 0, 0]
+        );
+        let outcome = handle(&mut table, SCENES_CMD_VIEW_SCENE, &[0x01, 0x00, 1]).unwrap();
+        assert_eq!(response(&outcome)[4..6], [3, 0]);
+    }
+
+    #[test]
+    fn recall() {
+        let mut table = SceneTable::<4>::new();
+        handle(
+            &mut table,
+            SCENES_CMD_ENHANCED_ADD_SCENE,
+            &add_arguments(1, 25),
+        )
+        .unwrap();
+        assert!(!table.is_valid());
+        // Without a transition time or with 0xffff, the time of the scene
+        for arguments in [&[0x01, 0x00, 1][..], &[0x01, 0x00, 1, 0xff, 0xff][..]] {
+            let outcome = handle(&mut table, SCENES_CMD_RECALL_SCENE, arguments).unwrap();
+            let recall = outcome.recall.unwrap();
+            assert_eq!(recall.transition_time, 25);
+            assert_eq!(recall.state, SceneState::parse(&EXTENSIONS).unwrap());
+            assert!(outcome.response.is_none());
+        }
+        // Otherwise the given time
+        let outcome = handle(&mut table, SCENES_CMD_RECALL_SCENE, &[0x01, 0x00, 1, 7, 0]).unwrap();
+        assert_eq!(outcome.recall.unwrap().transition_time, 7);
+        assert!(table.is_valid());
+        assert_eq!(table.current_group(), 0x0001);
+        assert_eq!(table.current_scene(), 1);
+        assert_eq!(
+            handle(&mut table, SCENES_CMD_RECALL_SCENE, &[0x01, 0x00, 2]).unwrap_err(),
+            ClusterLibraryStatus::NotFound
+        );
+        assert_eq!(
+            handle(&mut table, SCENES_CMD_RECALL_SCENE, &[0x02, 0x00, 1]).unwrap_err(),
+            ClusterLibraryStatus::InvalidField
+        );
+    }
+
+    #[test]
+    fn table_full() {
+        let mut table = SceneTable::<1>::new();
+        let outcome = handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(1, 0)).unwrap();
+        assert_eq!(response(&outcome)[0], STATUS_SUCCESS);
+        // Replacing a scene needs no space
+        let outcome = handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(1, 4)).unwrap();
+        assert_eq!(response(&outcome)[0], STATUS_SUCCESS);
+        assert_eq!(table.count(), 1);
+        let outcome = handle(&mut table, SCENES_CMD_ADD_SCENE, &add_arguments(2, 0)).unwrap();
+        assert!(!outcome.changed);
+        assert_eq!(
+            response(&outcome),
+            [STATUS_INSUFFICIENT_SPACE, 0x01, 0x00, 2]
+        );
+        let outcome = handle(&mut table, SCENES_CMD_STORE_SCENE, &[0x01, 0x00, 2]).unwrap();
+        assert_eq!(
+            response(&outcome),
+            [STATUS_INSUFFICIENT_SPACE, 0x01, 0x00, 2]
+        );
+    }
+
+    #[test]
+    fn unknown_group() {
+        let mut table = SceneTable::<4>::new();
+        let mut arguments = add_arguments(1, 0);
+        arguments[0] = 0x02;
+        let outcome = handle(&mut table, SCENES_CMD_ADD_SCENE, &arguments).unwrap();
+        assert!(!outcome.changed);
+        assert_eq!(response(&outcome), [STATUS_INVALID_FIELD, 0x02, 0x00, 1]);
+        assert_eq!(table.count(), 0);
+    }
+}
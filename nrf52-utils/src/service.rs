@@ -0,0 +1,288 @@
+//! Psila service plumbing
+//!
+//! The parts of the Zigbee examples that are the same for every device: the
+//! frames passed between the radio and the service, the network state kept
+//! across restarts and the commands queued by the cluster handler. The RTIC
+//! tasks stay in the examples and call these with their resources.
+//!
+//! Uses the `psila-service` additions listed in `doc/psila-service.md`.
+
+use bbqueue::{Consumer, Producer};
+use psila_crypto::CryptoBackend;
+use psila_data::{
+    cluster_library::{Direction, FrameType},
+    ExtendedAddress, Key,
+};
+use psila_nrf52::radio::{self, Radio, MAX_PACKET_LENGHT};
+use psila_service::{
+    self, ClusterLibraryHandler, CommandDestination, NetworkIdentity, PsilaService,
+};
+
+use crate::{
+    binding::{BindingDestination, BindingTable},
+    csma::Backoff,
+    network::NetworkState,
+    outbox::{Message, Recipient},
+};
+
+/// Short address and endpoint of the coordinator, the destination of
+/// commands to bound devices when the cluster is not bound
+pub const COORDINATOR: (u16, u8) = (0x0000, 0x01);
+
+/// A cluster handler that queues commands to send
+///
+/// `B` is the capacity of the binding table.
+pub trait Outgoing<const B: usize> {
+    /// Take the next queued command to send
+    fn take_message(&mut self) -> Option<Message>;
+    /// Bindings, the destinations of commands to bound devices
+    fn binding_table(&self) -> BindingTable<B>;
+}
+
+impl NetworkState {
+    /// Network state from the identity of the service
+    pub fn from_identity(channel: u8, identity: &NetworkIdentity) -> Self {
+        Self {
+            channel,
+            pan_identifier: identity.pan_identifier,
+            extended_pan_identifier: identity.extended_pan_identifier,
+            short_address: identity.short_address,
+            parent_short_address: identity.parent_short_address,
+            parent_extended_address: identity.parent_extended_address,
+            network_key: identity.network_key.into(),
+            network_key_sequence: identity.network_key_sequence,
+            network_frame_counter: identity.network_frame_counter,
+            application_frame_counter: identity.application_frame_counter,
+        }
+    }
+
+    /// Service identity from a stored network state
+    pub fn identity(&self) -> NetworkIdentity {
+        NetworkIdentity {
+            pan_identifier: self.pan_identifier,
+            extended_pan_identifier: self.extended_pan_identifier,
+            short_address: self.short_address,
+            parent_short_address: self.parent_short_address,
+            parent_extended_address: self.parent_extended_address,
+            network_key: Key::from(self.network_key),
+            network_key_sequence: self.network_key_sequence,
+            network_frame_counter: self.network_frame_counter,
+            application_frame_counter: self.application_frame_counter,
+        }
+    }
+}
+
+/// Compare the network of the service with the stored network state
+///
+/// Returns the state to store, see `NetworkState::track`, or `None` while
+/// the service is not joined. The channel of the stored state is kept,
+/// `channel` is used when there is none.
+pub fn network_update<CB, H, const N: usize>(
+    service: &PsilaService<'_, CB, H, N>,
+    stored: &mut Option<NetworkState>,
+    channel: u8,
+) -> Option<NetworkState>
+where
+    CB: CryptoBackend,
+    H: ClusterLibraryHandler,
+{
+    let identity = service.network_identity()?;
+    let channel = stored.map_or(channel, |state| state.channel);
+    NetworkState::track(stored, NetworkState::from_identity(channel, &identity))
+}
+
+/// Send the commands queued by the cluster handler
+///
+/// Commands to the bound destinations are sent to every destination bound
+/// to the cluster of the source endpoint, or to the short address and
+/// endpoint `unbound` if the cluster is not bound. Without `unbound` such
+/// commands are dropped. Returns true if any command was queued.
+pub fn send_messages<CB, H, const N: usize, const B: usize>(
+    service: &mut PsilaService<'_, CB, H, N>,
+    unbound: Option<(u16, u8)>,
+) -> bool
+where
+    CB: CryptoBackend,
+    H: ClusterLibraryHandler + Outgoing<B>,
+{
+    let bindings = service.cluster_library_handler_mut().binding_table();
+    let mut sent = false;
+    while let Some(message) = service.cluster_library_handler_mut().take_message() {
+        sent = true;
+        match message.recipient {
+            Recipient::Reply => send_command(service, CommandDestination::Reply, &message),
+            Recipient::Device { address, endpoint } => send_command(
+                service,
+                CommandDestination::Device(address, endpoint),
+                &message,
+            ),
+            Recipient::Group(group) => {
+                send_command(service, CommandDestination::Group(group), &message)
+            }
+            Recipient::Bound => {
+                let mut bound = false;
+                for destination in bindings.destinations(message.endpoint, message.cluster) {
+                    let destination = match destination {
+                        BindingDestination::Group(group) => CommandDestination::Group(group),
+                        BindingDestination::Device { address, endpoint } => {
+                            CommandDestination::Extended(ExtendedAddress::new(address), endpoint)
+                        }
+                    };
+                    send_command(service, destination, &message);
+                    bound = true;
+                }
+                match unbound {
+                    Some((address, endpoint)) if !bound => send_command(
+                        service,
+                        CommandDestination::Device(address, endpoint),
+                        &message,
+                    ),
+                    _ => (),
+                }
+            }
+        }
+    }
+    sent
+}
+
+/// Send a command to a destination
+pub fn send_command<CB, H, const N: usize>(
+    service: &mut PsilaService<'_, CB, H, N>,
+    destination: CommandDestination,
+    message: &Message,
+) where
+    CB: CryptoBackend,
+    H: ClusterLibraryHandler,
+{
+    let frame_type = if message.general {
+        FrameType::Global
+    } else {
+        FrameType::Local
+    };
+    let direction = if message.from_server {
+        Direction::ToClient
+    } else {
+        Direction::ToServer
+    };
+    if service
+        .send_cluster_command(
+            destination,
+            message.profile,
+            message.cluster,
+            message.endpoint,
+            frame_type,
+            direction,
+            message.command,
+            message.payload(),
+        )
+        .is_err()
+    {
+        defmt::warn!("Failed to send command");
+    }
+}
+
+/// Take a received frame from the radio, for the radio interrupt
+///
+/// The frame is acknowledged by the service and, if it is for this device,
+/// queued for `handle_frame`. The radio error is returned to the caller,
+/// which decides what to do about a busy channel.
+pub fn receive_frame<CB, H, const N: usize, const R: usize>(
+    radio: &mut Radio,
+    service: &mut PsilaService<'_, CB, H, N>,
+    queue: &mut Producer<'_, R>,
+) -> Result<(), radio::Error>
+where
+    CB: CryptoBackend,
+    H: ClusterLibraryHandler,
+{
+    let mut packet = [0u8; MAX_PACKET_LENGHT as usize];
+    let packet_len = radio.receive(&mut packet)?;
+    if packet_len > 0 {
+        match service.handle_acknowledge(&packet[1..packet_len - 1]) {
+            Ok(to_me) => {
+                if to_me {
+                    if let Ok(mut grant) = queue.grant_exact(packet_len) {
+                        grant.copy_from_slice(&packet[..packet_len]);
+                        grant.commit(packet_len);
+                    }
+                }
+            }
+            Err(psila_service::Error::MalformedPacket) => {
+                defmt::warn!("service handle acknowledge failed, malformed package");
+            }
+            Err(psila_service::Error::NotEnoughSpace) => {
+                defmt::warn!("service handle acknowledge failed, queue full");
+            }
+            Err(_) => {
+                defmt::warn!("service handle acknowledge failed");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pass the next frame queued by `receive_frame` to the service
+///
+/// Returns false if there was no frame.
+pub fn handle_frame<CB, H, const N: usize, const R: usize>(
+    service: &mut PsilaService<'_, CB, H, N>,
+    queue: &mut Consumer<'_, R>,
+    timestamp: u32,
+) -> bool
+where
+    CB: CryptoBackend,
+    H: ClusterLibraryHandler,
+{
+    match queue.read() {
+        Ok(grant) => {
+            let packet_length = grant[0] as usize;
+            if service
+                .receive(timestamp, &grant[1..packet_length - 1])
+                .is_err()
+            {
+                defmt::warn!("service receive failed");
+            }
+            grant.release(packet_length);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Queue the next frame of `queue` for transmission
+///
+/// The frames are prefixed with their length, as written by the service.
+/// Returns false if there was no frame.
+pub fn transmit<const T: usize>(radio: &mut Radio, queue: &mut Consumer<'_, T>) -> bool {
+    match queue.read() {
+        Ok(grant) => {
+            let packet_length = grant[0] as usize;
+            let _ = radio.queue_transmission(&grant[1..=packet_length]);
+            grant.release(packet_length + 1);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Queue the next frame of `queue` for transmission, with CSMA-CA
+///
+/// As `transmit`, the frame is also kept by `backoff` for a retry after a
+/// busy channel.
+pub fn transmit_with_backoff<const T: usize, const F: usize>(
+    radio: &mut Radio,
+    queue: &mut Consumer<'_, T>,
+    backoff: &mut Backoff<F>,
+) -> bool {
+    match queue.read() {
+        Ok(grant) => {
+            let packet_length = grant[0] as usize;
+            let data = &grant[1..=packet_length];
+            let _ = radio.queue_transmission(data);
+            backoff.transmitted(data);
+            grant.release(packet_length + 1);
+            true
+        }
+        Err(_) => false,
+    }
+}
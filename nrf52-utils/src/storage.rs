@@ -0,0 +1,133 @@
+//! Page based storage
+//!
+//! Abstraction of a NOR flash like memory. The memory is divided into pages
+//! which are erased as a whole, an erased page reads as `0xff`. Writes are
+//! word aligned and can only clear bits.
+
+/// Size of a storage word in bytes, the smallest unit that can be written
+pub const WORD_SIZE: usize = 4;
+
+/// Errors from storage operations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The offset or length is not word aligned
+    Unaligned,
+    /// The access is outside of the storage
+    OutOfBounds,
+}
+
+/// Page based storage
+pub trait Storage {
+    /// Size of a page in bytes
+    fn page_size(&self) -> usize;
+    /// Number of pages
+    fn page_count(&self) -> usize;
+    /// Read from `offset` into `data`
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<(), Error>;
+    /// Write `data` at `offset`
+    ///
+    /// The offset must be word aligned. A trailing partial word is padded with
+    /// `0xff`.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+    /// Erase a page
+    fn erase_page(&mut self, page: usize) -> Result<(), Error>;
+}
+
+/// Storage backed by RAM
+///
+/// Behaves as flash, writes can only clear bits. Useful for testing on the host.
+pub struct RamStorage<const PAGE_SIZE: usize, const PAGES: usize> {
+    pages: [[u8; PAGE_SIZE]; PAGES],
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> RamStorage<PAGE_SIZE, PAGES> {
+    /// Create a new storage with all pages erased
+    pub fn new() -> Self {
+        Self {
+            pages: [[0xff; PAGE_SIZE]; PAGES],
+        }
+    }
+
+    fn check(&self, offset: usize, length: usize) -> Result<(), Error> {
+        if offset + length > PAGE_SIZE * PAGES {
+            Err(Error::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> Default for RamStorage<PAGE_SIZE, PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> Storage for RamStorage<PAGE_SIZE, PAGES> {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        PAGES
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<(), Error> {
+        self.check(offset, data.len())?;
+        for (n, byte) in data.iter_mut().enumerate() {
+            let position = offset + n;
+            *byte = self.pages[position / PAGE_SIZE][position % PAGE_SIZE];
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        if !offset.is_multiple_of(WORD_SIZE) {
+            return Err(Error::Unaligned);
+        }
+        self.check(offset, data.len())?;
+        for (n, byte) in data.iter().enumerate() {
+            let position = offset + n;
+            self.pages[position / PAGE_SIZE][position % PAGE_SIZE] &= *byte;
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), Error> {
+        if page >= PAGES {
+            return Err(Error::OutOfBounds);
+        }
+        self.pages[page] = [0xff; PAGE_SIZE];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_storage() {
+        let mut storage: RamStorage<16, 2> = RamStorage::new();
+        assert_eq!(storage.page_size(), 16);
+        assert_eq!(storage.page_count(), 2);
+        let mut data = [0u8; 4];
+        storage.read(16, &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+
+        // Writes can only clear bits
+        storage.write(16, &[0x0f, 0xf0, 0x00]).unwrap();
+        storage.write(16, &[0xf3, 0x3f, 0xff, 0x00]).unwrap();
+        storage.read(16, &mut data).unwrap();
+        assert_eq!(data, [0x03, 0x30, 0x00, 0x00]);
+
+        assert_eq!(storage.write(18, &[0]), Err(Error::Unaligned));
+        assert_eq!(storage.write(28, &[0; 8]), Err(Error::OutOfBounds));
+        assert_eq!(storage.read(30, &mut data), Err(Error::OutOfBounds));
+        assert_eq!(storage.erase_page(2), Err(Error::OutOfBounds));
+
+        storage.erase_page(1).unwrap();
+        storage.read(16, &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+    }
+}
@@ -0,0 +1,295 @@
+//! LED strips
+//!
+//! A strip of WS2812 LEDs is split into segments of about equal length, each
+//! with its own colour. A strip driven as a single light has one segment.
+//!
+//! The current of the strip is estimated from the colours, and the
+//! brightness of all LEDs is scaled down when it would exceed the power
+//! budget. The colours are written to anything that implements
+//! `SmartLedsWrite`.
+
+use core::ops::Range;
+
+use smart_leds_trait::{SmartLedsWrite, RGB8};
+
+/// Current of one colour of a WS2812 at full brightness, in microamperes
+pub const WS2812_CHANNEL_CURRENT: u32 = 20_000;
+/// Current of a WS2812 that is dark, in microamperes
+pub const WS2812_IDLE_CURRENT: u32 = 1_000;
+
+/// Largest current the strip may draw
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerBudget {
+    /// Current of one colour of an LED at full brightness, in microamperes
+    pub channel_current: u32,
+    /// Current of an LED that is dark, in microamperes
+    pub idle_current: u32,
+    /// Largest current of the strip, in milliamperes
+    pub limit: u32,
+}
+
+impl PowerBudget {
+    /// Budget of a WS2812 strip, with the limit in milliamperes
+    pub const fn new(limit: u32) -> Self {
+        Self {
+            channel_current: WS2812_CHANNEL_CURRENT,
+            idle_current: WS2812_IDLE_CURRENT,
+            limit,
+        }
+    }
+}
+
+/// Scale a colour, 255 keeps the colour
+fn scale_colour(colour: RGB8, scale: u8) -> RGB8 {
+    let scale = |value: u8| ((u16::from(value) * u16::from(scale)) / 255) as u8;
+    RGB8 {
+        r: scale(colour.r),
+        g: scale(colour.g),
+        b: scale(colour.b),
+    }
+}
+
+/// A strip of `LENGTH` LEDs in `SEGMENTS` segments
+pub struct Strip<const LENGTH: usize, const SEGMENTS: usize> {
+    colours: [RGB8; SEGMENTS],
+    budget: PowerBudget,
+}
+
+impl<const LENGTH: usize, const SEGMENTS: usize> Strip<LENGTH, SEGMENTS> {
+    /// A dark strip
+    pub const fn new(budget: PowerBudget) -> Self {
+        Self {
+            colours: [RGB8 { r: 0, g: 0, b: 0 }; SEGMENTS],
+            budget,
+        }
+    }
+
+    /// The LEDs of a segment, the lengths of the segments differ by at most
+    /// one LED
+    pub fn segment(segment: usize) -> Range<usize> {
+        (segment * LENGTH / SEGMENTS)..((segment + 1) * LENGTH / SEGMENTS)
+    }
+
+    /// Colour of a segment, before scaling
+    pub fn colour(&self, segment: usize) -> RGB8 {
+        self.colours[segment]
+    }
+
+    /// Set the colour of a segment
+    pub fn set_colour(&mut self, segment: usize, colour: RGB8) {
+        self.colours[segment] = colour;
+    }
+
+    /// Estimated current of the colours before scaling, in microamperes
+    fn lit_current(&self) -> u64 {
+        let sum: u64 = self
+            .colours
+            .iter()
+            .enumerate()
+            .map(|(segment, colour)| {
+                let length = Self::segment(segment).len() as u64;
+                let sum = u64::from(colour.r) + u64::from(colour.g) + u64::from(colour.b);
+                length * sum
+            })
+            .sum();
+        sum * u64::from(self.budget.channel_current) / 255
+    }
+
+    /// Estimated current of the LEDs when dark, in microamperes
+    fn idle_current(&self) -> u64 {
+        LENGTH as u64 * u64::from(self.budget.idle_current)
+    }
+
+    /// Estimated current of the strip before scaling, in milliamperes
+    pub fn current(&self) -> u32 {
+        let current = self.lit_current() + self.idle_current();
+        current.div_ceil(1000) as u32
+    }
+
+    /// Brightness scale that keeps the strip within the budget, 255 is full
+    /// brightness
+    pub fn scale(&self) -> u8 {
+        let lit = self.lit_current();
+        let available = (u64::from(self.budget.limit) * 1000).saturating_sub(self.idle_current());
+        if lit <= available {
+            255
+        } else {
+            (available * 255 / lit) as u8
+        }
+    }
+
+    /// Colours of the LEDs in order, scaled to the budget
+    pub fn pixels(&self) -> impl Iterator<Item = RGB8> + '_ {
+        let scale = self.scale();
+        self.colours
+            .iter()
+            .enumerate()
+            .flat_map(move |(segment, colour)| {
+                let colour = scale_colour(*colour, scale);
+                Self::segment(segment).map(move |_| colour)
+            })
+    }
+
+    /// Write the colours of the LEDs
+    pub fn write<W>(&self, leds: &mut W) -> Result<(), W::Error>
+    where
+        W: SmartLedsWrite<Color = RGB8>,
+    {
+        leds.write(self.pixels())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the colours written to it
+    struct Recorder {
+        pixels: [RGB8; 64],
+        length: usize,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Self {
+                pixels: [RGB8 { r: 0, g: 0, b: 0 }; 64],
+                length: 0,
+            }
+        }
+
+        fn pixels(&self) -> &[RGB8] {
+            &self.pixels[..self.length]
+        }
+
+        /// Estimated current of the written colours, in microamperes
+        fn current(&self, budget: &PowerBudget) -> u64 {
+            self.pixels()
+                .iter()
+                .map(|pixel| {
+                    let sum = u64::from(pixel.r) + u64::from(pixel.g) + u64::from(pixel.b);
+                    sum * u64::from(budget.channel_current) / 255 + u64::from(budget.idle_current)
+                })
+                .sum()
+        }
+    }
+
+    impl SmartLedsWrite for Recorder {
+        type Error = ();
+        type Color = RGB8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), ()>
+        where
+            T: Iterator<Item = I>,
+            I: Into<RGB8>,
+        {
+            self.length = 0;
+            for colour in iterator {
+                self.pixels[self.length] = colour.into();
+                self.length += 1;
+            }
+            Ok(())
+        }
+    }
+
+    const WHITE: RGB8 = RGB8 {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+
+    fn colour(segment: usize) -> RGB8 {
+        let value = segment as u8 + 1;
+        RGB8 {
+            r: value,
+            g: 0,
+            b: value,
+        }
+    }
+
+    /// Segments cover the strip in order, and differ by at most one LED
+    fn check_segments<const LENGTH: usize, const SEGMENTS: usize>() {
+        let mut strip = Strip::<LENGTH, SEGMENTS>::new(PowerBudget::new(10_000));
+        for segment in 0..SEGMENTS {
+            strip.set_colour(segment, colour(segment));
+        }
+        let mut leds = Recorder::new();
+        strip.write(&mut leds).unwrap();
+        assert_eq!(leds.pixels().len(), LENGTH);
+
+        let mut start = 0;
+        for segment in 0..SEGMENTS {
+            let range = Strip::<LENGTH, SEGMENTS>::segment(segment);
+            assert_eq!(range.start, start);
+            let length = range.len();
+            assert!(length == LENGTH / SEGMENTS || length == LENGTH / SEGMENTS + 1);
+            assert!(leds.pixels()[range.clone()]
+                .iter()
+                .all(|pixel| *pixel == colour(segment)));
+            start = range.end;
+        }
+        assert_eq!(start, LENGTH);
+    }
+
+    #[test]
+    fn segments() {
+        check_segments::<10, 3>();
+        check_segments::<11, 4>();
+        check_segments::<30, 7>();
+        check_segments::<5, 5>();
+        check_segments::<8, 1>();
+        assert_eq!(Strip::<10, 3>::segment(0), 0..3);
+        assert_eq!(Strip::<10, 3>::segment(1), 3..6);
+        assert_eq!(Strip::<10, 3>::segment(2), 6..10);
+    }
+
+    #[test]
+    fn within_budget() {
+        let budget = PowerBudget::new(2_000);
+        let mut strip = Strip::<30, 3>::new(budget);
+        strip.set_colour(0, WHITE);
+        strip.set_colour(1, RGB8 { r: 255, g: 0, b: 0 });
+        assert_eq!(strip.scale(), 255);
+        assert!(strip.current() <= budget.limit);
+        let mut leds = Recorder::new();
+        strip.write(&mut leds).unwrap();
+        assert!(leds.pixels()[..10].iter().all(|pixel| *pixel == WHITE));
+        assert!(leds.current(&budget) <= u64::from(budget.limit) * 1000);
+    }
+
+    #[test]
+    fn scaled_to_limit() {
+        for &limit in [100, 250, 500, 1_000, 1_234].iter() {
+            let budget = PowerBudget::new(limit);
+            let mut strip = Strip::<30, 3>::new(budget);
+            for segment in 0..3 {
+                strip.set_colour(segment, WHITE);
+            }
+            assert!(strip.current() > limit);
+            assert!(strip.scale() < 255);
+            let mut leds = Recorder::new();
+            strip.write(&mut leds).unwrap();
+            let current = leds.current(&budget);
+            assert!(current <= u64::from(limit) * 1000);
+            // Scaled down no more than needed, a step of the scale and the
+            // rounding of each colour change the current of the strip by
+            // up to this much
+            let step = 2 * 30 * 3 * u64::from(budget.channel_current) / 255;
+            assert!(current + step >= u64::from(limit) * 1000);
+        }
+    }
+
+    #[test]
+    fn limit_below_idle() {
+        // 30 dark LEDs already draw 30 mA
+        let budget = PowerBudget::new(20);
+        let mut strip = Strip::<30, 1>::new(budget);
+        strip.set_colour(0, WHITE);
+        assert_eq!(strip.scale(), 0);
+        let mut leds = Recorder::new();
+        strip.write(&mut leds).unwrap();
+        assert!(leds
+            .pixels()
+            .iter()
+            .all(|pixel| *pixel == RGB8 { r: 0, g: 0, b: 0 }));
+    }
+}
@@ -0,0 +1,146 @@
+//! Temperature measurement
+//!
+//! Conversion of the readings of the nRF52840 TEMP peripheral to the
+//! MeasuredValue attribute of the temperature measurement cluster, with a
+//! filter that smooths out the noise of the readings.
+
+/// Temperature measurement cluster
+pub const CLUSTER_TEMPERATURE_MEASUREMENT: u16 = 0x0402;
+/// Temperature measurement cluster attribute, measured value
+pub const TEMPERATURE_ATTR_MEASURED_VALUE: u16 = 0x0000;
+/// Temperature measurement cluster attribute, minimum measured value
+pub const TEMPERATURE_ATTR_MIN_MEASURED_VALUE: u16 = 0x0001;
+/// Temperature measurement cluster attribute, maximum measured value
+pub const TEMPERATURE_ATTR_MAX_MEASURED_VALUE: u16 = 0x0002;
+/// Temperature measurement cluster attribute, tolerance
+pub const TEMPERATURE_ATTR_TOLERANCE: u16 = 0x0003;
+
+/// Measured value when there is no valid measurement
+pub const MEASURED_VALUE_UNKNOWN: i16 = -0x8000;
+/// Lowest temperature of the sensor, in 0.01 °C
+pub const MIN_MEASURED_VALUE: i16 = -4000;
+/// Highest temperature of the sensor, in 0.01 °C
+pub const MAX_MEASURED_VALUE: i16 = 8500;
+/// Accuracy of the sensor, in 0.01 °C
+pub const TOLERANCE: u16 = 500;
+
+/// Number of readings the filter averages over, as a power of two
+const FILTER_SHIFT: u32 = 2;
+
+/// Convert a TEMP peripheral reading, in 0.25 °C, to 0.01 °C
+///
+/// The value is limited to the range of the sensor.
+pub fn raw_to_measured_value(raw: i32) -> i16 {
+    let value = raw.saturating_mul(25);
+    value.clamp(i32::from(MIN_MEASURED_VALUE), i32::from(MAX_MEASURED_VALUE)) as i16
+}
+
+/// Exponential moving average of temperature readings
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Filter {
+    /// The average scaled by 2^`FILTER_SHIFT`
+    sum: Option<i32>,
+}
+
+impl Filter {
+    pub const fn new() -> Self {
+        Self { sum: None }
+    }
+
+    /// Add a TEMP peripheral reading, returns the filtered measured value
+    ///
+    /// The first reading is used as is.
+    pub fn update(&mut self, raw: i32) -> i16 {
+        let sample = i32::from(raw_to_measured_value(raw));
+        let sum = match self.sum {
+            Some(sum) => sum - (sum >> FILTER_SHIFT) + sample,
+            None => sample << FILTER_SHIFT,
+        };
+        self.sum = Some(sum);
+        self.value()
+    }
+
+    /// Check if there has been a reading
+    pub fn is_valid(&self) -> bool {
+        self.sum.is_some()
+    }
+
+    /// The filtered measured value, `MEASURED_VALUE_UNKNOWN` before the first
+    /// reading
+    pub fn value(&self) -> i16 {
+        match self.sum {
+            Some(sum) => (sum >> FILTER_SHIFT) as i16,
+            None => MEASURED_VALUE_UNKNOWN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion() {
+        assert_eq!(raw_to_measured_value(0), 0);
+        assert_eq!(raw_to_measured_value(1), 25);
+        assert_eq!(raw_to_measured_value(2), 50);
+        assert_eq!(raw_to_measured_value(93), 2325);
+        assert_eq!(raw_to_measured_value(-1), -25);
+        assert_eq!(raw_to_measured_value(-3), -75);
+        assert_eq!(raw_to_measured_value(-41), -1025);
+        // Limited to the range of the sensor
+        assert_eq!(raw_to_measured_value(-160), MIN_MEASURED_VALUE);
+        assert_eq!(raw_to_measured_value(-161), MIN_MEASURED_VALUE);
+        assert_eq!(raw_to_measured_value(i32::MIN), MIN_MEASURED_VALUE);
+        assert_eq!(raw_to_measured_value(340), MAX_MEASURED_VALUE);
+        assert_eq!(raw_to_measured_value(341), MAX_MEASURED_VALUE);
+        assert_eq!(raw_to_measured_value(i32::MAX), MAX_MEASURED_VALUE);
+    }
+
+    /// Feed `raw` until the filter reaches its value, returns the number of
+    /// readings
+    fn converge(filter: &mut Filter, raw: i32) -> usize {
+        let target = raw_to_measured_value(raw);
+        let mut previous = filter.value();
+        for readings in 1..=64 {
+            let value = filter.update(raw);
+            // The value moves towards the reading without overshooting
+            if target >= previous {
+                assert!(value >= previous && value <= target);
+            } else {
+                assert!(value <= previous && value >= target);
+            }
+            if value == target {
+                return readings;
+            }
+            previous = value;
+        }
+        panic!("no convergence to {}", target);
+    }
+
+    #[test]
+    fn filter() {
+        let mut filter = Filter::new();
+        assert!(!filter.is_valid());
+        assert_eq!(filter.value(), MEASURED_VALUE_UNKNOWN);
+        // The first reading is used as is
+        assert_eq!(filter.update(-3), -75);
+        assert!(filter.is_valid());
+        // A quarter of the step per reading
+        assert_eq!(filter.update(-3 + 16), -75 + 100);
+
+        let readings = converge(&mut filter, 100);
+        assert!(readings > 4 && readings < 40);
+        // Settled, the same reading keeps the value
+        for _ in 0..8 {
+            assert_eq!(filter.update(100), 2500);
+        }
+        let readings = converge(&mut filter, -41);
+        assert!(readings > 4 && readings < 40);
+        for _ in 0..8 {
+            assert_eq!(filter.update(-41), -1025);
+        }
+        // Single readings of noise move the value by a quarter
+        assert_eq!(filter.update(-37), -1025 + 25);
+    }
+}
@@ -0,0 +1,51 @@
+//! Transitions
+//!
+//! Linear transition of a set of values over a number of ticks, used to
+//! smoothly change the level and colour of a light.
+
+/// Linear transition of `N` values
+#[derive(Clone, Copy, Debug)]
+pub struct Transition<const N: usize> {
+    from: [u16; N],
+    to: [u16; N],
+    ticks: u32,
+    elapsed: u32,
+}
+
+impl<const N: usize> Transition<N> {
+    /// Transition from `from` to `to` in `ticks` ticks
+    pub fn new(from: [u16; N], to: [u16; N], ticks: u32) -> Self {
+        Self {
+            from,
+            to,
+            ticks,
+            elapsed: 0,
+        }
+    }
+
+    /// Check if the target values have been reached
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.ticks
+    }
+
+    /// The target values
+    pub fn target(&self) -> [u16; N] {
+        self.to
+    }
+
+    /// Advance the transition by one tick, returns the values for the tick
+    pub fn tick(&mut self) -> [u16; N] {
+        if self.is_finished() {
+            return self.to;
+        }
+        self.elapsed += 1;
+        let mut values = [0u16; N];
+        for (n, value) in values.iter_mut().enumerate() {
+            let from = i64::from(self.from[n]);
+            let to = i64::from(self.to[n]);
+            let delta = (to - from) * i64::from(self.elapsed) / i64::from(self.ticks);
+            *value = (from + delta) as u16;
+        }
+        values
+    }
+}
@@ -0,0 +1,165 @@
+//! Crypto test vectors
+//!
+//! Known answers for AES-128, CCM* and the Matyas-Meyer-Oseas hash, shared by
+//! the checks of the crypto backends.
+
+/// AES-128 encryption of a single block
+pub struct BlockVector {
+    pub key: [u8; 16],
+    pub clear_text: [u8; 16],
+    pub cipher_text: [u8; 16],
+}
+
+/// CCM* encryption with a message integrity code
+pub struct CcmVector {
+    pub key: [u8; 16],
+    pub nonce: [u8; 13],
+    pub additional_data: &'static [u8],
+    pub message: &'static [u8],
+    pub mic_length: usize,
+    /// Encrypted message followed by the message integrity code
+    pub output: &'static [u8],
+}
+
+/// Matyas-Meyer-Oseas hash
+pub struct HashVector {
+    pub data: &'static [u8],
+    pub hash: [u8; 16],
+}
+
+/// FIPS-197, appendix C.1
+pub const BLOCK_VECTORS: [BlockVector; 1] = [BlockVector {
+    key: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ],
+    clear_text: [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ],
+    cipher_text: [
+        0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5,
+        0x5a,
+    ],
+}];
+
+pub const CCM_VECTORS: [CcmVector; 2] = [
+    // Zigbee specification, annex C.6.1
+    CcmVector {
+        key: [
+            0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xcb, 0xcc, 0xcd,
+            0xce, 0xcf,
+        ],
+        nonce: [
+            0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0x03, 0x02, 0x01, 0x00, 0x06,
+        ],
+        additional_data: &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+        message: &[
+            0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15,
+            0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e,
+        ],
+        mic_length: 8,
+        output: &[
+            0x1a, 0x55, 0xa3, 0x6a, 0xbb, 0x6c, 0x61, 0x0d, 0x06, 0x6b, 0x33, 0x75, 0x64, 0x9c,
+            0xef, 0x10, 0xd4, 0x66, 0x4e, 0xca, 0xd8, 0x54, 0xa8, 0x0a, 0x89, 0x5c, 0xc1, 0xd8,
+            0xff, 0x94, 0x69,
+        ],
+    },
+    // A network layer frame with security level 5, four byte MIC
+    CcmVector {
+        key: [
+            0x01, 0x03, 0x05, 0x07, 0x09, 0x0b, 0x0d, 0x0f, 0x00, 0x02, 0x04, 0x06, 0x08, 0x0a,
+            0x0c, 0x0d,
+        ],
+        nonce: [
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x02, 0x01, 0x00, 0x00, 0x2d,
+        ],
+        additional_data: &[
+            0x48, 0x02, 0x00, 0x00, 0xaf, 0x8e, 0x05, 0x1e, 0x2d, 0x02, 0x01, 0x00, 0x00, 0x88,
+            0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+        ],
+        message: &[0x12, 0x00, 0x3f, 0xab, 0x0b, 0x00, 0x00, 0x0d],
+        mic_length: 4,
+        output: &[
+            0x05, 0xc0, 0xc2, 0xc1, 0xc9, 0xb7, 0x9e, 0x1f, 0x9f, 0x5a, 0x13, 0xde,
+        ],
+    },
+];
+
+pub const HASH_VECTORS: [HashVector; 2] = [
+    // Zigbee install code example
+    HashVector {
+        data: &[
+            0x83, 0xfe, 0xd3, 0x40, 0x7a, 0x93, 0x97, 0x23, 0xa5, 0xc6, 0x39, 0xb2, 0x69, 0x16,
+            0xd5, 0x05, 0xc3, 0xb5,
+        ],
+        hash: [
+            0x66, 0xb6, 0x90, 0x09, 0x81, 0xe1, 0xee, 0x3c, 0xa4, 0x20, 0x6b, 0x6b, 0x86, 0x1c,
+            0x02, 0xbb,
+        ],
+    },
+    // Padding and length only
+    HashVector {
+        data: &[],
+        hash: [
+            0xba, 0xd7, 0x8e, 0x72, 0x6c, 0x1e, 0xc0, 0x2b, 0x7e, 0xbf, 0xe9, 0x2b, 0x23, 0xd9,
+            0xec, 0x34,
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ccm,
+        cipher::{mmo_hash, BlockCipher, SoftAes},
+    };
+
+    #[test]
+    fn blocks() {
+        for vector in BLOCK_VECTORS.iter() {
+            let mut block = vector.clear_text;
+            SoftAes.encrypt_block(&vector.key, &mut block);
+            assert_eq!(block, vector.cipher_text);
+        }
+    }
+
+    #[test]
+    fn ccm() {
+        for vector in CCM_VECTORS.iter() {
+            let mut output = [0u8; 64];
+            let length = ccm::encrypt(
+                &mut SoftAes,
+                &vector.key,
+                &vector.nonce,
+                vector.message,
+                vector.mic_length,
+                vector.additional_data,
+                &mut output,
+            )
+            .unwrap();
+            assert_eq!(&output[..length], vector.output);
+
+            let mut clear = [0u8; 64];
+            let length = ccm::decrypt(
+                &mut SoftAes,
+                &vector.key,
+                &vector.nonce,
+                vector.output,
+                vector.mic_length,
+                vector.additional_data,
+                &mut clear,
+            )
+            .unwrap();
+            assert_eq!(&clear[..length], vector.message);
+        }
+    }
+
+    #[test]
+    fn hashes() {
+        for vector in HASH_VECTORS.iter() {
+            assert_eq!(mmo_hash(&mut SoftAes, vector.data), vector.hash);
+        }
+    }
+}
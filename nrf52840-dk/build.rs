@@ -0,0 +1,5 @@
+fn main() {
+    nrf52_build::build_info();
+    nrf52_build::storage_script("storage.x");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,1272 @@
+#![no_main]
+#![no_std]
+
+use nrf52840_dk::build_info;
+
+use rtic::app;
+
+use nrf52840_hal::gpio;
+
+use nrf52840_pac as pac;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::{
+    cluster_library::{AttributeDataType, ClusterLibraryStatus, Destination},
+    device_profile::SimpleDescriptor,
+};
+use psila_service::{self, ClusterLibraryHandler};
+
+use nrf52_utils::{
+    attribute::{Attribute, AttributeTable, Value},
+    basic::{
+        DeviceIdentity, BASIC_ATTR_APPLICATION_VERSION, BASIC_ATTR_DATE_CODE,
+        BASIC_ATTR_HW_VERSION, BASIC_ATTR_MANUFACTURER_NAME, BASIC_ATTR_MODEL_IDENTIFIER,
+        BASIC_ATTR_POWER_SOURCE, BASIC_ATTR_STACK_VERSION, BASIC_ATTR_SW_BUILD_ID,
+        BASIC_ATTR_ZCL_VERSION, CLUSTER_BASIC, POWER_SOURCE_MAINS, ZCL_VERSION,
+    },
+    binding::BindingTable,
+    discovery::{self, is_discovery_command, ClusterCommands},
+    groups::{
+        GroupTable, CLUSTER_GROUPS, GROUPS_ATTR_NAME_SUPPORT, GROUPS_COMMANDS_GENERATED,
+        GROUPS_COMMANDS_RECEIVED,
+    },
+    identify::{Effect, Identify, Indication},
+    light::{LightState, StartUpOnOff},
+    outbox::{Message, Outbox, Recipient},
+    reporting::{ReportingTable, CMD_CONFIGURE_REPORTING, CMD_READ_REPORTING_CONFIGURATION},
+    reset::{handle_leave_request, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS},
+    scenes::{
+        SceneState, SceneTable, CLUSTER_SCENES, SCENES_ATTR_CURRENT_GROUP,
+        SCENES_ATTR_CURRENT_SCENE, SCENES_ATTR_NAME_SUPPORT, SCENES_ATTR_SCENE_COUNT,
+        SCENES_ATTR_SCENE_VALID, SCENES_COMMANDS_GENERATED, SCENES_COMMANDS_RECEIVED,
+    },
+    service::Outgoing,
+};
+
+const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
+const MODEL_IDENTIFIER: &'static str = "Fyrlampan";
+/// Hardware version, the board revision
+const HW_VERSION: u8 = 1;
+/// Psila has not been released, so there is no stack version yet
+const STACK_VERSION: u8 = 0;
+
+/// Identity of the device in the basic cluster
+const IDENTITY: DeviceIdentity = DeviceIdentity {
+    manufacturer_name: MANUFACTURER_NAME,
+    model_identifier: MODEL_IDENTIFIER,
+    application_version: build_info::APPLICATION_VERSION,
+    stack_version: STACK_VERSION,
+    hw_version: HW_VERSION,
+    date_code: build_info::DATE_CODE,
+    power_source: POWER_SOURCE_MAINS,
+    sw_build_id: build_info::SW_BUILD_ID,
+};
+
+/// Home automation profile
+const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
+/// On/off light device
+const DEVICE_ON_OFF_LIGHT: u16 = 0x0100;
+
+/// Identify cluster
+const CLUSTER_IDENTIFY: u16 = 0x0003;
+/// Identify cluster attribute, identify time
+const IDENTIFY_ATTR_IDENTIFY_TIME: u16 = 0x0000;
+/// Identify cluster command, identify
+const IDENTIFY_CMD_IDENTIFY: u8 = 0x00;
+/// Identify cluster command, identify query
+const IDENTIFY_CMD_IDENTIFY_QUERY: u8 = 0x01;
+/// Identify cluster command, trigger effect
+const IDENTIFY_CMD_TRIGGER_EFFECT: u8 = 0x40;
+/// Identify cluster command, identify query response
+const IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE: u8 = 0x00;
+
+/// On/off cluster
+const CLUSTER_ON_OFF: u16 = 0x0006;
+/// On/off cluster attribute, on/off state
+const ON_OFF_ATTR_ON_OFF_STATE: u16 = 0x0000;
+/// On/off cluster attribute, start-up on/off
+const ON_OFF_ATTR_START_UP_ON_OFF: u16 = 0x4003;
+/// On/off cluster command, off
+const ON_OFF_CMD_OFF: u8 = 0x00;
+/// On/off cluster command, on
+const ON_OFF_CMD_ON: u8 = 0x01;
+/// On/off cluster command, toggle
+const ON_OFF_CMD_TOGGLE: u8 = 0x02;
+
+/// Number of channels, one LED and one endpoint each
+pub const CHANNELS: usize = 4;
+/// Endpoints of the channels
+const ENDPOINTS: [u8; CHANNELS] = [0x01, 0x02, 0x03, 0x04];
+
+/// Server clusters of the channel endpoints
+const SERVER_CLUSTERS: [u16; 5] = [
+    CLUSTER_BASIC,
+    CLUSTER_IDENTIFY,
+    CLUSTER_GROUPS,
+    CLUSTER_SCENES,
+    CLUSTER_ON_OFF,
+];
+
+/// Commands of the server clusters of the channel endpoints
+const COMMANDS: [ClusterCommands; 5] = [
+    ClusterCommands {
+        cluster: CLUSTER_BASIC,
+        received: &[BASIC_CMD_RESET_TO_FACTORY_DEFAULTS],
+        generated: &[],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_IDENTIFY,
+        received: &[
+            IDENTIFY_CMD_IDENTIFY,
+            IDENTIFY_CMD_IDENTIFY_QUERY,
+            IDENTIFY_CMD_TRIGGER_EFFECT,
+        ],
+        generated: &[IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_GROUPS,
+        received: &GROUPS_COMMANDS_RECEIVED,
+        generated: &GROUPS_COMMANDS_GENERATED,
+    },
+    ClusterCommands {
+        cluster: CLUSTER_SCENES,
+        received: &SCENES_COMMANDS_RECEIVED,
+        generated: &SCENES_COMMANDS_GENERATED,
+    },
+    ClusterCommands {
+        cluster: CLUSTER_ON_OFF,
+        received: &[ON_OFF_CMD_OFF, ON_OFF_CMD_ON, ON_OFF_CMD_TOGGLE],
+        generated: &[],
+    },
+];
+
+/// Number of group memberships that can be stored, for all channels
+pub const GROUP_CAPACITY: usize = 16;
+
+pub type Groups = GroupTable<GROUP_CAPACITY>;
+
+/// Number of scenes that can be stored per channel
+pub const SCENE_CAPACITY: usize = 8;
+
+pub type Scenes = SceneTable<SCENE_CAPACITY>;
+
+/// Number of bindings that can be stored
+pub const BINDING_CAPACITY: usize = 16;
+
+pub type Bindings = BindingTable<BINDING_CAPACITY>;
+
+/// Shortest time between reports of an attribute, in seconds
+const REPORT_MIN_INTERVAL: u16 = 1;
+/// Longest time between reports of an attribute, in seconds
+const REPORT_MAX_INTERVAL: u16 = 300;
+/// Reports are sent to the bound destinations, or to the coordinator
+const REPORT_RECIPIENT: Recipient = Recipient::Bound;
+
+/// One LED with its own on/off state, identification and scenes
+pub struct Channel {
+    endpoint: u8,
+    led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+    on_off: bool,
+    start_up_on_off: StartUpOnOff,
+    changed: bool,
+    identify: Identify,
+    scenes: Scenes,
+    scenes_changed: bool,
+}
+
+impl Channel {
+    pub fn new(
+        endpoint: u8,
+        led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        state: LightState,
+        scenes: Scenes,
+    ) -> Self {
+        let start_up = state.start_up();
+        let mut channel = Self {
+            endpoint,
+            led,
+            on_off: false,
+            start_up_on_off: state.start_up_on_off,
+            changed: false,
+            identify: Identify::new(),
+            scenes,
+            scenes_changed: false,
+        };
+        channel.apply_on_off(start_up.on_off);
+        // Store the state if the start-up attribute changed it
+        channel.changed = start_up != state;
+        channel
+    }
+
+    fn set_led(&mut self, on: bool) {
+        // The LED is active low
+        if on {
+            let _ = self.led.set_low();
+        } else {
+            let _ = self.led.set_high();
+        }
+    }
+
+    fn apply_on_off(&mut self, enable: bool) {
+        self.on_off = enable;
+        self.set_led(enable);
+        self.changed = true;
+    }
+
+    fn set_on_off(&mut self, enable: bool) {
+        self.apply_on_off(enable);
+        self.scenes.invalidate();
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => self.set_led(level >= 0x80),
+            Indication::Finished => self.set_led(self.on_off),
+            Indication::None => (),
+        }
+    }
+
+    /// Current state of the channel, to be stored
+    fn light_state(&self) -> LightState {
+        LightState {
+            on_off: self.on_off,
+            start_up_on_off: self.start_up_on_off,
+            ..LightState::default()
+        }
+    }
+}
+
+type ChannelAttribute = Attribute<Channel>;
+
+/// Attributes of a channel endpoint
+const ATTRIBUTES: AttributeTable<Channel> = AttributeTable::new(&[
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_ZCL_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(ZCL_VERSION),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_APPLICATION_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.application_version),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_STACK_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.stack_version),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_HW_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.hw_version),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MANUFACTURER_NAME,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.manufacturer_name),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MODEL_IDENTIFIER,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.model_identifier),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_DATE_CODE,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.date_code),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_POWER_SOURCE,
+        AttributeDataType::Enumeration8,
+        |_| Value::Enumeration8(IDENTITY.power_source),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_SW_BUILD_ID,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.sw_build_id),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_IDENTIFY,
+        IDENTIFY_ATTR_IDENTIFY_TIME,
+        AttributeDataType::Unsigned16,
+        |channel| Value::Unsigned16(channel.identify.identify_time()),
+    )
+    .writable(|channel, value| {
+        if let Value::Unsigned16(time) = value {
+            channel.identify.identify(time);
+        }
+        Ok(())
+    }),
+    // Group names are not supported
+    ChannelAttribute::new(
+        CLUSTER_GROUPS,
+        GROUPS_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_COUNT,
+        AttributeDataType::Unsigned8,
+        |channel| Value::Unsigned8(channel.scenes.count()),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_SCENE,
+        AttributeDataType::Unsigned8,
+        |channel| Value::Unsigned8(channel.scenes.current_scene()),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_GROUP,
+        AttributeDataType::Unsigned16,
+        |channel| Value::Unsigned16(channel.scenes.current_group()),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_VALID,
+        AttributeDataType::Boolean,
+        |channel| Value::Boolean(channel.scenes.is_valid()),
+    ),
+    // Scene names are not supported
+    ChannelAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    ChannelAttribute::new(
+        CLUSTER_ON_OFF,
+        ON_OFF_ATTR_ON_OFF_STATE,
+        AttributeDataType::Boolean,
+        |channel| Value::Boolean(channel.on_off),
+    )
+    .reportable()
+    .writable(|channel, value| {
+        if let Value::Boolean(enable) = value {
+            channel.set_on_off(enable);
+        }
+        Ok(())
+    }),
+    ChannelAttribute::new(
+        CLUSTER_ON_OFF,
+        ON_OFF_ATTR_START_UP_ON_OFF,
+        AttributeDataType::Enumeration8,
+        |channel| Value::Enumeration8(channel.start_up_on_off as u8),
+    )
+    .writable(|channel, value| match value {
+        Value::Enumeration8(value) => {
+            let start_up =
+                StartUpOnOff::from_u8(value).ok_or(ClusterLibraryStatus::InvalidValue)?;
+            channel.start_up_on_off = start_up;
+            channel.changed = true;
+            Ok(())
+        }
+        _ => Err(ClusterLibraryStatus::InvalidValue),
+    }),
+]);
+
+pub struct ClusterHandler {
+    channels: [Channel; CHANNELS],
+    groups: Groups,
+    groups_changed: bool,
+    address: u64,
+    bindings: Bindings,
+    bindings_changed: bool,
+    reset_requested: bool,
+    reporting: ReportingTable<CHANNELS>,
+    outbox: Outbox<8>,
+}
+
+impl ClusterHandler {
+    pub fn new(
+        channels: [Channel; CHANNELS],
+        groups: Groups,
+        address: u64,
+        bindings: Bindings,
+    ) -> Self {
+        let mut reporting = ReportingTable::new();
+        for endpoint in ENDPOINTS {
+            for attribute in ATTRIBUTES.reportable() {
+                reporting.add(
+                    endpoint,
+                    attribute.cluster,
+                    attribute.id,
+                    u8::from(attribute.data_type),
+                    REPORT_MIN_INTERVAL,
+                    REPORT_MAX_INTERVAL,
+                );
+            }
+        }
+        Self {
+            channels,
+            groups,
+            groups_changed: false,
+            address,
+            bindings,
+            bindings_changed: false,
+            reset_requested: false,
+            reporting,
+            outbox: Outbox::new(),
+        }
+    }
+
+    /// Toggle a channel, used by the buttons
+    pub fn toggle(&mut self, index: usize) {
+        let channel = &mut self.channels[index];
+        channel.set_on_off(!channel.on_off);
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.identify_tick();
+        }
+    }
+
+    /// Take the state of a channel that has changed since it was last taken,
+    /// to be stored
+    pub fn take_changed(&mut self) -> Option<(u8, LightState)> {
+        let channel = self.channels.iter_mut().find(|channel| channel.changed)?;
+        channel.changed = false;
+        Some((channel.endpoint, channel.light_state()))
+    }
+
+    /// Group memberships of all channels, to be stored
+    pub fn group_table(&self) -> Groups {
+        self.groups
+    }
+
+    /// Check if the group memberships have changed since last call
+    pub fn take_groups_changed(&mut self) -> bool {
+        let changed = self.groups_changed;
+        self.groups_changed = false;
+        changed
+    }
+
+    /// Take the scenes of a channel that have changed since they were last
+    /// taken, to be stored
+    pub fn take_scenes_changed(&mut self) -> Option<(u8, Scenes)> {
+        let channel = self
+            .channels
+            .iter_mut()
+            .find(|channel| channel.scenes_changed)?;
+        channel.scenes_changed = false;
+        Some((channel.endpoint, channel.scenes))
+    }
+
+    /// Check if the bindings have changed since last call
+    pub fn take_bindings_changed(&mut self) -> bool {
+        let changed = self.bindings_changed;
+        self.bindings_changed = false;
+        changed
+    }
+
+    /// Check if a factory reset has been requested since last call, by the
+    /// basic cluster or a ZDO leave request
+    pub fn take_reset_requested(&mut self) -> bool {
+        let requested = self.reset_requested;
+        self.reset_requested = false;
+        requested
+    }
+
+    /// Queue the attribute reports that are due, called once per second
+    pub fn report_tick(&mut self) {
+        for channel in self.channels.iter() {
+            for attribute in ATTRIBUTES.reportable() {
+                if let Some(value) = (attribute.get)(channel).to_u32() {
+                    self.reporting.set_value(
+                        channel.endpoint,
+                        attribute.cluster,
+                        attribute.id,
+                        value,
+                    );
+                }
+            }
+        }
+        self.reporting
+            .tick(REPORT_RECIPIENT, PROFILE_HOME_AUTOMATION, &mut self.outbox);
+    }
+
+    /// Handle a profile wide command for one channel
+    fn run_general_channel(
+        &mut self,
+        index: usize,
+        profile: u16,
+        cluster: u16,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        let endpoint = self.channels[index].endpoint;
+        let response = match command {
+            CMD_CONFIGURE_REPORTING | CMD_READ_REPORTING_CONFIGURATION => self
+                .reporting
+                .handle_command(endpoint, profile, cluster, command, arguments)?,
+            command if is_discovery_command(command) => discovery::handle_command(
+                &ATTRIBUTES,
+                &COMMANDS,
+                endpoint,
+                profile,
+                cluster,
+                command,
+                arguments,
+            )?,
+            _ => return Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
+        };
+        self.outbox.push(response);
+        Ok(())
+    }
+
+    /// Handle a cluster specific command for one channel
+    fn run_channel(
+        &mut self,
+        index: usize,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        let channel = &mut self.channels[index];
+        let endpoint = channel.endpoint;
+        match (cluster, command) {
+            (CLUSTER_BASIC, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS) => {
+                // Leaves the network as well
+                self.reset_requested = true;
+                Ok(())
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_IDENTIFY) => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                channel
+                    .identify
+                    .identify(LittleEndian::read_u16(&arguments[0..2]));
+                Ok(())
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_IDENTIFY_QUERY) => {
+                // Only answered while identifying
+                if channel.identify.is_identifying() {
+                    let mut response = Message::new(
+                        Recipient::Reply,
+                        endpoint,
+                        profile,
+                        CLUSTER_IDENTIFY,
+                        IDENTIFY_CMD_IDENTIFY_QUERY_RESPONSE,
+                    );
+                    response.append_u16(channel.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (CLUSTER_IDENTIFY, IDENTIFY_CMD_TRIGGER_EFFECT) => {
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let effect =
+                    Effect::from_u8(arguments[0]).ok_or(ClusterLibraryStatus::InvalidValue)?;
+                channel.identify.trigger_effect(effect);
+                Ok(())
+            }
+            (CLUSTER_GROUPS, _) => {
+                let identifying = channel.identify.is_identifying();
+                let (changed, response) = self.groups.handle_command(
+                    endpoint,
+                    profile,
+                    destination,
+                    command,
+                    arguments,
+                    identifying,
+                )?;
+                if let Some(response) = response {
+                    self.outbox.push(response);
+                }
+                self.groups_changed |= changed;
+                // Scenes of removed groups are removed as well
+                channel.scenes_changed |= channel.scenes.retain_groups(endpoint, &self.groups);
+                Ok(())
+            }
+            (CLUSTER_SCENES, _) => {
+                let current = SceneState {
+                    on_off: Some(channel.on_off),
+                    ..SceneState::default()
+                };
+                let outcome = channel.scenes.handle_command(
+                    endpoint,
+                    destination,
+                    command,
+                    arguments,
+                    &self.groups,
+                    &current,
+                )?;
+                if let Some(response) = outcome.response {
+                    self.outbox.push(response);
+                }
+                if let Some(on_off) = outcome.recall.and_then(|recall| recall.state.on_off) {
+                    channel.apply_on_off(on_off);
+                }
+                channel.scenes_changed |= outcome.changed;
+                Ok(())
+            }
+            (CLUSTER_ON_OFF, ON_OFF_CMD_OFF) => {
+                channel.set_on_off(false);
+                Ok(())
+            }
+            (CLUSTER_ON_OFF, ON_OFF_CMD_ON) => {
+                channel.set_on_off(true);
+                Ok(())
+            }
+            (CLUSTER_ON_OFF, ON_OFF_CMD_TOGGLE) => {
+                channel.set_on_off(!channel.on_off);
+                Ok(())
+            }
+            (_, _) => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+    }
+}
+
+impl Outgoing<BINDING_CAPACITY> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    fn binding_table(&self) -> Bindings {
+        self.bindings
+    }
+}
+
+impl ClusterLibraryHandler for ClusterHandler {
+    fn active_endpoints(&self) -> &[u8] {
+        &ENDPOINTS
+    }
+    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
+        if !ENDPOINTS.contains(&endpoint) {
+            return None;
+        }
+        Some(SimpleDescriptor::new(
+            endpoint,
+            PROFILE_HOME_AUTOMATION,
+            DEVICE_ON_OFF_LIGHT,
+            0,
+            &SERVER_CLUSTERS,
+            &[],
+        ))
+    }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        if let Some((length, leave)) =
+            handle_leave_request(self.address, cluster, request, response)
+        {
+            self.reset_requested |= leave;
+            return Some(length);
+        }
+        let (length, changed) =
+            self.bindings
+                .handle_request(self.address, &ENDPOINTS, cluster, request, response)?;
+        self.bindings_changed |= changed;
+        Some(length)
+    }
+    fn read_attribute(
+        &self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        // Reads of a group or broadcast are answered by the first addressed
+        // channel
+        let channel = self
+            .channels
+            .iter()
+            .find(|channel| self.groups.is_addressed(destination, channel.endpoint));
+        match (profile, channel) {
+            (PROFILE_HOME_AUTOMATION, Some(channel)) => {
+                ATTRIBUTES.read(channel, cluster, attribute, value)
+            }
+            (_, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn write_attribute(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedAttribute);
+        }
+        let mut result = Ok(());
+        for channel in self.channels.iter_mut() {
+            if self.groups.is_addressed(destination, channel.endpoint) {
+                let outcome = ATTRIBUTES.write(channel, cluster, attribute, data_type, value);
+                result = result.and(outcome);
+            }
+        }
+        result
+    }
+    fn run_general(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedGeneralCommand);
+        }
+        let mut result = Ok(());
+        for index in 0..CHANNELS {
+            if self
+                .groups
+                .is_addressed(destination, self.channels[index].endpoint)
+            {
+                let outcome = self.run_general_channel(index, profile, cluster, command, arguments);
+                result = result.and(outcome);
+            }
+        }
+        result
+    }
+    fn run(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if profile != PROFILE_HOME_AUTOMATION {
+            return Err(ClusterLibraryStatus::UnsupportedClusterCommand);
+        }
+        // Each addressed channel runs the command, group commands are only
+        // for member endpoints
+        let mut result = Ok(());
+        for index in 0..CHANNELS {
+            if self
+                .groups
+                .is_addressed(destination, self.channels[index].endpoint)
+            {
+                let outcome =
+                    self.run_channel(index, profile, cluster, destination, command, arguments);
+                result = result.and(outcome);
+            }
+        }
+        result
+    }
+}
+
+#[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
+mod app {
+    use super::{
+        pac, Bindings, Channel, ClusterHandler, Groups, Scenes, BINDING_CAPACITY, CHANNELS,
+        ENDPOINTS, GROUP_CAPACITY, SCENE_CAPACITY,
+    };
+
+    use bbqueue::{self, BBBuffer};
+
+    use embedded_hal::digital::v2::InputPin;
+
+    use nrf52840_hal::{clocks, gpio, Rng};
+
+    use psila_crypto_rust_crypto::RustCryptoBackend;
+    use psila_data::{ExtendedAddress, Key};
+    use psila_nrf52::{radio::Radio, timer::Timer};
+    use psila_service::PsilaService;
+
+    use nrf52_utils::{
+        address,
+        binding::{binding_table_size, BINDING_TABLE_KEY},
+        button::{Button, Event},
+        ecb::Ecb,
+        groups::{group_table_size, GROUP_TABLE_KEY},
+        identify::TICKS_PER_SECOND,
+        install_code,
+        kv::{Error, Store},
+        light::{LightState, LIGHT_STATE_SIZE},
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        random::Random,
+        reset,
+        scenes::scene_table_size,
+        service::{
+            handle_frame, network_update, receive_frame, send_messages, transmit, Outgoing,
+            COORDINATOR,
+        },
+    };
+
+    const TIMER_SECOND: u32 = 1_000_000;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+    /// Buttons are sampled every 10 ms
+    const BUTTON_TICK: u32 = TIMER_SECOND / 100;
+    /// Samples before a button changes state
+    const BUTTON_DEBOUNCE: u32 = 3;
+    /// Samples before a press is long, 0.8 seconds
+    const BUTTON_LONG_PRESS: u32 = 80;
+    /// Samples before a press is very long, 10 seconds
+    const BUTTON_VERY_LONG_PRESS: u32 = 1000;
+    const BUTTON: Button = Button::new(BUTTON_DEBOUNCE, BUTTON_LONG_PRESS, BUTTON_VERY_LONG_PRESS);
+
+    const CHANNEL: u8 = 15;
+
+    /// Key of the light state of a channel, the endpoint is added
+    const CHANNEL_LIGHT_STATE_KEY: u16 = 0x0100;
+    /// Key of the scenes of a channel, the endpoint is added
+    const CHANNEL_SCENE_TABLE_KEY: u16 = 0x0200;
+
+    const TX_BUFFER_SIZE: usize = 1024;
+    const RX_BUFFER_SIZE: usize = 1024;
+
+    static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
+    static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
+
+    type Service = PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+
+    #[local]
+    struct LocalResources {
+        rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
+        rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
+        tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        button_pins: [gpio::Pin<gpio::Input<gpio::PullUp>>; CHANNELS],
+        buttons: [Button; CHANNELS],
+    }
+
+    #[shared]
+    struct SharedResources {
+        timer: pac::TIMER1,
+        radio: Radio,
+        service: Service,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+        reset_pending: bool,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
+        let mut timer0 = cx.device.TIMER0;
+        timer0.init();
+
+        // Configure to use external clocks, and start them
+        let _clocks = clocks::Clocks::new(cx.device.CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_external(clocks::LfOscConfiguration::NoExternalNoBypass)
+            .start_lfclk();
+
+        // LED 1 to 4 and button 1 to 4 of the DK
+        let port0 = gpio::p0::Parts::new(cx.device.P0);
+        let leds = [
+            port0
+                .p0_13
+                .into_push_pull_output(gpio::Level::High)
+                .degrade(),
+            port0
+                .p0_14
+                .into_push_pull_output(gpio::Level::High)
+                .degrade(),
+            port0
+                .p0_15
+                .into_push_pull_output(gpio::Level::High)
+                .degrade(),
+            port0
+                .p0_16
+                .into_push_pull_output(gpio::Level::High)
+                .degrade(),
+        ];
+        let button_pins = [
+            port0.p0_11.into_pullup_input().degrade(),
+            port0.p0_12.into_pullup_input().degrade(),
+            port0.p0_24.into_pullup_input().degrade(),
+            port0.p0_25.into_pullup_input().degrade(),
+        ];
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            nrf52840_dk::storage::address(),
+            nrf52840_dk::storage::pages(),
+        );
+        // Written to in init only to keep a generated install code
+        #[cfg_attr(not(feature = "install-code"), allow(unused_mut))]
+        let mut store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+
+        let mut endpoints = ENDPOINTS.iter();
+        let channels = leds.map(|led| {
+            let endpoint = *endpoints.next().unwrap();
+            Channel::new(
+                endpoint,
+                led,
+                load_light_state(&store, endpoint),
+                load_scene_table(&store, endpoint),
+            )
+        });
+        let handler = ClusterHandler::new(
+            channels,
+            load_group_table(&store),
+            extended_address,
+            load_binding_table(&store),
+        );
+
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
+
+        // Preconfigured link key from the install code, which has to be
+        // given to the trust center before joining, or the default link key
+        // without the install-code feature
+        let mut rng = Rng::new(cx.device.RNG);
+        #[cfg(feature = "install-code")]
+        let install_code = {
+            let (install_code, origin) = install_code::provision(
+                install_code::read_uicr(&cx.device.UICR),
+                &mut store,
+                || {
+                    let mut code = [0u8; 16];
+                    rng.random(&mut code);
+                    code
+                },
+            );
+            defmt::info!(
+                "Install code {=[u8]:02x} ({=str})",
+                install_code.as_bytes(),
+                origin.as_str()
+            );
+            Some(install_code)
+        };
+        #[cfg(not(feature = "install-code"))]
+        let install_code = None;
+        let link_key = install_code::link_key(install_code.as_ref(), &mut Ecb::new(cx.device.ECB));
+        // Seed the sequence numbers, so that devices started at the same
+        // time do not collide
+        let mut random = Random::new(rng.random_u32());
+
+        let extended_address = ExtendedAddress::new(extended_address);
+
+        let mut timer1 = cx.device.TIMER1;
+        timer1.init();
+        timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+        timer1.fire_in(3, BUTTON_TICK);
+
+        let stored_network = NetworkState::load(&store);
+        let channel = stored_network.map_or(CHANNEL, |state| state.channel);
+
+        let mut radio = Radio::new(cx.device.RADIO);
+        radio.set_channel(channel);
+        radio.set_transmission_power(8);
+        radio.receive_prepare();
+
+        let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
+        let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
+
+        let crypto_backend = RustCryptoBackend::default();
+        let link_key = Key::from(link_key);
+
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            extended_address,
+            link_key,
+            handler,
+        );
+        service.set_sequence_numbers(random.next_u8(), random.next_u8(), random.next_u8());
+
+        if let Some(state) = stored_network {
+            defmt::info!(
+                "Restore network {=u16:04x}:{=u16:04x}, channel {=u8}",
+                state.pan_identifier,
+                state.short_address,
+                state.channel
+            );
+            service.restore_network_identity(&state.identity());
+        }
+
+        (
+            SharedResources {
+                timer: timer1,
+                radio,
+                service,
+                stored_network,
+                store,
+                reset_pending: false,
+            },
+            LocalResources {
+                rx_producer,
+                rx_consumer,
+                tx_consumer,
+                button_pins,
+                buttons: [BUTTON; CHANNELS],
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Read the stored state of a channel, the default state if there is
+    /// none
+    fn load_light_state(store: &Store<NvmcStorage>, endpoint: u8) -> LightState {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        let key = CHANNEL_LIGHT_STATE_KEY + u16::from(endpoint);
+        match store.read(key, &mut data) {
+            Ok(Some(length)) => LightState::unpack(&data[..length]).unwrap_or_default(),
+            _ => LightState::default(),
+        }
+    }
+
+    /// Read the stored group memberships, an empty table if there is none
+    fn load_group_table(store: &Store<NvmcStorage>) -> Groups {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        match store.read(GROUP_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Groups::unpack(&data[..length]).unwrap_or_default(),
+            _ => Groups::default(),
+        }
+    }
+
+    /// Read the stored scenes of a channel, an empty table if there is none
+    fn load_scene_table(store: &Store<NvmcStorage>, endpoint: u8) -> Scenes {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        let key = CHANNEL_SCENE_TABLE_KEY + u16::from(endpoint);
+        match store.read(key, &mut data) {
+            Ok(Some(length)) => Scenes::unpack(&data[..length]).unwrap_or_default(),
+            _ => Scenes::default(),
+        }
+    }
+
+    /// Read the stored bindings, an empty table if there is none
+    fn load_binding_table(store: &Store<NvmcStorage>) -> Bindings {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        match store.read(BINDING_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Bindings::unpack(&data[..length]).unwrap_or_default(),
+            _ => Bindings::default(),
+        }
+    }
+
+    /// Remove the stored state of the channels
+    fn wipe_channels(store: &mut Store<NvmcStorage>) -> Result<(), Error> {
+        for endpoint in ENDPOINTS {
+            store.remove(CHANNEL_LIGHT_STATE_KEY + u16::from(endpoint))?;
+            store.remove(CHANNEL_SCENE_TABLE_KEY + u16::from(endpoint))?;
+        }
+        Ok(())
+    }
+
+    #[task(
+        binds = TIMER1,
+        shared = [service, timer, stored_network, reset_pending],
+        local = [button_pins, buttons]
+    )]
+    fn timer(cx: timer::Context) {
+        let button_pins = cx.local.button_pins;
+        let buttons = cx.local.buttons;
+        (
+            cx.shared.timer,
+            cx.shared.service,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|timer, service, stored_network, reset_pending| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    if *reset_pending {
+                        // The leave has been sent, start over without a network
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    let _ = service.update(timer.now());
+                    timer.fire_in(1, TIMER_SECOND);
+                    if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                        let _ = store_network_state::spawn(state);
+                    }
+                    let handler = service.cluster_library_handler_mut();
+                    while let Some((endpoint, state)) = handler.take_changed() {
+                        let _ = store_light_state::spawn(endpoint, state);
+                    }
+                    if handler.take_groups_changed() {
+                        let _ = store_group_table::spawn(handler.group_table());
+                    }
+                    while let Some((endpoint, scenes)) = handler.take_scenes_changed() {
+                        let _ = store_scene_table::spawn(endpoint, scenes);
+                    }
+                    if handler.take_bindings_changed() {
+                        let _ = store_binding_table::spawn(handler.binding_table());
+                    }
+                    if handler.take_reset_requested() {
+                        let _ = factory_reset::spawn();
+                    }
+                    handler.report_tick();
+                    send_messages(service, Some(COORDINATOR));
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    service.cluster_library_handler_mut().identify_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                if timer.is_compare_event(3) {
+                    timer.ack_compare_event(3);
+                    let handler = service.cluster_library_handler_mut();
+                    for (index, (pin, button)) in
+                        button_pins.iter().zip(buttons.iter_mut()).enumerate()
+                    {
+                        let pressed = pin.is_low().unwrap_or(false);
+                        match button.update(pressed) {
+                            Some(Event::Click) => {
+                                defmt::info!("Button toggle {=u8}", ENDPOINTS[index]);
+                                handler.toggle(index);
+                            }
+                            // Holding button 1 resets the device
+                            Some(Event::VeryLongPress) if index == 0 => {
+                                let _ = factory_reset::spawn();
+                            }
+                            _ => (),
+                        }
+                    }
+                    timer.fire_in(3, BUTTON_TICK);
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the state of a channel to flash
+    #[task(shared = [store], capacity = 4)]
+    fn store_light_state(mut cx: store_light_state::Context, endpoint: u8, state: LightState) {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        let length = state.pack(&mut data);
+        let key = CHANNEL_LIGHT_STATE_KEY + u16::from(endpoint);
+        cx.shared.store.lock(|store| {
+            if store.write(key, &data[..length]).is_err() {
+                defmt::warn!("Failed to store state of channel {=u8}", endpoint);
+            }
+        });
+    }
+
+    /// Write the group memberships to flash
+    #[task(shared = [store])]
+    fn store_group_table(mut cx: store_group_table::Context, groups: Groups) {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        let length = groups.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(GROUP_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store group table");
+            }
+        });
+    }
+
+    /// Write the scenes of a channel to flash
+    #[task(shared = [store], capacity = 4)]
+    fn store_scene_table(mut cx: store_scene_table::Context, endpoint: u8, scenes: Scenes) {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        let length = scenes.pack(&mut data);
+        let key = CHANNEL_SCENE_TABLE_KEY + u16::from(endpoint);
+        cx.shared.store.lock(|store| {
+            if store.write(key, &data[..length]).is_err() {
+                defmt::warn!("Failed to store scenes of channel {=u8}", endpoint);
+            }
+        });
+    }
+
+    /// Write the bindings to flash
+    #[task(shared = [store])]
+    fn store_binding_table(mut cx: store_binding_table::Context, bindings: Bindings) {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        let length = bindings.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(BINDING_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store binding table");
+            }
+        });
+    }
+
+    /// Leave the network and erase the stored state
+    ///
+    /// The device is restarted by the timer task once the leave has been sent.
+    #[task(shared = [service, store, stored_network, reset_pending])]
+    fn factory_reset(cx: factory_reset::Context) {
+        defmt::info!("Factory reset");
+        (
+            cx.shared.service,
+            cx.shared.store,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|service, store, stored_network, reset_pending| {
+                if service.leave().is_err() {
+                    defmt::warn!("Failed to leave the network");
+                }
+                if reset::wipe(store).and(wipe_channels(store)).is_err() {
+                    defmt::warn!("Failed to remove the stored state");
+                }
+                *stored_network = None;
+                *reset_pending = true;
+            });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(binds = RADIO, shared = [radio, service], local = [rx_producer])]
+    fn radio(cx: radio::Context) {
+        let queue = cx.local.rx_producer;
+        (cx.shared.radio, cx.shared.service).lock(|radio, service| {
+            if receive_frame(radio, service, queue).is_err() {
+                defmt::warn!("CCA Busy");
+            }
+            let _ = radio_tx::spawn();
+        });
+    }
+
+    #[task(shared = [service, timer], local = [rx_consumer])]
+    fn radio_rx(mut cx: radio_rx::Context) {
+        let queue = cx.local.rx_consumer;
+        let timestamp = cx.shared.timer.lock(|timer| timer.now());
+        cx.shared.service.lock(|service| {
+            if handle_frame(service, queue, timestamp) {
+                send_messages(service, Some(COORDINATOR));
+                let _ = radio_tx::spawn();
+            }
+        });
+    }
+
+    #[task(shared = [radio], local = [tx_consumer])]
+    fn radio_tx(mut cx: radio_tx::Context) {
+        let queue = cx.local.tx_consumer;
+        cx.shared.radio.lock(|radio| {
+            if !radio.is_tx_busy() {
+                transmit(radio, queue);
+                let _ = radio_rx::spawn();
+            }
+        });
+    }
+}
@@ -0,0 +1,873 @@
+#![no_main]
+#![no_std]
+
+use nrf52840_dk as _;
+
+use rtic::app;
+
+use nrf52840_hal::gpio;
+
+use nrf52840_pac as pac;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::{
+    cluster_library::{AttributeDataType, ClusterLibraryStatus, Destination},
+    device_profile::SimpleDescriptor,
+    Key,
+};
+use psila_service::{self, ClusterLibraryHandler};
+
+use nrf52_utils::{
+    binding::BindingTable,
+    host::{Device, DeviceTable, LinkKeyTable, ZDO_DEVICE_ANNCE},
+    identify::{Effect, Identify, Indication},
+    outbox::{Message, Outbox, Recipient},
+    service::Outgoing,
+};
+
+const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
+const MODEL_IDENTIFIER: &'static str = "Navet";
+
+/// Home automation profile
+const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
+/// Configuration tool device
+const DEVICE_CONFIGURATION_TOOL: u16 = 0x0005;
+
+/// Basic cluster
+const CLUSTER_BASIC: u16 = 0x0000;
+/// Identify cluster
+const CLUSTER_IDENTIFY: u16 = 0x0003;
+/// On/off cluster
+const CLUSTER_ON_OFF: u16 = 0x0006;
+/// Level control cluster
+const CLUSTER_LEVEL_CONTROL: u16 = 0x0008;
+/// Color control cluster
+const CLUSTER_COLOR_CONTROL: u16 = 0x0300;
+
+/// Number of devices that can be stored
+pub const DEVICE_CAPACITY: usize = 32;
+
+pub type Devices = DeviceTable<DEVICE_CAPACITY>;
+pub type LinkKeys = LinkKeyTable<DEVICE_CAPACITY>;
+
+pub struct ClusterHandler {
+    led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+    identify: Identify,
+    devices: Devices,
+    devices_changed: bool,
+    link_keys: LinkKeys,
+    link_keys_changed: bool,
+    outbox: Outbox<8>,
+}
+
+impl ClusterHandler {
+    pub fn new(
+        led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        devices: Devices,
+        link_keys: LinkKeys,
+    ) -> Self {
+        let mut handler = Self {
+            led,
+            identify: Identify::new(),
+            devices,
+            devices_changed: false,
+            link_keys,
+            link_keys_changed: false,
+            outbox: Outbox::new(),
+        };
+        handler.set_led(false);
+        handler
+    }
+
+    fn set_led(&mut self, on: bool) {
+        // The LED is active low
+        if on {
+            let _ = self.led.set_low();
+        } else {
+            let _ = self.led.set_high();
+        }
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => self.set_led(level >= 0x80),
+            Indication::Finished => self.set_led(false),
+            Indication::None => (),
+        }
+    }
+
+    /// Devices in the network, to be stored or listed
+    pub fn device_table(&self) -> Devices {
+        self.devices
+    }
+
+    /// Check if the devices have changed since last call
+    pub fn take_devices_changed(&mut self) -> bool {
+        let changed = self.devices_changed;
+        self.devices_changed = false;
+        changed
+    }
+
+    /// Link keys of devices given an install code, to be stored
+    pub fn link_key_table(&self) -> LinkKeys {
+        self.link_keys
+    }
+
+    /// Check if the link keys have changed since last call
+    pub fn take_link_keys_changed(&mut self) -> bool {
+        let changed = self.link_keys_changed;
+        self.link_keys_changed = false;
+        changed
+    }
+
+    /// Add the link key derived from the install code of a device, returns
+    /// false if the link key table is full
+    pub fn add_link_key(&mut self, extended_address: u64, key: [u8; 16]) -> bool {
+        defmt::info!("Link key for {=u64:016x}", extended_address);
+        if self.link_keys.get(extended_address) == Some(key) {
+            return true;
+        }
+        let added = self.link_keys.add(extended_address, key);
+        self.link_keys_changed |= added;
+        added
+    }
+
+    /// Add a device that joined or announced itself, returns false if the
+    /// device table is full
+    fn add_device(&mut self, device: Device) -> bool {
+        defmt::info!(
+            "Device {=u16:04x} {=u64:016x}",
+            device.short_address,
+            device.extended_address
+        );
+        if self.devices.devices().any(|known| *known == device) {
+            return true;
+        }
+        let added = self.devices.add(device);
+        self.devices_changed |= added;
+        added
+    }
+}
+
+impl Outgoing<0> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    /// The coordinator has no bindings
+    fn binding_table(&self) -> BindingTable<0> {
+        BindingTable::new()
+    }
+}
+
+impl ClusterLibraryHandler for ClusterHandler {
+    fn active_endpoints(&self) -> &[u8] {
+        &[0x01]
+    }
+    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
+        match endpoint {
+            0x01 => Some(SimpleDescriptor::new(
+                0x01,
+                PROFILE_HOME_AUTOMATION,
+                DEVICE_CONFIGURATION_TOOL,
+                0,
+                &[CLUSTER_BASIC, CLUSTER_IDENTIFY],
+                &[
+                    CLUSTER_IDENTIFY,
+                    CLUSTER_ON_OFF,
+                    CLUSTER_LEVEL_CONTROL,
+                    CLUSTER_COLOR_CONTROL,
+                ],
+            )),
+            _ => None,
+        }
+    }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        _response: &mut [u8],
+    ) -> Option<usize> {
+        // Device announcements are broadcast, nothing is sent in response
+        if cluster == ZDO_DEVICE_ANNCE {
+            if let Some(device) = Device::from_announce(request) {
+                self.add_device(device);
+            }
+        }
+        None
+    }
+    fn child_associated(
+        &mut self,
+        short_address: u16,
+        extended_address: u64,
+        rx_on_when_idle: bool,
+    ) -> bool {
+        self.add_device(Device {
+            short_address,
+            extended_address,
+            rx_on_when_idle,
+        })
+    }
+    fn link_key(&self, extended_address: u64) -> Option<Key> {
+        self.link_keys.get(extended_address).map(Key::from)
+    }
+    fn read_attribute(
+        &self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        match (profile, cluster, attribute) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000) => {
+                value[0] = 0x02;
+                Ok((AttributeDataType::Unsigned8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004) => {
+                value[0] = MANUFACTURER_NAME.len() as u8;
+                let end = MANUFACTURER_NAME.len() + 1;
+                value[1..end].copy_from_slice(MANUFACTURER_NAME.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005) => {
+                value[0] = MODEL_IDENTIFIER.len() as u8;
+                let end = MODEL_IDENTIFIER.len() + 1;
+                value[1..end].copy_from_slice(MODEL_IDENTIFIER.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007) => {
+                value[0] = 0x01;
+                Ok((AttributeDataType::Enumeration8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000) => {
+                LittleEndian::write_u16(&mut value[0..2], self.identify.identify_time());
+                Ok((AttributeDataType::Unsigned16, 2))
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn write_attribute(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, attribute, data_type) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007, _) => {
+                Err(ClusterLibraryStatus::ReadOnly)
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, AttributeDataType::Unsigned16) => {
+                self.identify.identify(LittleEndian::read_u16(&value[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, _) => {
+                Err(ClusterLibraryStatus::InvalidValue)
+            }
+            (_, _, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn run_general(
+        &mut self,
+        _profile: u16,
+        _cluster: u16,
+        _destination: Destination,
+        _command: u8,
+        _arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        Err(ClusterLibraryStatus::UnsupportedGeneralCommand)
+    }
+    fn run(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, command) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x00) => {
+                // identify
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                self.identify
+                    .identify(LittleEndian::read_u16(&arguments[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x01) => {
+                // identify query, only answered while identifying
+                if self.identify.is_identifying() {
+                    let mut response = Message::new(
+                        Recipient::Reply,
+                        0x01,
+                        PROFILE_HOME_AUTOMATION,
+                        CLUSTER_IDENTIFY,
+                        0x00,
+                    );
+                    response.append_u16(self.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x40) => {
+                // trigger effect
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                match Effect::from_u8(arguments[0]) {
+                    Some(effect) => {
+                        self.identify.trigger_effect(effect);
+                        Ok(())
+                    }
+                    None => Err(ClusterLibraryStatus::InvalidValue),
+                }
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+    }
+}
+
+#[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
+mod app {
+    use super::{pac, ClusterHandler, Devices, LinkKeys, DEVICE_CAPACITY};
+
+    use bbqueue::{self, BBBuffer};
+
+    use embedded_hal::serial::{Read, Write};
+
+    use nrf52840_hal::{clocks, gpio, uarte, Rng};
+
+    use psila_crypto_rust_crypto::RustCryptoBackend;
+    use psila_data::{
+        cluster_library::{Direction, FrameType},
+        security::DEFAULT_LINK_KEY,
+        ExtendedAddress, Key,
+    };
+    use psila_nrf52::{radio::Radio, timer::Timer};
+    use psila_service::{CommandDestination, PsilaService};
+
+    use nrf52_utils::{
+        address,
+        ecb::Ecb,
+        host::{
+            device_table_size, link_key_table_size, slip_encode, write_device,
+            write_device_list_end, write_network, write_status, Request, SlipDecoder,
+            DEVICE_TABLE_KEY, LINK_KEY_TABLE_KEY, REQUEST_INSTALL_CODE, REQUEST_NETWORK,
+            REQUEST_PERMIT_JOINING, REQUEST_SEND_COMMAND, STATUS_FAILURE, STATUS_INVALID,
+            STATUS_SUCCESS,
+        },
+        identify::TICKS_PER_SECOND,
+        kv::Store,
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        scan::{ChannelScan, FIRST_CHANNEL},
+        service::{handle_frame, network_update, receive_frame, send_messages, transmit},
+    };
+
+    const TIMER_SECOND: u32 = 1_000_000;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+
+    /// Used if the energy detection did not complete
+    const CHANNEL: u8 = 15;
+    /// Energy detection iterations per channel, 128 µs each
+    const ENERGY_DETECT_COUNT: u32 = 1024;
+    /// Short address of the coordinator
+    const COORDINATOR_ADDRESS: u16 = 0x0000;
+
+    /// Largest request or response frame on the serial port
+    const HOST_FRAME_SIZE: usize = 128;
+
+    const TX_BUFFER_SIZE: usize = 1024;
+    const RX_BUFFER_SIZE: usize = 1024;
+
+    static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
+    static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
+
+    type Service = PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+    type HostTx = uarte::UarteTx<pac::UARTE0>;
+
+    #[local]
+    struct LocalResources {
+        rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
+        rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
+        tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        host_rx: uarte::UarteRx<pac::UARTE0>,
+        host_tx: HostTx,
+        decoder: SlipDecoder<HOST_FRAME_SIZE>,
+        /// Derives link keys from install codes given by the host
+        ecb: Ecb,
+        scan: ChannelScan,
+        new_network: NetworkState,
+    }
+
+    #[shared]
+    struct SharedResources {
+        timer: pac::TIMER1,
+        radio: Radio,
+        service: Service,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+        /// Energy detection is running, before the network is formed
+        scanning: bool,
+    }
+
+    #[init(local = [host_tx_buffer: [u8; 1] = [0; 1], host_rx_buffer: [u8; 1] = [0; 1]])]
+    fn init(cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
+        let mut timer0 = cx.device.TIMER0;
+        timer0.init();
+
+        // Configure to use external clocks, and start them
+        let _clocks = clocks::Clocks::new(cx.device.CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_external(clocks::LfOscConfiguration::NoExternalNoBypass)
+            .start_lfclk();
+
+        let port0 = gpio::p0::Parts::new(cx.device.P0);
+        let led_1 = port0
+            .p0_13
+            .into_push_pull_output(gpio::Level::High)
+            .degrade();
+
+        // The virtual serial port of the J-Link
+        let uarte0 = uarte::Uarte::new(
+            cx.device.UARTE0,
+            uarte::Pins {
+                txd: port0
+                    .p0_06
+                    .into_push_pull_output(gpio::Level::High)
+                    .degrade(),
+                rxd: port0.p0_08.into_floating_input().degrade(),
+                cts: Some(port0.p0_07.into_floating_input().degrade()),
+                rts: Some(
+                    port0
+                        .p0_05
+                        .into_push_pull_output(gpio::Level::High)
+                        .degrade(),
+                ),
+            },
+            uarte::Parity::EXCLUDED,
+            uarte::Baudrate::BAUD115200,
+        );
+        let (host_tx, mut host_rx) = uarte0
+            .split(cx.local.host_tx_buffer, cx.local.host_rx_buffer)
+            .unwrap();
+        // Interrupt for every received byte, reading starts the reception
+        unsafe {
+            (*pac::UARTE0::ptr())
+                .intenset
+                .write(|w| w.endrx().set_bit());
+        }
+        let _ = host_rx.read();
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            nrf52840_dk::storage::address(),
+            nrf52840_dk::storage::pages(),
+        );
+        let store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
+
+        // Parameters of a new network, the channel is picked after the scan
+        let mut rng = Rng::new(cx.device.RNG);
+        let mut network_key = [0u8; 16];
+        rng.random(&mut network_key);
+        let new_network = NetworkState {
+            channel: CHANNEL,
+            // PAN identifiers above 0x3fff are reserved
+            pan_identifier: rng.random_u16() & 0x3fff,
+            extended_pan_identifier: extended_address,
+            short_address: COORDINATOR_ADDRESS,
+            parent_short_address: 0xffff,
+            parent_extended_address: 0,
+            network_key,
+            network_key_sequence: 0,
+            network_frame_counter: 0,
+            application_frame_counter: 0,
+        };
+
+        let handler = ClusterHandler::new(
+            led_1,
+            load_device_table(&store),
+            load_link_key_table(&store),
+        );
+
+        let extended_address = ExtendedAddress::new(extended_address);
+
+        let mut timer1 = cx.device.TIMER1;
+        timer1.init();
+        timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+
+        let stored_network = NetworkState::load(&store);
+
+        let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
+        let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
+
+        let crypto_backend = RustCryptoBackend::default();
+        let default_link_key = Key::from(DEFAULT_LINK_KEY);
+
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            extended_address,
+            default_link_key,
+            handler,
+        );
+
+        let mut radio = Radio::new(cx.device.RADIO);
+        radio.set_transmission_power(8);
+
+        let scanning = match stored_network {
+            Some(state) => {
+                defmt::info!(
+                    "Restore network {=u16:04x}, channel {=u8}",
+                    state.pan_identifier,
+                    state.channel
+                );
+                service.form_network(&state.identity());
+                radio.set_channel(state.channel);
+                radio.receive_prepare();
+                false
+            }
+            None => {
+                defmt::info!("Scan for a quiet channel");
+                radio.set_channel(FIRST_CHANNEL);
+                radio.start_energy_detect(ENERGY_DETECT_COUNT);
+                true
+            }
+        };
+
+        (
+            SharedResources {
+                timer: timer1,
+                radio,
+                service,
+                stored_network,
+                store,
+                scanning,
+            },
+            LocalResources {
+                rx_producer,
+                rx_consumer,
+                tx_consumer,
+                host_rx,
+                host_tx,
+                decoder: SlipDecoder::new(),
+                ecb: Ecb::new(cx.device.ECB),
+                scan: ChannelScan::new(),
+                new_network,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Read the stored devices, an empty table if there is none
+    fn load_device_table(store: &Store<NvmcStorage>) -> Devices {
+        let mut data = [0u8; device_table_size(DEVICE_CAPACITY)];
+        match store.read(DEVICE_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Devices::unpack(&data[..length]).unwrap_or_default(),
+            _ => Devices::default(),
+        }
+    }
+
+    /// Read the stored link keys, an empty table if there is none
+    fn load_link_key_table(store: &Store<NvmcStorage>) -> LinkKeys {
+        let mut data = [0u8; link_key_table_size(DEVICE_CAPACITY)];
+        match store.read(LINK_KEY_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => LinkKeys::unpack(&data[..length]).unwrap_or_default(),
+            _ => LinkKeys::default(),
+        }
+    }
+
+    /// Send a response to the host as a SLIP frame
+    fn send_to_host(tx: &mut HostTx, response: &[u8]) {
+        let mut frame = [0u8; HOST_FRAME_SIZE * 2 + 2];
+        if let Some(length) = slip_encode(response, &mut frame) {
+            for &byte in &frame[..length] {
+                while tx.write(byte).is_err() {}
+            }
+            while tx.flush().is_err() {}
+        }
+    }
+
+    /// Handle a request frame from the host
+    fn handle_request(
+        service: &mut Service,
+        stored_network: &Option<NetworkState>,
+        ecb: &mut Ecb,
+        tx: &mut HostTx,
+        frame: &[u8],
+    ) {
+        let mut response = [0u8; 16];
+        let length = match Request::parse(frame) {
+            Some(Request::ListDevices) => {
+                let devices = service.cluster_library_handler_mut().device_table();
+                for device in devices.devices() {
+                    let length = write_device(device, &mut response);
+                    send_to_host(tx, &response[..length]);
+                }
+                write_device_list_end(devices.count(), &mut response)
+            }
+            Some(Request::PermitJoining { duration }) => {
+                defmt::info!("Permit joining for {=u8} s", duration);
+                let status = match service.permit_joining(duration) {
+                    Ok(()) => STATUS_SUCCESS,
+                    Err(_) => STATUS_FAILURE,
+                };
+                write_status(REQUEST_PERMIT_JOINING, status, &mut response)
+            }
+            Some(Request::SendCommand(command)) => {
+                let frame_type = if command.general {
+                    FrameType::Global
+                } else {
+                    FrameType::Local
+                };
+                let direction = if command.from_server {
+                    Direction::ToClient
+                } else {
+                    Direction::ToServer
+                };
+                let status = match service.send_cluster_command(
+                    CommandDestination::Device(command.address, command.endpoint),
+                    command.profile,
+                    command.cluster,
+                    0x01,
+                    frame_type,
+                    direction,
+                    command.command,
+                    command.payload,
+                ) {
+                    Ok(_) => STATUS_SUCCESS,
+                    Err(_) => STATUS_FAILURE,
+                };
+                write_status(REQUEST_SEND_COMMAND, status, &mut response)
+            }
+            Some(Request::Network) => match stored_network {
+                Some(state) => write_network(
+                    state.channel,
+                    state.pan_identifier,
+                    state.extended_pan_identifier,
+                    &mut response,
+                ),
+                None => write_status(REQUEST_NETWORK, STATUS_FAILURE, &mut response),
+            },
+            Some(Request::InstallCode {
+                extended_address,
+                install_code,
+            }) => {
+                let key = install_code.link_key(ecb);
+                let status = if service
+                    .cluster_library_handler_mut()
+                    .add_link_key(extended_address, key)
+                {
+                    STATUS_SUCCESS
+                } else {
+                    STATUS_FAILURE
+                };
+                write_status(REQUEST_INSTALL_CODE, status, &mut response)
+            }
+            None => write_status(frame[0], STATUS_INVALID, &mut response),
+        };
+        send_to_host(tx, &response[..length]);
+    }
+
+    #[task(binds = TIMER1, shared = [service, timer, stored_network])]
+    fn timer(cx: timer::Context) {
+        (cx.shared.timer, cx.shared.service, cx.shared.stored_network).lock(
+            |timer, service, stored_network| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    let _ = service.update(timer.now());
+                    timer.fire_in(1, TIMER_SECOND);
+                    // The network is stored when it is formed
+                    if stored_network.is_some() {
+                        if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                            let _ = store_network_state::spawn(state);
+                        }
+                    }
+                    let handler = service.cluster_library_handler_mut();
+                    if handler.take_devices_changed() {
+                        let _ = store_device_table::spawn(handler.device_table());
+                    }
+                    if handler.take_link_keys_changed() {
+                        let _ = store_link_key_table::spawn(handler.link_key_table());
+                    }
+                    send_messages(service, None);
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    service.cluster_library_handler_mut().identify_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                let _ = radio_tx::spawn();
+            },
+        );
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the devices to flash
+    #[task(shared = [store])]
+    fn store_device_table(mut cx: store_device_table::Context, devices: Devices) {
+        let mut data = [0u8; device_table_size(DEVICE_CAPACITY)];
+        let length = devices.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(DEVICE_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store device table");
+            }
+        });
+    }
+
+    /// Write the link keys to flash
+    #[task(shared = [store])]
+    fn store_link_key_table(mut cx: store_link_key_table::Context, link_keys: LinkKeys) {
+        let mut data = [0u8; link_key_table_size(DEVICE_CAPACITY)];
+        let length = link_keys.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(LINK_KEY_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store link key table");
+            }
+        });
+    }
+
+    /// Requests from the host, one interrupt per received byte
+    #[task(
+        binds = UARTE0_UART0,
+        shared = [service, stored_network],
+        local = [host_rx, host_tx, decoder, ecb]
+    )]
+    fn host(cx: host::Context) {
+        let rx = cx.local.host_rx;
+        let tx = cx.local.host_tx;
+        let decoder = cx.local.decoder;
+        let ecb = cx.local.ecb;
+        let mut service = cx.shared.service;
+        let mut stored_network = cx.shared.stored_network;
+        // Reading a byte restarts the reception, which then is pending
+        while let Ok(byte) = rx.read() {
+            if let Some(frame) = decoder.push(byte) {
+                let network = stored_network.lock(|stored_network| *stored_network);
+                service.lock(|service| handle_request(service, &network, ecb, tx, frame));
+                let _ = radio_tx::spawn();
+            }
+        }
+    }
+
+    /// Select the quietest channel and form the network once every channel
+    /// has been scanned
+    fn scan_channel(
+        radio: &mut Radio,
+        service: &mut Service,
+        scan: &mut ChannelScan,
+        new_network: &NetworkState,
+    ) -> Option<NetworkState> {
+        let level = radio.report_energy_detect()?;
+        let channel = radio.get_channel();
+        scan.record(channel, level);
+        if let Some(next) = ChannelScan::next_channel(channel) {
+            radio.set_channel(next);
+            radio.start_energy_detect(ENERGY_DETECT_COUNT);
+            return None;
+        }
+        let state = NetworkState {
+            channel: scan.quietest().unwrap_or(CHANNEL),
+            ..*new_network
+        };
+        defmt::info!(
+            "Form network {=u16:04x}, channel {=u8}",
+            state.pan_identifier,
+            state.channel
+        );
+        service.form_network(&state.identity());
+        radio.set_channel(state.channel);
+        radio.receive_prepare();
+        Some(state)
+    }
+
+    #[task(
+        binds = RADIO,
+        shared = [radio, service, scanning, stored_network],
+        local = [rx_producer, scan, new_network]
+    )]
+    fn radio(cx: radio::Context) {
+        let queue = cx.local.rx_producer;
+        let scan = cx.local.scan;
+        let new_network = cx.local.new_network;
+        let shared = cx.shared;
+        (
+            shared.radio,
+            shared.service,
+            shared.scanning,
+            shared.stored_network,
+        )
+            .lock(|radio, service, scanning, stored_network| {
+                if *scanning {
+                    if let Some(state) = scan_channel(radio, service, scan, new_network) {
+                        let state = state.with_margin();
+                        *scanning = false;
+                        *stored_network = Some(state);
+                        let _ = store_network_state::spawn(state);
+                    }
+                    return;
+                }
+                if receive_frame(radio, service, queue).is_err() {
+                    defmt::warn!("CCA Busy");
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    #[task(shared = [service, timer], local = [rx_consumer])]
+    fn radio_rx(mut cx: radio_rx::Context) {
+        let queue = cx.local.rx_consumer;
+        let timestamp = cx.shared.timer.lock(|timer| timer.now());
+        cx.shared.service.lock(|service| {
+            if handle_frame(service, queue, timestamp) {
+                send_messages(service, None);
+                let _ = radio_tx::spawn();
+            }
+        });
+    }
+
+    #[task(shared = [radio, scanning], local = [tx_consumer])]
+    fn radio_tx(cx: radio_tx::Context) {
+        let queue = cx.local.tx_consumer;
+        (cx.shared.radio, cx.shared.scanning).lock(|radio, scanning| {
+            // Nothing is sent before the network is formed
+            if !*scanning && !radio.is_tx_busy() {
+                transmit(radio, queue);
+                let _ = radio_rx::spawn();
+            }
+        });
+    }
+}
@@ -0,0 +1,248 @@
+#![no_main]
+#![no_std]
+
+use nrf52840_dk as _;
+
+use rtic::app;
+
+use nrf52840_pac as pac;
+
+use psila_crypto::CryptoBackend;
+use psila_nrf52::timer::Timer;
+
+use nrf52_utils::{
+    cipher::{mmo_hash, BlockCipher, BLOCK_SIZE},
+    vectors::{BLOCK_VECTORS, CCM_VECTORS, HASH_VECTORS},
+};
+
+/// Largest output of the CCM* vectors
+const MAX_OUTPUT_SIZE: usize = 64;
+/// Number of operations timed by the benchmark
+const BENCHMARK_ROUNDS: u32 = 100;
+
+/// Block cipher on top of a psila crypto backend, for the MMO hash
+struct BackendCipher<'a, B: CryptoBackend>(&'a mut B);
+
+impl<'a, B: CryptoBackend> BlockCipher for BackendCipher<'a, B> {
+    fn encrypt_block(&mut self, key: &[u8; BLOCK_SIZE], block: &mut [u8; BLOCK_SIZE]) {
+        let input = *block;
+        if self.0.aes128_ecb_encrypt_set_key(key).is_err()
+            || self.0.aes128_ecb_encrypt_finish(&input, block).is_err()
+        {
+            defmt::warn!("Block encryption failed");
+        }
+    }
+}
+
+fn verdict(passed: bool) -> &'static str {
+    if passed {
+        "ok"
+    } else {
+        "FAILED"
+    }
+}
+
+/// Run the test vectors through a backend and report the time each
+/// operation takes, returns true if all vectors pass
+fn check_backend<B: CryptoBackend>(name: &str, backend: &mut B, timer: &mut pac::TIMER0) -> bool {
+    let mut all_passed = true;
+    for vector in BLOCK_VECTORS.iter() {
+        let mut output = [0u8; BLOCK_SIZE];
+        let start = timer.now();
+        let passed = backend.aes128_ecb_encrypt_set_key(&vector.key).is_ok()
+            && backend
+                .aes128_ecb_encrypt_finish(&vector.clear_text, &mut output)
+                .is_ok()
+            && output == vector.cipher_text;
+        let elapsed = timer.now().wrapping_sub(start);
+        defmt::info!(
+            "{=str} AES-128 block, {=u32} us, {=str}",
+            name,
+            elapsed,
+            verdict(passed)
+        );
+        all_passed &= passed;
+    }
+    for vector in CCM_VECTORS.iter() {
+        let mut output = [0u8; MAX_OUTPUT_SIZE];
+        let start = timer.now();
+        let result = backend.ccmstar_encrypt(
+            &vector.key,
+            &vector.nonce,
+            vector.message,
+            vector.mic_length,
+            vector.additional_data,
+            &mut output,
+        );
+        let elapsed = timer.now().wrapping_sub(start);
+        let passed = match result {
+            Ok(length) => &output[..length] == vector.output,
+            Err(_) => false,
+        };
+        defmt::info!(
+            "{=str} CCM* encrypt {=usize} bytes, {=u32} us, {=str}",
+            name,
+            vector.message.len(),
+            elapsed,
+            verdict(passed)
+        );
+        all_passed &= passed;
+
+        let start = timer.now();
+        let result = backend.ccmstar_decrypt(
+            &vector.key,
+            &vector.nonce,
+            vector.output,
+            vector.mic_length,
+            vector.additional_data,
+            &mut output,
+        );
+        let elapsed = timer.now().wrapping_sub(start);
+        let passed = match result {
+            Ok(length) => &output[..length] == vector.message,
+            Err(_) => false,
+        };
+        defmt::info!(
+            "{=str} CCM* decrypt {=usize} bytes, {=u32} us, {=str}",
+            name,
+            vector.message.len(),
+            elapsed,
+            verdict(passed)
+        );
+        all_passed &= passed;
+
+        // A modified MIC has to be rejected
+        let mut tampered = [0u8; MAX_OUTPUT_SIZE];
+        tampered[..vector.output.len()].copy_from_slice(vector.output);
+        tampered[vector.output.len() - 1] ^= 0x01;
+        let passed = backend
+            .ccmstar_decrypt(
+                &vector.key,
+                &vector.nonce,
+                &tampered[..vector.output.len()],
+                vector.mic_length,
+                vector.additional_data,
+                &mut output,
+            )
+            .is_err();
+        defmt::info!("{=str} CCM* MIC check, {=str}", name, verdict(passed));
+        all_passed &= passed;
+    }
+    for vector in HASH_VECTORS.iter() {
+        let start = timer.now();
+        let hash = mmo_hash(&mut BackendCipher(backend), vector.data);
+        let elapsed = timer.now().wrapping_sub(start);
+        let passed = hash == vector.hash;
+        defmt::info!(
+            "{=str} MMO hash {=usize} bytes, {=u32} us, {=str}",
+            name,
+            vector.data.len(),
+            elapsed,
+            verdict(passed)
+        );
+        all_passed &= passed;
+    }
+    all_passed
+}
+
+/// Time CCM* of a network layer frame, the common operation of the radio
+/// path, and report the average time of an operation
+fn benchmark<B: CryptoBackend>(name: &str, backend: &mut B, timer: &mut pac::TIMER0) {
+    let vector = &CCM_VECTORS[1];
+    let mut output = [0u8; MAX_OUTPUT_SIZE];
+    let start = timer.now();
+    for _ in 0..BENCHMARK_ROUNDS {
+        let _ = backend.ccmstar_encrypt(
+            &vector.key,
+            &vector.nonce,
+            vector.message,
+            vector.mic_length,
+            vector.additional_data,
+            &mut output,
+        );
+    }
+    let encrypt = timer.now().wrapping_sub(start) / BENCHMARK_ROUNDS;
+    let start = timer.now();
+    for _ in 0..BENCHMARK_ROUNDS {
+        let _ = backend.ccmstar_decrypt(
+            &vector.key,
+            &vector.nonce,
+            vector.output,
+            vector.mic_length,
+            vector.additional_data,
+            &mut output,
+        );
+    }
+    let decrypt = timer.now().wrapping_sub(start) / BENCHMARK_ROUNDS;
+    defmt::info!(
+        "{=str} CCM* of a network frame, encrypt {=u32} us, decrypt {=u32} us",
+        name,
+        encrypt,
+        decrypt
+    );
+}
+
+#[app(device = nrf52840_pac, peripherals = true)]
+mod app {
+    use super::{benchmark, check_backend};
+
+    use nrf52840_hal::clocks;
+
+    use psila_crypto_rust_crypto::RustCryptoBackend;
+    use psila_nrf52::timer::Timer;
+
+    use nrf52_utils::ecb::{Ecb, EcbCryptoBackend};
+
+    #[shared]
+    struct SharedResources {}
+
+    #[local]
+    struct LocalResources {}
+
+    #[init]
+    fn init(cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
+        let _clocks = clocks::Clocks::new(cx.device.CLOCK).enable_ext_hfosc();
+
+        let mut timer0 = cx.device.TIMER0;
+        timer0.init();
+
+        let mut software = RustCryptoBackend::default();
+        let software_passed = check_backend("RustCrypto", &mut software, &mut timer0);
+        benchmark("RustCrypto", &mut software, &mut timer0);
+
+        let mut ecb = EcbCryptoBackend::new(Ecb::new(cx.device.ECB));
+        let ecb_passed = check_backend("ECB", &mut ecb, &mut timer0);
+        benchmark("ECB", &mut ecb, &mut timer0);
+
+        #[cfg(feature = "cryptocell")]
+        let cryptocell_passed = match nrf52_cryptocell::CryptoCellBackend::new(cx.device.CRYPTOCELL)
+        {
+            Some(mut cryptocell) => {
+                let passed = check_backend("CryptoCell", &mut cryptocell, &mut timer0);
+                benchmark("CryptoCell", &mut cryptocell, &mut timer0);
+                passed
+            }
+            None => {
+                defmt::error!("CryptoCell initialisation failed");
+                false
+            }
+        };
+        #[cfg(not(feature = "cryptocell"))]
+        let cryptocell_passed = true;
+
+        if software_passed && ecb_passed && cryptocell_passed {
+            defmt::info!("All vectors passed");
+        } else {
+            defmt::error!("Vectors failed");
+        }
+
+        (SharedResources {}, LocalResources {}, init::Monotonics())
+    }
+
+    #[idle]
+    fn idle(_cx: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+}
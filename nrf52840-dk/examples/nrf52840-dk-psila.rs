@@ -1,43 +1,580 @@
 #![no_main]
 #![no_std]
 
-use nrf52840_dk as _;
+use nrf52840_dk::build_info;
 
 use rtic::app;
 
-use nrf52840_hal::gpio;
+use nrf52840_hal::{
+    gpio,
+    pwm::{Channel, Pwm},
+};
 
 use nrf52840_pac as pac;
 
-use embedded_hal::digital::v2::OutputPin;
+use byteorder::{ByteOrder, LittleEndian};
 
 use psila_data::{
-    cluster_library::{AttributeDataType, ClusterLibraryStatus},
+    cluster_library::{AttributeDataType, ClusterLibraryStatus, Destination},
     device_profile::SimpleDescriptor,
 };
 use psila_service::{self, ClusterLibraryHandler};
 
+use nrf52_utils::{
+    attribute::{Attribute, AttributeTable, Value},
+    basic::{
+        DeviceIdentity, BASIC_ATTR_APPLICATION_VERSION, BASIC_ATTR_DATE_CODE,
+        BASIC_ATTR_HW_VERSION, BASIC_ATTR_MANUFACTURER_NAME, BASIC_ATTR_MODEL_IDENTIFIER,
+        BASIC_ATTR_POWER_SOURCE, BASIC_ATTR_STACK_VERSION, BASIC_ATTR_SW_BUILD_ID,
+        BASIC_ATTR_ZCL_VERSION, CLUSTER_BASIC, POWER_SOURCE_MAINS, ZCL_VERSION,
+    },
+    binding::BindingTable,
+    discovery::{self, is_discovery_command, ClusterCommands},
+    groups::{
+        GroupTable, CLUSTER_GROUPS, GROUPS_ATTR_NAME_SUPPORT, GROUPS_COMMANDS_GENERATED,
+        GROUPS_COMMANDS_RECEIVED,
+    },
+    identify::{Effect, Identify, Indication, TICKS_PER_SECOND},
+    light::{LightState, StartUpOnOff},
+    outbox::{Message, Outbox, Recipient},
+    reporting::{ReportingTable, CMD_CONFIGURE_REPORTING, CMD_READ_REPORTING_CONFIGURATION},
+    reset::{handle_leave_request, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS},
+    router::{Router, NWK_CMD_LINK_STATUS},
+    scenes::{
+        SceneState, SceneTable, CLUSTER_SCENES, SCENES_ATTR_CURRENT_GROUP,
+        SCENES_ATTR_CURRENT_SCENE, SCENES_ATTR_NAME_SUPPORT, SCENES_ATTR_SCENE_COUNT,
+        SCENES_ATTR_SCENE_VALID, SCENES_COMMANDS_GENERATED, SCENES_COMMANDS_RECEIVED,
+    },
+    service::Outgoing,
+    transition::Transition,
+};
+
 const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
 const MODEL_IDENTIFIER: &'static str = "Lampan";
+/// Hardware version, the board revision
+const HW_VERSION: u8 = 1;
+/// Psila has not been released, so there is no stack version yet
+const STACK_VERSION: u8 = 0;
+
+/// Identity of the light in the basic cluster
+const IDENTITY: DeviceIdentity = DeviceIdentity {
+    manufacturer_name: MANUFACTURER_NAME,
+    model_identifier: MODEL_IDENTIFIER,
+    application_version: build_info::APPLICATION_VERSION,
+    stack_version: STACK_VERSION,
+    hw_version: HW_VERSION,
+    date_code: build_info::DATE_CODE,
+    power_source: POWER_SOURCE_MAINS,
+    sw_build_id: build_info::SW_BUILD_ID,
+};
+
+/// Server clusters of the light endpoint
+const SERVER_CLUSTERS: [u16; 6] = [
+    CLUSTER_BASIC,
+    0x0003,
+    CLUSTER_GROUPS,
+    CLUSTER_SCENES,
+    0x0006,
+    0x0008,
+];
+
+/// Commands of the server clusters of the light endpoint
+const COMMANDS: [ClusterCommands; 6] = [
+    ClusterCommands {
+        cluster: CLUSTER_BASIC,
+        received: &[BASIC_CMD_RESET_TO_FACTORY_DEFAULTS],
+        generated: &[],
+    },
+    // Identify, identify query and trigger effect, identify query response
+    ClusterCommands {
+        cluster: 0x0003,
+        received: &[0x00, 0x01, 0x40],
+        generated: &[0x00],
+    },
+    ClusterCommands {
+        cluster: CLUSTER_GROUPS,
+        received: &GROUPS_COMMANDS_RECEIVED,
+        generated: &GROUPS_COMMANDS_GENERATED,
+    },
+    ClusterCommands {
+        cluster: CLUSTER_SCENES,
+        received: &SCENES_COMMANDS_RECEIVED,
+        generated: &SCENES_COMMANDS_GENERATED,
+    },
+    // Off, on and toggle
+    ClusterCommands {
+        cluster: 0x0006,
+        received: &[0x00, 0x01, 0x02],
+        generated: &[],
+    },
+    // Move to level, with and without on/off
+    ClusterCommands {
+        cluster: 0x0008,
+        received: &[0x00, 0x04],
+        generated: &[],
+    },
+];
+
+/// Number of group memberships that can be stored
+pub const GROUP_CAPACITY: usize = 8;
+
+pub type Groups = GroupTable<GROUP_CAPACITY>;
+
+/// Number of scenes that can be stored
+pub const SCENE_CAPACITY: usize = 16;
+
+pub type Scenes = SceneTable<SCENE_CAPACITY>;
+
+/// Number of bindings that can be stored
+pub const BINDING_CAPACITY: usize = 16;
+
+pub type Bindings = BindingTable<BINDING_CAPACITY>;
+
+/// Number of neighbours, routers and children, that are tracked
+pub const NEIGHBOUR_CAPACITY: usize = 16;
+
+pub type Routing = Router<NEIGHBOUR_CAPACITY>;
+
+/// Shortest time between reports of an attribute, in seconds
+const REPORT_MIN_INTERVAL: u16 = 1;
+/// Longest time between reports of an attribute, in seconds
+const REPORT_MAX_INTERVAL: u16 = 300;
+/// Reports are sent to the bound destinations, or to the coordinator
+const REPORT_RECIPIENT: Recipient = Recipient::Bound;
+
+/// Lowest level of the light when on
+const LEVEL_MIN: u8 = 0x01;
+/// Highest level of the light
+const LEVEL_MAX: u8 = 0xfe;
+
+/// Transition of the level of the light
+struct LevelTransition {
+    level: Transition<1>,
+    /// On/off state at the end of the transition
+    on_off: bool,
+    /// Level at the end of the transition
+    target: u8,
+}
 
 pub struct ClusterHandler {
     on_off: bool,
-    led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+    level: u8,
+    led: Pwm<pac::PWM0>,
+    start_up_on_off: StartUpOnOff,
+    start_up_level: u8,
+    changed: bool,
+    transition: Option<LevelTransition>,
+    identify: Identify,
+    groups: Groups,
+    groups_changed: bool,
+    scenes: Scenes,
+    scenes_changed: bool,
+    address: u64,
+    bindings: Bindings,
+    bindings_changed: bool,
+    reset_requested: bool,
+    router: Routing,
+    reporting: ReportingTable<2>,
+    outbox: Outbox<8>,
 }
 
 impl ClusterHandler {
-    pub fn new(mut led: gpio::Pin<gpio::Output<gpio::PushPull>>) -> Self {
-        let _ = led.set_high();
-        Self { on_off: false, led }
+    pub fn new(
+        led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        pwm: pac::PWM0,
+        state: LightState,
+        groups: Groups,
+        scenes: Scenes,
+        address: u64,
+        bindings: Bindings,
+    ) -> Self {
+        let start_up = state.start_up();
+        let mut reporting = ReportingTable::new();
+        for attribute in ATTRIBUTES.reportable() {
+            reporting.add(
+                0x01,
+                attribute.cluster,
+                attribute.id,
+                u8::from(attribute.data_type),
+                REPORT_MIN_INTERVAL,
+                REPORT_MAX_INTERVAL,
+            );
+        }
+        let pwm = Pwm::new(pwm);
+        pwm.set_output_pin(Channel::C0, led);
+        let mut handler = Self {
+            on_off: false,
+            level: start_up.level.clamp(LEVEL_MIN, LEVEL_MAX),
+            led: pwm,
+            start_up_on_off: state.start_up_on_off,
+            start_up_level: state.start_up_level,
+            changed: false,
+            transition: None,
+            identify: Identify::new(),
+            groups,
+            groups_changed: false,
+            scenes,
+            scenes_changed: false,
+            address,
+            bindings,
+            bindings_changed: false,
+            reset_requested: false,
+            router: Routing::new(),
+            reporting,
+            outbox: Outbox::new(),
+        };
+        handler.apply_on_off(start_up.on_off);
+        // Store the state if the start-up attributes changed it
+        handler.changed = start_up != state;
+        handler
     }
 
-    pub fn set_on_off(&mut self, enable: bool) {
+    /// Light the LED with a brightness from 0 to 255
+    fn set_led(&mut self, brightness: u8) {
+        // Squared for a perceived linear brightness
+        let brightness = u32::from(brightness);
+        let duty = u32::from(self.led.max_duty()) * brightness * brightness / (255 * 255);
+        // The LED is active low, so it is lit during the off part of the cycle
+        self.led.set_duty_off(Channel::C0, duty as u16);
+    }
+
+    fn update_led(&mut self) {
+        let brightness = if self.on_off { self.level } else { 0 };
+        self.set_led(brightness);
+    }
+
+    fn apply_on_off(&mut self, enable: bool) {
         self.on_off = enable;
-        if self.on_off {
-            let _ = self.led.set_low();
+        self.update_led();
+        self.changed = true;
+    }
+
+    fn apply_level(&mut self, level: u8) {
+        self.level = level.clamp(LEVEL_MIN, LEVEL_MAX);
+        self.update_led();
+        self.changed = true;
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on_off
+    }
+
+    pub fn set_on_off(&mut self, enable: bool) {
+        self.transition = None;
+        self.apply_on_off(enable);
+        self.scenes.invalidate();
+    }
+
+    pub fn set_level(&mut self, level: u8) {
+        self.transition = None;
+        self.apply_level(level);
+        self.scenes.invalidate();
+    }
+
+    /// Start a transition to a level and on/off state, with the transition
+    /// time in tenths of a second
+    ///
+    /// A light that is turned on fades in from the lowest level, a light that
+    /// is turned off fades out to the lowest level.
+    fn start_transition(&mut self, on_off: bool, level: u8, transition_time: u16) {
+        if !self.on_off && !on_off {
+            // Nothing to see, the level of a light that is off is set at once
+            self.transition = None;
+            self.apply_level(level);
+            return;
+        }
+        let level = level.clamp(LEVEL_MIN, LEVEL_MAX);
+        let from = if self.on_off { self.level } else { LEVEL_MIN };
+        let to = if on_off { level } else { LEVEL_MIN };
+        let ticks = u32::from(transition_time) * TICKS_PER_SECOND / 10;
+        self.on_off = self.on_off || on_off;
+        self.transition = Some(LevelTransition {
+            level: Transition::new([u16::from(from)], [u16::from(to)], ticks),
+            on_off,
+            target: level,
+        });
+        self.transition_tick();
+    }
+
+    /// Advance a transition, called `TICKS_PER_SECOND` times per second
+    pub fn transition_tick(&mut self) {
+        let transition = match self.transition.as_mut() {
+            Some(transition) => transition,
+            None => return,
+        };
+        let [level] = transition.level.tick();
+        self.level = level as u8;
+        if transition.level.is_finished() {
+            self.on_off = transition.on_off;
+            self.level = transition.target;
+            self.transition = None;
+            self.changed = true;
+        }
+        // Identification has the LED until it is finished
+        if !self.identify.is_identifying() {
+            self.update_led();
+        }
+    }
+
+    /// Change the level one step up or down, used while a button is held
+    ///
+    /// Dimming up turns an off light on at the lowest level.
+    pub fn dim(&mut self, up: bool) {
+        if !self.on_off {
+            if up {
+                self.apply_level(LEVEL_MIN);
+                self.set_on_off(true);
+            }
+            return;
+        }
+        let level = if up {
+            self.level.saturating_add(1)
         } else {
-            let _ = self.led.set_high();
+            self.level.saturating_sub(1)
+        };
+        self.set_level(level);
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => self.set_led(level),
+            Indication::Finished => self.update_led(),
+            Indication::None => (),
+        }
+    }
+
+    /// Current state of the light, to be stored
+    pub fn light_state(&self) -> LightState {
+        LightState {
+            on_off: self.on_off,
+            level: self.level,
+            start_up_on_off: self.start_up_on_off,
+            start_up_level: self.start_up_level,
+            ..LightState::default()
+        }
+    }
+
+    /// Check if the state has changed since last call
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
+    /// Group memberships, to be stored
+    pub fn group_table(&self) -> Groups {
+        self.groups
+    }
+
+    /// Check if the group memberships have changed since last call
+    pub fn take_groups_changed(&mut self) -> bool {
+        let changed = self.groups_changed;
+        self.groups_changed = false;
+        changed
+    }
+
+    /// Queue the attribute reports that are due, called once per second
+    pub fn report_tick(&mut self) {
+        for attribute in ATTRIBUTES.reportable() {
+            if let Some(value) = (attribute.get)(self).to_u32() {
+                self.reporting
+                    .set_value(0x01, attribute.cluster, attribute.id, value);
+            }
+        }
+        self.reporting
+            .tick(REPORT_RECIPIENT, 0x0104, &mut self.outbox);
+    }
+
+    /// Scenes, to be stored
+    pub fn scene_table(&self) -> Scenes {
+        self.scenes
+    }
+
+    /// Check if the scenes have changed since last call
+    pub fn take_scenes_changed(&mut self) -> bool {
+        let changed = self.scenes_changed;
+        self.scenes_changed = false;
+        changed
+    }
+
+    /// Check if the bindings have changed since last call
+    pub fn take_bindings_changed(&mut self) -> bool {
+        let changed = self.bindings_changed;
+        self.bindings_changed = false;
+        changed
+    }
+
+    /// Check if a factory reset has been requested since last call, by the
+    /// basic cluster or a ZDO leave request
+    pub fn take_reset_requested(&mut self) -> bool {
+        let requested = self.reset_requested;
+        self.reset_requested = false;
+        requested
+    }
+
+    /// Neighbour table and link status timing
+    pub fn router_mut(&mut self) -> &mut Routing {
+        &mut self.router
+    }
+}
+
+type LightAttribute = Attribute<ClusterHandler>;
+
+/// Attributes of the light endpoint
+const ATTRIBUTES: AttributeTable<ClusterHandler> = AttributeTable::new(&[
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_ZCL_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(ZCL_VERSION),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_APPLICATION_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.application_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_STACK_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.stack_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_HW_VERSION,
+        AttributeDataType::Unsigned8,
+        |_| Value::Unsigned8(IDENTITY.hw_version),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MANUFACTURER_NAME,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.manufacturer_name),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_MODEL_IDENTIFIER,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.model_identifier),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_DATE_CODE,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.date_code),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_POWER_SOURCE,
+        AttributeDataType::Enumeration8,
+        |_| Value::Enumeration8(IDENTITY.power_source),
+    ),
+    LightAttribute::new(
+        CLUSTER_BASIC,
+        BASIC_ATTR_SW_BUILD_ID,
+        AttributeDataType::CharacterString,
+        |_| Value::CharacterString(IDENTITY.sw_build_id),
+    ),
+    // Identify time
+    LightAttribute::new(0x0003, 0x0000, AttributeDataType::Unsigned16, |handler| {
+        Value::Unsigned16(handler.identify.identify_time())
+    })
+    .writable(|handler, value| {
+        if let Value::Unsigned16(time) = value {
+            handler.identify.identify(time);
         }
+        Ok(())
+    }),
+    // Group names are not supported
+    LightAttribute::new(
+        CLUSTER_GROUPS,
+        GROUPS_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_COUNT,
+        AttributeDataType::Unsigned8,
+        |handler| Value::Unsigned8(handler.scenes.count()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_SCENE,
+        AttributeDataType::Unsigned8,
+        |handler| Value::Unsigned8(handler.scenes.current_scene()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_CURRENT_GROUP,
+        AttributeDataType::Unsigned16,
+        |handler| Value::Unsigned16(handler.scenes.current_group()),
+    ),
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_SCENE_VALID,
+        AttributeDataType::Boolean,
+        |handler| Value::Boolean(handler.scenes.is_valid()),
+    ),
+    // Scene names are not supported
+    LightAttribute::new(
+        CLUSTER_SCENES,
+        SCENES_ATTR_NAME_SUPPORT,
+        AttributeDataType::Bitmap8,
+        |_| Value::Bitmap8(0x00),
+    ),
+    // On/off
+    LightAttribute::new(0x0006, 0x0000, AttributeDataType::Boolean, |handler| {
+        Value::Boolean(handler.on_off)
+    })
+    .reportable()
+    .writable(|handler, value| {
+        if let Value::Boolean(enable) = value {
+            handler.set_on_off(enable);
+        }
+        Ok(())
+    }),
+    // Start-up on/off
+    LightAttribute::new(0x0006, 0x4003, AttributeDataType::Enumeration8, |handler| {
+        Value::Enumeration8(handler.start_up_on_off as u8)
+    })
+    .writable(|handler, value| match value {
+        Value::Enumeration8(value) => {
+            let start_up =
+                StartUpOnOff::from_u8(value).ok_or(ClusterLibraryStatus::InvalidValue)?;
+            handler.start_up_on_off = start_up;
+            handler.changed = true;
+            Ok(())
+        }
+        _ => Err(ClusterLibraryStatus::InvalidValue),
+    }),
+    // Current level
+    LightAttribute::new(0x0008, 0x0000, AttributeDataType::Unsigned8, |handler| {
+        Value::Unsigned8(handler.level)
+    })
+    .reportable(),
+    // Start-up current level
+    LightAttribute::new(0x0008, 0x4000, AttributeDataType::Unsigned8, |handler| {
+        Value::Unsigned8(handler.start_up_level)
+    })
+    .writable(|handler, value| {
+        if let Value::Unsigned8(level) = value {
+            handler.start_up_level = level;
+            handler.changed = true;
+        }
+        Ok(())
+    }),
+]);
+
+impl Outgoing<BINDING_CAPACITY> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    fn binding_table(&self) -> Bindings {
+        self.bindings
     }
 }
 
@@ -50,19 +587,57 @@ impl ClusterLibraryHandler for ClusterHandler {
             0x01 => Some(SimpleDescriptor::new(
                 0x01,
                 0x0104,
-                0x0100,
+                0x0101,
                 0,
-                &[0x0000, 0x0006],
+                &SERVER_CLUSTERS,
                 &[],
             )),
             _ => None,
         }
     }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        if let Some((length, leave)) =
+            handle_leave_request(self.address, cluster, request, response)
+        {
+            self.reset_requested |= leave;
+            return Some(length);
+        }
+        let (length, changed) =
+            self.bindings
+                .handle_request(self.address, &[0x01], cluster, request, response)?;
+        self.bindings_changed |= changed;
+        Some(length)
+    }
+    fn child_associated(
+        &mut self,
+        short_address: u16,
+        extended_address: u64,
+        rx_on_when_idle: bool,
+    ) -> bool {
+        defmt::info!(
+            "Child {=u16:04x} {=u64:016x} associated",
+            short_address,
+            extended_address
+        );
+        self.router
+            .neighbours
+            .add_child(short_address, extended_address, rx_on_when_idle)
+    }
+    fn network_command(&mut self, source: u16, command: &[u8]) {
+        if command.first() == Some(&NWK_CMD_LINK_STATUS) {
+            self.router.link_status_received(source, command);
+        }
+    }
     fn read_attribute(
         &self,
         profile: u16,
         cluster: u16,
-        _endpoint: u8,
+        _destination: Destination,
         attribute: u16,
         value: &mut [u8],
     ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
@@ -72,64 +647,156 @@ impl ClusterLibraryHandler for ClusterHandler {
             cluster,
             attribute
         );
-        match (profile, cluster, attribute) {
-            (0x0104, 0x0000, 0x0000) => {
-                value[0] = 0x02;
-                Ok((AttributeDataType::Unsigned8, 1))
-            }
-            (0x0104, 0x0000, 0x0004) => {
-                value[0] = MANUFACTURER_NAME.len() as u8;
-                let end = MANUFACTURER_NAME.len() + 1;
-                value[1..end].copy_from_slice(MANUFACTURER_NAME.as_bytes());
-                Ok((AttributeDataType::CharacterString, end))
-            }
-            (0x0104, 0x0000, 0x0005) => {
-                value[0] = MODEL_IDENTIFIER.len() as u8;
-                let end = MODEL_IDENTIFIER.len() + 1;
-                value[1..end].copy_from_slice(MODEL_IDENTIFIER.as_bytes());
-                Ok((AttributeDataType::CharacterString, end))
-            }
-            (0x0104, 0x0000, 0x0007) => {
-                value[0] = 0x01;
-                Ok((AttributeDataType::Enumeration8, 1))
-            }
-            (0x0104, 0x0006, 0x0000) => {
-                value[0] = if self.on_off { 0x01 } else { 0x00 };
-                Ok((AttributeDataType::Boolean, 1))
-            }
-            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        match profile {
+            0x0104 => ATTRIBUTES.read(self, cluster, attribute, value),
+            _ => Err(ClusterLibraryStatus::UnsupportedAttribute),
         }
     }
     fn write_attribute(
         &mut self,
         profile: u16,
         cluster: u16,
-        _endpoint: u8,
+        destination: Destination,
         attribute: u16,
         data_type: AttributeDataType,
         value: &[u8],
     ) -> Result<(), ClusterLibraryStatus> {
-        match (profile, cluster, attribute, data_type) {
-            (0x0104, 0x0000, 0x0000, _) | (0x0104, 0x0000, 0x0007, _) => {
-                Err(ClusterLibraryStatus::ReadOnly)
+        if !self.groups.is_addressed(destination, 0x01) {
+            return Ok(());
+        }
+        match profile {
+            0x0104 => ATTRIBUTES.write(self, cluster, attribute, data_type, value),
+            _ => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn run_general(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        if !self.groups.is_addressed(destination, 0x01) {
+            return Ok(());
+        }
+        match (profile, command) {
+            (0x0104, CMD_CONFIGURE_REPORTING) | (0x0104, CMD_READ_REPORTING_CONFIGURATION) => {
+                let response = self
+                    .reporting
+                    .handle_command(0x01, profile, cluster, command, arguments)?;
+                self.outbox.push(response);
+                Ok(())
             }
-            (0x0104, 0x0006, 0x0000, AttributeDataType::Boolean) => {
-                self.set_on_off(value[0] == 0x01);
+            (0x0104, command) if is_discovery_command(command) => {
+                let response = discovery::handle_command(
+                    &ATTRIBUTES,
+                    &COMMANDS,
+                    0x01,
+                    profile,
+                    cluster,
+                    command,
+                    arguments,
+                )?;
+                self.outbox.push(response);
                 Ok(())
             }
-            (0x0104, 0x0006, 0x0000, _) => Err(ClusterLibraryStatus::InvalidValue),
-            (_, _, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+            (_, _) => Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
         }
     }
     fn run(
         &mut self,
         profile: u16,
         cluster: u16,
-        _endpoint: u8,
+        destination: Destination,
         command: u8,
-        _arguments: &[u8],
+        arguments: &[u8],
     ) -> Result<(), ClusterLibraryStatus> {
+        // Group commands are only for member endpoints
+        if !self.groups.is_addressed(destination, 0x01) {
+            return Ok(());
+        }
         match (profile, cluster, command) {
+            (0x0104, 0x0000, BASIC_CMD_RESET_TO_FACTORY_DEFAULTS) => {
+                // Leaves the network as well
+                self.reset_requested = true;
+                Ok(())
+            }
+            (0x0104, 0x0003, 0x00) => {
+                // identify
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                self.identify
+                    .identify(LittleEndian::read_u16(&arguments[0..2]));
+                Ok(())
+            }
+            (0x0104, 0x0003, 0x01) => {
+                // identify query, only answered while identifying
+                if self.identify.is_identifying() {
+                    let mut response = Message::new(Recipient::Reply, 0x01, 0x0104, 0x0003, 0x00);
+                    response.append_u16(self.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (0x0104, 0x0003, 0x40) => {
+                // trigger effect
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                match Effect::from_u8(arguments[0]) {
+                    Some(effect) => {
+                        self.identify.trigger_effect(effect);
+                        Ok(())
+                    }
+                    None => Err(ClusterLibraryStatus::InvalidValue),
+                }
+            }
+            (0x0104, CLUSTER_GROUPS, _) => {
+                let identifying = self.identify.is_identifying();
+                let (changed, response) = self.groups.handle_command(
+                    0x01,
+                    profile,
+                    destination,
+                    command,
+                    arguments,
+                    identifying,
+                )?;
+                if let Some(response) = response {
+                    self.outbox.push(response);
+                }
+                self.groups_changed |= changed;
+                // Scenes of removed groups are removed as well
+                self.scenes_changed |= self.scenes.retain_groups(0x01, &self.groups);
+                Ok(())
+            }
+            (0x0104, CLUSTER_SCENES, _) => {
+                let current = SceneState {
+                    on_off: Some(self.on_off),
+                    level: Some(self.level),
+                    ..SceneState::default()
+                };
+                let outcome = self.scenes.handle_command(
+                    0x01,
+                    destination,
+                    command,
+                    arguments,
+                    &self.groups,
+                    &current,
+                )?;
+                if let Some(response) = outcome.response {
+                    self.outbox.push(response);
+                }
+                if let Some(recall) = outcome.recall {
+                    // Fields that are missing in the scene keep their value
+                    let on_off = recall.state.on_off.unwrap_or(self.on_off);
+                    let level = recall.state.level.unwrap_or(self.level);
+                    self.start_transition(on_off, level, recall.transition_time);
+                }
+                self.scenes_changed |= outcome.changed;
+                Ok(())
+            }
             (0x0104, 0x0006, 0x00) => {
                 self.set_on_off(false);
                 Ok(())
@@ -142,6 +809,27 @@ impl ClusterLibraryHandler for ClusterHandler {
                 self.set_on_off(!self.on_off);
                 Ok(())
             }
+            (0x0104, 0x0008, 0x00) | (0x0104, 0x0008, 0x04) => {
+                // move to level
+                if arguments.len() < 3 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                let level = arguments[0];
+                // 0xffff selects the on/off transition time, which is zero
+                let transition_time = match LittleEndian::read_u16(&arguments[1..3]) {
+                    0xffff => 0,
+                    time => time,
+                };
+                let on_off = if command == 0x04 {
+                    // with on/off
+                    level > LEVEL_MIN
+                } else {
+                    self.on_off
+                };
+                self.start_transition(on_off, level, transition_time);
+                self.scenes.invalidate();
+                Ok(())
+            }
             (_, _, _) => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
         }
     }
@@ -149,40 +837,134 @@ impl ClusterLibraryHandler for ClusterHandler {
 
 #[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
 mod app {
-    use super::{pac, ClusterHandler};
+    use super::{
+        pac, Bindings, ClusterHandler, Groups, Scenes, BINDING_CAPACITY, GROUP_CAPACITY,
+        SCENE_CAPACITY,
+    };
 
     use bbqueue::{self, BBBuffer};
 
-    use nrf52840_hal::{clocks, gpio};
+    use embedded_hal::digital::v2::InputPin;
 
+    use nrf52840_hal::{clocks, gpio, Rng};
+
+    #[cfg(feature = "cryptocell")]
+    use nrf52_cryptocell::CryptoCellBackend as CryptoBackend;
+    #[cfg(all(feature = "ecb", not(feature = "cryptocell")))]
+    use nrf52_utils::ecb::EcbCryptoBackend as CryptoBackend;
+    #[cfg(not(any(feature = "cryptocell", feature = "ecb")))]
+    use psila_crypto_rust_crypto::RustCryptoBackend as CryptoBackend;
     use psila_crypto_rust_crypto::RustCryptoBackend;
-    use psila_data::{security::DEFAULT_LINK_KEY, ExtendedAddress, Key};
+    use psila_data::{ExtendedAddress, Key};
     use psila_nrf52::{
         radio::{Radio, MAX_PACKET_LENGHT},
         timer::Timer,
     };
-    use psila_service::{self, PsilaService};
+    use psila_service::{NetworkIdentity, PsilaService};
+
+    use nrf52_utils::{
+        address,
+        binding::{binding_table_size, BINDING_TABLE_KEY},
+        button::{Button, Event},
+        cipher::BackendCipher,
+        csma::Backoff,
+        ecb::Ecb,
+        groups::{group_table_size, GROUP_TABLE_KEY},
+        identify::TICKS_PER_SECOND,
+        install_code,
+        kv::Store,
+        light::{LightState, LIGHT_STATE_KEY, LIGHT_STATE_SIZE},
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        random::Random,
+        reset,
+        router::{
+            is_broadcast, relay_frame, write_acknowledge, BroadcastTable, IndirectQueue, MacHeader,
+            NetworkHeader, NextHop, BROADCAST_ALL, BROADCAST_ROUTERS, MAC_ACKNOWLEDGE_SIZE,
+            MAC_CMD_DATA_REQUEST, MAC_FRAME_COMMAND, MAC_FRAME_DATA, MAC_HEADER_SIZE,
+        },
+        scenes::{scene_table_size, SCENE_TABLE_KEY},
+        service::{
+            network_update, receive_frame, send_messages, transmit_with_backoff, Outgoing,
+            COORDINATOR,
+        },
+    };
 
     const TIMER_SECOND: u32 = 1_000_000;
+    /// Largest random delay added to the join attempts
+    const JOIN_JITTER: u32 = TIMER_SECOND / 4;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+    /// Buttons are sampled every 10 ms
+    const BUTTON_TICK: u32 = TIMER_SECOND / 100;
+    /// Samples before a button changes state
+    const BUTTON_DEBOUNCE: u32 = 3;
+    /// Samples before a press is long, 0.8 seconds
+    const BUTTON_LONG_PRESS: u32 = 80;
+    /// Samples before a press is very long, 10 seconds
+    const BUTTON_VERY_LONG_PRESS: u32 = 1000;
+
+    const CHANNEL: u8 = 15;
 
     const TX_BUFFER_SIZE: usize = 1024;
     const RX_BUFFER_SIZE: usize = 1024;
+    const RELAY_BUFFER_SIZE: usize = 1024;
+
+    /// Number of frames held for sleepy children
+    const INDIRECT_CAPACITY: usize = 4;
+    /// Number of broadcasts remembered
+    const BROADCAST_CAPACITY: usize = 16;
+    const FRAME_SIZE: usize = MAX_PACKET_LENGHT as usize;
 
     static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
     static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
+    /// Frames relayed for other devices
+    static RELAY_BUFFER: BBBuffer<RELAY_BUFFER_SIZE> = BBBuffer::new();
+
+    type Service = PsilaService<'static, CryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+    /// Relayed frames are secured in software, the crypto backend belongs to
+    /// the service
+    type RelayCipher = BackendCipher<RustCryptoBackend>;
+
+    /// Frames held for sleepy children, and the broadcasts relayed recently
+    struct RelayTables {
+        indirect: IndirectQueue<INDIRECT_CAPACITY, FRAME_SIZE>,
+        broadcasts: BroadcastTable<BROADCAST_CAPACITY>,
+    }
+
+    /// The last frame sent, and the timer of its backoff when the channel
+    /// was busy
+    struct Csma {
+        timer: pac::TIMER0,
+        backoff: Backoff<FRAME_SIZE>,
+    }
 
     #[local]
     struct LocalResources {
         rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
         rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
         tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        relay_producer: bbqueue::Producer<'static, RELAY_BUFFER_SIZE>,
+        relay_consumer: bbqueue::Consumer<'static, RELAY_BUFFER_SIZE>,
+        relay_cipher: RelayCipher,
+        extended_address: u64,
+        mac_sequence: u8,
+        button_1: gpio::Pin<gpio::Input<gpio::PullUp>>,
+        button: Button,
+        dim_up: bool,
+        join_random: Random,
+        backoff_random: Random,
     }
 
     #[shared]
     struct SharedResources {
         timer: pac::TIMER1,
         radio: Radio,
-        service: PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>,
+        service: Service,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+        reset_pending: bool,
+        relaying: RelayTables,
+        csma: Csma,
     }
 
     #[init]
@@ -201,138 +983,607 @@ mod app {
             .p0_13
             .into_push_pull_output(gpio::Level::Low)
             .degrade();
+        let button_1 = port0.p0_11.into_pullup_input().degrade();
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            nrf52840_dk::storage::address(),
+            nrf52840_dk::storage::pages(),
+        );
+        // Written to in init only to keep a generated install code
+        #[cfg_attr(not(feature = "install-code"), allow(unused_mut))]
+        let mut store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+
+        let handler = ClusterHandler::new(
+            led_1,
+            cx.device.PWM0,
+            load_light_state(&store),
+            load_group_table(&store),
+            load_scene_table(&store),
+            extended_address,
+            load_binding_table(&store),
+        );
+
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
 
-        let handler = ClusterHandler::new(led_1);
-
-        // MAC (EUI-48) address to EUI-64
-        // Add FF FE in the middle
-        //
-        //    01 23 45 67 89 AB
-        //  /  /  /       \  \  \
-        // 01 23 45 FF FE 67 89 AB
-        let devaddr_lo = cx.device.FICR.deviceaddr[0].read().bits();
-        let devaddr_hi = cx.device.FICR.deviceaddr[1].read().bits() as u16;
-        let extended_address = u64::from(devaddr_hi) << 48
-            | u64::from(devaddr_lo & 0xff00_0000) << 40
-            | u64::from(devaddr_lo & 0x00ff_ffff)
-            | 0x0000_00ff_fe00_0000u64;
-        let extended_address = ExtendedAddress::new(extended_address);
+        // Preconfigured link key from the install code, which has to be
+        // given to the trust center before joining, or the default link key
+        // without the install-code feature
+        let mut rng = Rng::new(cx.device.RNG);
+        #[cfg(feature = "install-code")]
+        let install_code = {
+            let (install_code, origin) = install_code::provision(
+                install_code::read_uicr(&cx.device.UICR),
+                &mut store,
+                || {
+                    let mut code = [0u8; 16];
+                    rng.random(&mut code);
+                    code
+                },
+            );
+            defmt::info!(
+                "Install code {=[u8]:02x} ({=str})",
+                install_code.as_bytes(),
+                origin.as_str()
+            );
+            Some(install_code)
+        };
+        #[cfg(not(feature = "install-code"))]
+        let install_code = None;
+        let mut ecb = Ecb::new(cx.device.ECB);
+        let link_key = install_code::link_key(install_code.as_ref(), &mut ecb);
+        // Seed the sequence numbers and backoffs, so that devices started
+        // at the same time do not collide
+        let mut random = Random::new(rng.random_u32());
 
         let mut timer1 = cx.device.TIMER1;
         timer1.init();
         timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+        timer1.fire_in(3, BUTTON_TICK);
+
+        let stored_network = NetworkState::load(&store);
+        let channel = stored_network.map_or(CHANNEL, |state| state.channel);
 
         let mut radio = Radio::new(cx.device.RADIO);
-        radio.set_channel(15);
+        radio.set_channel(channel);
         radio.set_transmission_power(8);
         radio.receive_prepare();
 
         let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
         let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
+        let (relay_producer, relay_consumer) = RELAY_BUFFER.try_split().unwrap();
+
+        // AES-128 and CCM* on the CryptoCell, with the ECB peripheral or in
+        // software
+        #[cfg(feature = "cryptocell")]
+        let crypto_backend = CryptoBackend::new(cx.device.CRYPTOCELL).unwrap();
+        #[cfg(all(feature = "ecb", not(feature = "cryptocell")))]
+        let crypto_backend = CryptoBackend::new(ecb);
+        #[cfg(not(any(feature = "cryptocell", feature = "ecb")))]
+        let crypto_backend = CryptoBackend::default();
+        let link_key = Key::from(link_key);
 
-        let crypto_backend = RustCryptoBackend::default();
-        let default_link_key = Key::from(DEFAULT_LINK_KEY);
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            ExtendedAddress::new(extended_address),
+            link_key,
+            handler,
+        );
+        // Associate as a router, accept associations while joining is
+        // permitted and report new children to the handler
+        service.set_router(true);
+        service.set_sequence_numbers(random.next_u8(), random.next_u8(), random.next_u8());
+
+        if let Some(state) = stored_network {
+            defmt::info!(
+                "Restore network {=u16:04x}:{=u16:04x}, channel {=u8}",
+                state.pan_identifier,
+                state.short_address,
+                state.channel
+            );
+            service.restore_network_identity(&state.identity());
+        }
 
         (
             SharedResources {
                 timer: timer1,
                 radio,
-                service: PsilaService::new(
-                    crypto_backend,
-                    tx_producer,
-                    extended_address,
-                    default_link_key,
-                    handler,
-                ),
+                service,
+                stored_network,
+                store,
+                reset_pending: false,
+                relaying: RelayTables {
+                    indirect: IndirectQueue::new(),
+                    broadcasts: BroadcastTable::new(),
+                },
+                csma: Csma {
+                    timer: timer0,
+                    backoff: Backoff::new(),
+                },
             },
             LocalResources {
                 rx_producer,
                 rx_consumer,
                 tx_consumer,
+                relay_producer,
+                relay_consumer,
+                relay_cipher: BackendCipher::new(RustCryptoBackend::default()),
+                extended_address,
+                mac_sequence: random.next_u8(),
+                button_1,
+                button: Button::new(BUTTON_DEBOUNCE, BUTTON_LONG_PRESS, BUTTON_VERY_LONG_PRESS),
+                dim_up: false,
+                join_random: Random::new(random.next_u32()),
+                backoff_random: Random::new(random.next_u32()),
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = TIMER1, shared = [service, timer])]
+    /// Read the stored light state, the default state if there is none
+    fn load_light_state(store: &Store<NvmcStorage>) -> LightState {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        match store.read(LIGHT_STATE_KEY, &mut data) {
+            Ok(Some(length)) => LightState::unpack(&data[..length]).unwrap_or_default(),
+            _ => LightState::default(),
+        }
+    }
+
+    /// Read the stored group memberships, an empty table if there is none
+    fn load_group_table(store: &Store<NvmcStorage>) -> Groups {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        match store.read(GROUP_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Groups::unpack(&data[..length]).unwrap_or_default(),
+            _ => Groups::default(),
+        }
+    }
+
+    /// Read the stored scenes, an empty table if there is none
+    fn load_scene_table(store: &Store<NvmcStorage>) -> Scenes {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        match store.read(SCENE_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Scenes::unpack(&data[..length]).unwrap_or_default(),
+            _ => Scenes::default(),
+        }
+    }
+
+    /// Read the stored bindings, an empty table if there is none
+    fn load_binding_table(store: &Store<NvmcStorage>) -> Bindings {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        match store.read(BINDING_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Bindings::unpack(&data[..length]).unwrap_or_default(),
+            _ => Bindings::default(),
+        }
+    }
+
+    /// Age the routing state and send Link Status commands, called once per
+    /// second while joined
+    fn route_tick(service: &mut Service, address: u16, tables: &mut RelayTables) {
+        let router = service.cluster_library_handler_mut().router_mut();
+        router.set_address(address);
+        if router.tick() {
+            let mut command = [0u8; 2 + 3 * super::NEIGHBOUR_CAPACITY];
+            let length = router.neighbours.write_link_status(&mut command);
+            // Link Status commands are sent to the neighbours only
+            if service
+                .send_network_command(BROADCAST_ROUTERS, 1, &command[..length])
+                .is_err()
+            {
+                defmt::warn!("Failed to send link status");
+            }
+        }
+        let dropped = tables.indirect.tick();
+        if dropped > 0 {
+            defmt::warn!("Dropped {=usize} frames not polled for", dropped);
+        }
+        tables.broadcasts.tick();
+    }
+
+    /// Relaying of frames for other devices
+    struct Relay<'a> {
+        queue: &'a mut bbqueue::Producer<'static, RELAY_BUFFER_SIZE>,
+        sequence: &'a mut u8,
+        tables: &'a mut RelayTables,
+        cipher: &'a mut RelayCipher,
+        extended_address: u64,
+    }
+
+    impl<'a> Relay<'a> {
+        /// Relay a received frame if it is for another device
+        ///
+        /// Returns true if the frame is for another device and should not be
+        /// passed to the service. Broadcasts are relayed once and passed to
+        /// the service as well.
+        fn handle(&mut self, service: &mut Service, frame: &[u8], lqi: u8) -> bool {
+            let identity = match service.network_identity() {
+                Some(identity) => identity,
+                None => return false,
+            };
+            let mac = match MacHeader::parse(frame) {
+                Some(mac) if mac.pan_identifier == identity.pan_identifier => mac,
+                _ => return false,
+            };
+            let router = service.cluster_library_handler_mut().router_mut();
+            router.neighbours.heard(mac.source, lqi);
+            if mac.frame_type == MAC_FRAME_COMMAND {
+                if frame.get(MAC_HEADER_SIZE) != Some(&MAC_CMD_DATA_REQUEST) {
+                    return false;
+                }
+                // A sleepy child polls, the acknowledge tells it if a frame
+                // follows, then the oldest frame held for it is sent
+                let pending = self.tables.indirect.has_pending(mac.source);
+                self.acknowledge(mac.sequence, pending);
+                let mut data = [0u8; FRAME_SIZE];
+                if let Some(length) = self.tables.indirect.take(mac.source, &mut data) {
+                    self.send(&identity, mac.source, &data[..length]);
+                }
+                return true;
+            }
+            if mac.frame_type != MAC_FRAME_DATA {
+                return false;
+            }
+            let payload = &frame[MAC_HEADER_SIZE..];
+            let header = match NetworkHeader::parse(payload) {
+                Some(header) => header,
+                None => return false,
+            };
+            if header.destination == identity.short_address {
+                return false;
+            }
+            // Broadcasts are passed to the service as well
+            let broadcast = is_broadcast(header.destination);
+            let next_hop = if broadcast {
+                if header.source == identity.short_address
+                    || !self
+                        .tables
+                        .broadcasts
+                        .is_new(header.source, header.sequence)
+                {
+                    return false;
+                }
+                NextHop::Direct(BROADCAST_ALL)
+            } else {
+                router.next_hop(header.destination, identity.parent_short_address)
+            };
+            // Secured again with the frame counter of this device, frames
+            // that cannot be relayed are dropped
+            let key: [u8; 16] = identity.network_key.into();
+            let mut data = [0u8; FRAME_SIZE];
+            let length = match relay_frame(
+                self.cipher,
+                &key,
+                self.extended_address,
+                || service.next_network_frame_counter(),
+                payload,
+                &mut data,
+            ) {
+                Some(length) => length,
+                None => return !broadcast,
+            };
+            let data = &data[..length];
+            match next_hop {
+                NextHop::Direct(next_hop) => self.send(&identity, next_hop, data),
+                NextHop::Indirect(child) => {
+                    if !self.tables.indirect.push(child, data) {
+                        defmt::warn!("No room for frame to {=u16:04x}", child);
+                    }
+                }
+            }
+            !broadcast
+        }
+
+        /// Queue an acknowledge of a data request
+        fn acknowledge(&mut self, sequence: u8, frame_pending: bool) {
+            // Frame and room for the FCS
+            let length = MAC_ACKNOWLEDGE_SIZE + 2;
+            match self.queue.grant_exact(length + 1) {
+                Ok(mut grant) => {
+                    grant[0] = length as u8;
+                    let offset = 1 + write_acknowledge(sequence, frame_pending, &mut grant[1..]);
+                    grant[offset..].fill(0);
+                    grant.commit(length + 1);
+                }
+                Err(_) => {
+                    defmt::warn!("Relay queue full");
+                }
+            }
+        }
+
+        /// Queue a NWK frame with a new MAC header for transmission
+        fn send(&mut self, identity: &NetworkIdentity, destination: u16, payload: &[u8]) {
+            let header = MacHeader {
+                frame_type: MAC_FRAME_DATA,
+                sequence: *self.sequence,
+                pan_identifier: identity.pan_identifier,
+                destination,
+                source: identity.short_address,
+            };
+            *self.sequence = self.sequence.wrapping_add(1);
+            // Header, payload and room for the FCS
+            let length = MAC_HEADER_SIZE + payload.len() + 2;
+            if length > FRAME_SIZE {
+                return;
+            }
+            match self.queue.grant_exact(length + 1) {
+                Ok(mut grant) => {
+                    grant[0] = length as u8;
+                    let offset = 1 + header.write_data(&mut grant[1..]);
+                    grant[offset..offset + payload.len()].copy_from_slice(payload);
+                    grant[offset + payload.len()..].fill(0);
+                    grant.commit(length + 1);
+                }
+                Err(_) => {
+                    defmt::warn!("Relay queue full");
+                }
+            }
+        }
+    }
+
+    #[task(
+        binds = TIMER1,
+        shared = [service, timer, stored_network, reset_pending, relaying],
+        local = [button_1, button, dim_up, join_random]
+    )]
     fn timer(cx: timer::Context) {
-        (cx.shared.timer, cx.shared.service).lock(|timer, service| {
-            if timer.is_compare_event(1) {
-                timer.ack_compare_event(1);
-                let _ = service.update(timer.now());
-                timer.fire_in(1, TIMER_SECOND);
+        let button_1 = cx.local.button_1;
+        let button = cx.local.button;
+        let dim_up = cx.local.dim_up;
+        let join_random = cx.local.join_random;
+        (
+            cx.shared.timer,
+            cx.shared.service,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+            cx.shared.relaying,
+        )
+            .lock(|timer, service, stored_network, reset_pending, relaying| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    if *reset_pending {
+                        // The leave has been sent, start over without a network
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    let _ = service.update(timer.now());
+                    if service.network_identity().is_some() {
+                        timer.fire_in(1, TIMER_SECOND);
+                    } else {
+                        // Spread the beacon requests and association retries
+                        timer.fire_in(1, TIMER_SECOND + join_random.below(JOIN_JITTER));
+                    }
+                    if let Some(identity) = service.network_identity() {
+                        route_tick(service, identity.short_address, relaying);
+                    }
+                    if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                        let _ = store_network_state::spawn(state);
+                    }
+                    let handler = service.cluster_library_handler_mut();
+                    if handler.take_changed() {
+                        let _ = store_light_state::spawn(handler.light_state());
+                    }
+                    if handler.take_groups_changed() {
+                        let _ = store_group_table::spawn(handler.group_table());
+                    }
+                    if handler.take_scenes_changed() {
+                        let _ = store_scene_table::spawn(handler.scene_table());
+                    }
+                    if handler.take_bindings_changed() {
+                        let _ = store_binding_table::spawn(handler.binding_table());
+                    }
+                    if handler.take_reset_requested() {
+                        let _ = factory_reset::spawn();
+                    }
+                    handler.report_tick();
+                    send_messages(service, Some(COORDINATOR));
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    let handler = service.cluster_library_handler_mut();
+                    handler.identify_tick();
+                    handler.transition_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                if timer.is_compare_event(3) {
+                    timer.ack_compare_event(3);
+                    let pressed = button_1.is_low().unwrap_or(false);
+                    let handler = service.cluster_library_handler_mut();
+                    match button.update(pressed) {
+                        Some(Event::Click) => {
+                            defmt::info!("Button toggle");
+                            handler.set_on_off(!handler.is_on());
+                        }
+                        Some(Event::LongPress) => {
+                            // Alternate the direction, always brighten a light that is off
+                            *dim_up = !*dim_up || !handler.is_on();
+                            handler.dim(*dim_up);
+                        }
+                        Some(Event::Held) => handler.dim(*dim_up),
+                        Some(Event::VeryLongPress) => {
+                            let _ = factory_reset::spawn();
+                        }
+                        Some(Event::LongRelease) | None => (),
+                    }
+                    timer.fire_in(3, BUTTON_TICK);
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the light state to flash
+    #[task(shared = [store])]
+    fn store_light_state(mut cx: store_light_state::Context, state: LightState) {
+        let mut data = [0u8; LIGHT_STATE_SIZE];
+        let length = state.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(LIGHT_STATE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store light state");
+            }
+        });
+    }
+
+    /// Write the group memberships to flash
+    #[task(shared = [store])]
+    fn store_group_table(mut cx: store_group_table::Context, groups: Groups) {
+        let mut data = [0u8; group_table_size(GROUP_CAPACITY)];
+        let length = groups.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(GROUP_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store group table");
+            }
+        });
+    }
+
+    /// Write the scenes to flash
+    #[task(shared = [store])]
+    fn store_scene_table(mut cx: store_scene_table::Context, scenes: Scenes) {
+        let mut data = [0u8; scene_table_size(SCENE_CAPACITY)];
+        let length = scenes.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(SCENE_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store scene table");
             }
-            let _ = radio_tx::spawn();
         });
     }
 
-    #[task(binds = RADIO, shared = [radio, service], local = [rx_producer])]
+    /// Write the bindings to flash
+    #[task(shared = [store])]
+    fn store_binding_table(mut cx: store_binding_table::Context, bindings: Bindings) {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        let length = bindings.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(BINDING_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store binding table");
+            }
+        });
+    }
+
+    /// Leave the network and erase the stored state
+    ///
+    /// The device is restarted by the timer task once the leave has been sent.
+    #[task(shared = [service, store, stored_network, reset_pending])]
+    fn factory_reset(cx: factory_reset::Context) {
+        defmt::info!("Factory reset");
+        (
+            cx.shared.service,
+            cx.shared.store,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|service, store, stored_network, reset_pending| {
+                if service.leave().is_err() {
+                    defmt::warn!("Failed to leave the network");
+                }
+                if reset::wipe(store).is_err() {
+                    defmt::warn!("Failed to remove the stored state");
+                }
+                *stored_network = None;
+                *reset_pending = true;
+            });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(
+        binds = RADIO,
+        shared = [radio, service, csma],
+        local = [rx_producer, backoff_random]
+    )]
     fn radio(cx: radio::Context) {
         let queue = cx.local.rx_producer;
-        (cx.shared.radio, cx.shared.service).lock(|radio, service| {
-            let mut packet = [0u8; MAX_PACKET_LENGHT as usize];
-            match radio.receive(&mut packet) {
-                Ok(packet_len) => {
-                    if packet_len > 0 {
-                        match service.handle_acknowledge(&packet[1..packet_len - 1]) {
-                            Ok(to_me) => {
-                                if to_me {
-                                    if let Ok(mut grant) = queue.grant_exact(packet_len) {
-                                        grant.copy_from_slice(&packet[..packet_len]);
-                                        grant.commit(packet_len);
-                                    }
-                                }
-                            }
-                            Err(e) => match e {
-                                psila_service::Error::MalformedPacket => {
-                                    defmt::warn!(
-                                        "service handle acknowledge failed, malformed package"
-                                    );
-                                }
-                                psila_service::Error::NotEnoughSpace => {
-                                    defmt::warn!("service handle acknowledge failed, queue full");
-                                }
-                                _ => {
-                                    defmt::warn!("service handle acknowledge failed");
-                                }
-                            },
-                        }
-                    }
-                }
-                Err(psila_nrf52::radio::Error::CcaBusy) => {
-                    defmt::warn!("CCA Busy");
+        let random = cx.local.backoff_random;
+        (cx.shared.radio, cx.shared.service, cx.shared.csma).lock(|radio, service, csma| {
+            if receive_frame(radio, service, queue).is_err() {
+                match csma.backoff.busy(random) {
+                    Some(0) => csma.backoff.expired(),
+                    Some(delay) => csma.timer.fire_in(1, delay),
+                    None => defmt::warn!("CCA Busy, frame dropped"),
                 }
             }
             let _ = radio_tx::spawn();
         });
     }
 
-    #[task(shared = [service, timer], local = [rx_consumer])]
+    #[task(binds = TIMER0, shared = [csma])]
+    fn backoff_expired(mut cx: backoff_expired::Context) {
+        cx.shared.csma.lock(|csma| {
+            if csma.timer.is_compare_event(1) {
+                csma.timer.ack_compare_event(1);
+                csma.backoff.expired();
+            }
+        });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(
+        shared = [service, timer, relaying],
+        local = [rx_consumer, relay_producer, relay_cipher, extended_address, mac_sequence]
+    )]
     fn radio_rx(mut cx: radio_rx::Context) {
         let queue = cx.local.rx_consumer;
+        let relay_queue = cx.local.relay_producer;
+        let relay_cipher = cx.local.relay_cipher;
+        let extended_address = *cx.local.extended_address;
+        let mac_sequence = cx.local.mac_sequence;
         let timestamp = cx.shared.timer.lock(|timer| timer.now());
-        cx.shared.service.lock(|service| {
+        (cx.shared.service, cx.shared.relaying).lock(|service, relaying| {
             if let Ok(grant) = queue.read() {
                 let packet_length = grant[0] as usize;
-                if let Err(_) = service.receive(timestamp, &grant[1..packet_length - 1]) {
+                let frame = &grant[1..packet_length - 1];
+                // The radio stores the LQI in place of the first FCS byte
+                let lqi = grant[packet_length - 1];
+                let mut relay = Relay {
+                    queue: relay_queue,
+                    sequence: mac_sequence,
+                    tables: relaying,
+                    cipher: relay_cipher,
+                    extended_address,
+                };
+                if relay.handle(service, frame, lqi) {
+                    // Relayed to another device
+                } else if let Err(_) = service.receive(timestamp, frame) {
                     defmt::warn!("service receive failed");
                 }
+                send_messages(service, Some(COORDINATOR));
                 grant.release(packet_length);
                 let _ = radio_tx::spawn();
             }
         });
     }
 
-    #[task(shared = [radio], local = [tx_consumer])]
-    fn radio_tx(mut cx: radio_tx::Context) {
+    #[task(shared = [radio, csma], local = [tx_consumer, relay_consumer])]
+    fn radio_tx(cx: radio_tx::Context) {
         let queue = cx.local.tx_consumer;
-        cx.shared.radio.lock(|radio| {
-            if !radio.is_tx_busy() {
-                if let Ok(grant) = queue.read() {
-                    let packet_length = grant[0] as usize;
-                    let data = &grant[1..=packet_length];
+        let relay_queue = cx.local.relay_consumer;
+        (cx.shared.radio, cx.shared.csma).lock(|radio, csma| {
+            // Other frames wait while a frame backs off
+            if !radio.is_tx_busy() && !csma.backoff.is_waiting() {
+                // A frame that found the channel busy first, then frames from
+                // this device and relayed frames
+                if let Some(data) = csma.backoff.retry() {
                     let _ = radio.queue_transmission(data);
-                    grant.release(packet_length + 1);
+                } else if !transmit_with_backoff(radio, queue, &mut csma.backoff) {
+                    transmit_with_backoff(radio, relay_queue, &mut csma.backoff);
                 }
                 let _ = radio_rx::spawn();
             }
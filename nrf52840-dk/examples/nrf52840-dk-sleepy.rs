@@ -0,0 +1,900 @@
+#![no_main]
+#![no_std]
+
+use nrf52840_dk as _;
+
+use rtic::app;
+
+use nrf52840_hal::gpio;
+
+use nrf52840_pac as pac;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::{
+    cluster_library::{AttributeDataType, ClusterLibraryStatus, Destination},
+    device_profile::SimpleDescriptor,
+};
+use psila_service::{self, ClusterLibraryHandler};
+
+use nrf52_utils::{
+    battery::{
+        battery_percentage_remaining, battery_voltage, CLUSTER_POWER_CONFIGURATION,
+        POWER_CONFIGURATION_ATTR_BATTERY_PERCENTAGE_REMAINING,
+        POWER_CONFIGURATION_ATTR_BATTERY_VOLTAGE,
+    },
+    binding::BindingTable,
+    identify::{Effect, Identify, Indication},
+    outbox::{Message, Outbox, Recipient},
+    reporting::{
+        ReportingTable, CMD_CONFIGURE_REPORTING, CMD_READ_REPORTING_CONFIGURATION, TYPE_SIGNED16,
+        TYPE_UNSIGNED8,
+    },
+    service::Outgoing,
+    temperature::{
+        Filter, CLUSTER_TEMPERATURE_MEASUREMENT, MAX_MEASURED_VALUE, MIN_MEASURED_VALUE,
+        TEMPERATURE_ATTR_MAX_MEASURED_VALUE, TEMPERATURE_ATTR_MEASURED_VALUE,
+        TEMPERATURE_ATTR_MIN_MEASURED_VALUE, TEMPERATURE_ATTR_TOLERANCE, TOLERANCE,
+    },
+};
+
+const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
+const MODEL_IDENTIFIER: &'static str = "Sovande termometern";
+
+/// Home automation profile
+const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
+/// Temperature sensor device
+const DEVICE_TEMPERATURE_SENSOR: u16 = 0x0302;
+
+/// Basic cluster
+const CLUSTER_BASIC: u16 = 0x0000;
+/// Identify cluster
+const CLUSTER_IDENTIFY: u16 = 0x0003;
+
+/// Shortest time between reports of an attribute, in seconds
+const REPORT_MIN_INTERVAL: u16 = 30;
+/// Longest time between reports of an attribute, in seconds
+const REPORT_MAX_INTERVAL: u16 = 3600;
+/// Reports are sent to the bound destinations, or to the coordinator
+const REPORT_RECIPIENT: Recipient = Recipient::Bound;
+
+/// Number of bindings that can be stored
+pub const BINDING_CAPACITY: usize = 16;
+
+pub type Bindings = BindingTable<BINDING_CAPACITY>;
+
+pub struct ClusterHandler {
+    led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+    identify: Identify,
+    address: u64,
+    bindings: Bindings,
+    bindings_changed: bool,
+    filter: Filter,
+    battery_millivolts: Option<u16>,
+    reporting: ReportingTable<3>,
+    outbox: Outbox<8>,
+}
+
+impl ClusterHandler {
+    pub fn new(
+        led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        address: u64,
+        bindings: Bindings,
+    ) -> Self {
+        let mut reporting = ReportingTable::new();
+        for (cluster, attribute, data_type) in [
+            (
+                CLUSTER_TEMPERATURE_MEASUREMENT,
+                TEMPERATURE_ATTR_MEASURED_VALUE,
+                TYPE_SIGNED16,
+            ),
+            (
+                CLUSTER_POWER_CONFIGURATION,
+                POWER_CONFIGURATION_ATTR_BATTERY_VOLTAGE,
+                TYPE_UNSIGNED8,
+            ),
+            (
+                CLUSTER_POWER_CONFIGURATION,
+                POWER_CONFIGURATION_ATTR_BATTERY_PERCENTAGE_REMAINING,
+                TYPE_UNSIGNED8,
+            ),
+        ] {
+            reporting.add(
+                0x01,
+                cluster,
+                attribute,
+                data_type,
+                REPORT_MIN_INTERVAL,
+                REPORT_MAX_INTERVAL,
+            );
+        }
+        let mut handler = Self {
+            led,
+            identify: Identify::new(),
+            address,
+            bindings,
+            bindings_changed: false,
+            filter: Filter::new(),
+            battery_millivolts: None,
+            reporting,
+            outbox: Outbox::new(),
+        };
+        handler.set_led(false);
+        handler
+    }
+
+    fn set_led(&mut self, on: bool) {
+        // The LED is active low
+        if on {
+            let _ = self.led.set_low();
+        } else {
+            let _ = self.led.set_high();
+        }
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => self.set_led(level >= 0x80),
+            Indication::Finished => self.set_led(false),
+            Indication::None => (),
+        }
+    }
+
+    /// Check if the bindings have changed since last call
+    pub fn take_bindings_changed(&mut self) -> bool {
+        let changed = self.bindings_changed;
+        self.bindings_changed = false;
+        changed
+    }
+
+    /// Add a reading of the TEMP peripheral, in 0.25 °C
+    pub fn measure(&mut self, raw: i32) {
+        let value = self.filter.update(raw);
+        defmt::debug!("Temperature {=i16}", value);
+        self.reporting.set_value(
+            0x01,
+            CLUSTER_TEMPERATURE_MEASUREMENT,
+            TEMPERATURE_ATTR_MEASURED_VALUE,
+            u32::from(value as u16),
+        );
+    }
+
+    /// Set the measured battery voltage, in millivolts
+    pub fn set_battery(&mut self, millivolts: u16) {
+        defmt::debug!("Battery {=u16} mV", millivolts);
+        self.battery_millivolts = Some(millivolts);
+        self.reporting.set_value(
+            0x01,
+            CLUSTER_POWER_CONFIGURATION,
+            POWER_CONFIGURATION_ATTR_BATTERY_VOLTAGE,
+            u32::from(battery_voltage(millivolts)),
+        );
+        self.reporting.set_value(
+            0x01,
+            CLUSTER_POWER_CONFIGURATION,
+            POWER_CONFIGURATION_ATTR_BATTERY_PERCENTAGE_REMAINING,
+            u32::from(battery_percentage_remaining(millivolts)),
+        );
+    }
+
+    /// Check if the device is identifying, the device stays awake meanwhile
+    pub fn is_identifying(&self) -> bool {
+        self.identify.is_identifying()
+    }
+
+    /// Queue the attribute reports that are due, called once per second
+    pub fn report_tick(&mut self) {
+        // Nothing is reported before the first measurement
+        if self.filter.is_valid() {
+            self.reporting
+                .tick(REPORT_RECIPIENT, PROFILE_HOME_AUTOMATION, &mut self.outbox);
+        }
+    }
+}
+
+impl Outgoing<BINDING_CAPACITY> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    fn binding_table(&self) -> Bindings {
+        self.bindings
+    }
+}
+
+impl ClusterLibraryHandler for ClusterHandler {
+    fn active_endpoints(&self) -> &[u8] {
+        &[0x01]
+    }
+    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
+        match endpoint {
+            0x01 => Some(SimpleDescriptor::new(
+                0x01,
+                PROFILE_HOME_AUTOMATION,
+                DEVICE_TEMPERATURE_SENSOR,
+                0,
+                &[
+                    CLUSTER_BASIC,
+                    CLUSTER_POWER_CONFIGURATION,
+                    CLUSTER_IDENTIFY,
+                    CLUSTER_TEMPERATURE_MEASUREMENT,
+                ],
+                &[],
+            )),
+            _ => None,
+        }
+    }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let (length, changed) =
+            self.bindings
+                .handle_request(self.address, &[0x01], cluster, request, response)?;
+        self.bindings_changed |= changed;
+        Some(length)
+    }
+    fn read_attribute(
+        &self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        match (profile, cluster, attribute) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000) => {
+                value[0] = 0x02;
+                Ok((AttributeDataType::Unsigned8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004) => {
+                value[0] = MANUFACTURER_NAME.len() as u8;
+                let end = MANUFACTURER_NAME.len() + 1;
+                value[1..end].copy_from_slice(MANUFACTURER_NAME.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005) => {
+                value[0] = MODEL_IDENTIFIER.len() as u8;
+                let end = MODEL_IDENTIFIER.len() + 1;
+                value[1..end].copy_from_slice(MODEL_IDENTIFIER.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007) => {
+                // Battery
+                value[0] = 0x03;
+                Ok((AttributeDataType::Enumeration8, 1))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_POWER_CONFIGURATION,
+                POWER_CONFIGURATION_ATTR_BATTERY_VOLTAGE,
+            ) => {
+                // 0xff when not yet measured
+                value[0] = self.battery_millivolts.map_or(0xff, battery_voltage);
+                Ok((AttributeDataType::Unsigned8, 1))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_POWER_CONFIGURATION,
+                POWER_CONFIGURATION_ATTR_BATTERY_PERCENTAGE_REMAINING,
+            ) => {
+                value[0] = self
+                    .battery_millivolts
+                    .map_or(0xff, battery_percentage_remaining);
+                Ok((AttributeDataType::Unsigned8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000) => {
+                LittleEndian::write_u16(&mut value[0..2], self.identify.identify_time());
+                Ok((AttributeDataType::Unsigned16, 2))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_TEMPERATURE_MEASUREMENT,
+                TEMPERATURE_ATTR_MEASURED_VALUE,
+            ) => {
+                LittleEndian::write_i16(&mut value[0..2], self.filter.value());
+                Ok((AttributeDataType::Signed16, 2))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_TEMPERATURE_MEASUREMENT,
+                TEMPERATURE_ATTR_MIN_MEASURED_VALUE,
+            ) => {
+                LittleEndian::write_i16(&mut value[0..2], MIN_MEASURED_VALUE);
+                Ok((AttributeDataType::Signed16, 2))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_TEMPERATURE_MEASUREMENT,
+                TEMPERATURE_ATTR_MAX_MEASURED_VALUE,
+            ) => {
+                LittleEndian::write_i16(&mut value[0..2], MAX_MEASURED_VALUE);
+                Ok((AttributeDataType::Signed16, 2))
+            }
+            (
+                PROFILE_HOME_AUTOMATION,
+                CLUSTER_TEMPERATURE_MEASUREMENT,
+                TEMPERATURE_ATTR_TOLERANCE,
+            ) => {
+                LittleEndian::write_u16(&mut value[0..2], TOLERANCE);
+                Ok((AttributeDataType::Unsigned16, 2))
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn write_attribute(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, attribute, data_type) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_TEMPERATURE_MEASUREMENT, 0x0000..=0x0003, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_POWER_CONFIGURATION, 0x0020..=0x0021, _) => {
+                Err(ClusterLibraryStatus::ReadOnly)
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, AttributeDataType::Unsigned16) => {
+                self.identify.identify(LittleEndian::read_u16(&value[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, _) => {
+                Err(ClusterLibraryStatus::InvalidValue)
+            }
+            (_, _, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn run_general(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, command) {
+            (PROFILE_HOME_AUTOMATION, CMD_CONFIGURE_REPORTING)
+            | (PROFILE_HOME_AUTOMATION, CMD_READ_REPORTING_CONFIGURATION) => {
+                let response = self
+                    .reporting
+                    .handle_command(0x01, profile, cluster, command, arguments)?;
+                self.outbox.push(response);
+                Ok(())
+            }
+            (_, _) => Err(ClusterLibraryStatus::UnsupportedGeneralCommand),
+        }
+    }
+    fn run(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, command) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x00) => {
+                // identify
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                self.identify
+                    .identify(LittleEndian::read_u16(&arguments[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x01) => {
+                // identify query, only answered while identifying
+                if self.identify.is_identifying() {
+                    let mut response = Message::new(
+                        Recipient::Reply,
+                        0x01,
+                        PROFILE_HOME_AUTOMATION,
+                        CLUSTER_IDENTIFY,
+                        0x00,
+                    );
+                    response.append_u16(self.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x40) => {
+                // trigger effect
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                match Effect::from_u8(arguments[0]) {
+                    Some(effect) => {
+                        self.identify.trigger_effect(effect);
+                        Ok(())
+                    }
+                    None => Err(ClusterLibraryStatus::InvalidValue),
+                }
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+    }
+}
+
+#[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
+mod app {
+    use super::{pac, Bindings, ClusterHandler, BINDING_CAPACITY};
+
+    use bbqueue::{self, BBBuffer};
+
+    use embedded_hal::adc::OneShot;
+
+    #[cfg(feature = "install-code")]
+    use nrf52840_hal::Rng;
+    use nrf52840_hal::{
+        clocks, gpio,
+        rtc::{Rtc, RtcCompareReg, RtcInterrupt},
+        saadc::{InternalVdd, Resolution, Saadc, SaadcConfig},
+    };
+
+    use psila_crypto_rust_crypto::RustCryptoBackend;
+    use psila_data::{ExtendedAddress, Key};
+    use psila_nrf52::{radio::Radio, timer::Timer};
+    use psila_service::PsilaService;
+
+    use nrf52_utils::{
+        address,
+        battery::vdd_millivolts,
+        binding::{binding_table_size, BINDING_TABLE_KEY},
+        ecb::Ecb,
+        identify::TICKS_PER_SECOND,
+        install_code,
+        kv::Store,
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        poll::PollControl,
+        service::{
+            handle_frame, network_update, receive_frame, send_messages, transmit, Outgoing,
+            COORDINATOR,
+        },
+    };
+
+    const TIMER_SECOND: u32 = 1_000_000;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+    /// Time the receiver is kept on after polling the parent
+    const RECEIVE_WINDOW: u32 = TIMER_SECOND / 20;
+    /// Time between temperature and battery readings, in seconds
+    const MEASUREMENT_INTERVAL: u32 = 30;
+
+    /// RTC frequency with no prescaler
+    const RTC_SECOND: u32 = 32_768;
+    /// Time between polls of the parent, in RTC ticks
+    const LONG_POLL_INTERVAL: u32 = 5 * RTC_SECOND;
+    /// Time between polls of the parent when waiting for a response, in RTC
+    /// ticks
+    const SHORT_POLL_INTERVAL: u32 = RTC_SECOND / 4;
+    /// Number of polls at the short interval after sending or receiving
+    const FAST_POLLS: u8 = 8;
+    /// The RTC counter is 24 bits
+    const RTC_COUNTER_MASK: u32 = 0x00ff_ffff;
+
+    const CHANNEL: u8 = 15;
+
+    const TX_BUFFER_SIZE: usize = 1024;
+    const RX_BUFFER_SIZE: usize = 1024;
+
+    static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
+    static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
+
+    type Service = PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+
+    #[local]
+    struct LocalResources {
+        rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
+        rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
+        tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        temp: pac::TEMP,
+        saadc: Saadc,
+        measurement_elapsed: u32,
+        /// Set while the device stays awake between polls
+        awake: bool,
+    }
+
+    #[shared]
+    struct SharedResources {
+        timer: pac::TIMER1,
+        rtc: Rtc<pac::RTC0>,
+        radio: Radio,
+        service: Service,
+        poll: PollControl,
+        /// RTC ticks slept that are not yet accounted as seconds
+        slept: u32,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
+        let mut timer0 = cx.device.TIMER0;
+        timer0.init();
+
+        // Configure to use external clocks, and start them
+        //
+        // The high frequency clock is stopped between polls, the low
+        // frequency clock runs the RTC that wakes the device.
+        let _clocks = clocks::Clocks::new(cx.device.CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_external(clocks::LfOscConfiguration::NoExternalNoBypass)
+            .start_lfclk();
+
+        let port0 = gpio::p0::Parts::new(cx.device.P0);
+        let led_1 = port0
+            .p0_13
+            .into_push_pull_output(gpio::Level::High)
+            .degrade();
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            nrf52840_dk::storage::address(),
+            nrf52840_dk::storage::pages(),
+        );
+        // Written to in init only to keep a generated install code
+        #[cfg_attr(not(feature = "install-code"), allow(unused_mut))]
+        let mut store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+
+        let handler = ClusterHandler::new(led_1, extended_address, load_binding_table(&store));
+
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
+
+        // Preconfigured link key from the install code, which has to be
+        // given to the trust center before joining, or the default link key
+        // without the install-code feature
+        #[cfg(feature = "install-code")]
+        let install_code = {
+            let mut rng = Rng::new(cx.device.RNG);
+            let (install_code, origin) = install_code::provision(
+                install_code::read_uicr(&cx.device.UICR),
+                &mut store,
+                || {
+                    let mut code = [0u8; 16];
+                    rng.random(&mut code);
+                    code
+                },
+            );
+            defmt::info!(
+                "Install code {=[u8]:02x} ({=str})",
+                install_code.as_bytes(),
+                origin.as_str()
+            );
+            Some(install_code)
+        };
+        #[cfg(not(feature = "install-code"))]
+        let install_code = None;
+        let link_key = install_code::link_key(install_code.as_ref(), &mut Ecb::new(cx.device.ECB));
+
+        let extended_address = ExtendedAddress::new(extended_address);
+
+        let mut timer1 = cx.device.TIMER1;
+        timer1.init();
+        timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+
+        let mut rtc = Rtc::new(cx.device.RTC0, 0).unwrap();
+        rtc.enable_event(RtcInterrupt::Compare0);
+        rtc.enable_interrupt(RtcInterrupt::Compare0, None);
+        rtc.enable_counter();
+
+        // 12-bit readings of VDD with the internal reference and a gain of 1/6
+        let saadc = Saadc::new(
+            cx.device.SAADC,
+            SaadcConfig {
+                resolution: Resolution::_12BIT,
+                ..SaadcConfig::default()
+            },
+        );
+
+        let stored_network = NetworkState::load(&store);
+        let channel = stored_network.map_or(CHANNEL, |state| state.channel);
+
+        let mut radio = Radio::new(cx.device.RADIO);
+        radio.set_channel(channel);
+        radio.set_transmission_power(8);
+        radio.receive_prepare();
+
+        let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
+        let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
+
+        let crypto_backend = RustCryptoBackend::default();
+        let link_key = Key::from(link_key);
+
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            extended_address,
+            link_key,
+            handler,
+        );
+        // Clear the receiver on when idle bit of the capability information
+        // sent when associating, the parent keeps the frames to this device
+        // until they are polled for
+        service.set_rx_on_when_idle(false);
+
+        if let Some(state) = stored_network {
+            defmt::info!(
+                "Restore network {=u16:04x}:{=u16:04x}, channel {=u8}",
+                state.pan_identifier,
+                state.short_address,
+                state.channel
+            );
+            service.restore_network_identity(&state.identity());
+        }
+
+        (
+            SharedResources {
+                timer: timer1,
+                rtc,
+                radio,
+                service,
+                poll: PollControl::new(LONG_POLL_INTERVAL, SHORT_POLL_INTERVAL, FAST_POLLS),
+                slept: 0,
+                stored_network,
+                store,
+            },
+            LocalResources {
+                rx_producer,
+                rx_consumer,
+                tx_consumer,
+                temp: cx.device.TEMP,
+                saadc,
+                // Take the first reading at the first tick
+                measurement_elapsed: MEASUREMENT_INTERVAL,
+                awake: false,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Read the stored bindings, an empty table if there is none
+    fn load_binding_table(store: &Store<NvmcStorage>) -> Bindings {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        match store.read(BINDING_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Bindings::unpack(&data[..length]).unwrap_or_default(),
+            _ => Bindings::default(),
+        }
+    }
+
+    /// Take a reading of the TEMP peripheral, in 0.25 °C
+    ///
+    /// The measurement takes about 36 µs.
+    fn read_temperature(temp: &pac::TEMP) -> i32 {
+        temp.tasks_start.write(|w| unsafe { w.bits(1) });
+        while temp.events_datardy.read().bits() == 0 {}
+        temp.events_datardy.write(|w| unsafe { w.bits(0) });
+        let raw = temp.temp.read().bits() as i32;
+        temp.tasks_stop.write(|w| unsafe { w.bits(1) });
+        raw
+    }
+
+    /// Start the high frequency crystal oscillator and the timer after sleep
+    fn wake(timer: &mut pac::TIMER1) {
+        // Safety: the clock registers are only used here and in `sleep` after
+        // the initialisation
+        let clock = unsafe { &*pac::CLOCK::ptr() };
+        clock.events_hfclkstarted.write(|w| unsafe { w.bits(0) });
+        clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+        while clock.events_hfclkstarted.read().bits() == 0 {}
+        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Turn off the receiver, the timer and the high frequency crystal
+    /// oscillator until the next poll
+    ///
+    /// The receiver is enabled again by `Radio::receive_prepare`.
+    fn sleep(timer: &mut pac::TIMER1) {
+        // Safety: the radio is idle, disabling it does not interfere with the
+        // radio driver
+        let radio = unsafe { &*pac::RADIO::ptr() };
+        radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+        timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        // Safety: see `wake`
+        let clock = unsafe { &*pac::CLOCK::ptr() };
+        clock.tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Wake up to poll the parent
+    #[task(
+        binds = RTC0,
+        shared = [rtc, timer, radio, service, slept]
+    )]
+    fn rtc(cx: rtc::Context) {
+        (
+            cx.shared.rtc,
+            cx.shared.timer,
+            cx.shared.radio,
+            cx.shared.service,
+            cx.shared.slept,
+        )
+            .lock(|rtc, timer, radio, service, slept| {
+                if !rtc.is_event_triggered(RtcInterrupt::Compare0) {
+                    return;
+                }
+                rtc.reset_event(RtcInterrupt::Compare0);
+                wake(timer);
+                radio.receive_prepare();
+                // Send a MAC data request, the parent responds with any
+                // frame it holds for this device
+                if service.poll().is_err() {
+                    defmt::warn!("Failed to poll parent");
+                }
+                let seconds = *slept / RTC_SECOND;
+                *slept %= RTC_SECOND;
+                if seconds > 0 {
+                    let _ = measure::spawn(seconds);
+                }
+                timer.fire_in(1, RECEIVE_WINDOW);
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Take readings and queue reports for the seconds that have passed
+    #[task(capacity = 2, shared = [service, poll], local = [temp, saadc, measurement_elapsed])]
+    fn measure(cx: measure::Context, seconds: u32) {
+        let temp = cx.local.temp;
+        let saadc = cx.local.saadc;
+        let measurement_elapsed = cx.local.measurement_elapsed;
+        (cx.shared.service, cx.shared.poll).lock(|service, poll| {
+            let handler = service.cluster_library_handler_mut();
+            for _ in 0..seconds {
+                *measurement_elapsed += 1;
+                if *measurement_elapsed >= MEASUREMENT_INTERVAL {
+                    *measurement_elapsed = 0;
+                    handler.measure(read_temperature(temp));
+                    if let Ok(raw) = saadc.read(&mut InternalVdd) {
+                        handler.set_battery(vdd_millivolts(raw));
+                    }
+                }
+                handler.report_tick();
+            }
+            if send_messages(service, Some(COORDINATOR)) {
+                poll.fast_poll();
+            }
+        });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(
+        binds = TIMER1,
+        shared = [service, timer, rtc, radio, poll, slept, stored_network],
+        local = [awake]
+    )]
+    fn timer(cx: timer::Context) {
+        let awake = cx.local.awake;
+        (
+            cx.shared.timer,
+            cx.shared.rtc,
+            cx.shared.radio,
+            cx.shared.service,
+            cx.shared.poll,
+            cx.shared.slept,
+            cx.shared.stored_network,
+        )
+            .lock(|timer, rtc, radio, service, poll, slept, stored_network| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    let _ = service.update(timer.now());
+                    if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                        let _ = store_network_state::spawn(state);
+                    }
+                    // Stay awake while joining, so that the association
+                    // response is received, and while identifying
+                    let joined = service.network_identity().is_some();
+                    let handler = service.cluster_library_handler_mut();
+                    if handler.take_bindings_changed() {
+                        let _ = store_binding_table::spawn(handler.binding_table());
+                    }
+                    let stay_awake = !joined || handler.is_identifying();
+                    if stay_awake || radio.is_tx_busy() {
+                        if *awake {
+                            let _ = measure::spawn(1);
+                        }
+                        *awake = stay_awake;
+                        timer.fire_in(
+                            1,
+                            if stay_awake {
+                                TIMER_SECOND
+                            } else {
+                                RECEIVE_WINDOW
+                            },
+                        );
+                    } else {
+                        *awake = false;
+                        let interval = poll.next_interval();
+                        *slept += interval;
+                        let wake_at = (rtc.get_counter() + interval) & RTC_COUNTER_MASK;
+                        let _ = rtc.set_compare(RtcCompareReg::Compare0, wake_at);
+                        sleep(timer);
+                    }
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    service.cluster_library_handler_mut().identify_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the bindings to flash
+    #[task(shared = [store])]
+    fn store_binding_table(mut cx: store_binding_table::Context, bindings: Bindings) {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        let length = bindings.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(BINDING_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store binding table");
+            }
+        });
+    }
+
+    #[task(binds = RADIO, shared = [radio, service], local = [rx_producer])]
+    fn radio(cx: radio::Context) {
+        let queue = cx.local.rx_producer;
+        (cx.shared.radio, cx.shared.service).lock(|radio, service| {
+            if receive_frame(radio, service, queue).is_err() {
+                defmt::warn!("CCA Busy");
+            }
+            let _ = radio_tx::spawn();
+        });
+    }
+
+    #[task(shared = [service, timer, poll], local = [rx_consumer])]
+    fn radio_rx(mut cx: radio_rx::Context) {
+        let queue = cx.local.rx_consumer;
+        let timestamp = cx.shared.timer.lock(|timer| timer.now());
+        (cx.shared.service, cx.shared.poll).lock(|service, poll| {
+            if handle_frame(service, queue, timestamp) {
+                // The parent may hold more frames, or a response may follow
+                poll.fast_poll();
+                send_messages(service, Some(COORDINATOR));
+                let _ = radio_tx::spawn();
+            }
+        });
+    }
+
+    #[task(shared = [radio], local = [tx_consumer])]
+    fn radio_tx(mut cx: radio_tx::Context) {
+        let queue = cx.local.tx_consumer;
+        cx.shared.radio.lock(|radio| {
+            if !radio.is_tx_busy() {
+                transmit(radio, queue);
+                let _ = radio_rx::spawn();
+            }
+        });
+    }
+}
@@ -0,0 +1,702 @@
+#![no_main]
+#![no_std]
+
+use nrf52840_dk as _;
+
+use rtic::app;
+
+use nrf52840_hal::gpio;
+
+use nrf52840_pac as pac;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use psila_data::{
+    cluster_library::{AttributeDataType, ClusterLibraryStatus, Destination},
+    device_profile::SimpleDescriptor,
+};
+use psila_service::{self, ClusterLibraryHandler};
+
+use nrf52_utils::{
+    binding::BindingTable,
+    identify::{Effect, Identify, Indication},
+    outbox::{Message, Outbox, Recipient},
+    service::Outgoing,
+};
+
+const MANUFACTURER_NAME: &'static str = "ERIK of Sweden";
+const MODEL_IDENTIFIER: &'static str = "Knappen";
+
+/// Home automation profile
+const PROFILE_HOME_AUTOMATION: u16 = 0x0104;
+/// On/off light switch device
+const DEVICE_ON_OFF_LIGHT_SWITCH: u16 = 0x0103;
+
+/// Basic cluster
+const CLUSTER_BASIC: u16 = 0x0000;
+/// Identify cluster
+const CLUSTER_IDENTIFY: u16 = 0x0003;
+/// On/off cluster
+pub const CLUSTER_ON_OFF: u16 = 0x0006;
+/// Level control cluster
+pub const CLUSTER_LEVEL_CONTROL: u16 = 0x0008;
+
+/// On/off cluster command, off
+pub const ON_OFF_CMD_OFF: u8 = 0x00;
+/// On/off cluster command, on
+pub const ON_OFF_CMD_ON: u8 = 0x01;
+/// On/off cluster command, toggle
+pub const ON_OFF_CMD_TOGGLE: u8 = 0x02;
+/// Level control cluster command, move with on/off
+pub const LEVEL_CONTROL_CMD_MOVE_ON_OFF: u8 = 0x05;
+/// Level control cluster command, step with on/off
+pub const LEVEL_CONTROL_CMD_STEP_ON_OFF: u8 = 0x06;
+/// Level control cluster command, stop with on/off
+pub const LEVEL_CONTROL_CMD_STOP_ON_OFF: u8 = 0x07;
+
+/// Number of bindings that can be stored
+pub const BINDING_CAPACITY: usize = 16;
+
+pub type Bindings = BindingTable<BINDING_CAPACITY>;
+
+pub struct ClusterHandler {
+    led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+    identify: Identify,
+    address: u64,
+    bindings: Bindings,
+    bindings_changed: bool,
+    outbox: Outbox<8>,
+}
+
+impl ClusterHandler {
+    pub fn new(
+        led: gpio::Pin<gpio::Output<gpio::PushPull>>,
+        address: u64,
+        bindings: Bindings,
+    ) -> Self {
+        let mut handler = Self {
+            led,
+            identify: Identify::new(),
+            address,
+            bindings,
+            bindings_changed: false,
+            outbox: Outbox::new(),
+        };
+        handler.set_led(false);
+        handler
+    }
+
+    fn set_led(&mut self, on: bool) {
+        // The LED is active low
+        if on {
+            let _ = self.led.set_low();
+        } else {
+            let _ = self.led.set_high();
+        }
+    }
+
+    /// Advance identification, called `TICKS_PER_SECOND` times per second
+    pub fn identify_tick(&mut self) {
+        match self.identify.tick() {
+            Indication::Level(level) => self.set_led(level >= 0x80),
+            Indication::Finished => self.set_led(false),
+            Indication::None => (),
+        }
+    }
+
+    /// Check if the bindings have changed since last call
+    pub fn take_bindings_changed(&mut self) -> bool {
+        let changed = self.bindings_changed;
+        self.bindings_changed = false;
+        changed
+    }
+
+    /// Queue a command to the devices and groups bound to the cluster
+    pub fn send_command(&mut self, cluster: u16, command: u8, payload: &[u8]) {
+        if self.bindings.destinations(0x01, cluster).next().is_none() {
+            defmt::warn!("Cluster {=u16:04x} is not bound", cluster);
+            return;
+        }
+        let mut message = Message::client(
+            Recipient::Bound,
+            0x01,
+            PROFILE_HOME_AUTOMATION,
+            cluster,
+            command,
+        );
+        message.append(payload);
+        if !self.outbox.push(message) {
+            defmt::warn!("Outbox full");
+        }
+    }
+}
+
+impl Outgoing<BINDING_CAPACITY> for ClusterHandler {
+    fn take_message(&mut self) -> Option<Message> {
+        self.outbox.pop()
+    }
+
+    fn binding_table(&self) -> Bindings {
+        self.bindings
+    }
+}
+
+impl ClusterLibraryHandler for ClusterHandler {
+    fn active_endpoints(&self) -> &[u8] {
+        &[0x01]
+    }
+    fn get_simple_descriptor(&self, endpoint: u8) -> Option<SimpleDescriptor> {
+        match endpoint {
+            0x01 => Some(SimpleDescriptor::new(
+                0x01,
+                PROFILE_HOME_AUTOMATION,
+                DEVICE_ON_OFF_LIGHT_SWITCH,
+                0,
+                &[CLUSTER_BASIC, CLUSTER_IDENTIFY],
+                &[CLUSTER_ON_OFF, CLUSTER_LEVEL_CONTROL],
+            )),
+            _ => None,
+        }
+    }
+    fn device_profile_request(
+        &mut self,
+        cluster: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let (length, changed) =
+            self.bindings
+                .handle_request(self.address, &[0x01], cluster, request, response)?;
+        self.bindings_changed |= changed;
+        Some(length)
+    }
+    fn read_attribute(
+        &self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        value: &mut [u8],
+    ) -> Result<(AttributeDataType, usize), ClusterLibraryStatus> {
+        match (profile, cluster, attribute) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000) => {
+                value[0] = 0x02;
+                Ok((AttributeDataType::Unsigned8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004) => {
+                value[0] = MANUFACTURER_NAME.len() as u8;
+                let end = MANUFACTURER_NAME.len() + 1;
+                value[1..end].copy_from_slice(MANUFACTURER_NAME.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005) => {
+                value[0] = MODEL_IDENTIFIER.len() as u8;
+                let end = MODEL_IDENTIFIER.len() + 1;
+                value[1..end].copy_from_slice(MODEL_IDENTIFIER.as_bytes());
+                Ok((AttributeDataType::CharacterString, end))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007) => {
+                value[0] = 0x01;
+                Ok((AttributeDataType::Enumeration8, 1))
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000) => {
+                LittleEndian::write_u16(&mut value[0..2], self.identify.identify_time());
+                Ok((AttributeDataType::Unsigned16, 2))
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn write_attribute(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        attribute: u16,
+        data_type: AttributeDataType,
+        value: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, attribute, data_type) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0000, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0004, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0005, _)
+            | (PROFILE_HOME_AUTOMATION, CLUSTER_BASIC, 0x0007, _) => {
+                Err(ClusterLibraryStatus::ReadOnly)
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, AttributeDataType::Unsigned16) => {
+                self.identify.identify(LittleEndian::read_u16(&value[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x0000, _) => {
+                Err(ClusterLibraryStatus::InvalidValue)
+            }
+            (_, _, _, _) => Err(ClusterLibraryStatus::UnsupportedAttribute),
+        }
+    }
+    fn run_general(
+        &mut self,
+        _profile: u16,
+        _cluster: u16,
+        _destination: Destination,
+        _command: u8,
+        _arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        Err(ClusterLibraryStatus::UnsupportedGeneralCommand)
+    }
+    fn run(
+        &mut self,
+        profile: u16,
+        cluster: u16,
+        _destination: Destination,
+        command: u8,
+        arguments: &[u8],
+    ) -> Result<(), ClusterLibraryStatus> {
+        match (profile, cluster, command) {
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x00) => {
+                // identify
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                self.identify
+                    .identify(LittleEndian::read_u16(&arguments[0..2]));
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x01) => {
+                // identify query, only answered while identifying
+                if self.identify.is_identifying() {
+                    let mut response = Message::new(
+                        Recipient::Reply,
+                        0x01,
+                        PROFILE_HOME_AUTOMATION,
+                        CLUSTER_IDENTIFY,
+                        0x00,
+                    );
+                    response.append_u16(self.identify.identify_time());
+                    self.outbox.push(response);
+                }
+                Ok(())
+            }
+            (PROFILE_HOME_AUTOMATION, CLUSTER_IDENTIFY, 0x40) => {
+                // trigger effect
+                if arguments.len() < 2 {
+                    return Err(ClusterLibraryStatus::MalformedCommand);
+                }
+                match Effect::from_u8(arguments[0]) {
+                    Some(effect) => {
+                        self.identify.trigger_effect(effect);
+                        Ok(())
+                    }
+                    None => Err(ClusterLibraryStatus::InvalidValue),
+                }
+            }
+            (_, _, _) => Err(ClusterLibraryStatus::UnsupportedClusterCommand),
+        }
+    }
+}
+
+#[app(device = nrf52840_pac, peripherals = true, dispatchers = [QDEC])]
+mod app {
+    use super::{
+        pac, Bindings, ClusterHandler, BINDING_CAPACITY, CLUSTER_LEVEL_CONTROL, CLUSTER_ON_OFF,
+        LEVEL_CONTROL_CMD_MOVE_ON_OFF, LEVEL_CONTROL_CMD_STEP_ON_OFF,
+        LEVEL_CONTROL_CMD_STOP_ON_OFF, ON_OFF_CMD_OFF, ON_OFF_CMD_ON, ON_OFF_CMD_TOGGLE,
+    };
+
+    use bbqueue::{self, BBBuffer};
+
+    use embedded_hal::digital::v2::InputPin;
+
+    #[cfg(feature = "install-code")]
+    use nrf52840_hal::Rng;
+    use nrf52840_hal::{clocks, gpio};
+
+    use psila_crypto_rust_crypto::RustCryptoBackend;
+    use psila_data::{ExtendedAddress, Key};
+    use psila_nrf52::{radio::Radio, timer::Timer};
+    use psila_service::PsilaService;
+
+    use nrf52_utils::{
+        address,
+        binding::{binding_table_size, BINDING_TABLE_KEY},
+        button::{Button, Event},
+        ecb::Ecb,
+        identify::TICKS_PER_SECOND,
+        install_code,
+        kv::Store,
+        network::NetworkState,
+        nvmc::{Nvmc, NvmcStorage},
+        reset,
+        service::{handle_frame, network_update, receive_frame, send_messages, transmit, Outgoing},
+    };
+
+    const TIMER_SECOND: u32 = 1_000_000;
+    const IDENTIFY_TICK: u32 = TIMER_SECOND / TICKS_PER_SECOND;
+    /// Buttons are sampled every 10 ms
+    const BUTTON_TICK: u32 = TIMER_SECOND / 100;
+    /// Samples before a button changes state
+    const BUTTON_DEBOUNCE: u32 = 3;
+    /// Samples before a press is long, 0.8 seconds
+    const BUTTON_LONG_PRESS: u32 = 80;
+    /// Samples before a press is very long, 10 seconds
+    const BUTTON_VERY_LONG_PRESS: u32 = 1000;
+    const BUTTON: Button = Button::new(BUTTON_DEBOUNCE, BUTTON_LONG_PRESS, BUTTON_VERY_LONG_PRESS);
+
+    /// Level change of a step, when button 4 is clicked
+    const DIM_STEP: u8 = 32;
+    /// Level change per second, while button 4 is held
+    const DIM_RATE: u8 = 64;
+    /// Transition time of a step, in tenths of a second
+    const DIM_STEP_TIME: u16 = 5;
+
+    const CHANNEL: u8 = 15;
+
+    const TX_BUFFER_SIZE: usize = 1024;
+    const RX_BUFFER_SIZE: usize = 1024;
+
+    static RX_BUFFER: BBBuffer<RX_BUFFER_SIZE> = BBBuffer::new();
+    static TX_BUFFER: BBBuffer<TX_BUFFER_SIZE> = BBBuffer::new();
+
+    type Service = PsilaService<'static, RustCryptoBackend, ClusterHandler, TX_BUFFER_SIZE>;
+
+    #[local]
+    struct LocalResources {
+        rx_producer: bbqueue::Producer<'static, RX_BUFFER_SIZE>,
+        rx_consumer: bbqueue::Consumer<'static, RX_BUFFER_SIZE>,
+        tx_consumer: bbqueue::Consumer<'static, TX_BUFFER_SIZE>,
+        button_pins: [gpio::Pin<gpio::Input<gpio::PullUp>>; 4],
+        buttons: [Button; 4],
+        dim_up: bool,
+    }
+
+    #[shared]
+    struct SharedResources {
+        timer: pac::TIMER1,
+        radio: Radio,
+        service: Service,
+        stored_network: Option<NetworkState>,
+        store: Store<NvmcStorage>,
+        reset_pending: bool,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (SharedResources, LocalResources, init::Monotonics) {
+        let mut timer0 = cx.device.TIMER0;
+        timer0.init();
+
+        // Configure to use external clocks, and start them
+        let _clocks = clocks::Clocks::new(cx.device.CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_external(clocks::LfOscConfiguration::NoExternalNoBypass)
+            .start_lfclk();
+
+        let port0 = gpio::p0::Parts::new(cx.device.P0);
+        let led_1 = port0
+            .p0_13
+            .into_push_pull_output(gpio::Level::High)
+            .degrade();
+        let button_pins = [
+            port0.p0_11.into_pullup_input().degrade(),
+            port0.p0_12.into_pullup_input().degrade(),
+            port0.p0_24.into_pullup_input().degrade(),
+            port0.p0_25.into_pullup_input().degrade(),
+        ];
+
+        let storage = NvmcStorage::new(
+            Nvmc::new(cx.device.NVMC),
+            nrf52840_dk::storage::address(),
+            nrf52840_dk::storage::pages(),
+        );
+        // Written to in init only to keep a generated install code
+        #[cfg_attr(not(feature = "install-code"), allow(unused_mut))]
+        let mut store = Store::new(storage).unwrap();
+
+        // EUI-64 provisioned in the UICR, or derived from the device address
+        let (extended_address, address_origin) = address::read(&cx.device.UICR, &cx.device.FICR);
+
+        let handler = ClusterHandler::new(led_1, extended_address, load_binding_table(&store));
+
+        defmt::info!(
+            "EUI-64 {=u64:016x} ({=str})",
+            extended_address,
+            address_origin.as_str()
+        );
+
+        // Preconfigured link key from the install code, which has to be
+        // given to the trust center before joining, or the default link key
+        // without the install-code feature
+        #[cfg(feature = "install-code")]
+        let install_code = {
+            let mut rng = Rng::new(cx.device.RNG);
+            let (install_code, origin) = install_code::provision(
+                install_code::read_uicr(&cx.device.UICR),
+                &mut store,
+                || {
+                    let mut code = [0u8; 16];
+                    rng.random(&mut code);
+                    code
+                },
+            );
+            defmt::info!(
+                "Install code {=[u8]:02x} ({=str})",
+                install_code.as_bytes(),
+                origin.as_str()
+            );
+            Some(install_code)
+        };
+        #[cfg(not(feature = "install-code"))]
+        let install_code = None;
+        let link_key = install_code::link_key(install_code.as_ref(), &mut Ecb::new(cx.device.ECB));
+
+        let extended_address = ExtendedAddress::new(extended_address);
+
+        let mut timer1 = cx.device.TIMER1;
+        timer1.init();
+        timer1.fire_in(1, TIMER_SECOND);
+        timer1.fire_in(2, IDENTIFY_TICK);
+        timer1.fire_in(3, BUTTON_TICK);
+
+        let stored_network = NetworkState::load(&store);
+        let channel = stored_network.map_or(CHANNEL, |state| state.channel);
+
+        let mut radio = Radio::new(cx.device.RADIO);
+        radio.set_channel(channel);
+        radio.set_transmission_power(8);
+        radio.receive_prepare();
+
+        let (rx_producer, rx_consumer) = RX_BUFFER.try_split().unwrap();
+        let (tx_producer, tx_consumer) = TX_BUFFER.try_split().unwrap();
+
+        let crypto_backend = RustCryptoBackend::default();
+        let link_key = Key::from(link_key);
+
+        let mut service = PsilaService::new(
+            crypto_backend,
+            tx_producer,
+            extended_address,
+            link_key,
+            handler,
+        );
+
+        if let Some(state) = stored_network {
+            defmt::info!(
+                "Restore network {=u16:04x}:{=u16:04x}, channel {=u8}",
+                state.pan_identifier,
+                state.short_address,
+                state.channel
+            );
+            service.restore_network_identity(&state.identity());
+        }
+
+        (
+            SharedResources {
+                timer: timer1,
+                radio,
+                service,
+                stored_network,
+                store,
+                reset_pending: false,
+            },
+            LocalResources {
+                rx_producer,
+                rx_consumer,
+                tx_consumer,
+                button_pins,
+                buttons: [BUTTON; 4],
+                dim_up: false,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Read the stored bindings, an empty table if there is none
+    fn load_binding_table(store: &Store<NvmcStorage>) -> Bindings {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        match store.read(BINDING_TABLE_KEY, &mut data) {
+            Ok(Some(length)) => Bindings::unpack(&data[..length]).unwrap_or_default(),
+            _ => Bindings::default(),
+        }
+    }
+
+    /// Send the command of a button event
+    ///
+    /// Buttons 1, 2 and 3 send on, off and toggle. Button 4 steps the level
+    /// when clicked and moves the level while held, every press changes the
+    /// direction.
+    fn button_command(
+        handler: &mut ClusterHandler,
+        button: usize,
+        event: Event,
+        dim_up: &mut bool,
+    ) {
+        let mode = if *dim_up { 0x00 } else { 0x01 };
+        match (button, event) {
+            (0, Event::Click) => handler.send_command(CLUSTER_ON_OFF, ON_OFF_CMD_ON, &[]),
+            (1, Event::Click) => handler.send_command(CLUSTER_ON_OFF, ON_OFF_CMD_OFF, &[]),
+            (2, Event::Click) => handler.send_command(CLUSTER_ON_OFF, ON_OFF_CMD_TOGGLE, &[]),
+            (3, Event::Click) => {
+                let time = DIM_STEP_TIME.to_le_bytes();
+                handler.send_command(
+                    CLUSTER_LEVEL_CONTROL,
+                    LEVEL_CONTROL_CMD_STEP_ON_OFF,
+                    &[mode, DIM_STEP, time[0], time[1]],
+                );
+                *dim_up = !*dim_up;
+            }
+            (3, Event::LongPress) => handler.send_command(
+                CLUSTER_LEVEL_CONTROL,
+                LEVEL_CONTROL_CMD_MOVE_ON_OFF,
+                &[mode, DIM_RATE],
+            ),
+            (3, Event::LongRelease) => {
+                handler.send_command(CLUSTER_LEVEL_CONTROL, LEVEL_CONTROL_CMD_STOP_ON_OFF, &[]);
+                *dim_up = !*dim_up;
+            }
+            (_, Event::VeryLongPress) => {
+                let _ = factory_reset::spawn();
+            }
+            (_, _) => (),
+        }
+    }
+
+    #[task(
+        binds = TIMER1,
+        shared = [service, timer, stored_network, reset_pending],
+        local = [button_pins, buttons, dim_up]
+    )]
+    fn timer(cx: timer::Context) {
+        let button_pins = cx.local.button_pins;
+        let buttons = cx.local.buttons;
+        let dim_up = cx.local.dim_up;
+        (
+            cx.shared.timer,
+            cx.shared.service,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|timer, service, stored_network, reset_pending| {
+                if timer.is_compare_event(1) {
+                    timer.ack_compare_event(1);
+                    if *reset_pending {
+                        // The leave has been sent, start over without a network
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    let _ = service.update(timer.now());
+                    timer.fire_in(1, TIMER_SECOND);
+                    if let Some(state) = network_update(service, stored_network, CHANNEL) {
+                        let _ = store_network_state::spawn(state);
+                    }
+                    let handler = service.cluster_library_handler_mut();
+                    if handler.take_bindings_changed() {
+                        let _ = store_binding_table::spawn(handler.binding_table());
+                    }
+                    send_messages(service, None);
+                }
+                if timer.is_compare_event(2) {
+                    timer.ack_compare_event(2);
+                    service.cluster_library_handler_mut().identify_tick();
+                    timer.fire_in(2, IDENTIFY_TICK);
+                }
+                if timer.is_compare_event(3) {
+                    timer.ack_compare_event(3);
+                    let handler = service.cluster_library_handler_mut();
+                    for (n, (pin, button)) in button_pins.iter().zip(buttons.iter_mut()).enumerate()
+                    {
+                        let pressed = pin.is_low().unwrap_or(false);
+                        if let Some(event) = button.update(pressed) {
+                            button_command(handler, n, event, dim_up);
+                        }
+                    }
+                    send_messages(service, None);
+                    timer.fire_in(3, BUTTON_TICK);
+                }
+                let _ = radio_tx::spawn();
+            });
+    }
+
+    /// Write the network state to flash
+    ///
+    /// The CPU is halted while the flash is written or erased.
+    #[task(shared = [store])]
+    fn store_network_state(mut cx: store_network_state::Context, state: NetworkState) {
+        cx.shared.store.lock(|store| {
+            if state.store(store).is_err() {
+                defmt::warn!("Failed to store network state");
+            } else {
+                defmt::info!("Stored network state");
+            }
+        });
+    }
+
+    /// Write the bindings to flash
+    #[task(shared = [store])]
+    fn store_binding_table(mut cx: store_binding_table::Context, bindings: Bindings) {
+        let mut data = [0u8; binding_table_size(BINDING_CAPACITY)];
+        let length = bindings.pack(&mut data);
+        cx.shared.store.lock(|store| {
+            if store.write(BINDING_TABLE_KEY, &data[..length]).is_err() {
+                defmt::warn!("Failed to store binding table");
+            }
+        });
+    }
+
+    /// Leave the network and erase the stored state
+    ///
+    /// The device is restarted by the timer task once the leave has been sent.
+    #[task(shared = [service, store, stored_network, reset_pending])]
+    fn factory_reset(cx: factory_reset::Context) {
+        defmt::info!("Factory reset");
+        (
+            cx.shared.service,
+            cx.shared.store,
+            cx.shared.stored_network,
+            cx.shared.reset_pending,
+        )
+            .lock(|service, store, stored_network, reset_pending| {
+                if service.leave().is_err() {
+                    defmt::warn!("Failed to leave the network");
+                }
+                // The install code is kept, to join again with the same key
+                if reset::wipe(store).is_err() {
+                    defmt::warn!("Failed to remove the stored state");
+                }
+                *stored_network = None;
+                *reset_pending = true;
+            });
+        let _ = radio_tx::spawn();
+    }
+
+    #[task(binds = RADIO, shared = [radio, service], local = [rx_producer])]
+    fn radio(cx: radio::Context) {
+        let queue = cx.local.rx_producer;
+        (cx.shared.radio, cx.shared.service).lock(|radio, service| {
+            if receive_frame(radio, service, queue).is_err() {
+                defmt::warn!("CCA Busy");
+            }
+            let _ = radio_tx::spawn();
+        });
+    }
+
+    #[task(shared = [service, timer], local = [rx_consumer])]
+    fn radio_rx(mut cx: radio_rx::Context) {
+        let queue = cx.local.rx_consumer;
+        let timestamp = cx.shared.timer.lock(|timer| timer.now());
+        cx.shared.service.lock(|service| {
+            if handle_frame(service, queue, timestamp) {
+                send_messages(service, None);
+                let _ = radio_tx::spawn();
+            }
+        });
+    }
+
+    #[task(shared = [radio], local = [tx_consumer])]
+    fn radio_tx(mut cx: radio_tx::Context) {
+        let queue = cx.local.tx_consumer;
+        cx.shared.radio.lock(|radio| {
+            if !radio.is_tx_busy() {
+                transmit(radio, queue);
+                let _ = radio_rx::spawn();
+            }
+        });
+    }
+}
@@ -0,0 +1,7 @@
+/* Flash pages of the key/value store, the last four pages of the flash */
+__storage_start = 0x000fc000;
+__storage_end = __storage_start + 4 * 4K;
+
+/* The end of .data in flash is the end of the application image */
+ASSERT(__sidata + (__edata - __sdata) <= __storage_start,
+  "the application overlaps the key/value store");